@@ -177,6 +177,8 @@ pub const ROOT_ZONE_IRQS_BITMAP: &[BitmapWord] = &get_irqs_bitmap(&[
     0x123, // i2c
 ]);
 
+pub const ROOT_ZONE_IVC_CONFIG: [HvIvcConfig; 0] = [];
+
 pub const ROOT_ARCH_ZONE_CONFIG: HvArchZoneConfig = HvArchZoneConfig {
     plic_base: PLIC_BASE,
     plic_size: PLIC_SIZE,
@@ -105,6 +105,8 @@ pub const HW_IRQS: [u32; 11] = [1, 2, 3, 4, 5, 8, 10, 33, 34, 35, 36];
 pub const ROOT_ZONE_IRQS_BITMAP: &[BitmapWord] =
     &get_irqs_bitmap(&[1, 2, 3, 4, 5, 8, 10, 33, 34, 35, 36]); // ARCH= riscv .It doesn't matter temporarily.
 
+pub const ROOT_ZONE_IVC_CONFIG: [HvIvcConfig; 0] = [];
+
 pub const ROOT_ARCH_ZONE_CONFIG: HvArchZoneConfig = HvArchZoneConfig {
     plic_base: 0x0,
     plic_size: 0x0,
@@ -119,6 +119,8 @@ const ROOT_ZONE_SCREEN_BASE_ADDR: GuestPhysAddr = 0x8000_0000;
 
 pub const ROOT_ZONE_IRQS: [u32; 32] = [0; 32];
 pub const ROOT_ZONE_IOAPIC_BASE: usize = 0xfec0_0000;
+pub const ROOT_ZONE_IVC_CONFIG: [HvIvcConfig; 0] = [];
+
 pub const ROOT_ARCH_ZONE_CONFIG: HvArchZoneConfig = HvArchZoneConfig {
     ioapic_base: ROOT_ZONE_IOAPIC_BASE,
     ioapic_size: 0x1000,
@@ -17,6 +17,8 @@ use crate::{
     arch::{mm::new_s2_memory_set, sysreg::write_sysreg},
     consts::{MAX_CPU_NUM, PAGE_SIZE, PER_CPU_ARRAY_PTR, PER_CPU_SIZE},
     cpu_data::this_cpu_data,
+    device::irqchip::gic_handle_irq,
+    event::wait_for_resume,
     memory::{
         addr::PHYS_VIRT_OFFSET, mm::PARKING_MEMORY_SET, GuestPhysAddr, HostPhysAddr, MemFlags,
         MemoryRegion, VirtAddr, PARKING_INST_PAGE,
@@ -29,10 +31,14 @@ use aarch64_cpu::registers::{
 };
 use core::ptr::addr_of;
 
+#[cfg(feature = "sched")]
+use super::sched::{idle_on_fresh_stack, VcpuContext, HCR_TWI};
 use super::{
     mm::{get_parange, get_parange_bits, is_s2_pt_level3},
     trap::vmreturn,
 };
+#[cfg(feature = "sched")]
+use crate::cpu_data::this_zone;
 
 pub const MPIDR_MASK: u64 = 0xff00ffffff;
 
@@ -71,6 +77,8 @@ pub struct ArchCpu {
     pub cpuid: usize,
     pub is_aarch32: bool,
     pub power_on: bool,
+    #[cfg(feature = "sched")]
+    pub ctx: VcpuContext,
 }
 
 impl ArchCpu {
@@ -79,6 +87,11 @@ impl ArchCpu {
             cpuid,
             is_aarch32: false,
             power_on: false,
+            // starts below the trap frame, see guest_reg
+            #[cfg(feature = "sched")]
+            ctx: VcpuContext::new(
+                PER_CPU_ARRAY_PTR as VirtAddr + (cpuid + 1) * PER_CPU_SIZE - 32 * 8,
+            ),
         }
     }
 
@@ -198,6 +211,8 @@ impl ArchCpu {
             // Return to AArch32 Supervisor (SVC) mode, disable IRQ, FIQ, ABT
             SPSR_EL2.set(0x1D3);
         }
+        #[cfg(feature = "sched")]
+        HCR_EL2.set(HCR_EL2.get() | HCR_TWI);
         self.power_on = true;
         info!(
             "cpu {} started at {:#x?}",
@@ -209,6 +224,18 @@ impl ArchCpu {
         }
     }
 
+    /// Park this cpu at EL2 until the zone is resumed.
+    ///
+    /// Guest registers stay in the trap frame, and the EL1 system registers and the
+    /// stage-2 table are left alone, so returning to the guest continues from the
+    /// trapped instruction.
+    pub fn pause(&mut self) {
+        assert!(this_cpu_id() == self.cpuid);
+        info!("cpu {} paused at {:#x?}", self.cpuid, ELR_EL2.get());
+        wait_for_resume();
+        info!("cpu {} resumed", self.cpuid);
+    }
+
     pub fn idle(&mut self) -> ! {
         debug!("cpu {} begin to be idle", self.cpuid);
         assert!(this_cpu_id() == self.cpuid);
@@ -217,6 +244,18 @@ impl ArchCpu {
         self.power_on = false;
         drop(_lock);
 
+        // an idle vcpu waits at EL2 and leaves its cpu to the other vcpus
+        #[cfg(feature = "sched")]
+        unsafe {
+            idle_on_fresh_stack(self.guest_reg() as *mut _ as usize)
+        }
+        #[cfg(not(feature = "sched"))]
+        self.park()
+    }
+
+    /// Run the guest in a wfi loop at address 0 until the cpu is woken up again.
+    #[cfg_attr(feature = "sched", allow(dead_code))]
+    fn park(&mut self) -> ! {
         // reset current cpu -> pc = 0x0 (wfi)
         PARKING_MEMORY_SET.call_once(|| {
             let parking_code: [u8; 8] = [0x7f, 0x20, 0x03, 0xd5, 0xff, 0xff, 0xff, 0x17]; // 1: wfi; b 1b
@@ -261,10 +300,50 @@ pub fn cpuid_to_mpidr_affinity(cpuid: u64) -> (u64, u64, u64, u64) {
     (aff3, aff2, aff1, aff0)
 }
 
-pub fn this_cpu_id() -> usize {
+/// The vcpu of the guest a cpu in a zone addresses by `mpidr`, e.g. in PSCI CPU_ON.
+pub fn guest_mpidr_to_cpuid(mpidr: u64) -> Option<usize> {
+    let mpidr = mpidr & MPIDR_MASK;
+    let cpu = (0..MAX_CPU_NUM).find(|&i| BOARD_MPIDR_MAPPINGS[i] == mpidr)?;
+    #[cfg(feature = "sched")]
+    let cpu = crate::sched::zone_vcpu(&this_zone().read(), cpu)?;
+    Some(cpu)
+}
+
+/// The physical cpu this code runs on.
+pub fn this_pcpu_id() -> usize {
     mpidr_to_cpuid(MPIDR_EL1.get()) as _
 }
 
+/// The vcpu this code runs for, its per-CPU data area holds the current stack.
+#[cfg(feature = "sched")]
+pub fn this_cpu_id() -> usize {
+    let sp: usize;
+    unsafe { core::arch::asm!("mov {}, sp", out(reg) sp) };
+    (sp - PER_CPU_ARRAY_PTR as usize) / PER_CPU_SIZE
+}
+
+#[cfg(not(feature = "sched"))]
+pub fn this_cpu_id() -> usize {
+    this_pcpu_id()
+}
+
+/// Sleep until an interrupt is pending, used while waiting for another cpu.
+///
+/// Interrupts are masked in hvisor and the pending one is neither taken nor
+/// acknowledged here, so once one is pending this returns at once and the
+/// caller polls until it goes back to the guest.
+pub fn wait_for_irq() {
+    aarch64_cpu::asm::wfi();
+}
+
+/// Sleep until an interrupt is pending and handle it as if it had been taken
+/// from the guest, so the ipi that woke the cpu up is acknowledged and the
+/// next call sleeps again.
+pub fn wait_for_irq_handled() {
+    aarch64_cpu::asm::wfi();
+    gic_handle_irq();
+}
+
 pub fn store_cpu_pointer_to_reg(_pointer: usize) {
     // println!("aarch64 doesn't support store cpu pointer to reg, pointer: {:#x}", pointer);
     return;
@@ -15,41 +15,11 @@
 //  ForeverYolo <2572131118@qq.com>
 
 use crate::arch::cpu::this_cpu_id;
-use crate::arch::ivc::{IvcInfo, IVC_INFOS};
 use crate::config::CONFIG_MAGIC_VERSION;
-use crate::cpu_data::this_zone;
-use crate::device::virtio_trampoline::MAX_DEVS;
 use crate::hypercall::HyperCall;
 use crate::hypercall::HyperCallResult;
-use crate::zone::this_zone_id;
 
 impl<'a> HyperCall<'a> {
-    pub fn hv_ivc_info(&mut self, ivc_info_ipa: u64) -> HyperCallResult {
-        let zone_id = this_zone_id();
-        let zone = this_zone();
-        // ipa->hpa->hva
-        let hpa = unsafe {
-            zone.read()
-                .gpm
-                .page_table_query(ivc_info_ipa as _)
-                .unwrap()
-                .0
-        };
-        // hva == hpa
-        let ivc_info = unsafe { &mut *(hpa as *mut IvcInfo) };
-        let ivc_infos = IVC_INFOS.lock();
-        let zone_ivc_info = ivc_infos.get(&(zone_id as _));
-        match zone_ivc_info {
-            Some(zone_ivc_info) => *ivc_info = *zone_ivc_info,
-            None => return hv_result_err!(ENODEV, "Zone {zone_id} has no ivc config!"),
-        }
-        HyperCallResult::Ok(0)
-    }
-
-    pub fn wait_for_interrupt(&mut self, _irq_list: &mut [u64; MAX_DEVS + 1]) {
-        trace!("wait_for_interrupt is not need for AArch64");
-    }
-
     pub fn hv_zone_config_check(&self, magic_version: *mut u64) -> HyperCallResult {
         unsafe {
             *magic_version = CONFIG_MAGIC_VERSION as _;
@@ -71,6 +71,7 @@ const STRTAB_STE_SIZE: usize = STRTAB_STE_DWORDS << 3;
 const STRTAB_STE_0_V: usize = 1;
 const STRTAB_STE_0_INVALID: usize = 0;
 const STRTAB_STE_0_CFG_OFF: usize = 1;
+const STRTAB_STE_0_CFG_ABORT: usize = 0;
 const STRTAB_STE_0_CFG_BYPASS: usize = 4;
 const STRTAB_STE_0_CFG_S2_TRANS: usize = 6;
 const STRTAB_STE_1_SHCFG_OFF: usize = 44;
@@ -107,6 +108,9 @@ const CMDQ_OP_CFGI_STE: usize = 3;
 const CMDQ_CFGI_0_SID_OFF: usize = 32;
 const CMDQ_CFGI_1_LEAF: usize = 1;
 
+const CMDQ_OP_TLBI_S12_VMALL: usize = 0x28;
+const CMDQ_TLBI_0_VMID_OFF: usize = 32;
+
 const DEFAULT_VCTR: usize =
     20 + (2 << 6) + (1 << 8) + (1 << 10) + (3 << 12) + (0 << 14) + (4 << 16);
 
@@ -209,6 +213,13 @@ impl LinearStreamTable {
         tab.0[1] = (STRTAB_STE_1_SHCFG_INCOMING << STRTAB_STE_1_SHCFG_OFF) as _;
     }
 
+    /// Transactions of `sid` are aborted until it is written again.
+    fn abort_ste(&self, sid: usize) {
+        let tab = self.ste(sid);
+        tab.0.fill(0);
+        tab.0[0] = (STRTAB_STE_0_V | (STRTAB_STE_0_CFG_ABORT << STRTAB_STE_0_CFG_OFF)) as _;
+    }
+
     fn write_ste(&self, sid: usize, vmid: usize, root_pt: usize) {
         info!(
             "write ste, sid: 0x{:x}, vmid: 0x{:x}, ste_addr:0x{:x}, root_pt: 0x{:x}",
@@ -341,6 +352,14 @@ impl CmdQueue {
         cmd.0[1] |= CMDQ_CFGI_1_LEAF as u64;
         cmd
     }
+
+    // TLBI_S12_VMALL
+    fn build_tlbi_vmall_cmd(&self, vmid: usize) -> Cmd {
+        let mut cmd: Cmd = Cmd::new();
+        cmd.0[0] |= CMDQ_OP_TLBI_S12_VMALL as u64;
+        cmd.0[0] |= (vmid << CMDQ_TLBI_0_VMID_OFF) as u64;
+        cmd
+    }
 }
 
 pub struct Smmuv3 {
@@ -502,6 +521,11 @@ impl Smmuv3 {
         self.strtab.write_ste(sid, vmid, root_pt);
     }
 
+    fn abort_ste(&mut self, sid: usize) {
+        self.strtab.abort_ste(sid);
+        self.sync_ste(sid);
+    }
+
     // invalidate the ste
     fn sync_ste(&mut self, sid: usize) {
         let cmd = self.cmdq.build_cfgi_cmd(sid);
@@ -509,6 +533,13 @@ impl Smmuv3 {
         self.sync_issue();
     }
 
+    // invalidate all tlb entries tagged with this vmid
+    fn tlbi_vmid(&mut self, vmid: usize) {
+        let cmd = self.cmdq.build_tlbi_vmall_cmd(vmid);
+        self.cmd_insert(cmd);
+        self.sync_issue();
+    }
+
     fn cmd_insert(&mut self, cmd: Cmd) {
         while self.cmdq.q_full() {
             self.cmdq.sync_cons(self.rp.CMDQ_CONS.get() as _);
@@ -558,3 +589,16 @@ pub fn iommu_add_device(vmid: usize, sid: usize, root_pt: usize) {
     let mut smmu = SMMUV3.get().unwrap().lock();
     smmu.write_ste(sid as _, vmid as _, root_pt as _);
 }
+
+/// block the dma of a device taken away from its zone
+pub fn iommu_remove_device(sid: usize) {
+    let mut smmu = SMMUV3.get().unwrap().lock();
+    smmu.abort_ste(sid);
+}
+
+/// drop the smmu tlb entries of a zone after its iommu page table shrinks
+pub fn iommu_flush_zone(vmid: usize) {
+    if let Some(smmu) = SMMUV3.get() {
+        smmu.lock().tlbi_vmid(vmid);
+    }
+}
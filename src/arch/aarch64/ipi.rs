@@ -14,9 +14,18 @@
 // Authors:
 //
 
+use crate::device::irqchip::gic_clear_pending_ipi;
+#[cfg(not(feature = "sched"))]
 use crate::device::irqchip::gic_send_event;
 pub fn arch_send_event(cpu_id: u64, sgi_num: u64) {
+    #[cfg(not(feature = "sched"))]
     gic_send_event(cpu_id, sgi_num);
+    // events go to vcpus, `kick` finds the physical cpu
+    #[cfg(feature = "sched")]
+    {
+        debug_assert_eq!(sgi_num, crate::hypercall::SGI_IPI_ID);
+        crate::sched::kick(cpu_id as _);
+    }
 }
 
 pub fn arch_check_events(event: Option<usize>) {
@@ -33,3 +42,9 @@ pub fn arch_check_events(event: Option<usize>) {
 pub fn arch_prepare_send_event(_cpu_id: usize, _ipi_int_id: usize, _event_id: usize) {
     debug!("aarch64 arch_prepare_send_event: do nothing now.")
 }
+
+/// Drop the pending ipi of this cpu once its events are taken by polling,
+/// otherwise the irq handler finds no event and injects it into the guest.
+pub fn arch_clear_event_ipi() {
+    gic_clear_pending_ipi();
+}
@@ -19,11 +19,12 @@ pub mod entry;
 pub mod hypercall;
 pub mod iommu;
 pub mod ipi;
-pub mod ivc;
 pub mod mm;
 pub mod mmu;
 pub mod paging;
 pub mod s2pt;
+#[cfg(feature = "sched")]
+pub mod sched;
 pub mod sysreg;
 pub mod time;
 pub mod trap;
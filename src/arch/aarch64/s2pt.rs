@@ -223,7 +223,13 @@ impl PagingInstr for S2PTInstr {
     }
 
     fn flush(_vaddr: Option<usize>) {
-        // do nothing
+        // all zones share VMID 0, drop every stage-1&2 entry on all cpus
+        unsafe {
+            core::arch::asm!("dsb ishst");
+            core::arch::asm!("tlbi vmalls12e1is");
+            core::arch::asm!("dsb ish");
+            core::arch::asm!("isb");
+        }
     }
 }
 
@@ -0,0 +1,349 @@
+// Copyright (c) 2025 Syswonder
+// hvisor is licensed under Mulan PSL v2.
+// You can use this software according to the terms and conditions of the Mulan PSL v2.
+// You may obtain a copy of Mulan PSL v2 at:
+//     http://license.coscl.org.cn/MulanPSL2
+// THIS SOFTWARE IS PROVIDED ON AN "AS IS" BASIS, WITHOUT WARRANTIES OF ANY KIND, EITHER
+// EXPRESS OR IMPLIED, INCLUDING BUT NOT LIMITED TO NON-INFRINGEMENT, MERCHANTABILITY OR
+// FIT FOR A PARTICULAR PURPOSE.
+// See the Mulan PSL v2 for more details.
+//
+// Syswonder Website:
+//      https://www.syswonder.org
+//
+// Authors:
+//
+//! aarch64 part of `crate::sched`: the vcpu state that is not in the trap frame, the
+//! switch between vcpu stacks, and the EL2 physical timer (CNTHP) used for preemption.
+
+use core::arch::asm;
+
+use aarch64_cpu::asm::wfi;
+
+use super::sysreg::{read_sysreg, write_sysreg};
+use crate::cpu_data::get_cpu_data;
+use crate::device::irqchip::gicv3::{has_pending_irq_queued, inject_pending_irqs, GicVcpuState};
+use crate::device::irqchip::{gic_handle_irq, gic_send_event};
+use crate::hypercall::SGI_IPI_ID;
+
+/// HCR_EL2.TWI, trap guest WFI so an idle vcpu gives its cpu away.
+pub const HCR_TWI: u64 = 1 << 13;
+
+macro_rules! sysreg_context {
+    ($name:ident { $($reg:ident),* $(,)? }) => {
+        #[derive(Debug, Default)]
+        struct $name {
+            $($reg: u64,)*
+        }
+
+        impl $name {
+            fn save(&mut self) {
+                $(self.$reg = read_sysreg!($reg);)*
+            }
+
+            // in declaration order
+            fn restore(&self) {
+                $(write_sysreg!($reg, self.$reg);)*
+            }
+        }
+    };
+}
+
+sysreg_context!(El1Regs {
+    sctlr_el1,
+    cpacr_el1,
+    ttbr0_el1,
+    ttbr1_el1,
+    tcr_el1,
+    mair_el1,
+    amair_el1,
+    vbar_el1,
+    contextidr_el1,
+    esr_el1,
+    far_el1,
+    par_el1,
+    afsr0_el1,
+    afsr1_el1,
+    tpidr_el0,
+    tpidrro_el0,
+    tpidr_el1,
+    sp_el0,
+    sp_el1,
+    elr_el1,
+    spsr_el1,
+    csselr_el1,
+    cntkctl_el1,
+    mdscr_el1,
+    // the offset goes first, the compare values are relative to it
+    cntvoff_el2,
+    cntv_cval_el0,
+    cntv_ctl_el0,
+    cntp_cval_el0,
+    cntp_ctl_el0,
+});
+
+sysreg_context!(Aarch32Regs {
+    dacr32_el2,
+    ifsr32_el2,
+    fpexc32_el2,
+    spsr_abt,
+    spsr_und,
+    spsr_irq,
+    spsr_fiq,
+});
+
+// the exception being handled when the vcpu was switched out, and its stage-2 table
+sysreg_context!(El2Regs {
+    elr_el2,
+    spsr_el2,
+    esr_el2,
+    far_el2,
+    hpfar_el2,
+    hcr_el2,
+    vttbr_el2,
+});
+
+#[repr(C, align(16))]
+#[derive(Debug)]
+struct FpRegs {
+    q: [u128; 32],
+    fpcr: u64,
+    fpsr: u64,
+}
+
+impl FpRegs {
+    fn save(&mut self) {
+        unsafe {
+            asm!(
+                "stp q0, q1, [{0}, #0]",
+                "stp q2, q3, [{0}, #32]",
+                "stp q4, q5, [{0}, #64]",
+                "stp q6, q7, [{0}, #96]",
+                "stp q8, q9, [{0}, #128]",
+                "stp q10, q11, [{0}, #160]",
+                "stp q12, q13, [{0}, #192]",
+                "stp q14, q15, [{0}, #224]",
+                "stp q16, q17, [{0}, #256]",
+                "stp q18, q19, [{0}, #288]",
+                "stp q20, q21, [{0}, #320]",
+                "stp q22, q23, [{0}, #352]",
+                "stp q24, q25, [{0}, #384]",
+                "stp q26, q27, [{0}, #416]",
+                "stp q28, q29, [{0}, #448]",
+                "stp q30, q31, [{0}, #480]",
+                in(reg) self.q.as_mut_ptr(),
+                options(nostack),
+            );
+        }
+        self.fpcr = read_sysreg!(fpcr);
+        self.fpsr = read_sysreg!(fpsr);
+    }
+
+    fn restore(&self) {
+        unsafe {
+            asm!(
+                "ldp q0, q1, [{0}, #0]",
+                "ldp q2, q3, [{0}, #32]",
+                "ldp q4, q5, [{0}, #64]",
+                "ldp q6, q7, [{0}, #96]",
+                "ldp q8, q9, [{0}, #128]",
+                "ldp q10, q11, [{0}, #160]",
+                "ldp q12, q13, [{0}, #192]",
+                "ldp q14, q15, [{0}, #224]",
+                "ldp q16, q17, [{0}, #256]",
+                "ldp q18, q19, [{0}, #288]",
+                "ldp q20, q21, [{0}, #320]",
+                "ldp q22, q23, [{0}, #352]",
+                "ldp q24, q25, [{0}, #384]",
+                "ldp q26, q27, [{0}, #416]",
+                "ldp q28, q29, [{0}, #448]",
+                "ldp q30, q31, [{0}, #480]",
+                in(reg) self.q.as_ptr(),
+                options(nostack),
+            );
+        }
+        write_sysreg!(fpcr, self.fpcr);
+        write_sysreg!(fpsr, self.fpsr);
+    }
+}
+
+/// What a switched out vcpu keeps besides its stack and trap frame.
+#[derive(Debug)]
+pub struct VcpuContext {
+    /// x19-x30 and sp at the call of `context_switch`.
+    switch: [u64; 13],
+    el1: El1Regs,
+    aarch32: Aarch32Regs,
+    el2: El2Regs,
+    fp: FpRegs,
+    gic: GicVcpuState,
+}
+
+impl VcpuContext {
+    /// A context that starts at `vcpu_first_run` on the stack below `trap_frame`.
+    pub fn new(trap_frame: usize) -> Self {
+        let mut switch = [0; 13];
+        switch[11] = vcpu_first_run as usize as _;
+        switch[12] = trap_frame as _;
+        Self {
+            switch,
+            el1: El1Regs::default(),
+            aarch32: Aarch32Regs::default(),
+            el2: El2Regs::default(),
+            fp: FpRegs {
+                q: [0; 32],
+                fpcr: 0,
+                fpsr: 0,
+            },
+            gic: GicVcpuState::default(),
+        }
+    }
+
+    fn save(&mut self, is_aarch32: bool) {
+        self.el1.save();
+        if is_aarch32 {
+            self.aarch32.save();
+        }
+        self.el2.save();
+        self.fp.save();
+        self.gic.save();
+    }
+
+    fn restore(&self, is_aarch32: bool) {
+        self.el1.restore();
+        if is_aarch32 {
+            self.aarch32.restore();
+        }
+        self.el2.restore();
+        self.fp.restore();
+        self.gic.restore();
+        // all zones share VMID 0
+        unsafe {
+            asm!("isb", "tlbi vmalls12e1", "dsb nsh", "isb");
+        }
+    }
+}
+
+extern "C" fn vcpu_first_run() -> ! {
+    crate::sched::vcpu_first_run()
+}
+
+extern "C" fn vcpu_idle() -> ! {
+    crate::sched::idle_loop()
+}
+
+#[naked]
+unsafe extern "C" fn context_switch(_prev: *mut [u64; 13], _next: *const [u64; 13]) {
+    asm!(
+        "
+        stp x19, x20, [x0, #0]
+        stp x21, x22, [x0, #16]
+        stp x23, x24, [x0, #32]
+        stp x25, x26, [x0, #48]
+        stp x27, x28, [x0, #64]
+        stp x29, x30, [x0, #80]
+        mov x9, sp
+        str x9, [x0, #96]
+
+        ldp x19, x20, [x1, #0]
+        ldp x21, x22, [x1, #16]
+        ldp x23, x24, [x1, #32]
+        ldp x25, x26, [x1, #48]
+        ldp x27, x28, [x1, #64]
+        ldp x29, x30, [x1, #80]
+        ldr x9, [x1, #96]
+        mov sp, x9
+        ret
+        ",
+        options(noreturn),
+    )
+}
+
+/// Drop the call stack of the current vcpu and wait for events in `idle_loop`.
+#[naked]
+pub unsafe extern "C" fn idle_on_fresh_stack(_trap_frame: usize) -> ! {
+    asm!(
+        "
+        mov sp, x0
+        mov x29, xzr
+        mov x30, xzr
+        b {idle}
+        ",
+        idle = sym vcpu_idle,
+        options(noreturn),
+    )
+}
+
+/// Save the state of vcpu `prev`, load `next` and continue on its stack. Returns when
+/// `prev` is switched in again.
+pub fn switch_to(prev: usize, next: usize) {
+    let prev_cpu = &mut get_cpu_data(prev).arch_cpu;
+    let next_cpu = &get_cpu_data(next).arch_cpu;
+    prev_cpu.ctx.save(prev_cpu.is_aarch32);
+    next_cpu.ctx.restore(next_cpu.is_aarch32);
+    unsafe { context_switch(&mut prev_cpu.ctx.switch, &next_cpu.ctx.switch) };
+}
+
+/// The physical counter.
+pub fn now() -> u64 {
+    read_sysreg!(cntpct_el0)
+}
+
+pub fn us_to_ticks(us: u64) -> u64 {
+    read_sysreg!(cntfrq_el0) * us / 1_000_000
+}
+
+/// Fire the EL2 physical timer at counter value `deadline`, or never.
+pub fn set_preempt_timer(deadline: Option<u64>) {
+    match deadline {
+        Some(deadline) => {
+            write_sysreg!(cnthp_cval_el2, deadline);
+            write_sysreg!(cnthp_ctl_el2, 1);
+        }
+        None => write_sysreg!(cnthp_ctl_el2, 0),
+    }
+}
+
+/// Sleep until an interrupt arrives and handle it.
+pub fn wait_for_irq() {
+    wfi();
+    gic_handle_irq();
+}
+
+/// Whether `vcpu` has a virtual interrupt to take. Only the running vcpu has its timers
+/// and list registers loaded.
+pub fn vcpu_irq_pending(vcpu: usize, is_current: bool, now: u64) -> bool {
+    if has_pending_irq_queued(vcpu) {
+        return true;
+    }
+    if is_current {
+        GicVcpuState::live_has_pending_irq()
+    } else {
+        get_cpu_data(vcpu).arch_cpu.ctx.gic.has_pending_irq()
+            || vcpu_timer_deadline(vcpu).is_some_and(|deadline| deadline <= now)
+    }
+}
+
+/// When the earliest guest timer of the switched out `vcpu` fires, in physical counter
+/// ticks.
+pub fn vcpu_timer_deadline(vcpu: usize) -> Option<u64> {
+    let el1 = &get_cpu_data(vcpu).arch_cpu.ctx.el1;
+    // ENABLE set, IMASK clear
+    let armed = |ctl: u64| ctl & 0b11 == 0b01;
+    let vtimer = armed(el1.cntv_ctl_el0).then(|| el1.cntv_cval_el0.wrapping_add(el1.cntvoff_el2));
+    let ptimer = armed(el1.cntp_ctl_el0).then_some(el1.cntp_cval_el0);
+    match (vtimer, ptimer) {
+        (Some(v), Some(p)) => Some(v.min(p)),
+        (v, p) => v.or(p),
+    }
+}
+
+/// Make physical cpu `cpu` reschedule.
+pub fn send_kick(cpu: usize) {
+    gic_send_event(cpu as _, SGI_IPI_ID);
+}
+
+/// Called right before the current vcpu returns to its guest.
+pub fn vcpu_enter() {
+    inject_pending_irqs();
+}
@@ -19,3 +19,9 @@
 pub fn init_timebase() {
     info!("Initializing aarch64 timebase not implemented yet.");
 }
+
+/// Return time in microseconds since some arbitrary point in the past.
+pub fn get_time_us() -> u64 {
+    use aarch64_cpu::registers::{Readable, CNTFRQ_EL0, CNTPCT_EL0};
+    (CNTPCT_EL0.get() as u128 * 1_000_000 / CNTFRQ_EL0.get() as u128) as u64
+}
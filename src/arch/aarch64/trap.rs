@@ -13,23 +13,28 @@
 //
 // Authors:
 //
-use aarch64_cpu::{asm::wfi, registers::*};
+#[cfg(not(feature = "sched"))]
+use aarch64_cpu::asm::wfi;
+use aarch64_cpu::registers::*;
 use core::arch::global_asm;
 
 use super::cpu::GeneralRegisters;
 use crate::arch::sysreg::smc_call;
+#[cfg(feature = "sched")]
+use crate::device::irqchip::gicv3::vgic::vgicv3_send_sgi;
 use crate::zone::zone_error;
 use crate::{
     arch::{
-        cpu::mpidr_to_cpuid,
-        sysreg::{read_sysreg, write_sysreg},
+        cpu::{guest_mpidr_to_cpuid, mpidr_to_cpuid},
+        sysreg::read_sysreg,
     },
     cpu_data::{get_cpu_data, this_cpu_data, this_zone},
-    device::irqchip::gic_handle_irq,
+    device::{device_poll, irqchip::gic_handle_irq},
     event::{send_event, IPI_EVENT_SHUTDOWN, IPI_EVENT_WAKEUP},
     hypercall::{HyperCall, SGI_IPI_ID},
     memory::{mmio_handle_access, MMIOAccess},
-    zone::{is_this_root_zone, remove_zone},
+    stats::{count_exit, ExitReason},
+    zone::{is_this_root_zone, remove_zone, this_zone_reboot},
 };
 
 global_asm!(
@@ -60,6 +65,7 @@ pub mod SmcType {
 
 const PSCI_VERSION_1_1: u64 = 0x10001;
 const PSCI_TOS_NOT_PRESENT_MP: u64 = 2;
+const PSCI_INVALID_PARAMETERS: u64 = -2i64 as u64;
 const ARM_SMCCC_VERSION_1_1: u64 = 0x10001;
 
 #[allow(unused)]
@@ -85,6 +91,7 @@ pub mod PsciFnId {
     pub const PSCI_AFFINITY_INFO_32: u64 = 0x84000004;
     pub const PSCI_MIG_INFO_TYPE: u64 = 0x84000006;
     pub const PSCI_SYSTEM_OFF: u64 = 0x84000008;
+    pub const PSCI_SYSTEM_RESET: u64 = 0x84000009;
     pub const PSCI_FEATURES: u64 = 0x8400000a;
 
     pub const PSCI_CPU_SUSPEND_64: u64 = 0xc4000001;
@@ -121,12 +128,16 @@ pub fn arch_handle_exit(regs: &mut GeneralRegisters) -> ! {
         ExceptionType::EXIT_REASON_EL2_IRQ => irqchip_handle_irq2(),
         _ => arch_dump_exit(regs.exit_reason),
     }
+    #[cfg(feature = "sched")]
+    crate::sched::schedule_tail();
     unsafe { vmreturn(regs as *const _ as usize) }
 }
 
 fn irqchip_handle_irq1() {
+    count_exit(ExitReason::Irq);
     trace!("irq from el1");
     gic_handle_irq();
+    device_poll();
 }
 
 fn irqchip_handle_irq2() {
@@ -149,6 +160,8 @@ fn arch_handle_trap_el1(regs: &mut GeneralRegisters) {
         Some(ESR_EL2::EC::Value::TrappedMsrMrs) => handle_sysreg(regs),
         Some(ESR_EL2::EC::Value::DataAbortLowerEL) => handle_dabt(regs),
         Some(ESR_EL2::EC::Value::InstrAbortLowerEL) => handle_iabt(regs),
+        #[cfg(feature = "sched")]
+        Some(ESR_EL2::EC::Value::TrappedWFIorWFE) => handle_wfi(regs),
         _ => {
             error!(
                 "Unsupported Exception EC:{:#x?}!",
@@ -213,6 +226,7 @@ fn handle_iabt(_regs: &mut GeneralRegisters) {
 }
 
 fn handle_dabt(regs: &mut GeneralRegisters) {
+    count_exit(ExitReason::Mmio);
     let iss = ESR_EL2.read(ESR_EL2::ISS);
     let is_write = (iss >> 6 & 0x1) != 0;
     let srt = iss >> 16 & 0x1f;
@@ -256,7 +270,17 @@ fn handle_dabt(regs: &mut GeneralRegisters) {
     arch_skip_instruction(regs);
 }
 
+/// The guest has nothing to do, let the other vcpus of this cpu run until an interrupt
+/// arrives for it.
+#[cfg(feature = "sched")]
+fn handle_wfi(regs: &mut GeneralRegisters) {
+    count_exit(ExitReason::Other);
+    arch_skip_instruction(regs);
+    crate::sched::wait_for_irq();
+}
+
 fn handle_sysreg(regs: &mut GeneralRegisters) {
+    count_exit(ExitReason::SysReg);
     //TODO check sysreg type
     //send sgi
     trace!("esr_el2: iss {:#x?}", ESR_EL2.read(ESR_EL2::ISS));
@@ -268,13 +292,17 @@ fn handle_sysreg(regs: &mut GeneralRegisters) {
         warn!("skip send sgi {:#x?}", sgi_id);
     } else {
         trace!("send sgi {:#x?}", sgi_id);
-        write_sysreg!(icc_sgi1r_el1, val);
+        #[cfg(not(feature = "sched"))]
+        crate::arch::sysreg::write_sysreg!(icc_sgi1r_el1, val);
+        #[cfg(feature = "sched")]
+        vgicv3_send_sgi(val);
     }
 
     arch_skip_instruction(regs); //skip sgi write
 }
 
 fn handle_hvc(regs: &mut GeneralRegisters) {
+    count_exit(ExitReason::Hypercall);
     /*
     if ESR_EL2.read(ESR_EL2::ISS) != 0x4a48 {
         return;
@@ -302,6 +330,7 @@ fn handle_hvc(regs: &mut GeneralRegisters) {
 }
 
 fn handle_smc(regs: &mut GeneralRegisters) {
+    count_exit(ExitReason::FirmwareCall);
     let (code, arg0, arg1, arg2) = (regs.usr[0], regs.usr[1], regs.usr[2], regs.usr[3]);
     //info!(
     //    "SMC from CPU{}, func_id:{:#x?}, arg0:{:#x?}, arg1:{:#x?}, arg2:{:#x?}",
@@ -345,9 +374,16 @@ fn psci_emulate_features_info(code: u64) -> u64 {
 }
 
 fn psci_emulate_cpu_on(regs: &mut GeneralRegisters) -> u64 {
-    // Todo: Check if `cpu` is in the cpuset of current zone
-    let cpu = mpidr_to_cpuid(regs.usr[1]);
+    let Some(cpu) = guest_mpidr_to_cpuid(regs.usr[1]) else {
+        warn!("psci: no cpu with mpidr {:#x} in this zone", regs.usr[1]);
+        return PSCI_INVALID_PARAMETERS;
+    };
     info!("psci: try to wake up cpu {}", cpu);
+    // cpus move between zones with HvZoneCpuAdd/HvZoneCpuRemove
+    if !this_zone().read().cpu_set.contains_cpu(cpu as _) {
+        warn!("psci: cpu {} is not in this zone", cpu);
+        return PSCI_INVALID_PARAMETERS;
+    }
 
     let target_data = get_cpu_data(cpu as _);
     let _lock = target_data.ctrl_lock.lock();
@@ -375,15 +411,24 @@ fn handle_psci_smc(
     match code {
         PsciFnId::PSCI_VERSION => PSCI_VERSION_1_1,
         PsciFnId::PSCI_CPU_SUSPEND_32 | PsciFnId::PSCI_CPU_SUSPEND_64 => {
-            wfi();
-            gic_handle_irq();
+            #[cfg(not(feature = "sched"))]
+            {
+                wfi();
+                gic_handle_irq();
+            }
+            #[cfg(feature = "sched")]
+            crate::sched::wait_for_irq();
             0
         }
         PsciFnId::PSCI_CPU_OFF_32 | PsciFnId::PSCI_CPU_OFF_64 => {
-            todo!();
+            info!("psci: cpu {} off", this_cpu_data().id);
+            this_cpu_data().arch_cpu.idle();
         }
         PsciFnId::PSCI_AFFINITY_INFO_32 | PsciFnId::PSCI_AFFINITY_INFO_64 => {
-            !get_cpu_data(arg0 as _).arch_cpu.power_on as _
+            match guest_mpidr_to_cpuid(arg0) {
+                Some(cpu) => !get_cpu_data(cpu).arch_cpu.power_on as _,
+                None => PSCI_INVALID_PARAMETERS,
+            }
         }
         PsciFnId::PSCI_MIG_INFO_TYPE => PSCI_TOS_NOT_PRESENT_MP,
         PsciFnId::PSCI_FEATURES => psci_emulate_features_info(regs.usr[1]),
@@ -401,6 +446,11 @@ fn handle_psci_smc(
             }
 
             this_cpu_data().zone = None;
+            #[cfg(feature = "sched")]
+            zone.read()
+                .cpu_set
+                .iter()
+                .for_each(crate::sched::release_vcpu);
             drop(zone);
             remove_zone(zone_id);
 
@@ -410,6 +460,12 @@ fn handle_psci_smc(
 
             this_cpu_data().arch_cpu.idle();
         }
+        PsciFnId::PSCI_SYSTEM_RESET => {
+            if is_this_root_zone() {
+                psci::system_reset().unwrap();
+            }
+            this_zone_reboot();
+        }
 
         _ => {
             warn!("unsupported smc standard service {:#x?}", code);
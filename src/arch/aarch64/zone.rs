@@ -112,8 +112,7 @@ impl Zone {
         Ok(())
     }
 
-    pub fn arch_zone_pre_configuration(&mut self, config: &HvZoneConfig) -> HvResult {
-        self.ivc_init(config.ivc_config());
+    pub fn arch_zone_pre_configuration(&mut self, _config: &HvZoneConfig) -> HvResult {
         Ok(())
     }
 
@@ -129,6 +128,58 @@ pub struct HvArchZoneConfig {
     pub gic_config: GicConfig,
 }
 
+/// `HvArchZoneConfig` as passed in by the root zone. `gic` holds the fields of
+/// the `Gicv2Config` or `Gicv3Config` picked by `gic_version`, the
+/// `GicConfig` discriminant.
+#[repr(C)]
+#[derive(Debug, Clone, Copy)]
+pub struct HvArchZoneConfigWire {
+    pub is_aarch32: u8,
+    pub gic_version: usize,
+    pub gic: [usize; 9],
+}
+
+const _: () = assert!(
+    core::mem::size_of::<HvArchZoneConfigWire>() == core::mem::size_of::<HvArchZoneConfig>()
+);
+
+impl HvArchZoneConfig {
+    pub fn from_wire(wire: HvArchZoneConfigWire) -> HvResult<Self> {
+        let gic = wire.gic;
+        let gic_config = match wire.gic_version {
+            0 => GicConfig::Gicv2(Gicv2Config {
+                gicd_base: gic[0],
+                gicd_size: gic[1],
+                gicc_base: gic[2],
+                gicc_size: gic[3],
+                gicc_offset: gic[4],
+                gich_base: gic[5],
+                gich_size: gic[6],
+                gicv_base: gic[7],
+                gicv_size: gic[8],
+            }),
+            1 => GicConfig::Gicv3(Gicv3Config {
+                gicd_base: gic[0],
+                gicd_size: gic[1],
+                gicr_base: gic[2],
+                gicr_size: gic[3],
+                gits_base: gic[4],
+                gits_size: gic[5],
+            }),
+            version => {
+                return hv_result_err!(
+                    EINVAL,
+                    format!("arch config: unknown gic version {}", version)
+                )
+            }
+        };
+        Ok(Self {
+            is_aarch32: wire.is_aarch32,
+            gic_config,
+        })
+    }
+}
+
 #[repr(C, usize)]
 #[derive(Debug, Clone)]
 #[allow(unused)]
@@ -19,6 +19,7 @@ use super::zone::ZoneContext;
 use crate::arch::zone::disable_hwi_through;
 use crate::cpu_data::this_cpu_data;
 use crate::device::common::MMIODerefWrapper;
+use crate::event::wait_for_resume;
 use crate::zone::find_zone;
 use core::arch::asm;
 use core::fmt::{self, Debug, Formatter};
@@ -121,6 +122,24 @@ impl ArchCpu {
 
         panic!("loongarch64: ArchCpu::run: unreachable");
     }
+    /// Park this cpu in host mode until the zone is resumed.
+    ///
+    /// The guest context is saved in `self.ctx` by the trap entry and the guest CSRs
+    /// are left alone, so `_hyp_trap_return` continues the guest where it stopped.
+    pub fn pause(&mut self) {
+        assert!(this_cpu_id() == self.get_cpuid());
+        info!(
+            "loongarch64: ArchCpu::pause: cpuid={}, era={:#x}",
+            self.get_cpuid(),
+            self.ctx.sepc
+        );
+        wait_for_resume();
+        info!(
+            "loongarch64: ArchCpu::pause: cpuid={} resumed",
+            self.get_cpuid()
+        );
+    }
+
     pub fn idle(&mut self) -> ! {
         let ctx_addr = &mut self.ctx as *mut ZoneContext;
         unsafe {
@@ -144,6 +163,21 @@ pub fn this_cpu_id() -> usize {
     cpuid::read().core_id()
 }
 
+/// Sleep until an interrupt is pending, used while waiting for another cpu.
+///
+/// The pending interrupt is not taken while hvisor runs, so `idle` returns at
+/// once after the first one and the caller polls.
+pub fn wait_for_irq() {
+    unsafe { asm!("idle 0") };
+}
+
+/// Events sent to this cpu may drop the ones it has not taken yet, so it
+/// cannot sleep until one arrives. `HV_FEATURE_VIRTIO_CFG_WAIT` is not
+/// advertised and this is never called.
+pub fn wait_for_irq_handled() {
+    wait_for_irq();
+}
+
 pub fn cpu_start(cpuid: usize, start_addr: usize, opaque: usize) {
     let start_addr = start_addr & 0x0000_ffff_ffff_ffff;
     let ipi: &MMIODerefWrapper<IpiRegisters> = match cpuid {
@@ -25,3 +25,10 @@ pub fn iommu_add_device(vmid: usize, sid: usize) {
         vmid, sid
     );
 }
+
+pub fn iommu_flush_zone(vmid: usize) {
+    debug!(
+        "loongarch64: iommu_flush_zone: do nothing now, vmid: {}",
+        vmid
+    );
+}
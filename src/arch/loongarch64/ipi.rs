@@ -365,3 +365,8 @@ pub fn arch_prepare_send_event(cpu_id: usize, ipi_int_id: usize, event_id: usize
         cpu_id, ipi_int_id, event_id
     );
 }
+
+/// Drop the pending ipi of this cpu once its events are taken by polling.
+pub fn arch_clear_event_ipi() {
+    clear_all_ipi(this_cpu_id());
+}
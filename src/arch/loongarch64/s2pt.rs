@@ -185,11 +185,9 @@ impl PagingInstr for S2PTInstr {
             tlbrentry::read().addr()
         );
     }
-    fn flush(vaddr: Option<usize>) {
-        warn!(
-            "[[memory virtualization]] flushing stage2 page table: vaddr: {:#x?}",
-            vaddr
-        );
+    /// Local to this cpu, other cpus flush on `IPI_EVENT_FLUSH_S2`.
+    fn flush(_vaddr: Option<usize>) {
+        unsafe { core::arch::asm!("invtlb 0, $r0, $r0") };
     }
 }
 
@@ -18,3 +18,10 @@
 pub fn init_timebase() {
     info!("Initializing loongArch64 timebase not implemented yet.");
 }
+
+/// Return time in microseconds since some arbitrary point in the past.
+pub fn get_time_us() -> u64 {
+    use super::clock::read_stable_counter;
+    use loongArch64::time::get_timer_freq;
+    (read_stable_counter() as u128 * 1_000_000 / get_timer_freq() as u128) as u64
+}
@@ -21,11 +21,13 @@ use crate::arch::cpu::this_cpu_id;
 use crate::arch::ipi::*;
 use crate::consts::{IPI_EVENT_CLEAR_INJECT_IRQ, MAX_CPU_NUM};
 use crate::cpu_data::this_cpu_data;
+use crate::device::device_poll;
 use crate::device::irqchip::inject_irq;
 use crate::device::irqchip::ls7a2000::chip::*;
 use crate::event::{check_events, dump_cpu_events, dump_events};
 use crate::hypercall::{SGI_IPI_ID, *};
 use crate::memory::{addr, mmio_handle_access, MMIOAccess};
+use crate::stats::{count_exit, ExitReason};
 use crate::zone::Zone;
 use crate::PHY_TO_DMW_UNCACHED;
 use core::arch;
@@ -356,6 +358,13 @@ fn handle_exception(
     badv: usize,
     ctx: &mut ZoneContext,
 ) {
+    count_exit(match ecode {
+        ECODE_INT => ExitReason::Irq,
+        ECODE_GSPR => ExitReason::SysReg,
+        ECODE_HVC => ExitReason::Hypercall,
+        ECODE_PIL | ECODE_PIS | ECODE_PNR => ExitReason::Mmio,
+        _ => ExitReason::Other,
+    });
     match ecode {
         ECODE_INT => {
             debug!(
@@ -365,6 +374,7 @@ fn handle_exception(
             );
             // INT = 0x0,   Interrupt
             handle_interrupt(is);
+            device_poll();
         }
         ECODE_GSPR => {
             // according to kvm's code, we should emulate the instruction that cause the GSPR exception - wheatfox 2024.4.12
@@ -401,6 +401,15 @@ pub struct HvArchZoneConfig {
     pub dummy: usize,
 }
 
+/// Only integer fields, read from the zone config as is.
+pub type HvArchZoneConfigWire = HvArchZoneConfig;
+
+impl HvArchZoneConfig {
+    pub fn from_wire(wire: HvArchZoneConfigWire) -> HvResult<Self> {
+        Ok(wire)
+    }
+}
+
 #[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
 struct MMIOAccessKey {
     offset: usize,
@@ -14,7 +14,11 @@
 // Authors:
 //
 use super::csr::*;
+#[cfg(feature = "plic")]
+use super::trap::handle_external_interrupt;
+use super::trap::{handle_software_interrupt, handle_timer_interrupt};
 use crate::cpu_data::this_cpu_data;
+use crate::event::wait_for_resume;
 use crate::platform::{BOARD_HARTID_MAP, BOARD_NCPUS};
 use crate::{
     arch::mm::new_s2_memory_set,
@@ -152,6 +156,17 @@ impl ArchCpu {
         }
     }
 
+    /// Park this cpu in HS-mode until the zone is resumed.
+    ///
+    /// The guest context lives in `self` and the VS CSRs are not touched, so the
+    /// trap return continues the guest where it stopped.
+    pub fn pause(&mut self) {
+        assert!(this_cpu_id() == self.cpuid);
+        info!("CPU{} paused at {:#x}", self.cpuid, self.sepc);
+        wait_for_resume();
+        info!("CPU{} resumed", self.cpuid);
+    }
+
     pub fn idle(&mut self) -> ! {
         extern "C" {
             fn vcpu_arch_entry() -> !;
@@ -223,6 +238,33 @@ pub fn this_cpu_id() -> usize {
     this_cpu_arch().get_cpuid()
 }
 
+/// Sleep until an interrupt is pending, used while waiting for another cpu.
+///
+/// `wfi` also wakes up for interrupts hvisor keeps disabled, which are not
+/// taken until the guest runs again. Once one is pending the caller polls.
+pub fn wait_for_irq() {
+    unsafe { core::arch::asm!("wfi") };
+}
+
+/// Sleep until an interrupt is pending and handle it as if it had been taken
+/// from the guest, so the ipi that woke the cpu up is acknowledged and the
+/// next call sleeps again.
+pub fn wait_for_irq_handled() {
+    wait_for_irq();
+    let sip = riscv::register::sip::read();
+    let cpu = this_cpu_arch();
+    if sip.ssoft() {
+        handle_software_interrupt(cpu);
+    }
+    if sip.stimer() {
+        handle_timer_interrupt(cpu);
+    }
+    #[cfg(feature = "plic")]
+    if sip.sext() {
+        handle_external_interrupt(cpu);
+    }
+}
+
 pub fn hartid_to_cpuid(hartid: usize) -> usize {
     (0..BOARD_NCPUS)
         .find(|&i| BOARD_HARTID_MAP[i] == hartid)
@@ -26,3 +26,8 @@ pub fn iommu_add_device(vmid: usize, sid: usize) {
         vmid, sid
     );
 }
+
+#[allow(unused)]
+pub fn iommu_flush_zone(vmid: usize) {
+    debug!("riscv: iommu_flush_zone: do nothing now, vmid: {}", vmid);
+}
@@ -62,3 +62,10 @@ pub fn arch_check_events(event: Option<usize>) {
 pub fn arch_prepare_send_event(_cpu_id: usize, _ipi_int_id: usize, _event_id: usize) {
     debug!("risc-v arch_prepare_send_event: do nothing now.")
 }
+
+/// Drop the pending ipi of this cpu once its events are taken by polling.
+pub fn arch_clear_event_ipi() {
+    unsafe {
+        riscv::register::sip::clear_ssoft();
+    }
+}
@@ -251,8 +251,9 @@ impl PagingInstr for S2PTInstr {
         }
     }
 
+    /// Local to this hart, other harts flush on `IPI_EVENT_FLUSH_S2`.
     fn flush(_vaddr: Option<usize>) {
-        warn!("S2PTInstr::flush is not implemented.");
+        unsafe { riscv_h::asm::hfence_gvma(0, 0) };
     }
 }
 
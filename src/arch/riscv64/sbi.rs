@@ -19,32 +19,40 @@ use super::cpu::ArchCpu;
 use crate::arch::cpu::hartid_to_cpuid;
 use crate::arch::csr::*;
 use crate::consts::IPI_EVENT_SEND_IPI;
-use crate::cpu_data::{get_cpu_data, this_cpu_data};
+use crate::cpu_data::{get_cpu_data, this_cpu_data, this_zone};
 use crate::event::{send_event, IPI_EVENT_WAKEUP};
 use crate::hypercall::HyperCall;
+use crate::zone::{is_this_root_zone, this_zone_reboot};
 use core::sync::atomic;
 use riscv::register::sie;
 use riscv_h::register::hvip;
 use sbi_rt::{HartMask, SbiRet};
 use sbi_spec::binary::{
-    RET_ERR_ALREADY_AVAILABLE, RET_ERR_FAILED, RET_ERR_NOT_SUPPORTED, RET_SUCCESS,
+    RET_ERR_ALREADY_AVAILABLE, RET_ERR_FAILED, RET_ERR_INVALID_PARAM, RET_ERR_NOT_SUPPORTED,
+    RET_SUCCESS,
 };
-use sbi_spec::{base, hsm, legacy, rfnc, spi, time};
+use sbi_spec::{base, hsm, legacy, rfnc, spi, srst, time};
 
 // Reserved for hvisor-tool.
 pub const EID_HVISOR: usize = 0x114514;
 
 // Hvisor supported SBI extensions.
-pub const NUM_EXT: usize = 6;
+pub const NUM_EXT: usize = 7;
 pub const EXT_TABLE: [usize; NUM_EXT] = [
     base::EID_BASE,
     time::EID_TIME,
     hsm::EID_HSM,
     spi::EID_SPI,
     rfnc::EID_RFNC,
+    srst::EID_SRST,
     EID_HVISOR,
 ];
 
+// SRST reset types (SBI Spec Chapter 10)
+const SRST_TYPE_SHUTDOWN: usize = 0;
+const SRST_TYPE_COLD_REBOOT: usize = 1;
+const SRST_TYPE_WARM_REBOOT: usize = 2;
+
 /// Use sbi call to putchar in console (qemu uart handler)
 pub fn sbi_console_putchar(c: u8) {
     #[allow(deprecated)]
@@ -88,6 +96,10 @@ pub fn sbi_vs_handler(current_cpu: &mut ArchCpu) {
         rfnc::EID_RFNC => {
             sbi_ret = sbi_rfence_handler(fid, current_cpu);
         }
+        // System Reset Extension (SBI Spec Chapter 10)
+        srst::EID_SRST => {
+            sbi_ret = sbi_srst_handler(fid, current_cpu);
+        }
         // Hvisor Extension (Hvisor Defined)
         EID_HVISOR => {
             sbi_ret = sbi_hvisor_handler(current_cpu);
@@ -238,14 +250,11 @@ pub fn sbi_hsm_handler(fid: usize, current_cpu: &mut ArchCpu) -> SbiRet {
             sbi_ret = sbi_hsm_start_handler(current_cpu);
         }
         hsm::HART_STOP => {
-            // Todo: support hart stop.
-            sbi_ret.error = RET_ERR_NOT_SUPPORTED;
-            warn!("SBI: unsupported sbi_hsm_hart_stop");
+            info!("SBI: cpu {} stop", current_cpu.cpuid);
+            current_cpu.idle();
         }
         hsm::HART_GET_STATUS => {
-            // Todo: support hart get status.
-            sbi_ret.error = RET_ERR_NOT_SUPPORTED;
-            warn!("SBI: unsupported sbi_hsm_hart_get_status");
+            sbi_ret = sbi_hsm_get_status_handler(current_cpu);
         }
         hsm::HART_SUSPEND => {
             // Todo: support hart suspend.
@@ -271,6 +280,10 @@ pub fn sbi_hsm_start_handler(current_cpu: &mut ArchCpu) -> SbiRet {
     let opaque = current_cpu.x[12];
     if cpuid == current_cpu.cpuid {
         sbi_ret.error = RET_ERR_ALREADY_AVAILABLE;
+    } else if !this_zone().read().cpu_set.contains_cpu(cpuid) {
+        // cpus move between zones with HvZoneCpuAdd/HvZoneCpuRemove
+        warn!("SBI: cpu {} is not in this zone", cpuid);
+        sbi_ret.error = RET_ERR_INVALID_PARAM;
     } else {
         info!(
             "SBI: try to wake up cpu {} run@ {:#x}, opaque@ {:#x}",
@@ -294,6 +307,27 @@ pub fn sbi_hsm_start_handler(current_cpu: &mut ArchCpu) -> SbiRet {
     sbi_ret
 }
 
+/// SBI Hart State Management get status handler.
+pub fn sbi_hsm_get_status_handler(current_cpu: &mut ArchCpu) -> SbiRet {
+    let cpuid = hartid_to_cpuid(current_cpu.x[10]);
+    if !this_zone().read().cpu_set.contains_cpu(cpuid) {
+        return SbiRet {
+            error: RET_ERR_INVALID_PARAM,
+            value: 0,
+        };
+    }
+    // Todo: report the pending states, hvisor only tracks power_on.
+    let status = if get_cpu_data(cpuid).arch_cpu.power_on {
+        HSM_STATUS::STARTED
+    } else {
+        HSM_STATUS::STOPPED
+    };
+    SbiRet {
+        error: RET_SUCCESS,
+        value: status as usize,
+    }
+}
+
 /// SBI IPI handler.
 pub fn sbi_ipi_handler(fid: usize, current_cpu: &mut ArchCpu) -> SbiRet {
     if fid != spi::SEND_IPI {
@@ -358,6 +392,39 @@ pub fn sbi_rfence_handler(fid: usize, current_cpu: &mut ArchCpu) -> SbiRet {
     sbi_ret
 }
 
+/// SBI System Reset handler.
+pub fn sbi_srst_handler(fid: usize, current_cpu: &mut ArchCpu) -> SbiRet {
+    let reset_type = current_cpu.x[10];
+    if fid != srst::SYSTEM_RESET {
+        warn!("SBI: unsupported sbi_srst_extension fid {:#x}", fid);
+        return SbiRet {
+            error: RET_ERR_NOT_SUPPORTED,
+            value: 0,
+        };
+    }
+    match reset_type {
+        SRST_TYPE_COLD_REBOOT | SRST_TYPE_WARM_REBOOT => {
+            if is_this_root_zone() {
+                // The root zone owns the machine, so pass the reset through.
+                return sbi_rt::system_reset(sbi_rt::ColdReboot, sbi_rt::NoReason);
+            }
+            this_zone_reboot();
+        }
+        SRST_TYPE_SHUTDOWN => {
+            // Todo: support guest-initiated shutdown.
+            warn!("SBI: unsupported sbi_srst shutdown");
+            SbiRet {
+                error: RET_ERR_NOT_SUPPORTED,
+                value: 0,
+            }
+        }
+        _ => SbiRet {
+            error: RET_ERR_NOT_SUPPORTED,
+            value: 0,
+        },
+    }
+}
+
 /// SBI hvisor handler.
 pub fn sbi_hvisor_handler(current_cpu: &mut ArchCpu) -> SbiRet {
     // Todo: according SBI spec, use a6 instead of a0 to tranfer code is more reasonable.
@@ -14,12 +14,14 @@
 // Authors:
 //
 use super::cpu::ArchCpu;
-use crate::arch::sbi::sbi_vs_handler;
+use crate::arch::sbi::{sbi_vs_handler, EID_HVISOR};
+use crate::device::device_poll;
 #[cfg(feature = "plic")]
 use crate::device::irqchip::plic::{inject_irq, plic_get_hwirq};
 use crate::event::check_events;
 use crate::memory::GuestPhysAddr;
 use crate::memory::{mmio_handle_access, MMIOAccess};
+use crate::stats::{count_exit, ExitReason};
 use core::arch::{asm, global_asm};
 use riscv::register::stvec::TrapMode;
 use riscv::register::{sie, stvec};
@@ -132,15 +134,22 @@ pub fn sync_exception_handler(current_cpu: &mut ArchCpu) {
     match trap_code {
         ExceptionType::ECALL_VS => {
             trace!("ECALL_VS");
+            count_exit(if current_cpu.x[17] == EID_HVISOR {
+                ExitReason::Hypercall
+            } else {
+                ExitReason::FirmwareCall
+            });
             sbi_vs_handler(current_cpu);
             current_cpu.sepc += 4; // For ecall, skip the ecall instruction.
         }
         ExceptionType::LOAD_GUEST_PAGE_FAULT => {
             trace!("LOAD_GUEST_PAGE_FAULT");
+            count_exit(ExitReason::Mmio);
             guest_page_fault_handler(current_cpu);
         }
         ExceptionType::STORE_GUEST_PAGE_FAULT => {
             trace!("STORE_GUEST_PAGE_FAULT");
+            count_exit(ExitReason::Mmio);
             guest_page_fault_handler(current_cpu);
         }
         _ => {
@@ -373,6 +382,7 @@ fn decode_inst(inst: u32) -> (usize, Option<Instruction>) {
 /// Handle interrupts which hvisor receives.
 pub fn interrupts_arch_handle(current_cpu: &mut ArchCpu) {
     trace!("interrupts_arch_handle @CPU{}", current_cpu.cpuid);
+    count_exit(ExitReason::Irq);
     let trap_code = riscv::register::scause::read().code();
     match trap_code {
         InterruptType::STI => {
@@ -395,6 +405,7 @@ pub fn interrupts_arch_handle(current_cpu: &mut ArchCpu) {
             );
         }
     }
+    device_poll();
 }
 
 /// Handle supervisor timer interrupt.
@@ -74,3 +74,12 @@ pub struct HvArchZoneConfig {
     pub aplic_base: usize,
     pub aplic_size: usize,
 }
+
+/// Only integer fields, read from the zone config as is.
+pub type HvArchZoneConfigWire = HvArchZoneConfig;
+
+impl HvArchZoneConfig {
+    pub fn from_wire(wire: HvArchZoneConfigWire) -> HvResult<Self> {
+        Ok(wire)
+    }
+}
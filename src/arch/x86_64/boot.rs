@@ -265,8 +265,8 @@ impl BootParams {
         }
 
         self.e820_table[index] = BootE820Entry {
-            addr: config.pci_config[0].ecam_base as _,
-            size: config.pci_config[0].ecam_size as _,
+            addr: config.pci_config()[0].ecam_base as _,
+            size: config.pci_config()[0].ecam_size as _,
             _type: E820Type::E820_RESERVED,
         };
         index += 1;
@@ -310,9 +310,9 @@ impl BootParams {
         let mem_desc = unsafe { &mut *mem_map };
         *mem_desc = MemoryDescriptor {
             ty: MemoryType::MMIO,
-            phys_start: config.pci_config[0].ecam_base,
-            virt_start: config.pci_config[0].ecam_base,
-            page_count: config.pci_config[0].ecam_size / (PAGE_SIZE as u64),
+            phys_start: config.pci_config()[0].ecam_base,
+            virt_start: config.pci_config()[0].ecam_base,
+            page_count: config.pci_config()[0].ecam_size / (PAGE_SIZE as u64),
             att: MemoryAttribute::UNCACHEABLE,
         };
         cnt += 1;
@@ -33,6 +33,7 @@ use crate::{
     cpu_data::{this_cpu_data, this_zone},
     device::irqchip::pic::{check_pending_vectors, clear_vectors, ioapic, lapic::VirtLocalApic},
     error::{HvError, HvResult},
+    event::wait_for_resume,
     memory::{
         addr::{phys_to_virt, PHYS_VIRT_OFFSET},
         mm::PARKING_MEMORY_SET,
@@ -258,6 +259,17 @@ impl ArchCpu {
         }
     }
 
+    /// Park this cpu in VMX root mode until the zone is resumed.
+    ///
+    /// The current VMCS stays loaded and the guest registers are restored by
+    /// `vmx_exit` once the exit handler returns, so the guest resumes where it stopped.
+    pub fn pause(&mut self) {
+        assert!(this_cpu_id() == self.cpuid);
+        info!("cpu {} paused", self.cpuid);
+        wait_for_resume();
+        info!("cpu {} resumed", self.cpuid);
+    }
+
     /// Guest general-purpose registers.
     pub fn regs(&self) -> &GeneralRegisters {
         &self.guest_regs
@@ -627,6 +639,18 @@ pub fn this_cpu_id() -> usize {
     crate::arch::acpi::get_cpu_id(this_apic_id())
 }
 
+/// Used while waiting for another cpu. Interrupts are disabled in VMX root
+/// operation, so `hlt` would never return; spin instead. Callers busy-wait.
+pub fn wait_for_irq() {
+    core::hint::spin_loop();
+}
+
+/// `hlt` cannot sleep with interrupts disabled, see `wait_for_irq`.
+/// `HV_FEATURE_VIRTIO_CFG_WAIT` is not advertised and this is never called.
+pub fn wait_for_irq_handled() {
+    wait_for_irq();
+}
+
 pub fn this_apic_id() -> usize {
     match CpuId::new().get_feature_info() {
         Some(info) => info.initial_local_apic_id() as usize,
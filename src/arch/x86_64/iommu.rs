@@ -17,7 +17,6 @@
 use crate::{
     arch::{acpi, hpet::current_time_nanos},
     memory::{addr::virt_to_phys, Frame, HostPhysAddr},
-    zone::this_zone_id,
 };
 use ::acpi::sdt::Signature;
 use alloc::{collections::btree_map::BTreeMap, vec::Vec};
@@ -203,6 +202,7 @@ impl Vtd {
 
     fn update_context_entry(
         &mut self,
+        zone_id: usize,
         bus: u8,
         dev_func: u8,
         zone_s2pt_hpa: HostPhysAddr,
@@ -210,7 +210,6 @@ impl Vtd {
     ) {
         let root_entry_hpa = self.root_table.start_paddr() + (bus as usize) * ROOT_TABLE_ENTRY_SIZE;
         let root_entry_low = unsafe { &mut *(root_entry_hpa as *mut u64) };
-        let zone_id = this_zone_id();
 
         // context table not present
         if !root_entry_low.get_bit(0) {
@@ -252,6 +251,22 @@ impl Vtd {
         self.devices.insert(bdf, zone_id);
     }
 
+    /// Add a device to a running zone, whose tables are filled already.
+    fn attach_device(&mut self, zone_id: usize, bdf: u64, zone_s2pt_hpa: HostPhysAddr) {
+        self.devices.insert(bdf, zone_id);
+        let (bus, dev_func) = (bdf.get_bits(8..=15) as u8, bdf.get_bits(0..=7) as u8);
+        self.update_context_entry(zone_id, bus, dev_func, zone_s2pt_hpa, true);
+        self.invalid_iotlb(zone_id as _);
+    }
+
+    fn remove_device(&mut self, bdf: u64) {
+        if let Some(zone_id) = self.devices.remove(&bdf) {
+            let (bus, dev_func) = (bdf.get_bits(8..=15) as u8, bdf.get_bits(0..=7) as u8);
+            self.update_context_entry(zone_id, bus, dev_func, 0, false);
+            self.invalid_iotlb(zone_id as _);
+        }
+    }
+
     fn add_interrupt_table_entry(&mut self, irq: u32) {
         assert!(irq < (IR_ENTRY_CNT as u32));
 
@@ -292,7 +307,7 @@ impl Vtd {
             .collect();
 
         for (bus, dev_func) in bdfs {
-            self.update_context_entry(bus, dev_func, 0, false);
+            self.update_context_entry(zone_id, bus, dev_func, 0, false);
         }
         self.invalid_iotlb(zone_id as _);
     }
@@ -410,7 +425,7 @@ impl Vtd {
             .collect();
 
         for (bus, dev_func) in bdfs {
-            self.update_context_entry(bus, dev_func, zone_s2pt_hpa, true);
+            self.update_context_entry(zone_id, bus, dev_func, zone_s2pt_hpa, true);
         }
         self.invalid_iotlb(zone_id as _);
     }
@@ -508,6 +523,18 @@ pub fn iommu_add_device(zone_id: usize, bdf: usize, _: usize) {
     VTD.get().unwrap().lock().add_device(zone_id, bdf as _);
 }
 
+pub fn iommu_attach_device(zone_id: usize, bdf: usize, zone_s2pt_hpa: HostPhysAddr) {
+    VTD.get()
+        .unwrap()
+        .lock()
+        .attach_device(zone_id, bdf as _, zone_s2pt_hpa);
+}
+
+/// block the dma of a device taken away from its zone
+pub fn iommu_remove_device(bdf: usize) {
+    VTD.get().unwrap().lock().remove_device(bdf as _);
+}
+
 pub fn clear_dma_translation_tables(zone_id: usize) {
     VTD.get().unwrap().lock().clear_devices(zone_id);
 }
@@ -528,6 +555,13 @@ pub fn flush(zone_id: usize, bus: u8, dev_func: u8) {
     VTD.get().unwrap().lock().flush(zone_id, bus, dev_func);
 }
 
+/// drop the iotlb entries of a zone after its s2pt shrinks
+pub fn iommu_flush_zone(zone_id: usize) {
+    if let Some(vtd) = VTD.get() {
+        vtd.lock().invalid_iotlb(zone_id as _);
+    }
+}
+
 fn flush_cache_range(hpa: usize, size: usize) {
     let mut i = 0usize;
     while i < size {
@@ -20,7 +20,7 @@ use crate::{
         cpu::this_cpu_id,
         idt::IdtVector,
     },
-    cpu_data::{this_cpu_data, this_zone, CpuSet},
+    cpu_data::{get_cpu_data, this_cpu_data, this_zone, CpuSet},
     device::irqchip::inject_vector,
     error::HvResult,
     event,
@@ -111,6 +111,8 @@ pub fn send_ipi(value: u64) -> HvResult {
         }
         _ => {}
     }
+    // cpus move between zones with HvZoneCpuAdd/HvZoneCpuRemove, never target other zones
+    dest_set.bitmap &= cpu_set.bitmap;
 
     dest_set.iter().for_each(|dest| {
         match delivery_mode {
@@ -121,7 +123,14 @@ pub fn send_ipi(value: u64) -> HvResult {
             IpiDeliveryMode::NMI => {
                 inject_vector(dest, 2, None, false);
             }
-            IpiDeliveryMode::INIT => {}
+            IpiDeliveryMode::INIT => {
+                // park an offlined cpu so that HvZoneCpuRemove or a later SIPI can take it
+                let target = get_cpu_data(dest);
+                let _lock = target.ctrl_lock.lock();
+                if dest != cpu_id && target.arch_cpu.power_on {
+                    event::send_event(dest, SGI_IPI_ID as _, event::IPI_EVENT_SHUTDOWN);
+                }
+            }
             IpiDeliveryMode::START_UP => {
                 let mut ipi_info = get_ipi_info(dest).unwrap().lock();
                 ipi_info.start_up_addr = (vector as usize) << 12;
@@ -168,3 +177,6 @@ pub fn arch_check_events(event: Option<usize>) {
 pub fn arch_prepare_send_event(cpu_id: usize, ipi_int_id: usize, event_id: usize) {
     debug!("x86_64 arch_prepare_send_event: do nothing now.")
 }
+
+/// A virt ipi that finds no event is not passed to the guest, nothing to do.
+pub fn arch_clear_event_ipi() {}
@@ -267,7 +267,10 @@ impl PagingInstr for S2PTInstr {
         }
     }
 
-    fn flush(_vaddr: Option<usize>) {}
+    /// Local to this cpu, other cpus flush on `IPI_EVENT_FLUSH_S2`.
+    fn flush(_vaddr: Option<usize>) {
+        unsafe { invs2pt(InvS2PTType::Global, 0) };
+    }
 }
 
 /// Information about nested page faults.
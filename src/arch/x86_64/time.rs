@@ -19,3 +19,8 @@
 pub fn init_timebase() {
     info!("Initializing x86_64 timebase not implemented yet.");
 }
+
+/// Return time in microseconds since some arbitrary point in the past.
+pub fn get_time_us() -> u64 {
+    super::hpet::current_time_nanos() / 1_000
+}
@@ -28,6 +28,7 @@ use crate::{
     },
     cpu_data::{this_cpu_data, this_zone},
     device::{
+        device_poll,
         irqchip::{
             inject_vector,
             pic::{ioapic::irqs, lapic::VirtLocalApic},
@@ -37,7 +38,8 @@ use crate::{
     error::HvResult,
     hypercall::HyperCall,
     memory::{mmio_handle_access, MMIOAccess, MemFlags},
-    zone::this_zone_id,
+    stats::{count_exit, ExitReason},
+    zone::{is_this_root_zone, this_zone_id, this_zone_reboot},
 };
 use bit_field::BitField;
 use core::mem::size_of;
@@ -216,6 +218,7 @@ fn handle_external_interrupt() -> HvResult {
     trace!("VM-exit: external interrupt: {:#x?}", int_info);
     assert!(int_info.valid);
     handle_irq(int_info.vector);
+    device_poll();
     Ok(())
 }
 
@@ -379,12 +382,19 @@ fn handle_s2pt_violation(arch_cpu: &mut ArchCpu, exit_info: &VmxExitInfo) -> HvR
 }
 
 fn handle_triple_fault(arch_cpu: &mut ArchCpu, exit_info: &VmxExitInfo) -> HvResult {
-    panic!(
-        "VM exit: Triple fault @ {:#x}, instr length: {:x}\n {:#x?}",
-        exit_info.guest_rip, exit_info.exit_instruction_length, arch_cpu
+    if is_this_root_zone() {
+        panic!(
+            "VM exit: Triple fault @ {:#x}, instr length: {:x}\n {:#x?}",
+            exit_info.guest_rip, exit_info.exit_instruction_length, arch_cpu
+        );
+    }
+    // A triple fault is how the guest resets itself, e.g. via the keyboard controller fallback.
+    warn!(
+        "VM exit: Triple fault @ {:#x} in zone {}, rebooting",
+        exit_info.guest_rip,
+        this_zone_id()
     );
-    // arch_cpu.advance_guest_rip(exit_info.exit_instruction_length as _)?;
-    Ok(())
+    this_zone_reboot();
 }
 
 pub fn handle_vmexit(arch_cpu: &mut ArchCpu) -> HvResult {
@@ -395,6 +405,18 @@ pub fn handle_vmexit(arch_cpu: &mut ArchCpu) -> HvResult {
         panic!("VM entry failed: {:#x?}", exit_info);
     }
 
+    count_exit(match exit_info.exit_reason {
+        VmxExitReason::EXTERNAL_INTERRUPT | VmxExitReason::INTERRUPT_WINDOW => ExitReason::Irq,
+        VmxExitReason::VMCALL => ExitReason::Hypercall,
+        VmxExitReason::CPUID => ExitReason::Cpuid,
+        VmxExitReason::CR_ACCESS
+        | VmxExitReason::IO_INSTRUCTION
+        | VmxExitReason::MSR_READ
+        | VmxExitReason::MSR_WRITE => ExitReason::SysReg,
+        VmxExitReason::EPT_VIOLATION => ExitReason::Mmio,
+        _ => ExitReason::Other,
+    });
+
     let res = match exit_info.exit_reason {
         VmxExitReason::EXTERNAL_INTERRUPT => handle_external_interrupt(),
         VmxExitReason::TRIPLE_FAULT => handle_triple_fault(arch_cpu, &exit_info),
@@ -62,6 +62,15 @@ pub struct HvArchZoneConfig {
     pub screen_base: usize,
 }
 
+/// Only integer fields, read from the zone config as is.
+pub type HvArchZoneConfigWire = HvArchZoneConfig;
+
+impl HvArchZoneConfig {
+    pub fn from_wire(wire: HvArchZoneConfigWire) -> HvResult<Self> {
+        Ok(wire)
+    }
+}
+
 impl Zone {
     pub fn pt_init(&mut self, mem_regions: &[HvConfigMemoryRegion]) -> HvResult {
         for mem_region in mem_regions.iter() {
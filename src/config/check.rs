@@ -0,0 +1,421 @@
+// Copyright (c) 2025 Syswonder
+// hvisor is licensed under Mulan PSL v2.
+// You can use this software according to the terms and conditions of the Mulan PSL v2.
+// You may obtain a copy of Mulan PSL v2 at:
+//     http://license.coscl.org.cn/MulanPSL2
+// THIS SOFTWARE IS PROVIDED ON AN "AS IS" BASIS, WITHOUT WARRANTIES OF ANY KIND, EITHER
+// EXPRESS OR IMPLIED, INCLUDING BUT NOT LIMITED TO NON-INFRINGEMENT, MERCHANTABILITY OR
+// FIT FOR A PARTICULAR PURPOSE.
+// See the Mulan PSL v2 for more details.
+//
+// Syswonder Website:
+//      https://www.syswonder.org
+//
+// Authors:
+//
+//! Validation of a zone config against the running system, done before
+//! `zone_create` allocates anything. Used by `hv_zone_start` and, as a dry
+//! run, by `HvConfigCheck`.
+
+use super::{
+    HvConfigMemoryRegion, HvNativeVirtioConfig, HvZoneConfig,
+    CONFIG_INTERRUPTS_BITMAP_BITS_PER_WORD, CONFIG_MAX_INTERRUPTS, MEM_TYPE_IO, MEM_TYPE_RAM,
+    MEM_TYPE_VIRTIO, SCHED_POLICY_FIFO, WDT_POLICY_REBOOT,
+};
+use crate::consts::{hv_end, hv_start, MAX_CPU_NUM};
+#[cfg(not(feature = "sched"))]
+use crate::cpu_data::get_cpu_data;
+use crate::device::virtio_native::{
+    NATIVE_VIRTIO_MMIO_SIZE, VIRTIO_BLK_SECTOR_SIZE, VIRTIO_ID_BLOCK, VIRTIO_ID_CONSOLE,
+};
+use crate::device::watchdog::WDT_MMIO_SIZE;
+use crate::error::HvResult;
+use crate::memory::addr::virt_to_phys;
+use crate::zone::{all_zones, find_zone, Zone};
+use alloc::sync::Arc;
+use spin::RwLock;
+
+fn overlaps(a_start: u64, a_size: u64, b_start: u64, b_size: u64) -> bool {
+    a_start < b_start + b_size && b_start < a_start + a_size
+}
+
+/// Physical range of hvisor's own image, per-cpu areas and memory pool.
+fn hv_phys_range() -> (u64, u64) {
+    let start = virt_to_phys(hv_start()) as u64;
+    let end = virt_to_phys(hv_end()) as u64;
+    #[cfg(target_arch = "loongarch64")]
+    let (start, end) = (
+        start & !crate::arch::mm::LOONGARCH64_CACHED_DMW_PREFIX,
+        end & !crate::arch::mm::LOONGARCH64_CACHED_DMW_PREFIX,
+    );
+    (start, end - start)
+}
+
+/// Guest-physical address of the entry point. On loongarch64 the entry is a
+/// DMW window address.
+#[cfg(not(target_arch = "x86_64"))]
+fn entry_ipa(entry: u64) -> u64 {
+    if cfg!(target_arch = "loongarch64") {
+        entry & 0x0000_ffff_ffff_ffff
+    } else {
+        entry
+    }
+}
+
+/// Check one memory region against hvisor itself and the RAM of the non-root
+/// zones in `zones`. Also used for regions added by `HvZoneMemAdd`.
+pub fn check_memory_region(region: &HvConfigMemoryRegion, zones: &[Arc<RwLock<Zone>>]) -> HvResult {
+    if region.size == 0 || region.physical_start.checked_add(region.size).is_none() {
+        return hv_result_err!(EINVAL, format!("invalid memory region {:#x?}", region));
+    }
+    match region.mem_type {
+        MEM_TYPE_RAM | MEM_TYPE_IO => {}
+        MEM_TYPE_VIRTIO => return Ok(()),
+        mem_type => return hv_result_err!(EINVAL, format!("unsupported memory type {}", mem_type)),
+    }
+    let (hv_start, hv_size) = hv_phys_range();
+    if overlaps(region.physical_start, region.size, hv_start, hv_size) {
+        return hv_result_err!(
+            EFAULT,
+            format!(
+                "memory region {:#x?} overlaps hvisor memory [{:#x}, {:#x})",
+                region,
+                hv_start,
+                hv_start + hv_size
+            )
+        );
+    }
+    if region.mem_type != MEM_TYPE_RAM {
+        return Ok(());
+    }
+    // The root zone loads images into other zones' RAM, so it is the
+    // only one allowed to share RAM.
+    for zone in zones.iter() {
+        let zone = zone.read();
+        if zone.id == 0 {
+            continue;
+        }
+        if let Some(other) = zone.memory_regions.iter().find(|other| {
+            other.mem_type == MEM_TYPE_RAM
+                && overlaps(
+                    region.physical_start,
+                    region.size,
+                    other.physical_start,
+                    other.size,
+                )
+        }) {
+            return hv_result_err!(
+                EBUSY,
+                format!(
+                    "memory region {:#x?} overlaps zone {}'s ram {:#x?}",
+                    region, zone.id, other
+                )
+            );
+        }
+    }
+    Ok(())
+}
+
+impl HvZoneConfig {
+    #[cfg(not(target_arch = "x86_64"))]
+    fn ram_regions(&self) -> impl Iterator<Item = &HvConfigMemoryRegion> {
+        self.memory_regions()
+            .iter()
+            .filter(|region| region.mem_type == MEM_TYPE_RAM)
+    }
+
+    /// Check that the zone described by this config can be created without
+    /// conflicting with hvisor itself or any existing zone.
+    pub fn check(&self) -> HvResult {
+        let zone_id = self.zone_id as usize;
+        if find_zone(zone_id).is_some() {
+            return hv_result_err!(EEXIST, format!("zone {} already exists", zone_id));
+        }
+        self.check_cpus()?;
+        self.check_memory_regions()?;
+        self.check_entry()?;
+        self.check_irqs()?;
+        self.check_sched()?;
+        self.check_watchdog()?;
+        self.check_native_virtio()?;
+        #[cfg(feature = "pci")]
+        self.check_pci_devs()?;
+        Ok(())
+    }
+
+    fn check_cpus(&self) -> HvResult {
+        let cpus = self.cpus();
+        if cpus.is_empty() {
+            return hv_result_err!(EINVAL, "zone config: no cpu assigned");
+        }
+        for &cpu_id in cpus.iter() {
+            if cpu_id as usize >= MAX_CPU_NUM {
+                return hv_result_err!(
+                    EINVAL,
+                    format!(
+                        "zone config: cpu {} does not exist, only {} cpus",
+                        cpu_id, MAX_CPU_NUM
+                    )
+                );
+            }
+            #[cfg(not(feature = "sched"))]
+            if let Some(zone) = get_cpu_data(cpu_id as _).zone.as_ref() {
+                return hv_result_err!(
+                    EBUSY,
+                    format!(
+                        "zone config: cpu {} already belongs to zone {}",
+                        cpu_id,
+                        zone.read().id
+                    )
+                );
+            }
+            #[cfg(feature = "sched")]
+            if crate::sched::free_vcpu(cpu_id as _).is_none() {
+                return hv_result_err!(
+                    EBUSY,
+                    format!("zone config: cpu {} has no free vcpu left", cpu_id)
+                );
+            }
+        }
+        Ok(())
+    }
+
+    fn check_memory_regions(&self) -> HvResult {
+        let others = all_zones();
+        for region in self.memory_regions() {
+            check_memory_region(region, &others)?;
+        }
+        Ok(())
+    }
+
+    #[cfg(not(target_arch = "x86_64"))]
+    fn check_entry(&self) -> HvResult {
+        let entry = entry_ipa(self.entry_point);
+        if !self
+            .ram_regions()
+            .any(|region| overlaps(entry, 1, region.virtual_start, region.size))
+        {
+            return hv_result_err!(
+                EINVAL,
+                format!(
+                    "zone config: entry point {:#x} is outside zone ram",
+                    self.entry_point
+                )
+            );
+        }
+        if !self
+            .ram_regions()
+            .any(|region| overlaps(self.dtb_load_paddr, 1, region.physical_start, region.size))
+        {
+            return hv_result_err!(
+                EINVAL,
+                format!(
+                    "zone config: dtb address {:#x} is outside zone ram",
+                    self.dtb_load_paddr
+                )
+            );
+        }
+        Ok(())
+    }
+
+    /// x86_64 zones boot through `arch_config.kernel_entry_gpa` and have no dtb.
+    #[cfg(target_arch = "x86_64")]
+    fn check_entry(&self) -> HvResult {
+        Ok(())
+    }
+
+    /// Irqs hvisor injects itself rather than passing through.
+    fn is_virtual_irq(&self, irq: u32) -> bool {
+        self.ivc_config().iter().any(|ivc| ivc.interrupt_num == irq)
+            || self.native_virtio().iter().any(|dev| dev.irq == irq)
+            || self
+                .watchdog()
+                .is_some_and(|wdt| wdt.irq != 0 && wdt.irq == irq)
+    }
+
+    /// A physical irq may only be shared with the root zone, which keeps the
+    /// irqs it hands out to the other zones. Irqs hvisor injects itself are
+    /// not checked.
+    fn check_irqs(&self) -> HvResult {
+        let others = all_zones();
+        for (index, &word) in self.interrupts_bitmap().iter().enumerate() {
+            for bit in 0..CONFIG_INTERRUPTS_BITMAP_BITS_PER_WORD {
+                if word & (1 << bit) == 0 {
+                    continue;
+                }
+                let irq = (index * CONFIG_INTERRUPTS_BITMAP_BITS_PER_WORD + bit) as u32;
+                if irq as usize >= CONFIG_MAX_INTERRUPTS {
+                    return hv_result_err!(
+                        EINVAL,
+                        format!(
+                            "zone config: irq {} is beyond the irqchip's {} irqs",
+                            irq, CONFIG_MAX_INTERRUPTS
+                        )
+                    );
+                }
+                // SGIs and PPIs are banked per cpu.
+                if cfg!(target_arch = "aarch64") && irq < 32 {
+                    continue;
+                }
+                if self.is_virtual_irq(irq) {
+                    continue;
+                }
+                for zone in others.iter() {
+                    let zone = zone.read();
+                    if zone.id != 0 && zone.irq_in_zone(irq) {
+                        return hv_result_err!(
+                            EINVAL,
+                            format!(
+                                "zone config: irq {} already belongs to zone {}",
+                                irq, zone.id
+                            )
+                        );
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn check_sched(&self) -> HvResult {
+        let sched = self.sched();
+        if sched.policy > SCHED_POLICY_FIFO {
+            return hv_result_err!(
+                EINVAL,
+                format!("zone config: unknown sched policy {}", sched.policy)
+            );
+        }
+        Ok(())
+    }
+
+    fn check_watchdog(&self) -> HvResult {
+        let wdt = match self.watchdog() {
+            Some(wdt) => wdt,
+            None => return Ok(()),
+        };
+        if wdt.base == 0 || wdt.base % WDT_MMIO_SIZE as u64 != 0 || wdt.clock_hz == 0 {
+            return hv_result_err!(EINVAL, format!("zone config: invalid watchdog {:#x?}", wdt));
+        }
+        if wdt.policy > WDT_POLICY_REBOOT {
+            return hv_result_err!(
+                EINVAL,
+                format!("zone config: unknown watchdog policy {}", wdt.policy)
+            );
+        }
+        if let Some(region) = self.memory_regions().iter().find(|region| {
+            region.mem_type == MEM_TYPE_RAM
+                && overlaps(
+                    wdt.base,
+                    WDT_MMIO_SIZE as _,
+                    region.virtual_start,
+                    region.size,
+                )
+        }) {
+            return hv_result_err!(
+                EINVAL,
+                format!(
+                    "zone config: watchdog at {:#x} overlaps ram {:#x?}",
+                    wdt.base, region
+                )
+            );
+        }
+        Ok(())
+    }
+
+    fn check_native_virtio(&self) -> HvResult {
+        for dev in self.native_virtio() {
+            let invalid = |dev: &HvNativeVirtioConfig| {
+                hv_result_err!(
+                    EINVAL,
+                    format!("zone config: invalid native virtio device {:#x?}", dev)
+                )
+            };
+            if dev.base == 0 || dev.base % NATIVE_VIRTIO_MMIO_SIZE as u64 != 0 {
+                return invalid(dev);
+            }
+            match dev.device_id {
+                VIRTIO_ID_CONSOLE => {}
+                VIRTIO_ID_BLOCK => {
+                    if dev.disk_size == 0 || dev.disk_size % VIRTIO_BLK_SECTOR_SIZE as u64 != 0 {
+                        return invalid(dev);
+                    }
+                    let (hv_start, hv_size) = hv_phys_range();
+                    if overlaps(dev.disk_paddr, dev.disk_size, hv_start, hv_size) {
+                        return hv_result_err!(
+                            EINVAL,
+                            format!(
+                                "zone config: ram disk at {:#x} overlaps hvisor",
+                                dev.disk_paddr
+                            )
+                        );
+                    }
+                }
+                _ => return invalid(dev),
+            }
+            if self.ram_regions().any(|region| {
+                overlaps(
+                    dev.base,
+                    NATIVE_VIRTIO_MMIO_SIZE as _,
+                    region.virtual_start,
+                    region.size,
+                )
+            }) {
+                return hv_result_err!(
+                    EINVAL,
+                    format!(
+                        "zone config: native virtio device at {:#x} overlaps ram",
+                        dev.base
+                    )
+                );
+            }
+        }
+        Ok(())
+    }
+
+    #[cfg(feature = "pci")]
+    fn check_pci_devs(&self) -> HvResult {
+        use crate::pci::{pci_config::GLOBAL_PCIE_LIST, pci_struct::Bdf, vpci_dev::VpciDevType};
+
+        let others = all_zones();
+        for dev in self.pci_devs() {
+            // virtio-pci has no interrupt without an emulated MSI controller
+            if cfg!(not(target_arch = "x86_64")) && dev.dev_type == VpciDevType::VirtioPci {
+                return hv_result_err!(
+                    EINVAL,
+                    format!(
+                        "zone config: virtio-pci device {:#x?} is only supported on x86_64",
+                        Bdf::new_from_config(*dev)
+                    )
+                );
+            }
+            if dev.dev_type != VpciDevType::Physical {
+                continue;
+            }
+            let bdf = Bdf::new_from_config(*dev);
+            // Free functions are in GLOBAL_PCIE_LIST, and so are the shared bridges.
+            if GLOBAL_PCIE_LIST.lock().contains_key(&bdf) {
+                continue;
+            }
+            if let Some(zone) = others.iter().find(|zone| {
+                zone.read()
+                    .pci_devs
+                    .iter()
+                    .any(|other| Bdf::new_from_config(*other) == bdf)
+            }) {
+                return hv_result_err!(
+                    EBUSY,
+                    format!(
+                        "zone config: pci device {:#x?} already belongs to zone {}",
+                        bdf,
+                        zone.read().id
+                    )
+                );
+            }
+            return hv_result_err!(
+                ENODEV,
+                format!("zone config: pci device {:#x?} does not exist", bdf)
+            );
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,553 @@
+// Copyright (c) 2025 Syswonder
+// hvisor is licensed under Mulan PSL v2.
+// You can use this software according to the terms and conditions of the Mulan PSL v2.
+// You may obtain a copy of Mulan PSL v2 at:
+//     http://license.coscl.org.cn/MulanPSL2
+// THIS SOFTWARE IS PROVIDED ON AN "AS IS" BASIS, WITHOUT WARRANTIES OF ANY KIND, EITHER
+// EXPRESS OR IMPLIED, INCLUDING BUT NOT LIMITED TO NON-INFRINGEMENT, MERCHANTABILITY OR
+// FIT FOR A PARTICULAR PURPOSE.
+// See the Mulan PSL v2 for more details.
+//
+// Syswonder Website:
+//      https://www.syswonder.org
+//
+// Authors:
+//
+use alloc::vec::Vec;
+use core::fmt::Debug;
+use spin::Once;
+
+use crate::{
+    arch::zone::{HvArchZoneConfig, HvArchZoneConfigWire},
+    error::HvResult,
+    pci::vpci_dev::VpciDevType,
+    platform,
+};
+
+mod check;
+pub mod tlv;
+
+pub use check::check_memory_region;
+
+pub const MEM_TYPE_RAM: u32 = 0;
+pub const MEM_TYPE_IO: u32 = 1;
+pub const MEM_TYPE_VIRTIO: u32 = 2;
+
+pub const CONFIG_MAGIC_VERSION: usize = 0x7;
+pub const CONFIG_MAX_MEMORY_REGIONS: usize = 64;
+
+pub type BitmapWord = u32;
+/// Irqs the irqchip drivers handle, they size the zone's irq tables by it.
+pub const CONFIG_MAX_INTERRUPTS: usize = 1024;
+pub const CONFIG_INTERRUPTS_BITMAP_BITS_PER_WORD: usize = 32;
+
+pub const CONFIG_NAME_MAXLEN: usize = 32;
+pub const CONFIG_MAX_IVC_CONFIGS: usize = 2;
+pub const CONFIG_PCI_BUS_MAXNUM: usize = 4;
+pub const CONFIG_MAX_PCI_DEV: usize = 32;
+
+#[repr(C)]
+#[derive(Debug, Clone, Copy)]
+
+pub struct HvConfigMemoryRegion {
+    pub mem_type: u32,
+    pub physical_start: u64,
+    pub virtual_start: u64,
+    pub size: u64,
+}
+
+#[repr(C)]
+#[derive(Debug, Clone, Copy)]
+pub struct HvPciConfig {
+    pub ecam_base: u64,
+    pub ecam_size: u64,
+    pub io_base: u64,
+    pub io_size: u64,
+    pub pci_io_base: u64,
+    pub mem32_base: u64,
+    pub mem32_size: u64,
+    pub pci_mem32_base: u64,
+    pub mem64_base: u64,
+    pub mem64_size: u64,
+    pub pci_mem64_base: u64,
+    pub bus_range_begin: u32,
+    pub bus_range_end: u32,
+    pub domain: u8,
+}
+
+impl HvPciConfig {
+    pub const fn new_empty() -> Self {
+        Self {
+            ecam_base: 0,
+            ecam_size: 0,
+            io_base: 0,
+            io_size: 0,
+            pci_io_base: 0,
+            mem32_base: 0,
+            mem32_size: 0,
+            pci_mem32_base: 0,
+            mem64_base: 0,
+            mem64_size: 0,
+            pci_mem64_base: 0,
+            bus_range_begin: 0,
+            bus_range_end: 0,
+            domain: 0,
+        }
+    }
+}
+pub type IrqBitmap = [BitmapWord; CONFIG_MAX_INTERRUPTS / CONFIG_INTERRUPTS_BITMAP_BITS_PER_WORD];
+
+/// The fixed-size zone config passed by `hv_zone_start` before the TLV format
+/// existed (see `tlv.rs`). It is still accepted for older tools.
+// Every time you change the HvLegacyZoneConfig, you need to change the `CONFIG_MAGIC_VERSION`
+#[repr(C)]
+#[derive(Debug, Clone)]
+pub struct HvLegacyZoneConfig {
+    pub zone_id: u32,
+    cpus: u64,
+    num_memory_regions: u32,
+    memory_regions: [HvConfigMemoryRegion; CONFIG_MAX_MEMORY_REGIONS],
+    interrupts_bitmap: IrqBitmap,
+    num_ivc_configs: u32,
+    ivc_configs: [HvIvcConfig; CONFIG_MAX_IVC_CONFIGS],
+    pub entry_point: u64,
+    pub kernel_load_paddr: u64,
+    pub kernel_size: u64,
+    pub dtb_load_paddr: u64,
+    pub dtb_size: u64,
+    pub name: [u8; CONFIG_NAME_MAXLEN],
+    pub arch_config: HvArchZoneConfigWire,
+    pub num_pci_bus: u64,
+    pub pci_config: [HvPciConfig; CONFIG_PCI_BUS_MAXNUM],
+    pub num_pci_devs: u64,
+    pub alloc_pci_devs: [HvPciDevConfigWire; CONFIG_MAX_PCI_DEV],
+}
+
+/// Zone config as used inside hvisor, parsed from either the legacy blob or
+/// the TLV format. The interrupt bitmap is as long as the highest irq needs,
+/// `check` rejects irqs the irqchip drivers can't handle.
+#[derive(Debug, Clone)]
+pub struct HvZoneConfig {
+    pub zone_id: u32,
+    cpus: u64,
+    memory_regions: Vec<HvConfigMemoryRegion>,
+    interrupts_bitmap: Vec<BitmapWord>,
+    ivc_configs: Vec<HvIvcConfig>,
+    pub entry_point: u64,
+    pub kernel_load_paddr: u64,
+    pub kernel_size: u64,
+    pub dtb_load_paddr: u64,
+    pub dtb_size: u64,
+    pub name: [u8; CONFIG_NAME_MAXLEN],
+    pub arch_config: HvArchZoneConfig,
+    pci_config: Vec<HvPciConfig>,
+    pci_devs: Vec<HvPciDevConfig>,
+    sched: Option<HvSchedConfig>,
+    watchdog: Option<HvWatchdogConfig>,
+    native_virtio: Vec<HvNativeVirtioConfig>,
+}
+
+impl HvZoneConfig {
+    pub fn new(
+        zone_id: u32,
+        cpus: u64,
+        memory_regions: Vec<HvConfigMemoryRegion>,
+        interrupts_bitmap: Vec<BitmapWord>,
+        ivc_configs: Vec<HvIvcConfig>,
+        entry_point: u64,
+        kernel_load_paddr: u64,
+        kernel_size: u64,
+        dtb_load_paddr: u64,
+        dtb_size: u64,
+        name: [u8; CONFIG_NAME_MAXLEN],
+        arch: HvArchZoneConfig,
+        pci: Vec<HvPciConfig>,
+        pci_devs: Vec<HvPciDevConfig>,
+        sched: Option<HvSchedConfig>,
+        watchdog: Option<HvWatchdogConfig>,
+        native_virtio: Vec<HvNativeVirtioConfig>,
+    ) -> Self {
+        Self {
+            zone_id,
+            cpus,
+            memory_regions,
+            interrupts_bitmap,
+            ivc_configs,
+            entry_point,
+            kernel_load_paddr,
+            kernel_size,
+            dtb_load_paddr,
+            dtb_size,
+            name,
+            arch_config: arch,
+            pci_config: pci,
+            pci_devs,
+            sched,
+            watchdog,
+            native_virtio,
+        }
+    }
+
+    /// Parse the zone config passed to `hv_zone_start`. `config` points to
+    /// `size` bytes in hvisor's address space, either a TLV config or a
+    /// `HvLegacyZoneConfig`.
+    pub fn from_raw(config: *const u8, size: usize) -> HvResult<Self> {
+        if tlv::is_tlv_config(config, size) {
+            return tlv::parse(config, size);
+        }
+        if size != core::mem::size_of::<HvLegacyZoneConfig>() {
+            return hv_result_err!(
+                EINVAL,
+                format!(
+                    "zone config: neither a TLV config nor a legacy config of {} bytes, got {} bytes",
+                    core::mem::size_of::<HvLegacyZoneConfig>(),
+                    size
+                )
+            );
+        }
+        // only integer fields, any content is a valid `HvLegacyZoneConfig`
+        Self::from_legacy(unsafe { &*(config as *const HvLegacyZoneConfig) })
+    }
+
+    fn from_legacy(config: &HvLegacyZoneConfig) -> HvResult<Self> {
+        let check = |name: &str, num: u64, max: usize| {
+            if num > max as u64 {
+                hv_result_err!(
+                    EINVAL,
+                    format!("zone config: {} ({}) exceeds limit ({})", name, num, max)
+                )
+            } else {
+                Ok(num as usize)
+            }
+        };
+        let num_memory_regions = check(
+            "num_memory_regions",
+            config.num_memory_regions as _,
+            CONFIG_MAX_MEMORY_REGIONS,
+        )?;
+        let num_ivc_configs = check(
+            "num_ivc_configs",
+            config.num_ivc_configs as _,
+            CONFIG_MAX_IVC_CONFIGS,
+        )?;
+        let num_pci_bus = check("num_pci_bus", config.num_pci_bus, CONFIG_PCI_BUS_MAXNUM)?;
+        let num_pci_devs = check("num_pci_devs", config.num_pci_devs, CONFIG_MAX_PCI_DEV)?;
+        let pci_devs = config.alloc_pci_devs[..num_pci_devs]
+            .iter()
+            .map(|&dev| HvPciDevConfig::from_wire(dev))
+            .collect::<HvResult<Vec<_>>>()?;
+
+        Ok(Self::new(
+            config.zone_id,
+            config.cpus,
+            config.memory_regions[..num_memory_regions].to_vec(),
+            config.interrupts_bitmap.to_vec(),
+            config.ivc_configs[..num_ivc_configs].to_vec(),
+            config.entry_point,
+            config.kernel_load_paddr,
+            config.kernel_size,
+            config.dtb_load_paddr,
+            config.dtb_size,
+            config.name,
+            HvArchZoneConfig::from_wire(config.arch_config)?,
+            config.pci_config[..num_pci_bus].to_vec(),
+            pci_devs,
+            None,
+            None,
+            Vec::new(),
+        ))
+    }
+
+    pub fn memory_regions(&self) -> &[HvConfigMemoryRegion] {
+        &self.memory_regions
+    }
+
+    pub fn interrupts_bitmap(&self) -> &[BitmapWord] {
+        &self.interrupts_bitmap
+    }
+
+    pub fn cpus(&self) -> Vec<u64> {
+        let mut v = Vec::new();
+        for i in 0..64u64 {
+            if (self.cpus >> i) & 1 == 1 {
+                v.push(i);
+            }
+        }
+        v
+    }
+
+    pub fn ivc_config(&self) -> &[HvIvcConfig] {
+        &self.ivc_configs
+    }
+
+    #[allow(unused)]
+    pub fn pci_config(&self) -> &[HvPciConfig] {
+        &self.pci_config
+    }
+
+    #[allow(unused)]
+    pub fn pci_devs(&self) -> &[HvPciDevConfig] {
+        &self.pci_devs
+    }
+
+    /// Scheduling of the zone's vcpus, the default one if the config has none.
+    pub fn sched(&self) -> HvSchedConfig {
+        self.sched.unwrap_or_default()
+    }
+
+    pub fn watchdog(&self) -> Option<&HvWatchdogConfig> {
+        self.watchdog.as_ref()
+    }
+
+    pub fn native_virtio(&self) -> &[HvNativeVirtioConfig] {
+        &self.native_virtio
+    }
+}
+
+pub static mut HV_ROOT_ZONE_CONFIG: Once<HvZoneConfig> = Once::new();
+
+pub fn init() {
+    unsafe { HV_ROOT_ZONE_CONFIG.call_once(|| platform::platform_root_zone_config()) };
+}
+
+pub fn root_zone_config() -> &'static HvZoneConfig {
+    init();
+    unsafe { HV_ROOT_ZONE_CONFIG.get().unwrap() }
+}
+
+#[allow(unused)]
+pub const IVC_PROTOCOL_USER: u32 = 0x0;
+
+#[allow(unused)]
+pub const IVC_PROTOCOL_HVISOR: u32 = 0x1;
+
+#[repr(C)]
+#[derive(Debug, Copy, Clone, Default)]
+pub struct HvIvcConfig {
+    pub ivc_id: u32,
+    pub peer_id: u32,
+    pub control_table_ipa: u64,
+    pub shared_mem_ipa: u64,
+    pub rw_sec_size: u32,
+    pub out_sec_size: u32,
+    pub interrupt_num: u32,
+    pub max_peers: u32,
+}
+
+/// Highest priority first, vcpus of the same priority take turns every time slice.
+pub const SCHED_POLICY_RR: u32 = 0;
+/// Highest priority first, a vcpu keeps its cpu until it blocks or a higher priority
+/// vcpu becomes runnable.
+pub const SCHED_POLICY_FIFO: u32 = 1;
+
+/// Scheduling of the vcpus of a zone, only used by hvisor built with `SCHED=on`. See
+/// `sched.rs`.
+#[repr(C)]
+#[derive(Debug, Copy, Clone, Default)]
+pub struct HvSchedConfig {
+    /// `SCHED_POLICY_*`
+    pub policy: u32,
+    /// Vcpus with higher values run first.
+    pub priority: u32,
+    /// Time slice of `SCHED_POLICY_RR` in microseconds, 0 for the default.
+    pub slice_us: u32,
+    pub reserved: u32,
+}
+
+/// Mark the zone as errored, like `zone_error()`.
+pub const WDT_POLICY_ERROR: u32 = 0;
+pub const WDT_POLICY_SHUTDOWN: u32 = 1;
+pub const WDT_POLICY_REBOOT: u32 = 2;
+
+/// Virtual SP805 watchdog of a zone, see `device/watchdog.rs`.
+#[repr(C)]
+#[derive(Debug, Copy, Clone, Default)]
+pub struct HvWatchdogConfig {
+    /// Guest physical address of the 4 KiB register window.
+    pub base: u64,
+    /// Rate of the emulated WDOGCLK, the same as the clock given to the guest.
+    pub clock_hz: u64,
+    /// Interrupt injected into the zone on the first timeout, 0 for none.
+    pub irq: u32,
+    /// `WDT_POLICY_*`, applied when the second timeout would reset the board.
+    pub policy: u32,
+}
+
+/// Let the native virtio-console read from the physical UART, which takes
+/// the input away from whoever else reads it.
+pub const NATIVE_VIRTIO_F_CONSOLE_RX: u32 = 1 << 0;
+
+/// virtio-mmio device served by hvisor itself instead of a root zone backend,
+/// see `device/virtio_native.rs`.
+#[repr(C)]
+#[derive(Debug, Copy, Clone, Default)]
+pub struct HvNativeVirtioConfig {
+    /// Guest physical address of the register window.
+    pub base: u64,
+    /// virtio device id, `VIRTIO_ID_CONSOLE` or `VIRTIO_ID_BLOCK`.
+    pub device_id: u32,
+    /// Interrupt injected into the zone when a queue is used.
+    pub irq: u32,
+    /// `NATIVE_VIRTIO_F_*`
+    pub flags: u32,
+    pub _padding: u32,
+    /// Host physical address and size of the RAM disk of a virtio-blk.
+    pub disk_paddr: u64,
+    pub disk_size: u64,
+}
+
+#[repr(C)]
+#[derive(Copy, Clone, Default)]
+pub struct HvPciDevConfig {
+    pub domain: u8,
+    pub bus: u8,
+    pub device: u8,
+    pub function: u8,
+    pub dev_type: VpciDevType,
+    /// Bit `n` hides the extended capability with id `n` from the zone.
+    pub ext_cap_hide: u64,
+    /// Bit `n` gives the zone its own copy of the extended capability with
+    /// id `n`, the device's registers are never written.
+    pub ext_cap_emulate: u64,
+    /// VFs hvisor enables on this SR-IOV PF, only read from the root zone's
+    /// config.
+    pub num_vfs: u16,
+    pub _padding: [u8; 6],
+}
+
+#[macro_export]
+macro_rules! pci_dev {
+    ($domain:expr, $bus:expr, $dev:expr, $func:expr, $dev_type:expr) => {
+        $crate::pci_dev!($domain, $bus, $dev, $func, $dev_type, 0, 0)
+    };
+    ($domain:expr, $bus:expr, $dev:expr, $func:expr, $dev_type:expr, $hide:expr, $emulate:expr) => {
+        HvPciDevConfig {
+            domain: $domain,
+            bus: $bus,
+            device: $dev,
+            function: $func,
+            dev_type: $dev_type,
+            ext_cap_hide: $hide,
+            ext_cap_emulate: $emulate,
+            num_vfs: 0,
+            _padding: [0; 6],
+        }
+    };
+}
+
+impl HvPciDevConfig {
+    pub fn from_wire(wire: HvPciDevConfigWire) -> HvResult<Self> {
+        let dev_type = match VpciDevType::from_u32(wire.dev_type) {
+            Some(dev_type) => dev_type,
+            None => {
+                return hv_result_err!(
+                    EINVAL,
+                    format!("pci dev config: unknown dev_type {}", wire.dev_type)
+                )
+            }
+        };
+        Ok(Self {
+            domain: wire.domain,
+            bus: wire.bus,
+            device: wire.device,
+            function: wire.function,
+            dev_type,
+            ext_cap_hide: wire.ext_cap_hide,
+            ext_cap_emulate: wire.ext_cap_emulate,
+            num_vfs: wire.num_vfs,
+            _padding: [0; 6],
+        })
+    }
+}
+
+/// `HvPciDevConfig` as passed in by the root zone, with `dev_type` still a
+/// plain number. TLV configs carry it in a sized section, so new fields go at
+/// the end.
+#[repr(C)]
+#[derive(Debug, Copy, Clone)]
+pub struct HvPciDevConfigWire {
+    pub domain: u8,
+    pub bus: u8,
+    pub device: u8,
+    pub function: u8,
+    pub dev_type: u32,
+    pub ext_cap_hide: u64,
+    pub ext_cap_emulate: u64,
+    pub num_vfs: u16,
+    pub _padding: [u8; 6],
+}
+
+impl Debug for HvPciDevConfig {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        let bdf =
+            crate::pci::pci_struct::Bdf::new(self.domain, self.bus, self.device, self.function);
+        write!(f, "bdf {:#x?}", bdf)
+    }
+}
+
+#[repr(C)]
+#[derive(Debug, Copy, Clone)]
+pub struct HvDwcAtuConfig {
+    // ECAM (Enhanced Configuration Access Mechanism) base address
+    // This is used to match with HvPciConfig::ecam_base
+    pub ecam_base: u64,
+    pub dbi_base: u64,
+    pub dbi_size: u64,
+    pub apb_base: u64,
+    pub apb_size: u64,
+    pub cfg_base: u64,
+    pub cfg_size: u64,
+    // set 1 if io base use atu0, when hvisor need set mmio for io
+    // normally, when num-viewport less than 4, io_cfg_atu_shared is 1, otherwise is 0
+    pub io_cfg_atu_shared: u64,
+}
+
+impl HvDwcAtuConfig {
+    pub const fn new_empty() -> Self {
+        // Use ATU_UNUSED for ATU indices that are not used by default
+        // ATU_UNUSED is u32::MAX, cast to usize for consistency
+        // Default ATU types: CFG0=4, CFG1=5, MEM=0, IO=2
+        Self {
+            ecam_base: 0,
+            dbi_base: 0,
+            dbi_size: 0,
+            apb_base: 0,
+            apb_size: 0,
+            cfg_base: 0,
+            cfg_size: 0,
+            io_cfg_atu_shared: 0,
+        }
+    }
+}
+
+impl Default for HvDwcAtuConfig {
+    fn default() -> Self {
+        Self::new_empty()
+    }
+}
+pub const fn get_irqs_bitmap<const N: usize>(numbers: &[u32; N]) -> IrqBitmap {
+    assert!(
+        CONFIG_MAX_INTERRUPTS % CONFIG_INTERRUPTS_BITMAP_BITS_PER_WORD == 0,
+        "Configuration error: CONFIG_MAX_INTERRUPTS must be a multiple of 32 for a [u32] bitmap without rounding.",
+    );
+
+    let mut bitmap = [0; CONFIG_MAX_INTERRUPTS / CONFIG_INTERRUPTS_BITMAP_BITS_PER_WORD];
+
+    let mut i = 0;
+    while i < N {
+        let num = numbers[i];
+
+        assert!(
+            (num as usize) < CONFIG_MAX_INTERRUPTS,
+            "Input IRQ number is out of bounds. It must be less than CONFIG_MAX_INTERRUPTS.",
+        );
+
+        let word_index = num as usize / CONFIG_INTERRUPTS_BITMAP_BITS_PER_WORD;
+        let bit_index = num as usize & (CONFIG_INTERRUPTS_BITMAP_BITS_PER_WORD - 1);
+
+        bitmap[word_index] |= (1 as BitmapWord) << bit_index;
+        i += 1;
+    }
+
+    bitmap
+}
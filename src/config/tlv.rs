@@ -0,0 +1,607 @@
+// Copyright (c) 2025 Syswonder
+// hvisor is licensed under Mulan PSL v2.
+// You can use this software according to the terms and conditions of the Mulan PSL v2.
+// You may obtain a copy of Mulan PSL v2 at:
+//     http://license.coscl.org.cn/MulanPSL2
+// THIS SOFTWARE IS PROVIDED ON AN "AS IS" BASIS, WITHOUT WARRANTIES OF ANY KIND, EITHER
+// EXPRESS OR IMPLIED, INCLUDING BUT NOT LIMITED TO NON-INFRINGEMENT, MERCHANTABILITY OR
+// FIT FOR A PARTICULAR PURPOSE.
+// See the Mulan PSL v2 for more details.
+//
+// Syswonder Website:
+//      https://www.syswonder.org
+//
+// Authors:
+//
+//! Variable-length zone config format.
+//!
+//! ```text
+//! +---------------------------+
+//! | HvConfigHeader            |  magic, version, total_size
+//! +---------------------------+
+//! | HvConfigSection           |  tag, flags, length
+//! | payload (length bytes)    |
+//! | padding to 8 bytes        |
+//! +---------------------------+
+//! | HvConfigSection ...       |
+//! +---------------------------+
+//! ```
+//!
+//! Array sections (memory regions, irqs, ivc, pci, native virtio) are plain
+//! arrays of the matching `#[repr(C)]` struct and may appear more than once;
+//! their entries are appended. Structs holding an enum are passed as their
+//! wire struct (`HvArchZoneConfigWire`, `HvPciDevConfigWire`), whose values
+//! are checked. `HV_CONFIG_TAG_ZONE` and `HV_CONFIG_TAG_ARCH` must appear
+//! exactly once, `HV_CONFIG_TAG_SCHED` and `HV_CONFIG_TAG_WATCHDOG` at most
+//! once. Unknown tags are skipped unless the section is flagged
+//! `HV_CONFIG_SECTION_MANDATORY`, so newer tools can pass optional sections to
+//! an older hvisor.
+//!
+//! The layout of an existing tag never changes. Structs that grow are passed
+//! in a sized array section instead: a `HvConfigArrayHeader` giving the entry
+//! size, then the entries. Fields past the end of an entry read as zero, and
+//! fields hvisor doesn't know are ignored.
+
+use alloc::vec::Vec;
+use core::mem::size_of;
+
+use super::{
+    BitmapWord, HvConfigMemoryRegion, HvIvcConfig, HvNativeVirtioConfig, HvPciConfig,
+    HvPciDevConfig, HvPciDevConfigWire, HvSchedConfig, HvWatchdogConfig, HvZoneConfig,
+    CONFIG_INTERRUPTS_BITMAP_BITS_PER_WORD, CONFIG_NAME_MAXLEN,
+};
+use crate::arch::zone::{HvArchZoneConfig, HvArchZoneConfigWire};
+use crate::error::HvResult;
+
+/// "HVZC" in little endian.
+pub const HV_CONFIG_TLV_MAGIC: u32 = 0x435a_5648;
+pub const HV_CONFIG_TLV_VERSION: u32 = 1;
+/// Upper bound of a TLV config, to reject garbage sizes early.
+pub const HV_CONFIG_TLV_MAX_SIZE: usize = 1 << 20;
+pub const HV_CONFIG_SECTION_ALIGN: usize = 8;
+/// Bound of the irq numbers, only there to keep the bitmap allocation small.
+/// Which irqs a zone may really have is up to the irqchip, see `check_irqs`.
+pub const HV_CONFIG_TLV_MAX_IRQ: usize = 1 << 16;
+
+/// hvisor must fail instead of skipping this section if it doesn't know the tag.
+pub const HV_CONFIG_SECTION_MANDATORY: u16 = 1 << 0;
+
+/// `HvConfigZoneSection`
+pub const HV_CONFIG_TAG_ZONE: u16 = 1;
+/// `[HvConfigMemoryRegion]`
+pub const HV_CONFIG_TAG_MEMORY_REGIONS: u16 = 2;
+/// `[u32]`, irq numbers
+pub const HV_CONFIG_TAG_IRQS: u16 = 3;
+/// `[HvIvcConfig]`
+pub const HV_CONFIG_TAG_IVC: u16 = 4;
+/// `HvArchZoneConfigWire`
+pub const HV_CONFIG_TAG_ARCH: u16 = 5;
+/// `[HvPciConfig]`
+pub const HV_CONFIG_TAG_PCI_BUS: u16 = 6;
+/// `[HvPciDevConfigV1]`
+pub const HV_CONFIG_TAG_PCI_DEVS: u16 = 7;
+/// `HvSchedConfig`
+pub const HV_CONFIG_TAG_SCHED: u16 = 8;
+/// `HvWatchdogConfig`
+pub const HV_CONFIG_TAG_WATCHDOG: u16 = 9;
+/// `[HvNativeVirtioConfig]`
+pub const HV_CONFIG_TAG_NATIVE_VIRTIO: u16 = 10;
+/// `HvConfigArrayHeader` and `[HvPciDevConfigWire]`, sized
+pub const HV_CONFIG_TAG_PCI_DEVS_SIZED: u16 = 11;
+
+#[repr(C)]
+#[derive(Debug, Clone, Copy)]
+pub struct HvConfigHeader {
+    pub magic: u32,
+    pub version: u32,
+    /// Size of the whole config, including this header.
+    pub total_size: u32,
+    pub reserved: u32,
+}
+
+#[repr(C)]
+#[derive(Debug, Clone, Copy)]
+pub struct HvConfigSection {
+    pub tag: u16,
+    pub flags: u16,
+    /// Size of the payload following this header, without padding.
+    pub length: u32,
+}
+
+#[repr(C)]
+#[derive(Debug, Clone, Copy)]
+pub struct HvConfigZoneSection {
+    pub zone_id: u32,
+    pub reserved: u32,
+    pub cpus: u64,
+    pub entry_point: u64,
+    pub kernel_load_paddr: u64,
+    pub kernel_size: u64,
+    pub dtb_load_paddr: u64,
+    pub dtb_size: u64,
+    pub name: [u8; CONFIG_NAME_MAXLEN],
+}
+
+/// Start of a sized array section.
+#[repr(C)]
+#[derive(Debug, Clone, Copy)]
+pub struct HvConfigArrayHeader {
+    /// Size of each entry, at least the size of the entry's first version.
+    pub entry_size: u32,
+    pub reserved: u32,
+}
+
+/// A pci device as passed in `HV_CONFIG_TAG_PCI_DEVS`, before the extended
+/// capability policy and SR-IOV fields were added.
+#[repr(C)]
+#[derive(Debug, Clone, Copy)]
+pub struct HvPciDevConfigV1 {
+    pub domain: u8,
+    pub bus: u8,
+    pub device: u8,
+    pub function: u8,
+    pub dev_type: u32,
+}
+
+impl From<HvPciDevConfigV1> for HvPciDevConfigWire {
+    fn from(v1: HvPciDevConfigV1) -> Self {
+        Self {
+            domain: v1.domain,
+            bus: v1.bus,
+            device: v1.device,
+            function: v1.function,
+            dev_type: v1.dev_type,
+            ext_cap_hide: 0,
+            ext_cap_emulate: 0,
+            num_vfs: 0,
+            _padding: [0; 6],
+        }
+    }
+}
+
+/// Types every bit pattern is a valid value of, the only ones read straight
+/// from the config bytes. Structs with enums have a wire struct of plain
+/// integers instead, checked when converted.
+unsafe trait Pod: Copy {}
+
+unsafe impl Pod for u32 {}
+unsafe impl Pod for HvConfigHeader {}
+unsafe impl Pod for HvConfigSection {}
+unsafe impl Pod for HvConfigZoneSection {}
+unsafe impl Pod for HvConfigArrayHeader {}
+unsafe impl Pod for HvConfigMemoryRegion {}
+unsafe impl Pod for HvIvcConfig {}
+unsafe impl Pod for HvArchZoneConfigWire {}
+unsafe impl Pod for HvPciConfig {}
+unsafe impl Pod for HvPciDevConfigV1 {}
+unsafe impl Pod for HvPciDevConfigWire {}
+unsafe impl Pod for HvWatchdogConfig {}
+unsafe impl Pod for HvNativeVirtioConfig {}
+
+pub fn is_tlv_config(config: *const u8, size: usize) -> bool {
+    size >= size_of::<HvConfigHeader>()
+        && unsafe { (config as *const u32).read_unaligned() } == HV_CONFIG_TLV_MAGIC
+}
+
+fn read_struct<T: Pod>(payload: &[u8], name: &str) -> HvResult<T> {
+    if payload.len() != size_of::<T>() {
+        return hv_result_err!(
+            EINVAL,
+            format!(
+                "zone config: {} section should be {} bytes, but got {}",
+                name,
+                size_of::<T>(),
+                payload.len()
+            )
+        );
+    }
+    Ok(unsafe { (payload.as_ptr() as *const T).read_unaligned() })
+}
+
+fn read_array<T: Pod>(payload: &[u8], name: &str) -> HvResult<Vec<T>> {
+    if payload.len() % size_of::<T>() != 0 {
+        return hv_result_err!(
+            EINVAL,
+            format!(
+                "zone config: {} section ({} bytes) is not a multiple of {} bytes",
+                name,
+                payload.len(),
+                size_of::<T>()
+            )
+        );
+    }
+    Ok(payload
+        .chunks_exact(size_of::<T>())
+        .map(|entry| unsafe { (entry.as_ptr() as *const T).read_unaligned() })
+        .collect())
+}
+
+/// Read a sized array section whose entries are at least `min_size` bytes.
+fn read_sized_array<T: Pod>(payload: &[u8], name: &str, min_size: usize) -> HvResult<Vec<T>> {
+    let header_size = size_of::<HvConfigArrayHeader>();
+    if payload.len() < header_size {
+        return hv_result_err!(
+            EINVAL,
+            format!("zone config: {} section has no array header", name)
+        );
+    }
+    let header: HvConfigArrayHeader = read_struct(&payload[..header_size], name)?;
+    let entry_size = header.entry_size as usize;
+    let entries = &payload[header_size..];
+    if entry_size < min_size || entries.len() % entry_size != 0 {
+        return hv_result_err!(
+            EINVAL,
+            format!(
+                "zone config: {} section has {} bytes of {}-byte entries, expect at least {}",
+                name,
+                entries.len(),
+                entry_size,
+                min_size
+            )
+        );
+    }
+    let copy = entry_size.min(size_of::<T>());
+    Ok(entries
+        .chunks_exact(entry_size)
+        .map(|entry| {
+            // `T: Pod`, so zero is a valid value for the fields not passed
+            let mut value: T = unsafe { core::mem::zeroed() };
+            unsafe {
+                core::ptr::copy_nonoverlapping(
+                    entry.as_ptr(),
+                    &mut value as *mut T as *mut u8,
+                    copy,
+                );
+            }
+            value
+        })
+        .collect())
+}
+
+/// Parse a TLV config of `size` bytes at `config`, `is_tlv_config` must hold.
+pub fn parse(config: *const u8, size: usize) -> HvResult<HvZoneConfig> {
+    if size > HV_CONFIG_TLV_MAX_SIZE {
+        return hv_result_err!(
+            EINVAL,
+            format!(
+                "zone config: {} bytes exceeds limit ({})",
+                size, HV_CONFIG_TLV_MAX_SIZE
+            )
+        );
+    }
+    if size < size_of::<HvConfigHeader>() {
+        return hv_result_err!(EINVAL, "zone config: truncated header");
+    }
+    let data = unsafe { core::slice::from_raw_parts(config, size) };
+    let header: HvConfigHeader = read_struct(&data[..size_of::<HvConfigHeader>()], "header")?;
+    if header.version != HV_CONFIG_TLV_VERSION {
+        return hv_result_err!(
+            EINVAL,
+            format!(
+                "zone config: unsupported version {}, expect {}",
+                header.version, HV_CONFIG_TLV_VERSION
+            )
+        );
+    }
+    if header.total_size as usize != size {
+        return hv_result_err!(
+            EINVAL,
+            format!(
+                "zone config: header says {} bytes, but got {}",
+                header.total_size, size
+            )
+        );
+    }
+
+    let mut zone: Option<HvConfigZoneSection> = None;
+    let mut arch: Option<HvArchZoneConfig> = None;
+    let mut memory_regions = Vec::new();
+    let mut interrupts_bitmap: Vec<BitmapWord> = Vec::new();
+    let mut ivc_configs = Vec::new();
+    let mut pci_config = Vec::new();
+    let mut pci_devs = Vec::new();
+    let mut sched: Option<HvSchedConfig> = None;
+    let mut watchdog: Option<HvWatchdogConfig> = None;
+    let mut native_virtio = Vec::new();
+
+    let mut offset = size_of::<HvConfigHeader>();
+    while offset < size {
+        if size - offset < size_of::<HvConfigSection>() {
+            return hv_result_err!(EINVAL, "zone config: truncated section header");
+        }
+        let payload_start = offset + size_of::<HvConfigSection>();
+        let section: HvConfigSection = read_struct(&data[offset..payload_start], "section")?;
+        let payload_end = payload_start + section.length as usize;
+        if payload_end > size {
+            return hv_result_err!(
+                EINVAL,
+                format!(
+                    "zone config: section {} at {:#x} overflows the config",
+                    section.tag, offset
+                )
+            );
+        }
+        let payload = &data[payload_start..payload_end];
+
+        match section.tag {
+            HV_CONFIG_TAG_ZONE => {
+                if zone.is_some() {
+                    return hv_result_err!(EINVAL, "zone config: duplicated zone section");
+                }
+                zone = Some(read_struct(payload, "zone")?);
+            }
+            HV_CONFIG_TAG_ARCH => {
+                if arch.is_some() {
+                    return hv_result_err!(EINVAL, "zone config: duplicated arch section");
+                }
+                let wire: HvArchZoneConfigWire = read_struct(payload, "arch")?;
+                arch = Some(HvArchZoneConfig::from_wire(wire)?);
+            }
+            HV_CONFIG_TAG_MEMORY_REGIONS => {
+                memory_regions.extend(read_array::<HvConfigMemoryRegion>(payload, "memory")?)
+            }
+            HV_CONFIG_TAG_IRQS => {
+                for irq in read_array::<u32>(payload, "irqs")? {
+                    let irq = irq as usize;
+                    if irq >= HV_CONFIG_TLV_MAX_IRQ {
+                        return hv_result_err!(
+                            EINVAL,
+                            format!(
+                                "zone config: irq {} exceeds limit ({})",
+                                irq, HV_CONFIG_TLV_MAX_IRQ
+                            )
+                        );
+                    }
+                    let word = irq / CONFIG_INTERRUPTS_BITMAP_BITS_PER_WORD;
+                    if word >= interrupts_bitmap.len() {
+                        interrupts_bitmap.resize(word + 1, 0);
+                    }
+                    interrupts_bitmap[word] |= 1 << (irq % CONFIG_INTERRUPTS_BITMAP_BITS_PER_WORD);
+                }
+            }
+            HV_CONFIG_TAG_IVC => ivc_configs.extend(read_array::<HvIvcConfig>(payload, "ivc")?),
+            HV_CONFIG_TAG_PCI_BUS => {
+                pci_config.extend(read_array::<HvPciConfig>(payload, "pci bus")?)
+            }
+            HV_CONFIG_TAG_PCI_DEVS => {
+                for v1 in read_array::<HvPciDevConfigV1>(payload, "pci devs")? {
+                    pci_devs.push(HvPciDevConfig::from_wire(v1.into())?);
+                }
+            }
+            HV_CONFIG_TAG_PCI_DEVS_SIZED => {
+                let min_size = size_of::<HvPciDevConfigV1>();
+                for wire in read_sized_array::<HvPciDevConfigWire>(payload, "pci devs", min_size)? {
+                    pci_devs.push(HvPciDevConfig::from_wire(wire)?);
+                }
+            }
+            HV_CONFIG_TAG_SCHED => {
+                if sched.is_some() {
+                    return hv_result_err!(EINVAL, "zone config: duplicated sched section");
+                }
+                sched = Some(read_struct(payload, "sched")?);
+            }
+            HV_CONFIG_TAG_WATCHDOG => {
+                if watchdog.is_some() {
+                    return hv_result_err!(EINVAL, "zone config: duplicated watchdog section");
+                }
+                watchdog = Some(read_struct(payload, "watchdog")?);
+            }
+            HV_CONFIG_TAG_NATIVE_VIRTIO => native_virtio.extend(
+                read_array::<HvNativeVirtioConfig>(payload, "native virtio")?,
+            ),
+            tag if section.flags & HV_CONFIG_SECTION_MANDATORY != 0 => {
+                return hv_result_err!(
+                    EINVAL,
+                    format!("zone config: unknown mandatory section {}", tag)
+                );
+            }
+            tag => warn!("zone config: skip unknown section {}", tag),
+        }
+
+        offset = (payload_end + HV_CONFIG_SECTION_ALIGN - 1) & !(HV_CONFIG_SECTION_ALIGN - 1);
+    }
+
+    let zone = match zone {
+        Some(zone) => zone,
+        None => return hv_result_err!(EINVAL, "zone config: missing zone section"),
+    };
+    let arch = match arch {
+        Some(arch) => arch,
+        None => return hv_result_err!(EINVAL, "zone config: missing arch section"),
+    };
+
+    Ok(HvZoneConfig::new(
+        zone.zone_id,
+        zone.cpus,
+        memory_regions,
+        interrupts_bitmap,
+        ivc_configs,
+        zone.entry_point,
+        zone.kernel_load_paddr,
+        zone.kernel_size,
+        zone.dtb_load_paddr,
+        zone.dtb_size,
+        zone.name,
+        arch,
+        pci_config,
+        pci_devs,
+        sched,
+        watchdog,
+        native_virtio,
+    ))
+}
+
+#[cfg(test)]
+fn push_section<T>(config: &mut Vec<u8>, tag: u16, flags: u16, payload: &[T]) {
+    let payload = unsafe {
+        core::slice::from_raw_parts(
+            payload.as_ptr() as *const u8,
+            size_of::<T>() * payload.len(),
+        )
+    };
+    config.extend_from_slice(&tag.to_le_bytes());
+    config.extend_from_slice(&flags.to_le_bytes());
+    config.extend_from_slice(&(payload.len() as u32).to_le_bytes());
+    config.extend_from_slice(payload);
+    config.resize(config.len().next_multiple_of(HV_CONFIG_SECTION_ALIGN), 0);
+}
+
+/// A valid config with the zone and arch sections, `extra` adds more.
+#[cfg(test)]
+fn test_config(extra: impl FnOnce(&mut Vec<u8>)) -> Vec<u8> {
+    let mut config = Vec::new();
+    config.resize(size_of::<HvConfigHeader>(), 0);
+    let zone: HvConfigZoneSection = unsafe { core::mem::zeroed() };
+    push_section(&mut config, HV_CONFIG_TAG_ZONE, 0, &[zone]);
+    let arch: HvArchZoneConfigWire = unsafe { core::mem::zeroed() };
+    push_section(&mut config, HV_CONFIG_TAG_ARCH, 0, &[arch]);
+    extra(&mut config);
+    set_total_size(&mut config, 0);
+    config
+}
+
+#[cfg(test)]
+fn set_total_size(config: &mut Vec<u8>, truncate: usize) {
+    config.truncate(config.len() - truncate);
+    let header = [
+        HV_CONFIG_TLV_MAGIC,
+        HV_CONFIG_TLV_VERSION,
+        config.len() as u32,
+        0,
+    ];
+    for (i, word) in header.iter().enumerate() {
+        config[i * 4..i * 4 + 4].copy_from_slice(&word.to_le_bytes());
+    }
+}
+
+#[test_case]
+fn test_tlv_truncated() {
+    let config = test_config(|_| {});
+    assert!(parse(config.as_ptr(), config.len()).is_ok());
+    assert!(parse(config.as_ptr(), size_of::<HvConfigHeader>() - 1).is_err());
+    // cut into the arch section's payload
+    let mut config = test_config(|_| {});
+    set_total_size(&mut config, 8);
+    assert!(parse(config.as_ptr(), config.len()).is_err());
+    // half a section header
+    let mut config = test_config(|config| config.extend_from_slice(&[0; 8]));
+    set_total_size(&mut config, 4);
+    assert!(parse(config.as_ptr(), config.len()).is_err());
+}
+
+#[test_case]
+fn test_tlv_unknown_tag() {
+    let config = test_config(|config| push_section(config, 0x7fff, 0, &[0u32; 3]));
+    assert!(parse(config.as_ptr(), config.len()).is_ok());
+    let config =
+        test_config(|config| push_section(config, 0x7fff, HV_CONFIG_SECTION_MANDATORY, &[0u32; 3]));
+    assert!(parse(config.as_ptr(), config.len()).is_err());
+}
+
+#[test_case]
+fn test_tlv_oversized_length() {
+    let config = test_config(|config| {
+        let length = config.len() + 4;
+        push_section(config, HV_CONFIG_TAG_IRQS, 0, &[33u32]);
+        config[length..length + 4].copy_from_slice(&0x1000u32.to_le_bytes());
+    });
+    assert!(parse(config.as_ptr(), config.len()).is_err());
+    assert!(parse(config.as_ptr(), HV_CONFIG_TLV_MAX_SIZE + 1).is_err());
+    let config = test_config(|config| {
+        push_section(
+            config,
+            HV_CONFIG_TAG_IRQS,
+            0,
+            &[HV_CONFIG_TLV_MAX_IRQ as u32],
+        )
+    });
+    assert!(parse(config.as_ptr(), config.len()).is_err());
+}
+
+#[test_case]
+fn test_tlv_bad_enum() {
+    let mut dev: HvPciDevConfigV1 = unsafe { core::mem::zeroed() };
+    let config = test_config(|config| push_section(config, HV_CONFIG_TAG_PCI_DEVS, 0, &[dev]));
+    assert!(parse(config.as_ptr(), config.len()).is_ok());
+    dev.dev_type = 0x100;
+    let config = test_config(|config| push_section(config, HV_CONFIG_TAG_PCI_DEVS, 0, &[dev]));
+    assert!(parse(config.as_ptr(), config.len()).is_err());
+
+    #[cfg(target_arch = "aarch64")]
+    {
+        let mut config = test_config(|_| {});
+        let gic_version = size_of::<HvConfigHeader>()
+            + size_of::<HvConfigSection>() * 2
+            + size_of::<HvConfigZoneSection>().next_multiple_of(HV_CONFIG_SECTION_ALIGN)
+            + 8;
+        config[gic_version..gic_version + 8].copy_from_slice(&2usize.to_le_bytes());
+        assert!(parse(config.as_ptr(), config.len()).is_err());
+    }
+}
+
+#[cfg(test)]
+fn push_pci_devs_sized(config: &mut Vec<u8>, entry_size: usize, devs: &[HvPciDevConfigWire]) {
+    let mut payload = Vec::new();
+    payload.extend_from_slice(&(entry_size as u32).to_le_bytes());
+    payload.extend_from_slice(&0u32.to_le_bytes());
+    for dev in devs {
+        let bytes = unsafe {
+            core::slice::from_raw_parts(
+                dev as *const HvPciDevConfigWire as *const u8,
+                size_of::<HvPciDevConfigWire>(),
+            )
+        };
+        payload.extend_from_slice(&bytes[..entry_size.min(bytes.len())]);
+        // fields of a newer tool
+        payload.resize(payload.len() + entry_size.saturating_sub(bytes.len()), 0xff);
+    }
+    push_section(config, HV_CONFIG_TAG_PCI_DEVS_SIZED, 0, &payload);
+}
+
+#[test_case]
+fn test_tlv_pci_devs_v1() {
+    let v1 = HvPciDevConfigV1 {
+        domain: 0,
+        bus: 1,
+        device: 2,
+        function: 3,
+        dev_type: 0,
+    };
+    let config = test_config(|config| push_section(config, HV_CONFIG_TAG_PCI_DEVS, 0, &[v1, v1]));
+    let parsed = parse(config.as_ptr(), config.len()).unwrap();
+    assert_eq!(parsed.pci_devs().len(), 2);
+    assert_eq!(parsed.pci_devs()[1].function, 3);
+    assert_eq!(parsed.pci_devs()[1].ext_cap_hide, 0);
+    assert_eq!(parsed.pci_devs()[1].num_vfs, 0);
+}
+
+#[test_case]
+fn test_tlv_pci_devs_sized() {
+    let mut dev: HvPciDevConfigWire = HvPciDevConfigV1 {
+        domain: 0,
+        bus: 1,
+        device: 2,
+        function: 3,
+        dev_type: 0,
+    }
+    .into();
+    dev.ext_cap_hide = 1 << 1;
+    dev.num_vfs = 4;
+    let v1_size = size_of::<HvPciDevConfigV1>();
+    // a v1 entry, one without num_vfs, the current one and one from a newer tool
+    for (entry_size, hide, num_vfs) in [
+        (v1_size, 0, 0),
+        (24, 1 << 1, 0),
+        (32, 1 << 1, 4),
+        (40, 1 << 1, 4),
+    ] {
+        let config = test_config(|config| push_pci_devs_sized(config, entry_size, &[dev, dev]));
+        let parsed = parse(config.as_ptr(), config.len()).unwrap();
+        assert_eq!(parsed.pci_devs().len(), 2);
+        assert_eq!(parsed.pci_devs()[1].bus, 1);
+        assert_eq!(parsed.pci_devs()[1].ext_cap_hide, hide);
+        assert_eq!(parsed.pci_devs()[1].num_vfs, num_vfs);
+    }
+    let config = test_config(|config| push_pci_devs_sized(config, v1_size - 4, &[dev]));
+    assert!(parse(config.as_ptr(), config.len()).is_err());
+}
@@ -52,8 +52,27 @@ pub const INVALID_ADDRESS: usize = usize::MAX;
 /// This is determined by the board-specific configuration.
 pub const MAX_CPU_NUM: usize = BOARD_NCPUS;
 
+/// Maximum number of vcpus that time-share one physical cpu with the `sched` feature.
+#[cfg(feature = "sched")]
+pub const SCHED_MAX_VCPUS_PER_CPU: usize = 4;
+
+/// Maximum number of vcpus, each with its own per-CPU data area.
+///
+/// Without `sched` every vcpu is pinned to its own physical cpu and the vcpu ids are
+/// the physical cpu ids. With `sched` vcpu `id` runs on physical cpu `id % MAX_CPU_NUM`.
+#[cfg(feature = "sched")]
+pub const MAX_VCPU_NUM: usize = MAX_CPU_NUM * SCHED_MAX_VCPUS_PER_CPU;
+#[cfg(not(feature = "sched"))]
+pub const MAX_VCPU_NUM: usize = MAX_CPU_NUM;
+
+// zone cpu sets are u64 bitmaps
+const _: () = assert!(MAX_VCPU_NUM <= 64);
+
 /// Maximum number of memory zones supported.
+#[cfg(not(feature = "sched"))]
 pub const MAX_ZONE_NUM: usize = 4;
+#[cfg(feature = "sched")]
+pub const MAX_ZONE_NUM: usize = 16;
 
 /// Maximum number of spin-wait iterations before timing out.
 pub const MAX_WAIT_TIMES: usize = 100000000;
@@ -76,7 +95,7 @@ pub fn core_end() -> VirtAddr {
 ///
 /// The memory pool follows immediately after all per-CPU data areas.
 pub fn mem_pool_start() -> VirtAddr {
-    core_end() + MAX_CPU_NUM * PER_CPU_SIZE
+    core_end() + MAX_VCPU_NUM * PER_CPU_SIZE
 }
 
 /// Returns the address marking the end of the entire hypervisor memory.
@@ -107,10 +126,10 @@ extern "C" {
 /// Total size of the extended memory area from the core end to the end of
 /// the hypervisor memory.
 ///
-/// This is calculated as (MAX_CPU_NUM * PER_CPU_SIZE) + HV_MEM_POOL_SIZE and
+/// This is calculated as (MAX_VCPU_NUM * PER_CPU_SIZE) + HV_MEM_POOL_SIZE and
 /// represents the total additional memory required beyond the core static area.
 #[allow(dead_code)]
-pub const HV_EXTENDED_SIZE: usize = MAX_CPU_NUM * PER_CPU_SIZE + HV_MEM_POOL_SIZE;
+pub const HV_EXTENDED_SIZE: usize = MAX_VCPU_NUM * PER_CPU_SIZE + HV_MEM_POOL_SIZE;
 
 // Expose HV_EXTENDED_SIZE as a global assembly symbol for linker accessibility.
 // This makes it easier for the linker script to directly reference this constant value.
@@ -19,10 +19,11 @@ use spin::{Mutex, RwLock};
 use crate::arch::cpu::{store_cpu_pointer_to_reg, this_cpu_id, ArchCpu};
 use crate::consts::{INVALID_ADDRESS, PER_CPU_ARRAY_PTR, PER_CPU_SIZE};
 use crate::memory::addr::VirtAddr;
+use crate::stats::VcpuStats;
 use crate::zone::Zone;
 use crate::ENTERED_CPUS;
 use core::fmt::Debug;
-use core::sync::atomic::Ordering;
+use core::sync::atomic::{AtomicUsize, Ordering};
 
 // global_asm!(include_str!("./arch/aarch64/page_table.S"),);
 
@@ -35,6 +36,10 @@ pub struct PerCpu {
     pub zone: Option<Arc<RwLock<Zone>>>,
     pub ctrl_lock: Mutex<()>,
     pub boot_cpu: bool,
+    pub paused: bool,
+    pub stats: VcpuStats,
+    /// `IPI_EVENT_FLUSH_S2` handled so far, for the sender to wait on.
+    pub s2_flushes: AtomicUsize,
     // percpu stack
 }
 
@@ -52,6 +57,9 @@ impl PerCpu {
                 zone: None,
                 ctrl_lock: Mutex::new(()),
                 boot_cpu: false,
+                paused: false,
+                stats: VcpuStats::default(),
+                s2_flushes: AtomicUsize::new(0),
             })
         };
         unsafe {
@@ -99,6 +107,16 @@ pub fn this_cpu_data<'a>() -> &'a mut PerCpu {
     get_cpu_data(this_cpu_id())
 }
 
+/// Back off in a loop polling for other cpus. With `sched` this lets the other vcpus
+/// of this cpu run, which may be the ones being waited for, so never call it with a
+/// spinlock held.
+pub fn cpu_relax() {
+    #[cfg(feature = "sched")]
+    crate::sched::yield_now();
+    #[cfg(not(feature = "sched"))]
+    core::hint::spin_loop();
+}
+
 #[allow(unused)]
 pub fn this_zone() -> Arc<RwLock<Zone>> {
     this_cpu_data().zone.clone().unwrap()
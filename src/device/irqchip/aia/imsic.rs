@@ -16,6 +16,7 @@
 use super::vimsic::*;
 use crate::arch::csr::{read_csr, write_csr};
 use crate::platform::__board::{IMSIC_GUEST_INDEX, IMSIC_NUM_IDS};
+use crate::stats::count_irq_injected;
 
 pub const CSR_SISELECT: usize = 0x150;
 pub const CSR_SIREG: usize = 0x151;
@@ -64,6 +65,7 @@ pub fn imsic_trigger(hart: u32, guest: u32, eiid: u32) {
         unsafe {
             core::ptr::write_volatile(addr as *mut u32, eiid);
         }
+        count_irq_injected(hart as usize);
     } else {
         panic!(
             "Unknown imsic set hart {} guest {} eiid {}",
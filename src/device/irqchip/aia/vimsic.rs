@@ -15,6 +15,7 @@
 //
 
 use crate::consts::PAGE_SIZE;
+use crate::error::HvResult;
 use crate::memory::GuestPhysAddr;
 use crate::memory::HostPhysAddr;
 use crate::memory::MemFlags;
@@ -33,26 +34,56 @@ use crate::zone::Zone;
  */
 
 pub fn vimsic_init(zone: &mut Zone, imsic_base: usize, guest_num: usize) {
-    let paddr = imsic_base as HostPhysAddr;
-    let size = crate::memory::PAGE_SIZE;
-    zone.cpu_set.iter().for_each(|cpu_id| {
-        let vcpu_id = cpu_id; // In hvisor, vcpu_id == cpu_id.
-        let imsic_hpa = imsic_base + PAGE_SIZE * ((1 + guest_num) * cpu_id + IMSIC_GUEST_INDEX);
-        // For VM, it couldn't see VS-files.
-        let imsic_gpa = imsic_base + PAGE_SIZE * vcpu_id; // In hvisor, vcpu_id == cpu_id.
-        info!(
-            "Zone {} vIMSIC map hart {} imsic hpa {:#x} gpa {:#x}",
-            zone.id, cpu_id, imsic_hpa, imsic_gpa
-        );
-        zone.gpm.insert(MemoryRegion::new_with_offset_mapper(
-            imsic_gpa as GuestPhysAddr,
-            imsic_hpa,
-            size,
-            MemFlags::READ | MemFlags::WRITE,
-        ));
+    let cpu_set = zone.cpu_set;
+    cpu_set.iter().for_each(|cpu_id| {
+        let _ = vimsic_map_cpu(zone, cpu_id, imsic_base, guest_num);
     });
 }
 
+/// Map the VS-file of `cpu_id` to the guest's S-file address of that hart.
+pub fn vimsic_map_cpu(
+    zone: &mut Zone,
+    cpu_id: usize,
+    imsic_base: usize,
+    guest_num: usize,
+) -> HvResult {
+    let size = crate::memory::PAGE_SIZE;
+    let vcpu_id = cpu_id; // In hvisor, vcpu_id == cpu_id.
+    let imsic_hpa = imsic_base + PAGE_SIZE * ((1 + guest_num) * cpu_id + IMSIC_GUEST_INDEX);
+    // For VM, it couldn't see VS-files.
+    let imsic_gpa = imsic_base + PAGE_SIZE * vcpu_id; // In hvisor, vcpu_id == cpu_id.
+    info!(
+        "Zone {} vIMSIC map hart {} imsic hpa {:#x} gpa {:#x}",
+        zone.id, cpu_id, imsic_hpa, imsic_gpa
+    );
+    zone.gpm.insert(MemoryRegion::new_with_offset_mapper(
+        imsic_gpa as GuestPhysAddr,
+        imsic_hpa as HostPhysAddr,
+        size,
+        MemFlags::READ | MemFlags::WRITE,
+    ))
+}
+
+pub fn vimsic_unmap_cpu(zone: &mut Zone, cpu_id: usize, imsic_base: usize) -> HvResult {
+    let imsic_gpa = imsic_base + PAGE_SIZE * cpu_id;
+    info!("Zone {} vIMSIC unmap hart {}", zone.id, cpu_id);
+    zone.gpm.delete(imsic_gpa as GuestPhysAddr, PAGE_SIZE)
+}
+
 pub fn imsic_vs_file_addr(hart_id: usize) -> usize {
     IMSIC_S_BASE + PAGE_SIZE * ((1 + IMSIC_GUEST_NUM) * hart_id + IMSIC_GUEST_INDEX)
 }
+
+/// The zone sees the S-file of hart n at `IMSIC_S_BASE + n * PAGE_SIZE`, its
+/// MSIs go to the VS-file hvisor maps there instead. Interrupt identities are
+/// per interrupt file, so only the hart is checked.
+pub fn vimsic_msi_translate(zone: &Zone, address: u64, data: u32) -> Option<(u64, u32)> {
+    // seteipnum_le and seteipnum_be
+    const IMSIC_SETEIPNUM_END: usize = 0x8;
+    let offset = (address as usize).checked_sub(IMSIC_S_BASE)?;
+    let (cpu_id, reg) = (offset / PAGE_SIZE, offset % PAGE_SIZE);
+    if reg >= IMSIC_SETEIPNUM_END || data == 0 || !zone.cpu_set.contains_cpu(cpu_id) {
+        return None;
+    }
+    Some(((imsic_vs_file_addr(cpu_id) + reg) as u64, data))
+}
@@ -15,13 +15,14 @@
 //      Hangqi Ren <2572131118@qq.com>
 use crate::arch::cpu::this_cpu_id;
 use crate::device::irqchip::gicv2::gicc::GICC;
-use crate::device::irqchip::gicv2::gicd::GICV2_SGIS_NUM;
+use crate::device::irqchip::gicv2::gicd::{GICD, GICV2_SGIS_NUM};
 use crate::device::irqchip::gicv2::gich::{
     GICH, GICV2_GICH_HCR_UIE, GICV2_GICH_LR_CPUID_SHIFT, GICV2_GICH_LR_HW,
     GICV2_GICH_LR_PENDING_STATE, GICV2_GICH_LR_PHYSID_SHIFT,
 };
 use crate::event::check_events;
 use crate::hypercall::SGI_IPI_ID;
+use crate::stats::count_irq_injected;
 /// This file defines and implements the functional functions of physical gicv2.
 /// author: ForeverYolo
 /// reference:
@@ -56,6 +57,16 @@ pub fn gicv2_handle_irq() {
     }
 }
 
+/// Clear a pending `SGI_IPI_ID` of this cpu, whose events were taken without
+/// handling the irq.
+pub fn clear_pending_ipi() {
+    // a byte of source cpus per sgi, banked per target cpu
+    let sgi = SGI_IPI_ID as usize;
+    GICD.get()
+        .unwrap()
+        .set_cpendsgir(sgi / 4, 0xff << (sgi % 4 * 8));
+}
+
 pub fn get_pending_irq() -> Option<usize> {
     let iar = GICC.get().unwrap().get_iar() as usize;
     let irq = iar & 0x3ff;
@@ -149,6 +160,7 @@ pub fn inject_irq(irq_id: usize, is_sgi: bool) -> bool {
             val = val | GICV2_GICH_LR_HW;
         }
         GICH.get().unwrap().set_lr(free_lr as usize, val as u32);
+        count_irq_injected(this_cpu_id());
         true
     }
 }
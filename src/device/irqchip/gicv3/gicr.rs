@@ -19,13 +19,15 @@ use core::ptr;
 use spin::{mutex::Mutex, Once};
 
 use crate::{
-    arch::cpu::this_cpu_id,
+    arch::cpu::this_pcpu_id,
     consts::{MAX_CPU_NUM, MAX_ZONE_NUM, PAGE_SIZE},
     hypercall::SGI_IPI_ID,
     memory::Frame,
     zone::this_zone_id,
 };
 
+#[cfg(feature = "sched")]
+use super::HV_TIMER_INTERRUPT;
 use super::{
     gicd::{
         GICD_ICACTIVER, GICD_ICENABLER, GICD_ICFGR, GICD_ICPENDR, GICD_IGROUPR, GICD_IPRIORITYR,
@@ -62,19 +64,26 @@ pub const GICR_PROPBASER: usize = 0x0070;
 pub const GICR_PENDBASER: usize = 0x0078;
 
 pub fn enable_ipi() {
-    let base = host_gicr_base(this_cpu_id()) + GICR_SGI_BASE;
+    let base = host_gicr_base(this_pcpu_id()) + GICR_SGI_BASE;
 
     unsafe {
         let gicr_waker = (base + GICR_WAKER) as *mut u32;
         gicr_waker.write_volatile(gicr_waker.read_volatile() & !0x02);
         while gicr_waker.read_volatile() & 0x04 != 0 {}
 
+        #[cfg(not(feature = "sched"))]
+        let irqs = [SGI_IPI_ID, MAINTENACE_INTERRUPT];
+        #[cfg(feature = "sched")]
+        let irqs = [SGI_IPI_ID, MAINTENACE_INTERRUPT, HV_TIMER_INTERRUPT];
+
         let gicr_igroupr0 = (base + GICR_IGROUPR) as *mut u32;
         gicr_igroupr0.write_volatile(gicr_igroupr0.read_volatile() | (1 << SGI_IPI_ID));
+        #[cfg(feature = "sched")]
+        gicr_igroupr0.write_volatile(gicr_igroupr0.read_volatile() | (1 << HV_TIMER_INTERRUPT));
 
         let gicr_isenabler0 = (base + GICR_ISENABLER) as *mut u32;
         let gicr_ipriorityr0 = (base + GICR_IPRIORITYR) as *mut u32;
-        for irq_id in [SGI_IPI_ID, MAINTENACE_INTERRUPT] {
+        for irq_id in irqs {
             let reg = irq_id / 4;
             let offset = irq_id % 4 * 8;
             let mask = ((1 << 8) - 1) << offset;
@@ -88,6 +97,13 @@ pub fn enable_ipi() {
     }
 }
 
+/// Clear a pending `SGI_IPI_ID` of this cpu, whose events were taken without
+/// handling the irq.
+pub fn clear_pending_ipi() {
+    let base = host_gicr_base(this_pcpu_id()) + GICR_SGI_BASE;
+    unsafe { ((base + GICR_ICPENDR) as *mut u32).write_volatile(1 << SGI_IPI_ID) };
+}
+
 pub struct LpiPropTable {
     phy_addr: usize,
     frame: Frame,
@@ -406,6 +406,18 @@ static CMDQ: Once<Mutex<Cmdq>> = Once::new();
 
 static CT_LIST: RwLock<Vec<Arc<RwLock<CollectionTable>>>> = RwLock::new(vec![]);
 
+/// Devices signal LPIs by writing their event id to `GITS_TRANSLATER`, the
+/// ITS maps it through the ITT the zone set up with MAPD/MAPTI for that
+/// device. Zones see the ITS at its host address, so only the doorbell is
+/// checked.
+pub fn gits_msi_translate(address: u64, data: u32) -> Option<(u64, u32)> {
+    let gits_base = host_gits_base();
+    if gits_base == 0 || address != (gits_base + GITS_TRANSLATER) as u64 {
+        return None;
+    }
+    Some((address, data))
+}
+
 pub fn gits_init() {
     CMDQ.call_once(|| Mutex::new(Cmdq::new()));
     dt_list_init();
@@ -36,13 +36,17 @@ use crate::arch::aarch64::sysreg::{read_sysreg, smc_arg1, write_sysreg};
 use crate::arch::cpu::{cpuid_to_mpidr_affinity, this_cpu_id};
 use crate::arch::zone::GicConfig;
 use crate::config::root_zone_config;
-use crate::consts::{self, MAX_CPU_NUM};
+use crate::consts::{self, MAX_CPU_NUM, MAX_VCPU_NUM};
 
 use crate::event::check_events;
 use crate::hypercall::SGI_IPI_ID;
+use crate::stats::count_irq_injected;
 use crate::zone::Zone;
 
 const ICH_HCR_UIE: u64 = 1 << 1;
+/// EL2 physical timer, preempts vcpus with `sched`.
+#[cfg(feature = "sched")]
+pub const HV_TIMER_INTERRUPT: u64 = 26;
 //TODO: add Distributor init
 pub fn gicc_init() {
     //TODO: add Redistributor init
@@ -96,6 +100,12 @@ pub fn gicv3_handle_irq_el1() {
         if irq_id < 8 {
             trace!("sgi get {}, try to handle...", irq_id);
             deactivate_irq(irq_id);
+            // events are handled in `sched::schedule_tail`, by the vcpu they are for
+            #[cfg(feature = "sched")]
+            if irq_id == SGI_IPI_ID as _ {
+                crate::sched::kicked();
+                continue;
+            }
             let mut ipi_handled = false;
             if irq_id == SGI_IPI_ID as _ {
                 ipi_handled = check_events();
@@ -108,6 +118,13 @@ pub fn gicv3_handle_irq_el1() {
             warn!("skip sgi {}", irq_id);
             deactivate_irq(irq_id);
         } else {
+            #[cfg(feature = "sched")]
+            if irq_id == HV_TIMER_INTERRUPT as _ {
+                crate::sched::timer_tick();
+                deactivate_irq(irq_id);
+                write_sysreg!(icc_dir_el1, irq_id as u64);
+                continue;
+            }
             if irq_id == 27 {
                 // virtual timer interrupt
                 TIMER_INTERRUPT_COUNTER.fetch_add(1, core::sync::atomic::Ordering::SeqCst);
@@ -130,7 +147,15 @@ pub fn gicv3_handle_irq_el1() {
                 warn!("not konw irq id = {}", irq_id);
             }
             if irq_id != 25 {
+                #[cfg(not(feature = "sched"))]
                 inject_irq(irq_id, true);
+                #[cfg(feature = "sched")]
+                match crate::sched::irq_target(irq_id) {
+                    Some(vcpu) => inject_irq_to(vcpu, irq_id, true),
+                    None => {
+                        inject_irq(irq_id, true);
+                    }
+                }
             }
             deactivate_irq(irq_id);
         }
@@ -235,6 +260,16 @@ impl PendingIrqs {
         }
     }
 
+    #[cfg(feature = "sched")]
+    fn add_irq_to(&self, cpu: usize, irq_id: usize, is_hardware: bool) {
+        self.inner[cpu].lock().push_back((irq_id, is_hardware));
+    }
+
+    #[cfg(feature = "sched")]
+    fn has_irq(&self, cpu: usize) -> bool {
+        !self.inner[cpu].lock().is_empty()
+    }
+
     fn fetch_irq(&self) -> Option<(usize, bool)> {
         match self.inner.get(this_cpu_id()) {
             Some(pending_irqs) => {
@@ -325,10 +360,155 @@ pub fn inject_irq(irq_id: usize, is_hardware: bool) -> bool {
             val |= (irq_id as u64) << 32; //pINTID
         }
         write_lr(free_ir as usize, val);
+        count_irq_injected(this_cpu_id());
         return true;
     }
 }
 
+/// Inject a virtual interrupt to `vcpu`. If it is not the running vcpu the interrupt is
+/// queued until `vcpu` is switched in, and its cpu is told to reschedule.
+#[cfg(feature = "sched")]
+pub fn inject_irq_to(vcpu: usize, irq_id: usize, is_hardware: bool) {
+    if vcpu == this_cpu_id() {
+        inject_irq(irq_id, is_hardware);
+    } else {
+        PENDING_VIRQS
+            .get()
+            .unwrap()
+            .add_irq_to(vcpu, irq_id, is_hardware);
+        crate::sched::kick(vcpu);
+    }
+}
+
+/// Whether virtual interrupts wait in the queue of `vcpu`.
+#[cfg(feature = "sched")]
+pub fn has_pending_irq_queued(vcpu: usize) -> bool {
+    PENDING_VIRQS.get().unwrap().has_irq(vcpu)
+}
+
+/// Move the queued virtual interrupts of the current vcpu to the list registers.
+#[cfg(feature = "sched")]
+pub fn inject_pending_irqs() {
+    if has_pending_irq_queued(this_cpu_id()) {
+        handle_maintenace_interrupt();
+    }
+}
+
+fn lr_num() -> usize {
+    (read_sysreg!(ich_vtr_el2) as usize & 0xf) + 1
+}
+
+// ICH_AP1R<n>_EL2 implemented for the number of priority bits, see gicv3_clear_pending_irqs
+#[cfg(feature = "sched")]
+fn ap1r_num() -> usize {
+    match (read_sysreg!(ich_vtr_el2) >> 29) + 1 {
+        5 => 1,
+        6 => 2,
+        _ => 4,
+    }
+}
+
+#[cfg(feature = "sched")]
+fn read_ap1r(id: usize) -> u64 {
+    match id {
+        0 => read_sysreg!(ICH_AP1R0_EL2),
+        1 => read_sysreg!(ICH_AP1R1_EL2),
+        2 => read_sysreg!(ICH_AP1R2_EL2),
+        _ => read_sysreg!(ICH_AP1R3_EL2),
+    }
+}
+
+#[cfg(feature = "sched")]
+fn write_ap1r(id: usize, val: u64) {
+    match id {
+        0 => write_sysreg!(ICH_AP1R0_EL2, val),
+        1 => write_sysreg!(ICH_AP1R1_EL2, val),
+        2 => write_sysreg!(ICH_AP1R2_EL2, val),
+        _ => write_sysreg!(ICH_AP1R3_EL2, val),
+    }
+}
+
+// PPIs whose active state belongs to the running vcpu, the maintenance and EL2 timer
+// interrupts stay with hvisor.
+#[cfg(feature = "sched")]
+const VCPU_PPI_MASK: u32 = 0xffff_0000 & !(1 << MAINTENACE_INTERRUPT) & !(1 << HV_TIMER_INTERRUPT);
+
+/// Virtual cpu interface state of a switched out vcpu.
+#[cfg(feature = "sched")]
+#[derive(Debug)]
+pub struct GicVcpuState {
+    hcr: u64,
+    vmcr: u64,
+    ap1r: [u64; 4],
+    lr: [u64; 16],
+    /// PPIs the vcpu has not deactivated yet, e.g. its virtual timer.
+    ppi_active: u32,
+}
+
+#[cfg(feature = "sched")]
+impl Default for GicVcpuState {
+    // what gicc_init sets up
+    fn default() -> Self {
+        Self {
+            hcr: 0x1,
+            vmcr: (0xf0 << 24) | (1 << 1),
+            ap1r: [0; 4],
+            lr: [0; 16],
+            ppi_active: 0,
+        }
+    }
+}
+
+#[cfg(feature = "sched")]
+impl GicVcpuState {
+    fn sgi_base() -> usize {
+        host_gicr_base(crate::arch::cpu::this_pcpu_id()) + gicr::GICR_SGI_BASE
+    }
+
+    pub fn save(&mut self) {
+        self.hcr = read_sysreg!(ich_hcr_el2);
+        self.vmcr = read_sysreg!(ich_vmcr_el2);
+        for i in 0..ap1r_num() {
+            self.ap1r[i] = read_ap1r(i);
+        }
+        for i in 0..lr_num() {
+            self.lr[i] = read_lr(i);
+        }
+        let sgi_base = Self::sgi_base();
+        unsafe {
+            let active = ((sgi_base + gicr::GICR_ISACTIVER) as *const u32).read_volatile();
+            self.ppi_active = active & VCPU_PPI_MASK;
+            ((sgi_base + gicr::GICR_ICACTIVER) as *mut u32).write_volatile(self.ppi_active);
+        }
+    }
+
+    pub fn restore(&self) {
+        write_sysreg!(ich_vmcr_el2, self.vmcr);
+        for i in 0..ap1r_num() {
+            write_ap1r(i, self.ap1r[i]);
+        }
+        for i in 0..lr_num() {
+            write_lr(i, self.lr[i]);
+        }
+        write_sysreg!(ich_hcr_el2, self.hcr);
+        unsafe {
+            ((Self::sgi_base() + gicr::GICR_ISACTIVER) as *mut u32).write_volatile(self.ppi_active);
+        }
+    }
+
+    pub fn has_pending_irq(&self) -> bool {
+        self.lr[..lr_num()]
+            .iter()
+            .any(|&lr| lr & LR_STATE_PENDING != 0)
+    }
+
+    pub fn live_has_pending_irq() -> bool {
+        (0..lr_num()).any(|i| read_lr(i) & LR_STATE_PENDING != 0)
+    }
+}
+
+const LR_STATE_PENDING: u64 = 1 << 62;
+
 pub static GIC: Once<Gic> = Once::new();
 pub const PER_GICR_SIZE: usize = 0x20000;
 
@@ -462,7 +642,7 @@ pub fn primary_init_early() {
         gits_init();
     }
 
-    PENDING_VIRQS.call_once(|| PendingIrqs::new(MAX_CPU_NUM));
+    PENDING_VIRQS.call_once(|| PendingIrqs::new(MAX_VCPU_NUM));
     debug!("gic = {:#x?}", GIC.get().unwrap());
 }
 
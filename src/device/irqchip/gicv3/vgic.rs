@@ -13,15 +13,22 @@
 //
 // Authors:
 //
+#[cfg(not(feature = "sched"))]
 use alloc::sync::Arc;
 
 use super::{gicd::GICD_LOCK, is_spi};
+#[cfg(feature = "sched")]
+use super::{inject_irq_to, HV_TIMER_INTERRUPT};
+#[cfg(feature = "sched")]
+use crate::arch::cpu::this_cpu_id;
+#[cfg(not(feature = "sched"))]
+use crate::cpu_data::get_cpu_data;
 use crate::platform::BOARD_MPIDR_MAPPINGS;
 use crate::{
     arch::zone::{GicConfig, HvArchZoneConfig},
     config::{BitmapWord, CONFIG_INTERRUPTS_BITMAP_BITS_PER_WORD, CONFIG_MAX_INTERRUPTS},
     consts::MAX_CPU_NUM,
-    cpu_data::{get_cpu_data, this_zone},
+    cpu_data::this_zone,
     device::irqchip::gicv3::{
         gicd::*, gicr::*, gits::*, host_gicd_base, host_gicr_base, host_gits_base,
         MAINTENACE_INTERRUPT, PER_GICR_SIZE,
@@ -31,6 +38,8 @@ use crate::{
     memory::{mmio_perform_access, MMIOAccess},
     zone::{this_zone_id, Zone},
 };
+#[cfg(feature = "sched")]
+use alloc::vec::Vec;
 pub fn reg_range(base: usize, n: usize, size: usize) -> core::ops::Range<usize> {
     base..(base + n * size)
 }
@@ -166,21 +175,65 @@ fn restrict_bitmask_access(
     Ok(())
 }
 
+/// Whether the current zone runs on physical cpu `cpu` and may configure its
+/// redistributor. With `sched` the redistributor is shared by all vcpus of the cpu.
+fn owns_redist(cpu: usize) -> bool {
+    #[cfg(not(feature = "sched"))]
+    {
+        get_cpu_data(cpu)
+            .zone
+            .as_ref()
+            .map_or(false, |zone| Arc::ptr_eq(&this_zone(), zone))
+    }
+    #[cfg(feature = "sched")]
+    {
+        crate::sched::zone_vcpu(&this_zone().read(), cpu).is_some()
+    }
+}
+
+/// Emulate a guest write of ICC_SGI1R_EL1. The target list names physical cpus by
+/// affinity, each one stands for the vcpu of the current zone on that cpu.
+#[cfg(feature = "sched")]
+pub fn vgicv3_send_sgi(sgi1r: u64) {
+    let sgi_id = ((sgi1r >> 24) & 0xf) as usize;
+    let irm = (sgi1r >> 40) & 1 != 0;
+    let aff = (((sgi1r >> 48) & 0xff) << 32)
+        | (((sgi1r >> 32) & 0xff) << 16)
+        | ((sgi1r & 0xff_0000) >> 8);
+    let range = ((sgi1r >> 44) & 0xf) * 16;
+    let target_list = sgi1r & 0xffff;
+
+    let this_vcpu = this_cpu_id();
+    let zone = this_zone();
+    let zone_r = zone.read();
+    let targets: Vec<usize> = (0..MAX_CPU_NUM)
+        .filter(|&cpu| {
+            let mpidr = BOARD_MPIDR_MAPPINGS[cpu];
+            if irm {
+                return true;
+            }
+            let aff0 = mpidr & 0xff;
+            mpidr & !0xff == aff
+                && aff0 >= range
+                && aff0 < range + 16
+                && target_list & (1 << (aff0 - range)) != 0
+        })
+        .filter_map(|cpu| crate::sched::zone_vcpu(&zone_r, cpu))
+        .filter(|&vcpu| !irm || vcpu != this_vcpu)
+        .collect();
+    drop(zone_r);
+    for vcpu in targets {
+        inject_irq_to(vcpu, sgi_id, false);
+    }
+}
+
 pub fn vgicv3_redist_handler(mmio: &mut MMIOAccess, cpu: usize) -> HvResult {
     trace!("gicr({}) mmio = {:#x?}", cpu, mmio);
     let gicr_base = host_gicr_base(cpu);
     match mmio.address {
         GICR_CTLR => {
-            if get_cpu_data(cpu).zone.is_none() {
-                if !mmio.is_write {
-                    mmio_perform_access(gicr_base, mmio);
-                }
-            } else if Arc::ptr_eq(&this_zone(), get_cpu_data(cpu).zone.as_ref().unwrap()) {
+            if owns_redist(cpu) || !mmio.is_write {
                 mmio_perform_access(gicr_base, mmio);
-            } else {
-                if !mmio.is_write {
-                    mmio_perform_access(gicr_base, mmio);
-                }
             }
         }
         reg if reg == GICR_TYPER || reg == GICR_TYPER + 0x4 => {
@@ -242,11 +295,17 @@ pub fn vgicv3_redist_handler(mmio: &mut MMIOAccess, cpu: usize) -> HvResult {
             || reg_range(GICR_SGI_BASE + GICR_IPRIORITYR, 8, 4).contains(&reg)
             || reg_range(GICR_SGI_BASE + GICR_ICFGR, 2, 4).contains(&reg) =>
         {
-            if Arc::ptr_eq(&this_zone(), get_cpu_data(cpu).zone.as_ref().unwrap()) {
+            if owns_redist(cpu) {
                 // avoid linux disable maintenance interrupt
                 if reg == GICR_SGI_BASE + GICR_ICENABLER {
                     mmio.value &= !(1 << MAINTENACE_INTERRUPT);
                     mmio.value &= !(1 << SGI_IPI_ID);
+                    // the timers are shared with the other vcpus of the cpu
+                    #[cfg(feature = "sched")]
+                    {
+                        mmio.value &= !(1 << HV_TIMER_INTERRUPT);
+                        mmio.value &= !(1 << 27);
+                    }
                 }
                 // ignore access to foreign redistributors
                 mmio_perform_access(gicr_base, mmio);
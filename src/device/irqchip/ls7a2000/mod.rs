@@ -24,6 +24,7 @@ use crate::{
         register::{read_gcsr_estat, write_gcsr_estat},
     },
     consts::MAX_CPU_NUM,
+    stats::count_irq_injected,
     zone::Zone,
 };
 use chip::*;
@@ -133,6 +134,7 @@ pub fn inject_irq(_irq: usize, is_hardware: bool) {
     }
     let mut status = GLOBAL_IRQ_INJECT_STATUS.lock();
     status.cpu_status[this_cpu_id()].status = InjectionStatus::Injecting;
+    count_irq_injected(this_cpu_id());
 
     tcfg::set_en(true); // start timer to avoid endless timer injection
                         // please only enable this for debugging because it may cause overheads for realtime nonroots
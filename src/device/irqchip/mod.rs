@@ -15,6 +15,7 @@
 //
 use crate::arch::zone::HvArchZoneConfig;
 use crate::config::HvZoneConfig;
+use crate::error::HvResult;
 use crate::zone::Zone;
 
 #[cfg(all(feature = "gicv2", target_arch = "aarch64"))]
@@ -40,6 +41,14 @@ pub fn gic_handle_irq() {
     gicv3::gicv3_handle_irq_el1();
 }
 
+#[cfg(target_arch = "aarch64")]
+pub fn gic_clear_pending_ipi() {
+    #[cfg(feature = "gicv2")]
+    gicv2::gic::clear_pending_ipi();
+    #[cfg(feature = "gicv3")]
+    gicv3::gicr::clear_pending_ipi();
+}
+
 #[cfg(target_arch = "aarch64")]
 pub fn gic_send_event(cpu_id: u64, sgi_num: u64) {
     #[cfg(feature = "gicv3")]
@@ -84,6 +93,56 @@ impl Zone {
         }
     }
 
+    /// Called before `cpu_id` joins `cpu_set`. GIC redistributors, the x86 LAPICs and
+    /// the 7A2000 are set up for every cpu already and need nothing here.
+    pub fn virqc_cpu_add(&mut self, _cpu_id: usize) -> HvResult {
+        #[cfg(all(feature = "plic", target_arch = "riscv64"))]
+        {
+            self.vplic_cpu_add(_cpu_id)?;
+        }
+        #[cfg(all(feature = "aia", target_arch = "riscv64"))]
+        {
+            aia::vimsic::vimsic_map_cpu(
+                self,
+                _cpu_id,
+                crate::platform::IMSIC_S_BASE,
+                crate::platform::IMSIC_GUEST_NUM,
+            )?;
+        }
+        Ok(())
+    }
+
+    /// Called before `cpu_id` leaves `cpu_set`.
+    pub fn virqc_cpu_remove(&mut self, _cpu_id: usize) -> HvResult {
+        #[cfg(all(feature = "plic", target_arch = "riscv64"))]
+        {
+            self.vplic_cpu_remove(_cpu_id)?;
+        }
+        #[cfg(all(feature = "aia", target_arch = "riscv64"))]
+        {
+            aia::vimsic::vimsic_unmap_cpu(self, _cpu_id, crate::platform::IMSIC_S_BASE)?;
+        }
+        Ok(())
+    }
+
+    /// Physical doorbell address and data for an MSI this zone programmed into
+    /// a passthrough device, `None` if the zone does not own the interrupt.
+    pub fn virqc_msi_translate(&self, _address: u64, _data: u32) -> Option<(u64, u32)> {
+        #[cfg(all(feature = "gicv3", target_arch = "aarch64"))]
+        return gicv3::gits::gits_msi_translate(_address, _data);
+        #[cfg(all(feature = "aia", target_arch = "riscv64"))]
+        return aia::vimsic::vimsic_msi_translate(self, _address, _data);
+        #[cfg(target_arch = "x86_64")]
+        return pic::msi_translate(self, _address, _data);
+        // no msi doorbell to hand out
+        #[cfg(not(any(
+            all(feature = "gicv3", target_arch = "aarch64"),
+            all(feature = "aia", target_arch = "riscv64"),
+            target_arch = "x86_64"
+        )))]
+        None
+    }
+
     pub fn mmio_init(&mut self, hv_config: &HvArchZoneConfig) {
         #[cfg(all(feature = "gicv2", target_arch = "aarch64"))]
         {
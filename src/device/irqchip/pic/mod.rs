@@ -20,9 +20,11 @@ pub mod lapic;
 use crate::{
     arch::{acpi, cpu::this_cpu_id, idt, iommu, ipi, msr, pio, vmcs::Vmcs},
     consts::{MAX_CPU_NUM, MAX_ZONE_NUM},
-    zone::Zone,
+    stats::count_irq_injected,
+    zone::{find_zone, Zone},
 };
 use alloc::{collections::vec_deque::VecDeque, vec::Vec};
+use bit_field::BitField;
 use core::arch::asm;
 use ioapic::ioapic_inject_irq;
 use spin::{Mutex, Once};
@@ -72,6 +74,7 @@ impl PendingVectors {
                 }
                 // if it's an exception, or an interrupt that is not blocked, inject it directly.
                 Vmcs::inject_interrupt(vector.0, vector.1).unwrap();
+                count_irq_injected(cpu_id);
                 vectors.has_eoi = false;
                 vectors.queue.pop_front();
                 return true;
@@ -105,6 +108,55 @@ pub fn inject_vector(cpu_id: usize, vector: u8, err_code: Option<u32>, allow_rep
     }
 }
 
+/// The cpu and vector of an MSI to `address`/`data`, if it uses fixed
+/// delivery to the physical APIC id of a cpu of `zone` and a vector that is
+/// not one of hvisor's own.
+fn msi_target(zone: &Zone, address: u64, data: u32) -> Option<(usize, u8)> {
+    const MSI_ADDRESS_BASE: u64 = 0xfee0_0000;
+    if address & !0xf_ffff != MSI_ADDRESS_BASE {
+        return None;
+    }
+    let apic_id = address.get_bits(12..20) as usize;
+    let vector = data.get_bits(0..8) as u8;
+    if vector < 0x20
+        || vector == idt::IdtVector::VIRT_IPI_VECTOR
+        || vector >= idt::IdtVector::APIC_ERROR_VECTOR
+        || !acpi::contains_apic_id(apic_id)
+    {
+        return None;
+    }
+    let cpu_id = acpi::get_cpu_id(apic_id);
+    if !zone.cpu_set.contains_cpu(cpu_id) {
+        return None;
+    }
+    Some((cpu_id, vector))
+}
+
+/// Deliver an MSI written by a virtual device of zone `zone_id`. Only fixed
+/// delivery to a physical APIC id of the zone is supported.
+pub fn inject_msi(zone_id: usize, address: u64, data: u32) -> bool {
+    let target = find_zone(zone_id).and_then(|zone| msi_target(&zone.read(), address, data));
+    match target {
+        Some((cpu_id, vector)) => {
+            inject_vector(cpu_id, vector, None, true);
+            true
+        }
+        None => false,
+    }
+}
+
+/// A passthrough device's MSI reaches the physical LAPIC of its target cpu,
+/// which reflects the vector into the guest running there. VT-d interrupt
+/// remapping is not enabled, so nothing stops a device from sending other
+/// messages by DMA. Only the root zone, which may target any cpu anyway, gets
+/// them.
+pub fn msi_translate(zone: &Zone, address: u64, data: u32) -> Option<(u64, u32)> {
+    if zone.id != 0 {
+        return None;
+    }
+    msi_target(zone, address, data).map(|_| (address, data))
+}
+
 pub fn check_pending_vectors(cpu_id: usize) -> bool {
     PENDING_VECTORS.get().unwrap().check_pending_vectors(cpu_id)
 }
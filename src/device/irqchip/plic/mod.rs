@@ -32,6 +32,7 @@ use crate::cpu_data::this_cpu_data;
 use crate::error::HvResult;
 use crate::memory::mmio::MMIOAccess;
 use crate::platform::*;
+use crate::stats::count_irq_injected;
 use crate::zone::Zone;
 use alloc::collections::BTreeMap;
 use alloc::sync::Arc;
@@ -98,6 +99,7 @@ pub fn inject_irq(irq: usize, is_hardware: bool) {
     };
     // Avoid holding the read lock when calling inject_irq
     vplic.inject_irq(vcontext_id, irq, is_hardware);
+    count_irq_injected(this_cpu_id());
 }
 
 /// Convert vcontext id to pcontext id.
@@ -223,6 +225,47 @@ impl Zone {
             .clone()
     }
 
+    /// vcontexts are numbered by the cpu's index in `cpu_set`, so only a cpu above all
+    /// current ones can be added without renumbering the running harts.
+    pub fn vplic_cpu_add(&self, cpu_id: usize) -> HvResult {
+        if self.cpu_set.iter().any(|id| id > cpu_id) {
+            return hv_result_err!(
+                EINVAL,
+                format!(
+                    "vplic: cpu {} must be above all cpus of zone {}",
+                    cpu_id, self.id
+                )
+            );
+        }
+        self.get_vplic()
+            .set_num_contexts((self.cpu_num + 1) * NUM_CONTEXTS_PER_HART);
+        Ok(())
+    }
+
+    /// Only the highest cpu can be removed, see `vplic_cpu_add`.
+    pub fn vplic_cpu_remove(&self, cpu_id: usize) -> HvResult {
+        if self.cpu_set.iter().any(|id| id > cpu_id) {
+            return hv_result_err!(
+                EINVAL,
+                format!(
+                    "vplic: cpu {} is not the last cpu of zone {}",
+                    cpu_id, self.id
+                )
+            );
+        }
+        // The new owner programs the physical context from scratch.
+        let pcontext_id = cpu_id * NUM_CONTEXTS_PER_HART + 1;
+        for &irq_id in HW_IRQS.iter() {
+            if self.irq_in_zone(irq_id) {
+                host_plic().set_enable_num(pcontext_id, irq_id as _, false);
+            }
+        }
+        host_plic().set_threshold(pcontext_id, 0);
+        self.get_vplic()
+            .set_num_contexts((self.cpu_num - 1) * NUM_CONTEXTS_PER_HART);
+        Ok(())
+    }
+
     pub fn arch_irqchip_reset(&self) {
         // We should make sure only one cpu to do this.
         // This func will only be called by one root zone's cpu.
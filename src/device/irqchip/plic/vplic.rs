@@ -19,6 +19,7 @@ use crate::arch::cpu::this_cpu_id;
 use crate::platform::NUM_CONTEXTS_PER_HART;
 use alloc::vec::Vec;
 use bitvec::prelude::*;
+use core::sync::atomic::{AtomicUsize, Ordering};
 use spin::Mutex;
 
 /// Virtual Platform-Level Interrupt Controller (vPLIC)
@@ -28,8 +29,9 @@ pub struct VirtualPLIC {
     base_addr: usize,
     /// Maximum number of interrupts (excluding interrupt 0)
     max_interrupts: usize,
-    /// Number of Hart contexts (contains S-mode and M-mode), only S-mode works
-    num_contexts: usize,
+    /// Number of Hart contexts (contains S-mode and M-mode), only S-mode works.
+    /// Changes when cpus are hotplugged into or out of the zone.
+    num_contexts: AtomicUsize,
     /// Inner state of the vPLIC (thread-safe)
     inner: Mutex<VirtualPLICInner>,
 }
@@ -67,11 +69,27 @@ impl VirtualPLIC {
         VirtualPLIC {
             base_addr,
             max_interrupts,
-            num_contexts,
+            num_contexts: AtomicUsize::new(num_contexts),
             inner: Mutex::new(vplic),
         }
     }
 
+    pub fn num_contexts(&self) -> usize {
+        self.num_contexts.load(Ordering::Acquire)
+    }
+
+    /// Change the number of hart contexts after a cpu hotplug. Per-context state is
+    /// never shrunk, so a racing access to a removed context stays in bounds.
+    pub fn set_num_contexts(&self, num_contexts: usize) {
+        let mut inner = self.inner.lock();
+        let max_interrupts = self.max_interrupts;
+        while inner.enable.len() < num_contexts {
+            inner.enable.push(bitvec![0; max_interrupts + 1]);
+            inner.threshold.push(0);
+        }
+        self.num_contexts.store(num_contexts, Ordering::Release);
+    }
+
     /// Set one interrupt as hardware interrupt.
     pub fn vplic_set_hw(&self, intr_id: usize, hw: bool) {
         let mut inner = self.inner.lock();
@@ -99,7 +117,7 @@ impl VirtualPLIC {
             if inner.hw[intr_id] {
                 inner.vplic_update_hart_line(vcontext_id);
             } else {
-                for vcontext_id in 0..self.num_contexts {
+                for vcontext_id in 0..self.num_contexts() {
                     if vcontext_id % NUM_CONTEXTS_PER_HART != 1 {
                         continue;
                     }
@@ -172,7 +190,7 @@ impl VirtualPLIC {
                         super::host_plic().set_priority(intr_id, value as u32);
                     } else {
                         // only support S-mode hart.
-                        for vcontext_id in 0..self.num_contexts {
+                        for vcontext_id in 0..self.num_contexts() {
                             if vcontext_id % NUM_CONTEXTS_PER_HART != 1 {
                                 continue;
                             }
@@ -208,9 +226,9 @@ impl VirtualPLIC {
                 }
             }
             // PLIC enable
-            offset if offset >= 0x2000 && offset < (0x2000 + 0x80 * self.num_contexts) => {
+            offset if offset >= 0x2000 && offset < (0x2000 + 0x80 * self.num_contexts()) => {
                 let vcontext_id = (offset - 0x2000) / 0x80;
-                if vcontext_id >= self.num_contexts || vcontext_id % NUM_CONTEXTS_PER_HART != 1 {
+                if vcontext_id >= self.num_contexts() || vcontext_id % NUM_CONTEXTS_PER_HART != 1 {
                     // context should be a S-mode hart context.
                     error!("Invalid context ID {}", vcontext_id);
                     return 0;
@@ -257,7 +275,7 @@ impl VirtualPLIC {
             // PLIC threshold
             offset if offset >= 0x200000 && (offset - 0x200000) % 0x1000 == 0 => {
                 let vcontext_id = (offset - 0x200000) / 0x1000;
-                if vcontext_id >= self.num_contexts || vcontext_id % NUM_CONTEXTS_PER_HART != 1 {
+                if vcontext_id >= self.num_contexts() || vcontext_id % NUM_CONTEXTS_PER_HART != 1 {
                     // context should be a S-mode hart context.
                     error!("Invalid context ID {}", vcontext_id);
                     return 0;
@@ -282,7 +300,7 @@ impl VirtualPLIC {
             // PLIC claim/complete
             offset if offset >= 0x200004 && (offset - 0x200004) % 0x1000 == 0 => {
                 let vcontext_id = (offset - 0x200004) / 0x1000;
-                if vcontext_id >= self.num_contexts || vcontext_id % NUM_CONTEXTS_PER_HART != 1 {
+                if vcontext_id >= self.num_contexts() || vcontext_id % NUM_CONTEXTS_PER_HART != 1 {
                     // context should be a S-mode hart context.
                     error!("Invalid context ID {}", vcontext_id);
                     return 0;
@@ -16,10 +16,23 @@
 pub mod common;
 pub mod irqchip;
 pub mod uart;
+pub mod virtio_mmio;
+pub mod virtio_native;
 pub mod virtio_trampoline;
+pub mod watchdog;
 
 #[cfg(feature = "eic7700_sysreg")]
 pub mod eic7700_syscrg;
 
 #[cfg(feature = "sifive_ccache")]
 pub mod sifive_ccache;
+
+/// Check the timeouts of emulated devices, the posted virtio results and the
+/// native consoles' input. hvisor has no timer of its own, so this runs after
+/// each physical interrupt.
+pub fn device_poll() {
+    watchdog::watchdog_poll();
+    virtio_trampoline::virtio_cfg_poll();
+    virtio_trampoline::virtio_res_poll();
+    virtio_native::native_virtio_poll();
+}
@@ -0,0 +1,359 @@
+// Copyright (c) 2025 Syswonder
+// hvisor is licensed under Mulan PSL v2.
+// You can use this software according to the terms and conditions of the Mulan PSL v2.
+// You may obtain a copy of Mulan PSL v2 at:
+//     http://license.coscl.org.cn/MulanPSL2
+// THIS SOFTWARE IS PROVIDED ON AN "AS IS" BASIS, WITHOUT WARRANTIES OF ANY KIND, EITHER
+// EXPRESS OR IMPLIED, INCLUDING BUT NOT LIMITED TO NON-INFRINGEMENT, MERCHANTABILITY OR
+// FIT FOR A PARTICULAR PURPOSE.
+// See the Mulan PSL v2 for more details.
+//
+// Syswonder Website:
+//      https://www.syswonder.org
+//
+// Authors:
+//
+
+//! virtio-mmio (version 2) transport emulated in hvisor.
+//!
+//! A backend registers a device with `HvVirtioDevRegister`, handing over a
+//! page of root zone memory that holds a `VirtioMmioShared`. From then on
+//! hvisor answers the guest's register accesses itself: feature negotiation,
+//! queue setup and config space reads never leave the hypervisor. Only queue
+//! notifications, status writes and config space writes go through the
+//! zone's virtio bridge. When the backend sees DRIVER_OK, the negotiated features
+//! and the queue layout are already in the shared page.
+//!
+//! Devices that are not registered keep using `mmio_virtio_handler`.
+
+use alloc::collections::BTreeMap;
+use alloc::sync::Arc;
+use core::sync::atomic::{AtomicU32, Ordering};
+use spin::Mutex;
+use tock_registers::interfaces::{Readable, Writeable};
+use tock_registers::registers::ReadWrite;
+
+use crate::config::MEM_TYPE_VIRTIO;
+use crate::consts::PAGE_SIZE;
+use crate::device::virtio_trampoline::virtio_bridge_forward;
+use crate::error::HvResult;
+use crate::memory::{GuestPhysAddr, MMIOAccess};
+use crate::zone::{find_zone, this_zone_id};
+
+pub const VIRTIO_MMIO_MAX_QUEUES: usize = 8;
+pub const VIRTIO_MMIO_CONFIG_SIZE: usize = 256;
+
+pub(crate) const VIRTIO_MMIO_MAGIC: u32 = 0x7472_6976; // "virt"
+pub(crate) const VIRTIO_MMIO_VERSION: u32 = 2;
+
+pub(crate) const MAGIC_VALUE: usize = 0x000;
+pub(crate) const VERSION: usize = 0x004;
+pub(crate) const DEVICE_ID: usize = 0x008;
+pub(crate) const VENDOR_ID: usize = 0x00c;
+pub(crate) const DEVICE_FEATURES: usize = 0x010;
+pub(crate) const DEVICE_FEATURES_SEL: usize = 0x014;
+pub(crate) const DRIVER_FEATURES: usize = 0x020;
+pub(crate) const DRIVER_FEATURES_SEL: usize = 0x024;
+pub(crate) const QUEUE_SEL: usize = 0x030;
+pub(crate) const QUEUE_NUM_MAX: usize = 0x034;
+pub(crate) const QUEUE_NUM: usize = 0x038;
+pub(crate) const QUEUE_READY: usize = 0x044;
+pub(crate) const QUEUE_NOTIFY: usize = 0x050;
+pub(crate) const INTERRUPT_STATUS: usize = 0x060;
+pub(crate) const INTERRUPT_ACK: usize = 0x064;
+pub(crate) const STATUS: usize = 0x070;
+pub(crate) const QUEUE_DESC_LOW: usize = 0x080;
+pub(crate) const QUEUE_DESC_HIGH: usize = 0x084;
+pub(crate) const QUEUE_DRIVER_LOW: usize = 0x090;
+pub(crate) const QUEUE_DRIVER_HIGH: usize = 0x094;
+pub(crate) const QUEUE_DEVICE_LOW: usize = 0x0a0;
+pub(crate) const QUEUE_DEVICE_HIGH: usize = 0x0a4;
+pub(crate) const CONFIG_GENERATION: usize = 0x0fc;
+pub(crate) const CONFIG: usize = 0x100;
+
+/// Registered devices, indexed by (zone id, base address).
+static VIRTIO_MMIO_DEVS: Mutex<BTreeMap<(usize, usize), Arc<VirtioMmioDev>>> =
+    Mutex::new(BTreeMap::new());
+
+#[repr(C)]
+struct VirtioMmioQueue {
+    /// Written by the backend.
+    num_max: ReadWrite<u32>,
+    num: ReadWrite<u32>,
+    ready: ReadWrite<u32>,
+    _padding: u32,
+    desc: ReadWrite<u64>,
+    driver: ReadWrite<u64>,
+    device: ReadWrite<u64>,
+}
+
+/// Page shared between hvisor and the backend, one per device.
+#[repr(C)]
+struct VirtioMmioShared {
+    // Filled by the backend before `HvVirtioDevRegister`.
+    zone_id: ReadWrite<u32>,
+    device_id: ReadWrite<u32>,
+    vendor_id: ReadWrite<u32>,
+    num_queues: ReadWrite<u32>,
+    base: ReadWrite<u64>,
+    device_features: ReadWrite<u64>,
+    // Updated by the backend at any time.
+    config_generation: ReadWrite<u32>,
+    /// The backend sets bits before injecting the irq, the guest acks them.
+    interrupt_status: AtomicU32,
+    // Written by hvisor on behalf of the guest.
+    status: ReadWrite<u32>,
+    _padding: u32,
+    driver_features: ReadWrite<u64>,
+    queues: [VirtioMmioQueue; VIRTIO_MMIO_MAX_QUEUES],
+    /// Device specific config space, kept up to date by the backend.
+    config: [u8; VIRTIO_MMIO_CONFIG_SIZE],
+}
+
+const _: () = assert!(core::mem::size_of::<VirtioMmioShared>() <= PAGE_SIZE);
+
+/// Guest-written selector registers, which the backend does not need.
+#[derive(Default)]
+struct Selectors {
+    device_features_sel: u32,
+    driver_features_sel: u32,
+    queue_sel: u32,
+}
+
+struct VirtioMmioDev {
+    base: usize,
+    shared: usize,
+    sel: Mutex<Selectors>,
+}
+
+impl VirtioMmioDev {
+    fn shared(&self) -> &VirtioMmioShared {
+        unsafe { &*(self.shared as *const VirtioMmioShared) }
+    }
+
+    fn queue(&self, sel: &Selectors) -> Option<&VirtioMmioQueue> {
+        let shared = self.shared();
+        let index = sel.queue_sel as usize;
+        if index < shared.num_queues.get() as usize {
+            Some(&shared.queues[index])
+        } else {
+            None
+        }
+    }
+
+    /// Clear everything the driver set up, as a status write of 0 does.
+    fn reset(&self) {
+        let shared = self.shared();
+        shared.status.set(0);
+        shared.driver_features.set(0);
+        shared.interrupt_status.store(0, Ordering::Release);
+        for queue in shared.queues.iter() {
+            queue.num.set(0);
+            queue.ready.set(0);
+            queue.desc.set(0);
+            queue.driver.set(0);
+            queue.device.set(0);
+        }
+        *self.sel.lock() = Selectors::default();
+    }
+
+    fn read_config(&self, offset: usize, size: usize) -> u64 {
+        let config = self.shared().config.as_ptr();
+        unsafe {
+            match size {
+                1 => core::ptr::read_volatile(config.add(offset)) as _,
+                2 => core::ptr::read_volatile(config.add(offset) as *const u16) as _,
+                4 => core::ptr::read_volatile(config.add(offset) as *const u32) as _,
+                _ => core::ptr::read_volatile(config.add(offset) as *const u64),
+            }
+        }
+    }
+
+    fn read(&self, offset: usize) -> u32 {
+        let shared = self.shared();
+        let sel = self.sel.lock();
+        match offset {
+            MAGIC_VALUE => VIRTIO_MMIO_MAGIC,
+            VERSION => VIRTIO_MMIO_VERSION,
+            DEVICE_ID => shared.device_id.get(),
+            VENDOR_ID => shared.vendor_id.get(),
+            DEVICE_FEATURES => match sel.device_features_sel {
+                0 => shared.device_features.get() as u32,
+                1 => (shared.device_features.get() >> 32) as u32,
+                _ => 0,
+            },
+            QUEUE_NUM_MAX => self.queue(&sel).map_or(0, |queue| queue.num_max.get()),
+            QUEUE_READY => self.queue(&sel).map_or(0, |queue| queue.ready.get()),
+            INTERRUPT_STATUS => shared.interrupt_status.load(Ordering::Acquire),
+            STATUS => shared.status.get(),
+            CONFIG_GENERATION => shared.config_generation.get(),
+            _ => {
+                warn!("virtio-mmio: read of write-only register {:#x}", offset);
+                0
+            }
+        }
+    }
+
+    /// Handle a register write, returns true if the backend has to see it.
+    fn write(&self, offset: usize, value: u32) -> bool {
+        let shared = self.shared();
+        let mut sel = self.sel.lock();
+        let set_low = |reg: &ReadWrite<u64>| reg.set(reg.get() & !0xffff_ffff | value as u64);
+        let set_high =
+            |reg: &ReadWrite<u64>| reg.set(reg.get() & 0xffff_ffff | (value as u64) << 32);
+        match offset {
+            DEVICE_FEATURES_SEL => sel.device_features_sel = value,
+            DRIVER_FEATURES => match sel.driver_features_sel {
+                0 => set_low(&shared.driver_features),
+                1 => set_high(&shared.driver_features),
+                _ => {}
+            },
+            DRIVER_FEATURES_SEL => sel.driver_features_sel = value,
+            QUEUE_SEL => sel.queue_sel = value,
+            QUEUE_NUM | QUEUE_READY | QUEUE_DESC_LOW..=QUEUE_DEVICE_HIGH => {
+                let queue = match self.queue(&sel) {
+                    Some(queue) => queue,
+                    None => return false,
+                };
+                match offset {
+                    QUEUE_NUM => queue.num.set(value.min(queue.num_max.get())),
+                    QUEUE_READY => queue.ready.set(value & 1),
+                    QUEUE_DESC_LOW => set_low(&queue.desc),
+                    QUEUE_DESC_HIGH => set_high(&queue.desc),
+                    QUEUE_DRIVER_LOW => set_low(&queue.driver),
+                    QUEUE_DRIVER_HIGH => set_high(&queue.driver),
+                    QUEUE_DEVICE_LOW => set_low(&queue.device),
+                    QUEUE_DEVICE_HIGH => set_high(&queue.device),
+                    _ => {}
+                }
+            }
+            INTERRUPT_ACK => {
+                shared.interrupt_status.fetch_and(!value, Ordering::AcqRel);
+            }
+            QUEUE_NOTIFY | STATUS => return true,
+            _ => warn!("virtio-mmio: write to read-only register {:#x}", offset),
+        }
+        false
+    }
+}
+
+pub fn virtio_mmio_handler(mmio: &mut MMIOAccess, base: usize) -> HvResult {
+    let zone_id = this_zone_id();
+    let dev = match VIRTIO_MMIO_DEVS.lock().get(&(zone_id, base)) {
+        Some(dev) => dev.clone(),
+        None => return hv_result_err!(ENODEV, format!("no virtio device at {:#x}", base)),
+    };
+    let offset = mmio.address;
+
+    if offset >= CONFIG {
+        let config_offset = offset - CONFIG;
+        if config_offset + mmio.size > VIRTIO_MMIO_CONFIG_SIZE {
+            return hv_result_err!(EINVAL, format!("virtio-mmio: config access {:#x?}", mmio));
+        }
+        if !mmio.is_write {
+            mmio.value = dev.read_config(config_offset, mmio.size) as _;
+            return Ok(());
+        }
+        mmio.address += dev.base;
+        return virtio_bridge_forward(mmio, false);
+    }
+
+    if mmio.size != 4 {
+        warn!("virtio-mmio: unsupported {} bytes access", mmio.size);
+        return Ok(());
+    }
+    if !mmio.is_write {
+        mmio.value = dev.read(offset) as _;
+        return Ok(());
+    }
+    if !dev.write(offset, mmio.value as _) {
+        return Ok(());
+    }
+
+    mmio.address += dev.base;
+    if offset == QUEUE_NOTIFY {
+        return virtio_bridge_forward(mmio, true);
+    }
+    // Status: let the backend act on it first, so that a reset has finished
+    // and DRIVER_OK has set up the queues when the guest reads status back.
+    let status = mmio.value as u32;
+    virtio_bridge_forward(mmio, false)?;
+    if status == 0 {
+        dev.reset();
+    } else {
+        dev.shared().status.set(status);
+    }
+    Ok(())
+}
+
+/// Take over the virtio-mmio window described by the shared page at `shared_pa`.
+pub fn virtio_mmio_register(shared_pa: usize) -> HvResult {
+    if shared_pa % PAGE_SIZE != 0 {
+        return hv_result_err!(
+            EINVAL,
+            format!("virtio-mmio: shared page {:#x} is not aligned", shared_pa)
+        );
+    }
+    let shared = unsafe { &*(shared_pa as *const VirtioMmioShared) };
+    let zone_id = shared.zone_id.get() as usize;
+    let base = shared.base.get() as usize;
+    if shared.num_queues.get() as usize > VIRTIO_MMIO_MAX_QUEUES {
+        return hv_result_err!(
+            EINVAL,
+            format!(
+                "virtio-mmio: {} queues, at most {}",
+                shared.num_queues.get(),
+                VIRTIO_MMIO_MAX_QUEUES
+            )
+        );
+    }
+    let zone = match find_zone(zone_id) {
+        Some(zone) if zone_id != 0 => zone,
+        _ => return hv_result_err!(EINVAL, format!("virtio-mmio: invalid zone {}", zone_id)),
+    };
+    let region = zone.read().memory_regions.iter().copied().find(|region| {
+        region.mem_type == MEM_TYPE_VIRTIO && region.physical_start as usize == base
+    });
+    let size = match region {
+        Some(region) => region.size as usize,
+        None => {
+            return hv_result_err!(
+                EINVAL,
+                format!(
+                    "virtio-mmio: zone {} has no virtio region at {:#x}",
+                    zone_id, base
+                )
+            )
+        }
+    };
+
+    let dev = Arc::new(VirtioMmioDev {
+        base,
+        shared: shared_pa,
+        sel: Mutex::new(Selectors::default()),
+    });
+    dev.reset();
+    info!(
+        "zone {} virtio-mmio device {} at {:#x} emulated by hvisor",
+        zone_id,
+        shared.device_id.get(),
+        base
+    );
+    VIRTIO_MMIO_DEVS.lock().insert((zone_id, base), dev);
+    zone.write()
+        .mmio_region_register(base as GuestPhysAddr, size, virtio_mmio_handler, base);
+    Ok(())
+}
+
+/// Forget the state the guest set up, used when the zone reboots.
+pub fn virtio_mmio_reset(zone_id: usize) {
+    VIRTIO_MMIO_DEVS
+        .lock()
+        .iter()
+        .filter(|((id, _), _)| *id == zone_id)
+        .for_each(|(_, dev)| dev.reset());
+}
+
+pub fn virtio_mmio_remove(zone_id: usize) {
+    VIRTIO_MMIO_DEVS.lock().retain(|(id, _), _| *id != zone_id);
+}
@@ -0,0 +1,612 @@
+// Copyright (c) 2025 Syswonder
+// hvisor is licensed under Mulan PSL v2.
+// You can use this software according to the terms and conditions of the Mulan PSL v2.
+// You may obtain a copy of Mulan PSL v2 at:
+//     http://license.coscl.org.cn/MulanPSL2
+// THIS SOFTWARE IS PROVIDED ON AN "AS IS" BASIS, WITHOUT WARRANTIES OF ANY KIND, EITHER
+// EXPRESS OR IMPLIED, INCLUDING BUT NOT LIMITED TO NON-INFRINGEMENT, MERCHANTABILITY OR
+// FIT FOR A PARTICULAR PURPOSE.
+// See the Mulan PSL v2 for more details.
+//
+// Syswonder Website:
+//      https://www.syswonder.org
+//
+// Authors:
+//
+
+//! virtio-mmio devices served by hvisor itself, so that a zone can boot
+//! without a backend in the root zone.
+//!
+//! Two devices are available, both with split virtqueues only:
+//! - a virtio-console on the physical UART. Output is written as is. Input
+//!   is only taken with `NATIVE_VIRTIO_F_CONSOLE_RX`, since it steals the
+//!   characters from whoever else reads the UART.
+//! - a read-only virtio-blk on a RAM disk given by the zone config.
+//!
+//! Queues are processed on the guest's queue notify, console input from
+//! `device_poll`. A native device takes the place of `mmio_virtio_handler` if
+//! the zone also has a virtio region at the same address.
+
+use alloc::collections::BTreeMap;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use core::mem::size_of;
+use core::sync::atomic::{fence, AtomicUsize, Ordering};
+use spin::Mutex;
+
+use crate::config::{
+    HvConfigMemoryRegion, HvNativeVirtioConfig, MEM_TYPE_RAM, NATIVE_VIRTIO_F_CONSOLE_RX,
+};
+use crate::device::uart::{console_getchar, console_putchar};
+use crate::device::virtio_mmio::{
+    CONFIG, CONFIG_GENERATION, DEVICE_FEATURES, DEVICE_FEATURES_SEL, DEVICE_ID, DRIVER_FEATURES,
+    DRIVER_FEATURES_SEL, INTERRUPT_ACK, INTERRUPT_STATUS, MAGIC_VALUE, QUEUE_DESC_HIGH,
+    QUEUE_DESC_LOW, QUEUE_DEVICE_HIGH, QUEUE_DEVICE_LOW, QUEUE_DRIVER_HIGH, QUEUE_DRIVER_LOW,
+    QUEUE_NOTIFY, QUEUE_NUM, QUEUE_NUM_MAX, QUEUE_READY, QUEUE_SEL, STATUS, VENDOR_ID, VERSION,
+    VIRTIO_MMIO_MAGIC, VIRTIO_MMIO_VERSION,
+};
+use crate::device::virtio_trampoline::inject_zone_irq;
+use crate::error::HvResult;
+use crate::memory::{GuestPhysAddr, MMIOAccess};
+use crate::zone::{find_zone, this_zone_id, Zone};
+
+pub const NATIVE_VIRTIO_MMIO_SIZE: usize = 0x200;
+
+pub const VIRTIO_ID_BLOCK: u32 = 2;
+pub const VIRTIO_ID_CONSOLE: u32 = 3;
+pub const VIRTIO_BLK_SECTOR_SIZE: usize = 512;
+
+const NATIVE_VIRTIO_VENDOR: u32 = 0x5253_5648; // "HVSR"
+const NATIVE_VIRTIO_MAX_QUEUES: usize = 2;
+const QUEUE_SIZE_MAX: u32 = 256;
+
+const VIRTIO_F_VERSION_1: u64 = 1 << 32;
+const VIRTIO_BLK_F_RO: u64 = 1 << 5;
+
+const VIRTIO_STATUS_DRIVER_OK: u32 = 1 << 2;
+const VIRTIO_STATUS_FEATURES_OK: u32 = 1 << 3;
+const VIRTIO_MMIO_INT_VRING: u32 = 1 << 0;
+
+const VIRTQ_DESC_F_NEXT: u16 = 1 << 0;
+const VIRTQ_DESC_F_WRITE: u16 = 1 << 1;
+const VIRTQ_AVAIL_F_NO_INTERRUPT: u16 = 1 << 0;
+
+const CONSOLE_RECEIVEQ: usize = 0;
+const CONSOLE_TRANSMITQ: usize = 1;
+
+const VIRTIO_BLK_T_IN: u32 = 0;
+const VIRTIO_BLK_T_OUT: u32 = 1;
+const VIRTIO_BLK_T_GET_ID: u32 = 8;
+const VIRTIO_BLK_S_OK: u8 = 0;
+const VIRTIO_BLK_S_IOERR: u8 = 1;
+const VIRTIO_BLK_S_UNSUPP: u8 = 2;
+const VIRTIO_BLK_ID_BYTES: usize = 20;
+const VIRTIO_BLK_ID: &[u8] = b"hvisor-ramdisk";
+
+/// Native devices, indexed by (zone id, base address).
+static NATIVE_VIRTIO_DEVS: Mutex<BTreeMap<(usize, usize), Arc<NativeVirtioDev>>> =
+    Mutex::new(BTreeMap::new());
+/// Number of consoles in `NATIVE_VIRTIO_DEVS` that take UART input, so that
+/// `native_virtio_poll` does nothing on every irq when there are none.
+static NATIVE_CONSOLES_RX: AtomicUsize = AtomicUsize::new(0);
+
+#[repr(C)]
+#[derive(Debug, Clone, Copy)]
+struct VirtqDesc {
+    addr: u64,
+    len: u32,
+    flags: u16,
+    next: u16,
+}
+
+#[repr(C)]
+#[derive(Clone, Copy)]
+struct VirtioBlkReqHeader {
+    req_type: u32,
+    reserved: u32,
+    sector: u64,
+}
+
+/// The zone's memory regions, to reach the buffers the guest puts in the
+/// queues.
+struct GuestRam<'a>(&'a [HvConfigMemoryRegion]);
+
+impl GuestRam<'_> {
+    /// Host address of `len` bytes at `gpa`, which must not cross a region.
+    fn translate(&self, gpa: u64, len: usize) -> Option<usize> {
+        self.0
+            .iter()
+            .find(|region| {
+                region.mem_type == MEM_TYPE_RAM
+                    && gpa >= region.virtual_start
+                    && gpa
+                        .checked_add(len as u64)
+                        .map_or(false, |end| end <= region.virtual_start + region.size)
+            })
+            .map(|region| (region.physical_start + gpa - region.virtual_start) as usize)
+    }
+
+    fn read<T: Copy>(&self, gpa: u64) -> Option<T> {
+        let hpa = self.translate(gpa, size_of::<T>())?;
+        Some(unsafe { core::ptr::read_volatile(hpa as *const T) })
+    }
+
+    fn write<T: Copy>(&self, gpa: u64, value: T) -> Option<()> {
+        let hpa = self.translate(gpa, size_of::<T>())?;
+        unsafe { core::ptr::write_volatile(hpa as *mut T, value) };
+        Some(())
+    }
+}
+
+#[derive(Clone, Copy, Default)]
+struct Virtqueue {
+    num: u32,
+    ready: bool,
+    desc: u64,
+    driver: u64,
+    device: u64,
+    last_avail: u16,
+    next_used: u16,
+}
+
+impl Virtqueue {
+    fn has_avail(&self, ram: &GuestRam) -> bool {
+        self.ready && self.num != 0 && ram.read::<u16>(self.driver + 2) != Some(self.last_avail)
+    }
+
+    /// Take the next available chain. A malformed chain is cut short, the
+    /// device then fails the request but still returns the head.
+    fn pop(&mut self, ram: &GuestRam) -> Option<(u16, Vec<VirtqDesc>)> {
+        if !self.has_avail(ram) {
+            return None;
+        }
+        fence(Ordering::Acquire);
+        let slot = (self.last_avail as u32 % self.num) as u64;
+        let head: u16 = ram.read(self.driver + 4 + slot * 2)?;
+        self.last_avail = self.last_avail.wrapping_add(1);
+
+        let mut chain = Vec::new();
+        let mut index = head;
+        loop {
+            if index as u32 >= self.num || chain.len() >= self.num as usize {
+                warn!("native virtio: broken descriptor chain at {}", head);
+                break;
+            }
+            let desc: VirtqDesc = match ram.read(self.desc + index as u64 * 16) {
+                Some(desc) => desc,
+                None => break,
+            };
+            chain.push(desc);
+            if desc.flags & VIRTQ_DESC_F_NEXT == 0 {
+                break;
+            }
+            index = desc.next;
+        }
+        Some((head, chain))
+    }
+
+    fn push(&mut self, ram: &GuestRam, head: u16, len: u32) {
+        let slot = (self.next_used as u32 % self.num) as u64;
+        let elem = self.device + 4 + slot * 8;
+        ram.write(elem, head as u32);
+        ram.write(elem + 4, len);
+        self.next_used = self.next_used.wrapping_add(1);
+        fence(Ordering::Release);
+        ram.write(self.device + 2, self.next_used);
+    }
+
+    fn need_interrupt(&self, ram: &GuestRam) -> bool {
+        ram.read::<u16>(self.driver)
+            .map_or(true, |flags| flags & VIRTQ_AVAIL_F_NO_INTERRUPT == 0)
+    }
+}
+
+#[derive(Default)]
+struct NativeVirtioState {
+    status: u32,
+    device_features_sel: u32,
+    driver_features_sel: u32,
+    driver_features: u64,
+    queue_sel: u32,
+    interrupt_status: u32,
+    queues: [Virtqueue; NATIVE_VIRTIO_MAX_QUEUES],
+}
+
+struct NativeVirtioDev {
+    zone_id: usize,
+    config: HvNativeVirtioConfig,
+    state: Mutex<NativeVirtioState>,
+}
+
+impl NativeVirtioDev {
+    fn is_console_rx(&self) -> bool {
+        self.config.device_id == VIRTIO_ID_CONSOLE
+            && self.config.flags & NATIVE_VIRTIO_F_CONSOLE_RX != 0
+    }
+
+    /// Run `f` on the zone's current memory regions. The zone lock keeps
+    /// them from being removed while `f` reaches into them.
+    fn with_ram<R>(&self, f: impl FnOnce(&GuestRam) -> R) -> Option<R> {
+        let zone = find_zone(self.zone_id)?;
+        let zone = zone.read();
+        Some(f(&GuestRam(&zone.memory_regions)))
+    }
+
+    fn num_queues(&self) -> usize {
+        match self.config.device_id {
+            VIRTIO_ID_CONSOLE => 2,
+            _ => 1,
+        }
+    }
+
+    fn device_features(&self) -> u64 {
+        match self.config.device_id {
+            VIRTIO_ID_BLOCK => VIRTIO_F_VERSION_1 | VIRTIO_BLK_F_RO,
+            _ => VIRTIO_F_VERSION_1,
+        }
+    }
+
+    fn read_config(&self, offset: usize, size: usize) -> u64 {
+        // The console offers no feature that needs its config space.
+        let config = match self.config.device_id {
+            VIRTIO_ID_BLOCK => self.config.disk_size / VIRTIO_BLK_SECTOR_SIZE as u64,
+            _ => 0,
+        };
+        (0..size)
+            .map(|i| match offset + i {
+                byte @ 0..=7 => (config >> (byte * 8)) & 0xff,
+                _ => 0,
+            })
+            .enumerate()
+            .fold(0, |value, (i, byte)| value | byte << (i * 8))
+    }
+
+    fn read(&self, offset: usize) -> u32 {
+        let state = self.state.lock();
+        let queue = state.queues.get(state.queue_sel as usize);
+        match offset {
+            MAGIC_VALUE => VIRTIO_MMIO_MAGIC,
+            VERSION => VIRTIO_MMIO_VERSION,
+            DEVICE_ID => self.config.device_id,
+            VENDOR_ID => NATIVE_VIRTIO_VENDOR,
+            DEVICE_FEATURES => match state.device_features_sel {
+                0 => self.device_features() as u32,
+                1 => (self.device_features() >> 32) as u32,
+                _ => 0,
+            },
+            QUEUE_NUM_MAX if (state.queue_sel as usize) < self.num_queues() => QUEUE_SIZE_MAX,
+            QUEUE_NUM_MAX => 0,
+            QUEUE_READY => queue.map_or(0, |queue| queue.ready as u32),
+            INTERRUPT_STATUS => state.interrupt_status,
+            STATUS => state.status,
+            CONFIG_GENERATION => 0,
+            _ => {
+                warn!("native virtio: read of write-only register {:#x}", offset);
+                0
+            }
+        }
+    }
+
+    /// Handle a register write, returns true if the guest has to be interrupted.
+    fn write(&self, offset: usize, value: u32) -> bool {
+        let mut state = self.state.lock();
+        let set_low = |reg: &mut u64| *reg = *reg & !0xffff_ffff | value as u64;
+        let set_high = |reg: &mut u64| *reg = *reg & 0xffff_ffff | (value as u64) << 32;
+        match offset {
+            DEVICE_FEATURES_SEL => state.device_features_sel = value,
+            DRIVER_FEATURES => match state.driver_features_sel {
+                0 => set_low(&mut state.driver_features),
+                1 => set_high(&mut state.driver_features),
+                _ => {}
+            },
+            DRIVER_FEATURES_SEL => state.driver_features_sel = value,
+            QUEUE_SEL => state.queue_sel = value,
+            QUEUE_NUM | QUEUE_READY | QUEUE_DESC_LOW..=QUEUE_DEVICE_HIGH => {
+                let index = state.queue_sel as usize;
+                if index >= self.num_queues() {
+                    return false;
+                }
+                let queue = &mut state.queues[index];
+                match offset {
+                    QUEUE_NUM => queue.num = value.min(QUEUE_SIZE_MAX),
+                    QUEUE_READY => {
+                        queue.ready = value & 1 != 0;
+                        queue.last_avail = 0;
+                        queue.next_used = 0;
+                    }
+                    QUEUE_DESC_LOW => set_low(&mut queue.desc),
+                    QUEUE_DESC_HIGH => set_high(&mut queue.desc),
+                    QUEUE_DRIVER_LOW => set_low(&mut queue.driver),
+                    QUEUE_DRIVER_HIGH => set_high(&mut queue.driver),
+                    QUEUE_DEVICE_LOW => set_low(&mut queue.device),
+                    QUEUE_DEVICE_HIGH => set_high(&mut queue.device),
+                    _ => {}
+                }
+            }
+            QUEUE_NOTIFY => {
+                return self
+                    .with_ram(|ram| self.notify(ram, &mut state, value as usize))
+                    .unwrap_or(false)
+            }
+            INTERRUPT_ACK => state.interrupt_status &= !value,
+            STATUS if value == 0 => *state = NativeVirtioState::default(),
+            STATUS => {
+                let mut status = value;
+                if state.driver_features & !self.device_features() != 0 {
+                    status &= !VIRTIO_STATUS_FEATURES_OK;
+                }
+                state.status = status;
+            }
+            _ => warn!("native virtio: write to read-only register {:#x}", offset),
+        }
+        false
+    }
+
+    fn notify(&self, ram: &GuestRam, state: &mut NativeVirtioState, index: usize) -> bool {
+        if state.status & VIRTIO_STATUS_DRIVER_OK == 0 || index >= self.num_queues() {
+            return false;
+        }
+        let queue = &mut state.queues[index];
+        let mut used = false;
+        match (self.config.device_id, index) {
+            (VIRTIO_ID_CONSOLE, CONSOLE_TRANSMITQ) => {
+                while let Some((head, chain)) = queue.pop(ram) {
+                    self.console_write(ram, &chain);
+                    queue.push(ram, head, 0);
+                    used = true;
+                }
+            }
+            (VIRTIO_ID_BLOCK, _) => {
+                while let Some((head, chain)) = queue.pop(ram) {
+                    let len = self.blk_request(ram, &chain);
+                    queue.push(ram, head, len);
+                    used = true;
+                }
+            }
+            // receive buffers are filled from `native_virtio_poll`
+            _ => return false,
+        }
+        if used && queue.need_interrupt(ram) {
+            state.interrupt_status |= VIRTIO_MMIO_INT_VRING;
+            return true;
+        }
+        false
+    }
+
+    fn console_write(&self, ram: &GuestRam, chain: &[VirtqDesc]) {
+        for desc in chain
+            .iter()
+            .filter(|desc| desc.flags & VIRTQ_DESC_F_WRITE == 0)
+        {
+            match ram.translate(desc.addr, desc.len as _) {
+                Some(hpa) => {
+                    let buf =
+                        unsafe { core::slice::from_raw_parts(hpa as *const u8, desc.len as _) };
+                    buf.iter().for_each(|&c| console_putchar(c));
+                }
+                None => warn!("native virtio: console buffer {:#x?} outside ram", desc),
+            }
+        }
+    }
+
+    /// Move pending UART input into the receive queue, returns true if the
+    /// guest has to be interrupted.
+    fn console_poll(&self, ram: &GuestRam) -> bool {
+        let mut state = match self.state.try_lock() {
+            Some(state) => state,
+            None => return false,
+        };
+        if state.status & VIRTIO_STATUS_DRIVER_OK == 0 {
+            return false;
+        }
+        let queue = &mut state.queues[CONSOLE_RECEIVEQ];
+        let mut used = false;
+        // Leave the input in the UART while the guest has no buffer for it.
+        let mut next = None;
+        while queue.has_avail(ram) {
+            if next.is_none() {
+                next = console_getchar();
+            }
+            if next.is_none() {
+                break;
+            }
+            let (head, chain) = match queue.pop(ram) {
+                Some(buf) => buf,
+                None => break,
+            };
+            let mut len = 0;
+            for desc in chain
+                .iter()
+                .filter(|desc| desc.flags & VIRTQ_DESC_F_WRITE != 0)
+            {
+                let hpa = match ram.translate(desc.addr, desc.len as _) {
+                    Some(hpa) => hpa,
+                    None => continue,
+                };
+                let mut off = 0;
+                while let Some(c) = next.filter(|_| off < desc.len as usize) {
+                    unsafe { core::ptr::write_volatile((hpa + off) as *mut u8, c) };
+                    off += 1;
+                    next = console_getchar();
+                }
+                len += off as u32;
+            }
+            queue.push(ram, head, len);
+            used = true;
+        }
+        if next.is_some() {
+            warn!("native virtio: console input dropped");
+        }
+        if used && queue.need_interrupt(ram) {
+            state.interrupt_status |= VIRTIO_MMIO_INT_VRING;
+            return true;
+        }
+        false
+    }
+
+    /// Serve a request, returns the number of bytes written to the chain.
+    fn blk_request(&self, ram: &GuestRam, chain: &[VirtqDesc]) -> u32 {
+        let (header, data, status) = match chain {
+            [header, data @ .., status]
+                if header.len as usize >= size_of::<VirtioBlkReqHeader>()
+                    && status.flags & VIRTQ_DESC_F_WRITE != 0
+                    && status.len >= 1 =>
+            {
+                (header, data, status)
+            }
+            _ => {
+                warn!("native virtio: malformed blk request {:#x?}", chain);
+                return 0;
+            }
+        };
+        let (code, len) = match ram.read::<VirtioBlkReqHeader>(header.addr) {
+            Some(req) if req.req_type == VIRTIO_BLK_T_IN => self.blk_read(ram, req.sector, data),
+            Some(req) if req.req_type == VIRTIO_BLK_T_GET_ID => self.blk_get_id(ram, data),
+            // read-only
+            Some(req) if req.req_type == VIRTIO_BLK_T_OUT => (VIRTIO_BLK_S_IOERR, 0),
+            Some(_) => (VIRTIO_BLK_S_UNSUPP, 0),
+            None => (VIRTIO_BLK_S_IOERR, 0),
+        };
+        ram.write(status.addr, code);
+        len + 1
+    }
+
+    fn blk_read(&self, ram: &GuestRam, sector: u64, data: &[VirtqDesc]) -> (u8, u32) {
+        let mut offset = match sector.checked_mul(VIRTIO_BLK_SECTOR_SIZE as u64) {
+            Some(offset) => offset,
+            None => return (VIRTIO_BLK_S_IOERR, 0),
+        };
+        let mut len = 0;
+        for desc in data {
+            let hpa = match ram.translate(desc.addr, desc.len as _) {
+                Some(hpa) if desc.flags & VIRTQ_DESC_F_WRITE != 0 => hpa,
+                _ => return (VIRTIO_BLK_S_IOERR, len),
+            };
+            match offset.checked_add(desc.len as u64) {
+                Some(end) if end <= self.config.disk_size => {}
+                _ => return (VIRTIO_BLK_S_IOERR, len),
+            }
+            unsafe {
+                core::ptr::copy_nonoverlapping(
+                    (self.config.disk_paddr + offset) as *const u8,
+                    hpa as *mut u8,
+                    desc.len as _,
+                )
+            };
+            offset += desc.len as u64;
+            len += desc.len;
+        }
+        (VIRTIO_BLK_S_OK, len)
+    }
+
+    fn blk_get_id(&self, ram: &GuestRam, data: &[VirtqDesc]) -> (u8, u32) {
+        let desc = match data.first() {
+            Some(desc) if desc.flags & VIRTQ_DESC_F_WRITE != 0 => desc,
+            _ => return (VIRTIO_BLK_S_IOERR, 0),
+        };
+        let len = VIRTIO_BLK_ID_BYTES.min(desc.len as _);
+        let hpa = match ram.translate(desc.addr, len) {
+            Some(hpa) => hpa,
+            None => return (VIRTIO_BLK_S_IOERR, 0),
+        };
+        for i in 0..len {
+            let c = VIRTIO_BLK_ID.get(i).copied().unwrap_or(0);
+            unsafe { core::ptr::write_volatile((hpa + i) as *mut u8, c) };
+        }
+        (VIRTIO_BLK_S_OK, len as _)
+    }
+}
+
+pub fn native_virtio_handler(mmio: &mut MMIOAccess, base: usize) -> HvResult {
+    let zone_id = this_zone_id();
+    let dev = match NATIVE_VIRTIO_DEVS.lock().get(&(zone_id, base)) {
+        Some(dev) => dev.clone(),
+        None => return hv_result_err!(ENODEV, format!("no native virtio device at {:#x}", base)),
+    };
+    let offset = mmio.address;
+
+    if offset >= CONFIG {
+        if mmio.is_write {
+            warn!("native virtio: config space is read-only");
+        } else {
+            mmio.value = dev.read_config(offset - CONFIG, mmio.size) as _;
+        }
+        return Ok(());
+    }
+    if mmio.size != 4 {
+        warn!("native virtio: unsupported {} bytes access", mmio.size);
+        return Ok(());
+    }
+    if !mmio.is_write {
+        mmio.value = dev.read(offset) as _;
+    } else if dev.write(offset, mmio.value as _) {
+        inject_zone_irq(zone_id, dev.config.irq as _);
+    }
+    Ok(())
+}
+
+impl Zone {
+    pub fn native_virtio_init(&mut self, configs: &[HvNativeVirtioConfig]) {
+        for config in configs {
+            info!(
+                "zone {} native virtio device {} at {:#x}",
+                self.id, config.device_id, config.base
+            );
+            self.mmio_region_register(
+                config.base as GuestPhysAddr,
+                NATIVE_VIRTIO_MMIO_SIZE,
+                native_virtio_handler,
+                config.base as _,
+            );
+            let dev = NativeVirtioDev {
+                zone_id: self.id,
+                config: *config,
+                state: Mutex::new(NativeVirtioState::default()),
+            };
+            let mut devs = NATIVE_VIRTIO_DEVS.lock();
+            devs.insert((self.id, config.base as _), Arc::new(dev));
+            NATIVE_CONSOLES_RX.store(consoles_rx(&devs), Ordering::Release);
+        }
+    }
+}
+
+/// Feed UART input to the consoles that asked for it, called from `device_poll`.
+pub fn native_virtio_poll() {
+    if NATIVE_CONSOLES_RX.load(Ordering::Acquire) == 0 {
+        return;
+    }
+    // polled without the lock, `inject_zone_irq` takes VIRTIO_IRQS which
+    // zone_shutdown holds while removing the devices
+    let consoles: Vec<_> = match NATIVE_VIRTIO_DEVS.try_lock() {
+        Some(devs) => devs
+            .values()
+            .filter(|dev| dev.is_console_rx())
+            .cloned()
+            .collect(),
+        None => return,
+    };
+    for dev in consoles {
+        if dev.with_ram(|ram| dev.console_poll(ram)) == Some(true) {
+            inject_zone_irq(dev.zone_id, dev.config.irq as _);
+        }
+    }
+}
+
+/// Forget the state the guest set up, used when the zone reboots.
+pub fn native_virtio_reset(zone_id: usize) {
+    NATIVE_VIRTIO_DEVS
+        .lock()
+        .iter()
+        .filter(|((id, _), _)| *id == zone_id)
+        .for_each(|(_, dev)| *dev.state.lock() = NativeVirtioState::default());
+}
+
+pub fn native_virtio_remove(zone_id: usize) {
+    let mut devs = NATIVE_VIRTIO_DEVS.lock();
+    devs.retain(|(id, _), _| *id != zone_id);
+    NATIVE_CONSOLES_RX.store(consoles_rx(&devs), Ordering::Release);
+}
+
+fn consoles_rx(devs: &BTreeMap<(usize, usize), Arc<NativeVirtioDev>>) -> usize {
+    devs.values().filter(|dev| dev.is_console_rx()).count()
+}
@@ -20,19 +20,35 @@
 #![deny(unused_mut)]
 #![deny(unused)]
 
+#[cfg(not(feature = "sched"))]
+use crate::arch::cpu::wait_for_irq_handled;
+#[cfg(feature = "sched")]
+use crate::event::has_events;
 #[cfg(not(target_arch = "loongarch64"))]
+use crate::event::IPI_EVENT_WAKEUP_VIRTIO_DEVICE;
 use crate::{
-    arch::cpu::get_target_cpu,
-    event::{send_event, IPI_EVENT_WAKEUP_VIRTIO_DEVICE},
+    arch::{
+        cpu::{get_target_cpu, this_cpu_id},
+        time::get_time_us,
+    },
+    config::MEM_TYPE_RAM,
+    consts::{MAX_VCPU_NUM, MAX_WAIT_TIMES, PAGE_SIZE},
+    cpu_data::cpu_relax,
+    device::irqchip::inject_irq,
+    error::HvResult,
+    event::{
+        event_pending, send_event, IPI_EVENT_SHUTDOWN, IPI_EVENT_VIRTIO_CFG_DONE,
+        IPI_EVENT_VIRTIO_INJECT_IRQ,
+    },
     hypercall::SGI_IPI_ID,
+    memory::MMIOAccess,
+    pci::vpci_dev::virtio_pci::{virtio_pci_signal, VIRTIO_PCI_IRQ},
+    zone::{find_zone, this_zone_id, zone_error},
 };
-use crate::{
-    arch::cpu::this_cpu_id, consts::MAX_WAIT_TIMES, device::irqchip::inject_irq, error::HvResult,
-    memory::MMIOAccess, zone::this_zone_id,
-};
-use alloc::collections::BTreeMap;
+use alloc::{collections::BTreeMap, sync::Arc, vec::Vec};
 use core::{
     fmt::{Debug, Formatter, Result},
+    mem::{offset_of, size_of},
     sync::atomic::{fence, AtomicBool, AtomicUsize, Ordering},
 };
 use spin::{Mutex, MutexGuard};
@@ -41,16 +57,45 @@ use tock_registers::{
     registers::ReadWrite,
 };
 
-/// Save the irqs the virtio-device wants to inject. The format is <cpu_id, List<irq_id>>, and the first elem of List<irq_id> is the valid len of it.
-pub static VIRTIO_IRQS: Mutex<BTreeMap<usize, [u64; MAX_DEVS + 1]>> = Mutex::new(BTreeMap::new());
+/// Save the irqs the virtio-device wants to inject. The format is <cpu_id, List<irq_id>>.
+pub static VIRTIO_IRQS: Mutex<BTreeMap<usize, Vec<u64>>> = Mutex::new(BTreeMap::new());
 // Controller of the shared memory the root linux's virtio device and hvisor shares.
 pub static VIRTIO_BRIDGE: VirtioBridgeController = VirtioBridgeController::new();
+/// Rings the backend set up for a single zone, used instead of `VIRTIO_BRIDGE`
+/// for that zone's requests.
+static VIRTIO_ZONE_BRIDGES: Mutex<BTreeMap<usize, Arc<VirtioBridgeController>>> =
+    Mutex::new(BTreeMap::new());
+/// Cpus sleeping on a config request, and when to wake them up to time it out.
+static CFG_WAIT_DEADLINES: Mutex<BTreeMap<usize, u64>> = Mutex::new(BTreeMap::new());
+/// Sizes of `VIRTIO_ZONE_BRIDGES` and `CFG_WAIT_DEADLINES`, so that
+/// `device_poll` leaves the locks alone while they are empty.
+static ZONE_BRIDGE_COUNT: AtomicUsize = AtomicUsize::new(0);
+static CFG_WAIT_COUNT: AtomicUsize = AtomicUsize::new(0);
 
 const QUEUE_NOTIFY: usize = 0x50;
-pub const MAX_REQ: u32 = 32;
-pub const MAX_DEVS: usize = 8; // Attention: The max virtio-dev number for vm is 8 (loongarch64 needs 3 consoles and 3 disks for zgclab project).
-pub const MAX_CPUS: usize = 32;
+/// Sizes of the global `VirtioBridge`, fixed by the old backend ABI.
+const LEGACY_MAX_REQ: u32 = 32;
+const LEGACY_MAX_DEVS: usize = 8;
+const LEGACY_MAX_CPUS: usize = 32;
+// with `sched` the cfg slots are indexed by vcpu
+#[cfg(feature = "sched")]
+const _: () = assert!(MAX_VCPU_NUM <= LEGACY_MAX_CPUS);
+/// Bounds of a `VirtioZoneBridge`, which is sized by the backend.
+const ZONE_BRIDGE_MAX_REQ: u32 = 4096;
+const ZONE_BRIDGE_MAX_CPUS: usize = 256;
 pub const MAX_BACKOFF: usize = 1024;
+/// Most irqs waiting to be injected on one cpu. Past it, results stay in the
+/// response ring, so a flood of completions makes the backend wait for a free
+/// slot instead of growing the lists.
+const VIRTIO_IRQS_MAX_PENDING: usize = 64;
+
+/// `VirtioZoneBridge::flags`: the backend calls `HvVirtioCfgDone` when it has
+/// finished a request, so the vcpu sleeps instead of spinning meanwhile. Only
+/// accepted with `HV_FEATURE_VIRTIO_CFG_WAIT`.
+pub const VIRTIO_BRIDGE_F_CFG_DONE_IPI: u32 = 1 << 0;
+/// `VirtioZoneBridge::flags`: a request that times out marks the zone as
+/// errored, instead of only failing the access.
+pub const VIRTIO_BRIDGE_F_TIMEOUT_ERROR: u32 = 1 << 1;
 
 #[cfg(not(target_arch = "loongarch64"))]
 use crate::platform::IRQ_WAKEUP_VIRTIO_DEVICE;
@@ -64,38 +109,59 @@ use crate::platform::IRQ_WAKEUP_VIRTIO_DEVICE;
 /// non root zone's virtio request handler
 pub fn mmio_virtio_handler(mmio: &mut MMIOAccess, base: usize) -> HvResult {
     // debug!("mmio virtio handler");
+    let is_notify = mmio.address == QUEUE_NOTIFY;
+    mmio.address += base;
+    virtio_bridge_forward(mmio, is_notify)
+}
+
+/// Push an access, whose address is already absolute, to the virtio backend
+/// in the root zone. Except for notifies, wait until the backend finishes it.
+pub fn virtio_bridge_forward(mmio: &mut MMIOAccess, is_notify: bool) -> HvResult {
     let cpu_id = this_cpu_id() as usize;
-    let need_interrupt = if mmio.address == QUEUE_NOTIFY { 1 } else { 0 };
+    let zone_id = this_zone_id();
+    let zone_bridge = VIRTIO_ZONE_BRIDGES.lock().get(&zone_id).cloned();
+    let bridge = zone_bridge.as_deref().unwrap_or(&VIRTIO_BRIDGE);
+    // a good time to pick up the results the backend posted meanwhile
+    try_drain_res(bridge);
+    if cpu_id >= bridge.layout.cpu_slots {
+        return hv_result_err!(
+            EINVAL,
+            format!(
+                "virtio bridge of zone {} has no slot for cpu {}",
+                zone_id, cpu_id
+            )
+        );
+    }
+    let need_interrupt = if is_notify { 1 } else { 0 };
     if need_interrupt == 1 {
         trace!("notify !!!, cpu id is {}", cpu_id);
     }
-    mmio.address += base;
     // Ensure read old_cfg_flag before push_req
-    let old_cfg_flag = VIRTIO_BRIDGE.cfg_flag(cpu_id);
+    let old_cfg_flag = bridge.cfg_flag(cpu_id);
     fence(Ordering::Acquire);
 
     // Try to push req to req_list (in VirtioBridge critical area)
     // To avoid concurrent access to req_list, hvisor should lock VIRTIO_BRIDGE's req_list related part (here use req_agent)
     let mut backoff = 1;
-    let mut req_agent = VIRTIO_BRIDGE.req_agent();
+    let mut req_agent = bridge.req_agent();
     while req_agent.is_full() {
         // When root linux's cpu is in el2's finish req handler and is getting the dev lock,
         // if we don't release dev lock, it will cause a dead lock.
         drop(req_agent);
         // Exponential Backoff Algorithm, here especially useful for big.LITTLE architecture.
         for _ in 0..backoff {
-            core::hint::spin_loop();
+            cpu_relax();
         }
         backoff <<= 1;
         backoff = backoff.min(MAX_BACKOFF);
-        req_agent = VIRTIO_BRIDGE.req_agent();
+        req_agent = bridge.req_agent();
     }
     let hreq = HvisorDeviceReq::new(
         cpu_id as _,
         mmio.address as _,
         mmio.size as _,
         mmio.value as _,
-        this_zone_id() as _,
+        zone_id as _,
         mmio.is_write,
         need_interrupt,
     );
@@ -107,13 +173,18 @@ pub fn mmio_virtio_handler(mmio: &mut MMIOAccess, base: usize) -> HvResult {
     let mut is_ipi_sent = false;
     // If backend is sleep, hvisor needs to send ipi to wake it up.
     #[cfg(not(target_arch = "loongarch64"))]
-    check_need_wakeup_and_send_ipi(&mut is_ipi_sent);
+    check_need_wakeup_and_send_ipi(bridge, &mut is_ipi_sent);
+
+    if need_interrupt == 0 && bridge.flags & VIRTIO_BRIDGE_F_CFG_DONE_IPI != 0 {
+        bridge.wait_cfg_done(cpu_id, old_cfg_flag, mmio);
+        return Ok(());
+    }
 
     let mut count: usize = 0;
     // if it is cfg request, current cpu should be blocked until gets the result
     if need_interrupt == 0 {
         // when virtio backend finish the req, it will add 1 to cfg_flags[cpu_id].
-        while !VIRTIO_BRIDGE.is_cfg_updated(cpu_id, old_cfg_flag) {
+        while !bridge.is_cfg_updated(cpu_id, old_cfg_flag) {
             count += 1;
             if count == MAX_WAIT_TIMES {
                 warn!(
@@ -129,10 +200,12 @@ pub fn mmio_virtio_handler(mmio: &mut MMIOAccess, base: usize) -> HvResult {
                 count = 0;
             }
             // check_need_wakeup_and_send_ipi(&mut is_ipi_sent);
+            // with `sched` the root zone's vcpu may share this cpu
+            cpu_relax();
         }
         if !mmio.is_write {
             // ensure cfg value is right.
-            mmio.value = VIRTIO_BRIDGE.cfg_value(cpu_id) as _;
+            mmio.value = bridge.cfg_value(cpu_id) as _;
             // debug!("non root receives value: {:#x?}", mmio.value);
         }
     }
@@ -141,8 +214,8 @@ pub fn mmio_virtio_handler(mmio: &mut MMIOAccess, base: usize) -> HvResult {
 }
 
 #[cfg(not(target_arch = "loongarch64"))]
-pub fn check_need_wakeup_and_send_ipi(is_send_ipi: &mut bool) {
-    if !(*is_send_ipi) && VIRTIO_BRIDGE.need_wakeup() {
+fn check_need_wakeup_and_send_ipi(bridge: &VirtioBridgeController, is_send_ipi: &mut bool) {
+    if !(*is_send_ipi) && bridge.need_wakeup() {
         debug!("need wakeup (recheck), sending ipi to wake up virtio device");
         send_event(
             get_target_cpu(IRQ_WAKEUP_VIRTIO_DEVICE, 0),
@@ -158,17 +231,308 @@ pub fn check_need_wakeup_and_send_ipi(is_send_ipi: &mut bool) {
 pub fn handle_virtio_irq() {
     let mut map = VIRTIO_IRQS.lock();
     let irq_list = map.get_mut(&this_cpu_id()).unwrap();
-    let len = irq_list[0] as usize;
-    for irq_id in irq_list[1..=len].iter() {
+    for irq_id in irq_list.iter() {
         inject_irq(*irq_id as _, false);
     }
-    irq_list[0] = 0;
+    irq_list.clear();
+}
+
+/// Hand the results in `bridge`'s response ring to their target cpus. An irq
+/// already pending on a cpu is not queued again, so completions of the same
+/// device coalesce until the cpu injects it. Returns false if results were
+/// left in the ring because a target cpu has too many irqs pending.
+pub fn virtio_drain_res(bridge: &VirtioBridgeController) -> bool {
+    drain_res(bridge.res_agent(), VIRTIO_IRQS.lock())
+}
+
+fn drain_res(mut res_agent: ResAgent, mut map_irq: MutexGuard<BTreeMap<usize, Vec<u64>>>) -> bool {
+    while !res_agent.is_empty() {
+        let (_res_front, irq_id, target_zone) = res_agent.peek_front();
+        if irq_id & VIRTIO_PCI_IRQ as u64 != 0 {
+            virtio_pci_signal(target_zone as _, irq_id as _);
+            res_agent.advance_front();
+            continue;
+        }
+        if find_zone(target_zone as _).is_none() {
+            res_agent.advance_front();
+            continue;
+        }
+        let target_cpu = get_target_cpu(irq_id as _, target_zone as _);
+        let irq_list = map_irq.entry(target_cpu).or_default();
+        #[cfg(target_arch = "loongarch64")]
+        {
+            // CAUTION: this is a workaround for loongarch64
+            drop(crate::device::irqchip::ls7a2000::GLOBAL_IRQ_INJECT_STATUS.lock());
+            irq_list.clear();
+        }
+        if !irq_list.contains(&irq_id) {
+            if irq_list.len() >= VIRTIO_IRQS_MAX_PENDING {
+                trace!("cpu {} has too many virtio irqs pending", target_cpu);
+                return false;
+            }
+            irq_list.push(irq_id);
+            send_event(
+                target_cpu as _,
+                SGI_IPI_ID as _,
+                IPI_EVENT_VIRTIO_INJECT_IRQ,
+            );
+        }
+        res_agent.advance_front();
+    }
+    true
+}
+
+/// Inject an irq into a zone from hvisor's own emulated devices, the same way
+/// as the backend's results.
+pub fn inject_zone_irq(zone_id: usize, irq: usize) {
+    inject_zone_cpu_irq(zone_id, None, irq);
+}
+
+/// Like `inject_zone_irq`, but on the zone's `vcpu`th cpu if given instead of
+/// the one the irq is routed to. Returns false if there is no such cpu.
+pub fn inject_zone_cpu_irq(zone_id: usize, vcpu: Option<usize>, irq: usize) -> bool {
+    let mut map_irq = VIRTIO_IRQS.lock();
+    // zone_shutdown removes the zone while holding VIRTIO_IRQS
+    let zone = match find_zone(zone_id) {
+        Some(zone) => zone,
+        None => return false,
+    };
+    let target_cpu = match vcpu {
+        Some(index) => match zone.read().cpu_set.iter().nth(index) {
+            Some(cpu_id) => cpu_id,
+            None => return false,
+        },
+        None => get_target_cpu(irq, zone_id),
+    };
+    let irq_list = map_irq.entry(target_cpu).or_default();
+    if !irq_list.contains(&(irq as u64)) {
+        irq_list.push(irq as _);
+        send_event(target_cpu, SGI_IPI_ID as _, IPI_EVENT_VIRTIO_INJECT_IRQ);
+    }
+    true
+}
+
+/// Like `virtio_drain_res`, but gives up instead of waiting for the locks.
+fn try_drain_res(bridge: &VirtioBridgeController) {
+    let res_agent = match bridge.try_res_agent() {
+        Some(res_agent) if !res_agent.is_empty() => res_agent,
+        _ => return,
+    };
+    if let Some(map_irq) = VIRTIO_IRQS.try_lock() {
+        drain_res(res_agent, map_irq);
+    }
+}
+
+/// Inject the results the backend posted to the response rings, so that it
+/// does not need `HvVirtioInjectIrq` after each completion. Called from
+/// `device_poll`, and before a vcpu forwards a request.
+pub fn virtio_res_poll() {
+    if VIRTIO_BRIDGE.is_enabled() {
+        try_drain_res(&VIRTIO_BRIDGE);
+    }
+    if ZONE_BRIDGE_COUNT.load(Ordering::Acquire) == 0 {
+        return;
+    }
+    if let Some(bridges) = VIRTIO_ZONE_BRIDGES.try_lock() {
+        bridges.values().for_each(|bridge| try_drain_res(bridge));
+    }
+}
+
+/// Wake up the cpus whose config request has timed out, called from
+/// `device_poll`.
+pub fn virtio_cfg_poll() {
+    if CFG_WAIT_COUNT.load(Ordering::Acquire) == 0 {
+        return;
+    }
+    let Some(mut deadlines) = CFG_WAIT_DEADLINES.try_lock() else {
+        return;
+    };
+    let now = get_time_us();
+    deadlines.retain(|&cpu_id, &mut deadline| {
+        if now < deadline {
+            return true;
+        }
+        send_event(cpu_id, SGI_IPI_ID as _, IPI_EVENT_VIRTIO_CFG_DONE);
+        false
+    });
+    CFG_WAIT_COUNT.store(deadlines.len(), Ordering::Release);
+}
+
+/// Whether the `size` bytes at `ipa` are in a single RAM region of the root
+/// zone, so that they are contiguous behind the translation of `ipa`.
+fn in_root_ram(ipa: u64, size: usize) -> bool {
+    let Some(root) = find_zone(0) else {
+        return false;
+    };
+    let Some(end) = ipa.checked_add(size as u64) else {
+        return false;
+    };
+    let in_ram = root.read().memory_regions.iter().any(|region| {
+        region.mem_type == MEM_TYPE_RAM
+            && ipa >= region.virtual_start
+            && end <= region.virtual_start + region.size
+    });
+    in_ram
+}
+
+/// Use the `VirtioZoneBridge` the root zone set up at `ipa` for the requests
+/// of zone `zone_id`. `translate` gives the address hvisor reaches `ipa` at.
+pub fn virtio_zone_bridge_init(
+    zone_id: usize,
+    ipa: u64,
+    translate: impl FnOnce(u64) -> usize,
+) -> HvResult {
+    if zone_id == 0 || ipa % PAGE_SIZE as u64 != 0 {
+        return hv_result_err!(
+            EINVAL,
+            format!("virtio bridge: zone {}, base {:#x}", zone_id, ipa)
+        );
+    }
+    if find_zone(zone_id).is_none() {
+        return hv_result_err!(ENOENT, format!("virtio bridge: zone {} not found", zone_id));
+    }
+    if !in_root_ram(ipa, size_of::<VirtioZoneBridge>()) {
+        return hv_result_err!(
+            EINVAL,
+            format!("virtio bridge: {:#x} is not in root zone ram", ipa)
+        );
+    }
+    let base = translate(ipa);
+    let header = unsafe { &*(base as *const VirtioZoneBridge) };
+    let queue_size = header.queue_size.get();
+    let cpu_slots = header.cpu_slots.get() as usize;
+    let flags = header.flags.get();
+    // see `HV_FEATURE_VIRTIO_CFG_WAIT`
+    if flags & VIRTIO_BRIDGE_F_CFG_DONE_IPI != 0
+        && !cfg!(any(target_arch = "aarch64", target_arch = "riscv64"))
+    {
+        return hv_result_err!(
+            EINVAL,
+            "virtio bridge: VIRTIO_BRIDGE_F_CFG_DONE_IPI is not supported on this arch"
+        );
+    }
+    // requests are indexed by cpu id, which may change with cpu hotplug
+    if !queue_size.is_power_of_two()
+        || !(2..=ZONE_BRIDGE_MAX_REQ).contains(&queue_size)
+        || !(MAX_VCPU_NUM..=ZONE_BRIDGE_MAX_CPUS).contains(&cpu_slots)
+    {
+        return hv_result_err!(
+            EINVAL,
+            format!(
+                "virtio bridge: queue_size {} must be a power of two up to {}, cpu_slots {} between {} and {}",
+                queue_size, ZONE_BRIDGE_MAX_REQ, cpu_slots, MAX_VCPU_NUM, ZONE_BRIDGE_MAX_CPUS
+            )
+        );
+    }
+    let layout = BridgeLayout::zone(queue_size, cpu_slots);
+    if !in_root_ram(ipa, layout.size()) {
+        return hv_result_err!(
+            EINVAL,
+            format!(
+                "virtio bridge: {:#x} bytes at {:#x} are not in root zone ram",
+                layout.size(),
+                ipa
+            )
+        );
+    }
+    info!(
+        "zone {} virtio bridge at {:#x}, {} entries, {:#x} bytes",
+        zone_id,
+        base,
+        queue_size,
+        layout.size()
+    );
+    let mut bridges = VIRTIO_ZONE_BRIDGES.lock();
+    bridges.insert(
+        zone_id,
+        Arc::new(VirtioBridgeController::new_with_layout(
+            base,
+            layout,
+            flags,
+            header.cfg_timeout_ms.get(),
+        )),
+    );
+    ZONE_BRIDGE_COUNT.store(bridges.len(), Ordering::Release);
+    Ok(())
+}
+
+/// Send the requests of zone `zone_id` to the global `VIRTIO_BRIDGE` again.
+pub fn virtio_zone_bridge_remove(zone_id: usize) {
+    let mut bridges = VIRTIO_ZONE_BRIDGES.lock();
+    bridges.remove(&zone_id);
+    ZONE_BRIDGE_COUNT.store(bridges.len(), Ordering::Release);
+}
+
+/// The per-zone bridges to drain on `HvVirtioInjectIrq`: the one of `zone_id`,
+/// or all of them if `zone_id` is 0.
+pub fn virtio_zone_bridges(zone_id: usize) -> Vec<Arc<VirtioBridgeController>> {
+    VIRTIO_ZONE_BRIDGES
+        .lock()
+        .iter()
+        .filter(|(&id, _)| zone_id == 0 || id == zone_id)
+        .map(|(_, bridge)| bridge.clone())
+        .collect()
+}
+
+/// Where the lists of a bridge region are, relative to its base.
+#[derive(Debug, Clone, Copy)]
+struct BridgeLayout {
+    /// Entries of the req and res lists, a power of two.
+    queue_size: u32,
+    /// Entries of the cfg flags and values, indexed by cpu id.
+    cpu_slots: usize,
+    req_list: usize,
+    res_list: usize,
+    cfg_flags: usize,
+    cfg_values: usize,
+    need_wakeup: usize,
+}
+
+impl BridgeLayout {
+    /// The global `VirtioBridge`.
+    const LEGACY: Self = Self {
+        queue_size: LEGACY_MAX_REQ,
+        cpu_slots: LEGACY_MAX_CPUS,
+        req_list: offset_of!(VirtioBridge, req_list),
+        res_list: offset_of!(VirtioBridge, res_list),
+        cfg_flags: offset_of!(VirtioBridge, cfg_flags),
+        cfg_values: offset_of!(VirtioBridge, cfg_values),
+        need_wakeup: offset_of!(VirtioBridge, need_wakeup),
+    };
+
+    /// A `VirtioZoneBridge` header, followed by `queue_size` requests,
+    /// `queue_size` results, `cpu_slots` cfg flags and `cpu_slots` cfg values.
+    fn zone(queue_size: u32, cpu_slots: usize) -> Self {
+        let req_list = size_of::<VirtioZoneBridge>();
+        let res_list = req_list + queue_size as usize * size_of::<HvisorDeviceReqVolatile>();
+        let cfg_flags = res_list + queue_size as usize * size_of::<HvisorDeviceResVolatile>();
+        Self {
+            queue_size,
+            cpu_slots,
+            req_list,
+            res_list,
+            cfg_flags,
+            cfg_values: cfg_flags + cpu_slots * size_of::<u64>(),
+            need_wakeup: offset_of!(VirtioZoneBridge, need_wakeup),
+        }
+    }
+
+    fn size(&self) -> usize {
+        self.cfg_values + self.cpu_slots * size_of::<u64>()
+    }
 }
 
 /// Virtio bridge controller.
 pub struct VirtioBridgeController {
     base_address: AtomicUsize,
     is_enable: AtomicBool,
+    layout: BridgeLayout,
+    /// `VIRTIO_BRIDGE_F_*`
+    flags: u32,
+    /// How long a sleeping vcpu waits for `HvVirtioCfgDone`, 0 for ever.
+    cfg_timeout_ms: u32,
+    /// Per cpu, the cfg flag value that completes its last request.
+    cfg_expected: Mutex<BTreeMap<usize, u64>>,
     req_lock: Mutex<()>,
     res_lock: Mutex<()>,
 }
@@ -178,6 +542,23 @@ impl VirtioBridgeController {
         Self {
             base_address: AtomicUsize::new(0),
             is_enable: AtomicBool::new(false),
+            layout: BridgeLayout::LEGACY,
+            flags: 0,
+            cfg_timeout_ms: 0,
+            cfg_expected: Mutex::new(BTreeMap::new()),
+            req_lock: Mutex::new(()),
+            res_lock: Mutex::new(()),
+        }
+    }
+
+    fn new_with_layout(base: usize, layout: BridgeLayout, flags: u32, cfg_timeout_ms: u32) -> Self {
+        Self {
+            base_address: AtomicUsize::new(base),
+            is_enable: AtomicBool::new(true),
+            layout,
+            flags,
+            cfg_timeout_ms,
+            cfg_expected: Mutex::new(BTreeMap::new()),
             req_lock: Mutex::new(()),
             res_lock: Mutex::new(()),
         }
@@ -188,91 +569,203 @@ impl VirtioBridgeController {
         self.is_enable.store(true, Ordering::Release);
     }
 
+    pub fn is_enabled(&self) -> bool {
+        self.is_enable.load(Ordering::Acquire)
+    }
+
+    fn base(&self) -> usize {
+        self.base_address.load(Ordering::Relaxed)
+    }
+
+    /// Get res list agent, or None if another cpu holds it.
+    fn try_res_agent(&self) -> Option<ResAgent> {
+        if !self.is_enabled() {
+            return None;
+        }
+        let guard = self.res_lock.try_lock()?;
+        Some(ResAgent {
+            base: self.base(),
+            layout: self.layout,
+            _guard: guard,
+        })
+    }
+
     /// Get req list agent.
     fn req_agent(&self) -> ReqAgent {
-        if !self.is_enable.load(Ordering::Acquire) {
+        if !self.is_enabled() {
             panic!("VirtioBridge not enabled");
         }
         let guard = self.req_lock.lock();
         ReqAgent {
-            base: self.base_address.load(Ordering::Relaxed),
+            base: self.base(),
+            layout: self.layout,
             _guard: guard,
         }
     }
 
     /// Get res list agent.
     pub fn res_agent(&self) -> ResAgent {
-        if !self.is_enable.load(Ordering::Acquire) {
+        if !self.is_enabled() {
             panic!("VirtioBridge not enabled");
         }
         let guard = self.res_lock.lock();
         ResAgent {
-            base: self.base_address.load(Ordering::Relaxed),
+            base: self.base(),
+            layout: self.layout,
             _guard: guard,
         }
     }
 
-    /// Get cfg flags (0..MAX_CPUS)
-    fn cfg_flags(&self) -> &[ReadWrite<u64>; MAX_CPUS] {
-        let base = self.base_address.load(Ordering::Relaxed);
-        unsafe { &(*(base as *const VirtioBridge)).cfg_flags }
+    /// Get cfg flag of `cpu_id`, which must be below `layout.cpu_slots`.
+    fn cfg_flag_reg(&self, cpu_id: usize) -> &ReadWrite<u64> {
+        let flags = (self.base() + self.layout.cfg_flags) as *const ReadWrite<u64>;
+        unsafe { &*flags.add(cpu_id) }
     }
 
-    /// Get cfg values (0..MAX_CPUS)
-    fn cfg_values(&self) -> &[ReadWrite<u64>; MAX_CPUS] {
-        let base = self.base_address.load(Ordering::Relaxed);
-        unsafe { &(*(base as *const VirtioBridge)).cfg_values }
+    /// Get cfg value of `cpu_id`, which must be below `layout.cpu_slots`.
+    fn cfg_value_reg(&self, cpu_id: usize) -> &ReadWrite<u64> {
+        let values = (self.base() + self.layout.cfg_values) as *const ReadWrite<u64>;
+        unsafe { &*values.add(cpu_id) }
     }
 
     pub fn is_cfg_updated(&self, cpu_id: usize, old_val: u64) -> bool {
-        let val = self.cfg_flags()[cpu_id].get();
+        let val = self.cfg_flag_reg(cpu_id).get();
         fence(Ordering::Acquire);
         val != old_val
     }
 
     pub fn cfg_flag(&self, cpu_id: usize) -> u64 {
-        self.cfg_flags()[cpu_id].get()
+        self.cfg_flag_reg(cpu_id).get()
     }
 
     pub fn cfg_value(&self, cpu_id: usize) -> u64 {
-        self.cfg_values()[cpu_id].get()
+        self.cfg_value_reg(cpu_id).get()
+    }
+
+    /// Sleep until the backend has finished the request `cpu_id` just pushed,
+    /// woken up by `HvVirtioCfgDone` or, once it has timed out, by
+    /// `virtio_cfg_poll`. A failed read returns all ones.
+    fn wait_cfg_done(&self, cpu_id: usize, old_cfg_flag: u64, mmio: &mut MMIOAccess) {
+        let target = {
+            let mut expected = self.cfg_expected.lock();
+            // a request that timed out may still complete, don't take that
+            // completion for this request's
+            let target = match expected.get(&cpu_id) {
+                Some(&last) if last.wrapping_sub(old_cfg_flag) as i64 > 0 => last + 1,
+                _ => old_cfg_flag + 1,
+            };
+            expected.insert(cpu_id, target);
+            target
+        };
+        let deadline = match self.cfg_timeout_ms {
+            0 => u64::MAX,
+            ms => get_time_us() + ms as u64 * 1000,
+        };
+        if deadline != u64::MAX {
+            let mut deadlines = CFG_WAIT_DEADLINES.lock();
+            deadlines.insert(cpu_id, deadline);
+            CFG_WAIT_COUNT.store(deadlines.len(), Ordering::Release);
+        }
+
+        let done = loop {
+            if self.cfg_flag(cpu_id).wrapping_sub(target) as i64 >= 0 {
+                fence(Ordering::Acquire);
+                break true;
+            }
+            // let the zone be shut down even if the backend hangs
+            if event_pending(cpu_id, IPI_EVENT_SHUTDOWN) || get_time_us() >= deadline {
+                break false;
+            }
+            #[cfg(not(feature = "sched"))]
+            wait_for_irq_handled();
+            // the completion comes as an event, but other queued events keep the
+            // vcpu runnable
+            #[cfg(feature = "sched")]
+            if has_events(cpu_id) {
+                cpu_relax();
+            } else {
+                crate::sched::wait_for_event();
+            }
+        };
+        let mut deadlines = CFG_WAIT_DEADLINES.lock();
+        deadlines.remove(&cpu_id);
+        CFG_WAIT_COUNT.store(deadlines.len(), Ordering::Release);
+        drop(deadlines);
+
+        if done {
+            if !mmio.is_write {
+                mmio.value = self.cfg_value(cpu_id) as _;
+            }
+            return;
+        }
+        if !mmio.is_write {
+            mmio.value = match mmio.size {
+                1 | 2 | 4 => (1 << (mmio.size * 8)) - 1,
+                _ => usize::MAX,
+            };
+        }
+        if event_pending(cpu_id, IPI_EVENT_SHUTDOWN) {
+            return;
+        }
+        error!(
+            "virtio backend timed out, addr: {:#x} is_write: {:x?}",
+            mmio.address, mmio.is_write
+        );
+        if self.flags & VIRTIO_BRIDGE_F_TIMEOUT_ERROR != 0 {
+            zone_error();
+        }
     }
 
     #[allow(unused)]
     pub fn need_wakeup(&self) -> bool {
-        let base = self.base_address.load(Ordering::Relaxed);
-        let need_wakeup = unsafe { (&*(base as *const VirtioBridge)).need_wakeup.get() };
+        let need_wakeup = (self.base() + self.layout.need_wakeup) as *const ReadWrite<u8>;
+        let need_wakeup = unsafe { (*need_wakeup).get() };
         fence(Ordering::Acquire);
         need_wakeup == 1
     }
 }
 
+/// Ring indexes at the start of every bridge region.
+#[repr(C)]
+struct BridgeRings {
+    /// The first elem of req list, only virtio device updates
+    req_front: ReadWrite<u32>,
+    /// The last elem's next place of req list, only hvisor updates
+    req_rear: ReadWrite<u32>,
+    /// The first elem of res list, only hvisor updates
+    res_front: ReadWrite<u32>,
+    /// The last elem's next place of res list, only virtio device updates
+    res_rear: ReadWrite<u32>,
+}
+
 struct ReqAgent<'a> {
     base: usize,
+    layout: BridgeLayout,
     _guard: MutexGuard<'a, ()>,
 }
 
 impl<'a> ReqAgent<'a> {
-    fn region(&self) -> &mut VirtioBridge {
-        unsafe { &mut *(self.base as *mut VirtioBridge) }
+    fn rings(&self) -> &BridgeRings {
+        unsafe { &*(self.base as *const BridgeRings) }
     }
 
-    fn immut_region(&self) -> &VirtioBridge {
-        unsafe { &*(self.base as *const VirtioBridge) }
+    fn req(&self, index: u32) -> &HvisorDeviceReqVolatile {
+        let list = (self.base + self.layout.req_list) as *const HvisorDeviceReqVolatile;
+        unsafe { &*list.add(index as usize) }
     }
 
     pub fn is_full(&self) -> bool {
-        let region = self.immut_region();
-        let req_front = region.req_front.get();
-        let req_rear = (region.req_rear.get() + 1) & (MAX_REQ - 1);
+        let rings = self.rings();
+        let req_front = rings.req_front.get();
+        let req_rear = (rings.req_rear.get() + 1) & (self.layout.queue_size - 1);
         // fence: ensure all following req_list reads are visible after req_rear & req_front read
         fence(Ordering::Acquire);
         req_rear == req_front
     }
 
     pub fn push_req(&mut self, req: HvisorDeviceReq) {
-        let region = self.region();
-        let slot = &region.req_list[(region.req_rear.get() % MAX_REQ) as usize];
+        let rings = self.rings();
+        let slot = self.req(rings.req_rear.get() % self.layout.queue_size);
         slot.src_cpu.set(req.src_cpu);
         slot.address.set(req.address);
         slot.size.set(req.size);
@@ -282,83 +775,97 @@ impl<'a> ReqAgent<'a> {
         slot.need_interrupt.set(req.need_interrupt);
         // Write barrier so that virtio device sees changes to req_list before change to req_idx
         fence(Ordering::Release);
-        region.req_rear.set((region.req_rear.get() + 1) % MAX_REQ);
+        rings
+            .req_rear
+            .set((rings.req_rear.get() + 1) % self.layout.queue_size);
     }
 }
 
 pub struct ResAgent<'a> {
     base: usize,
+    layout: BridgeLayout,
     _guard: MutexGuard<'a, ()>,
 }
 
 impl<'a> ResAgent<'a> {
-    fn region(&self) -> &mut VirtioBridge {
-        unsafe { &mut *(self.base as *mut VirtioBridge) }
+    fn rings(&self) -> &BridgeRings {
+        unsafe { &*(self.base as *const BridgeRings) }
     }
 
-    fn immut_region(&self) -> &VirtioBridge {
-        unsafe { &*(self.base as *const VirtioBridge) }
+    fn res(&self, index: u32) -> &HvisorDeviceResVolatile {
+        let list = (self.base + self.layout.res_list) as *const HvisorDeviceResVolatile;
+        unsafe { &*list.add(index as usize) }
     }
 
     pub fn is_empty(&self) -> bool {
-        let region = self.immut_region();
-        let res_rear = region.res_rear.get();
-        let res_front = region.res_front.get();
+        let rings = self.rings();
+        let res_rear = rings.res_rear.get();
+        let res_front = rings.res_front.get();
         // fence: ensure all following res_list reads are visible after res_rear & res_front read
         fence(Ordering::Acquire);
         res_rear == res_front
     }
 
     pub fn peek_front(&self) -> (u32, u64, u32) {
-        let region = self.immut_region();
-        let res_front = region.res_front.get() as usize;
-        let res = &region.res_list[res_front];
+        let res_front = self.rings().res_front.get();
+        let res = self.res(res_front);
         let irq_id = res.irq_id.get() as u64;
         let target_zone = res.target_zone.get();
-        (res_front as u32, irq_id, target_zone)
+        (res_front, irq_id, target_zone)
     }
 
     pub fn advance_front(&mut self) {
-        let region = self.region();
+        let rings = self.rings();
         // fence: ensure all previous res_list updates are visible before advancing res_front
         fence(Ordering::Release);
-        region
+        rings
             .res_front
-            .set((region.res_front.get() + 1) & (MAX_REQ - 1));
+            .set((rings.res_front.get() + 1) & (self.layout.queue_size - 1));
     }
 }
 
 /// El1 and EL2 shared region for virtio requests and results.
 #[repr(C)]
+#[allow(dead_code)] // only accessed through `BridgeLayout::LEGACY`
 struct VirtioBridge {
-    /// The first elem of req list, only virtio device updates
-    req_front: ReadWrite<u32>,
-    /// The last elem's next place of req list, only hvisor updates
-    req_rear: ReadWrite<u32>,
-    /// The first elem of res list, only hvisor updates
-    res_front: ReadWrite<u32>,
-    /// The last elem's next place of res list, only virtio device updates
-    res_rear: ReadWrite<u32>,
-    req_list: [HvisorDeviceReqVolatile; MAX_REQ as usize],
-    res_list: [HvisorDeviceResVolatile; MAX_REQ as usize], // irqs
-    cfg_flags: [ReadWrite<u64>; MAX_CPUS],
-    cfg_values: [ReadWrite<u64>; MAX_CPUS],
-    _mmio_addrs: [ReadWrite<u64>; MAX_DEVS], // remove later
-    _mmio_avail: ReadWrite<u8>,              // remove later
+    rings: BridgeRings,
+    req_list: [HvisorDeviceReqVolatile; LEGACY_MAX_REQ as usize],
+    res_list: [HvisorDeviceResVolatile; LEGACY_MAX_REQ as usize], // irqs
+    cfg_flags: [ReadWrite<u64>; LEGACY_MAX_CPUS],
+    cfg_values: [ReadWrite<u64>; LEGACY_MAX_CPUS],
+    _mmio_addrs: [ReadWrite<u64>; LEGACY_MAX_DEVS], // remove later
+    _mmio_avail: ReadWrite<u8>,                     // remove later
     need_wakeup: ReadWrite<u8>,
 }
 
 impl Debug for VirtioBridge {
     fn fmt(&self, f: &mut Formatter<'_>) -> Result {
         f.debug_struct("VirtioBridge")
-            .field("req_front", &self.req_front.get())
-            .field("req_rear", &self.req_rear.get())
-            .field("res_front", &self.res_front.get())
-            .field("res_rear", &self.res_rear.get())
+            .field("req_front", &self.rings.req_front.get())
+            .field("req_rear", &self.rings.req_rear.get())
+            .field("res_front", &self.rings.res_front.get())
+            .field("res_rear", &self.rings.res_rear.get())
             .finish()
     }
 }
 
+/// Header of a per-zone bridge region, the lists follow as described by
+/// `BridgeLayout::zone`. The backend fills `queue_size`, `cpu_slots`,
+/// `flags` and `cfg_timeout_ms` before `HvVirtioInit`.
+#[repr(C)]
+#[allow(dead_code)]
+struct VirtioZoneBridge {
+    rings: BridgeRings,
+    queue_size: ReadWrite<u32>,
+    cpu_slots: ReadWrite<u32>,
+    /// `VIRTIO_BRIDGE_F_*`
+    flags: ReadWrite<u32>,
+    /// Only with `VIRTIO_BRIDGE_F_CFG_DONE_IPI`, 0 waits for ever.
+    cfg_timeout_ms: ReadWrite<u32>,
+    need_wakeup: ReadWrite<u8>,
+    _padding: [u8; 7],
+}
+
 /// Hvisor device requests
 #[repr(C)]
 #[derive(Debug)]
@@ -0,0 +1,316 @@
+// Copyright (c) 2025 Syswonder
+// hvisor is licensed under Mulan PSL v2.
+// You can use this software according to the terms and conditions of the Mulan PSL v2.
+// You may obtain a copy of Mulan PSL v2 at:
+//     http://license.coscl.org.cn/MulanPSL2
+// THIS SOFTWARE IS PROVIDED ON AN "AS IS" BASIS, WITHOUT WARRANTIES OF ANY KIND, EITHER
+// EXPRESS OR IMPLIED, INCLUDING BUT NOT LIMITED TO NON-INFRINGEMENT, MERCHANTABILITY OR
+// FIT FOR A PARTICULAR PURPOSE.
+// See the Mulan PSL v2 for more details.
+//
+// Syswonder Website:
+//      https://www.syswonder.org
+//
+// Authors:
+//
+
+//! Per-zone virtual watchdog, register compatible with the ARM SP805 so that
+//! the guest can use its `sp805-wdt` driver on every architecture.
+//!
+//! Like the SP805, the counter raises an interrupt when it first reaches zero
+//! and reloads. If the guest has not cleared the interrupt by the next time it
+//! reaches zero and reset is enabled, the zone's `HvWatchdogConfig::policy` is
+//! applied and the root zone is woken up through the virtio device interrupt,
+//! so hvisor-tool can look at the zone list.
+//!
+//! hvisor has no timer of its own, so expiry is checked when a root zone cpu
+//! takes a physical interrupt, at most every `WDT_POLL_INTERVAL_US`. The root
+//! zone's own watchdog is never checked.
+
+use alloc::collections::BTreeMap;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use core::sync::atomic::{AtomicU64, Ordering};
+use spin::Mutex;
+
+use crate::arch::cpu::get_target_cpu;
+use crate::arch::time::get_time_us;
+use crate::config::{HvWatchdogConfig, WDT_POLICY_REBOOT, WDT_POLICY_SHUTDOWN};
+use crate::cpu_data::this_cpu_data;
+use crate::device::virtio_trampoline::inject_zone_irq;
+use crate::error::HvResult;
+use crate::event::send_event;
+use crate::hypercall::SGI_IPI_ID;
+use crate::memory::{GuestPhysAddr, MMIOAccess};
+use crate::zone::{find_zone, zone_reboot, zone_shutdown, Zone};
+
+pub const WDT_MMIO_SIZE: usize = 0x1000;
+const WDT_POLL_INTERVAL_US: u64 = 10_000;
+
+const WDOG_LOAD: usize = 0x000;
+const WDOG_VALUE: usize = 0x004;
+const WDOG_CONTROL: usize = 0x008;
+const WDOG_INTCLR: usize = 0x00c;
+const WDOG_RIS: usize = 0x010;
+const WDOG_MIS: usize = 0x014;
+const WDOG_LOCK: usize = 0xc00;
+const WDOG_PERIPH_ID0: usize = 0xfe0;
+
+const WDOG_CONTROL_INTEN: u32 = 1 << 0;
+const WDOG_CONTROL_RESEN: u32 = 1 << 1;
+const WDOG_UNLOCK_KEY: u32 = 0x1acc_e551;
+/// PeriphID0-3 and PCellID0-3 of the SP805.
+const WDOG_ID: [u32; 8] = [0x05, 0x18, 0x14, 0x00, 0x0d, 0xf0, 0x05, 0xb1];
+
+static WATCHDOGS: Mutex<BTreeMap<usize, Arc<VirtWatchdog>>> = Mutex::new(BTreeMap::new());
+static NEXT_POLL_US: AtomicU64 = AtomicU64::new(0);
+
+struct WatchdogState {
+    load: u32,
+    control: u32,
+    ris: bool,
+    locked: bool,
+    /// Time the counter reaches zero, valid while `WDOG_CONTROL_INTEN` is set.
+    deadline_us: u64,
+}
+
+enum Expiry {
+    Interrupt,
+    Reset,
+}
+
+pub struct VirtWatchdog {
+    config: HvWatchdogConfig,
+    state: Mutex<WatchdogState>,
+}
+
+impl VirtWatchdog {
+    fn new(config: HvWatchdogConfig) -> Self {
+        Self {
+            config,
+            state: Mutex::new(WatchdogState {
+                load: u32::MAX,
+                control: 0,
+                ris: false,
+                locked: false,
+                deadline_us: 0,
+            }),
+        }
+    }
+
+    fn period_us(&self, load: u32) -> u64 {
+        ((load as u64 + 1) * 1_000_000 / self.config.clock_hz).max(1)
+    }
+
+    fn reload(&self, state: &mut WatchdogState, now: u64) {
+        state.deadline_us = now + self.period_us(state.load);
+    }
+
+    /// Back to the reset state, used when the zone reboots.
+    fn reset(&self) {
+        *self.state.lock() = WatchdogState {
+            load: u32::MAX,
+            control: 0,
+            ris: false,
+            locked: false,
+            deadline_us: 0,
+        };
+    }
+
+    fn read(&self, offset: usize) -> u32 {
+        let state = self.state.lock();
+        match offset {
+            WDOG_LOAD => state.load,
+            WDOG_VALUE => {
+                if state.control & WDOG_CONTROL_INTEN == 0 {
+                    return state.load;
+                }
+                let left_us = state.deadline_us.saturating_sub(get_time_us());
+                (left_us as u128 * self.config.clock_hz as u128 / 1_000_000) as u32
+            }
+            WDOG_CONTROL => state.control,
+            WDOG_RIS => state.ris as u32,
+            WDOG_MIS => (state.ris && state.control & WDOG_CONTROL_INTEN != 0) as u32,
+            WDOG_LOCK => state.locked as u32,
+            WDOG_PERIPH_ID0..=0xffc => WDOG_ID[(offset - WDOG_PERIPH_ID0) / 4],
+            _ => 0,
+        }
+    }
+
+    fn write(&self, offset: usize, value: u32) {
+        let mut state = self.state.lock();
+        if offset == WDOG_LOCK {
+            state.locked = value != WDOG_UNLOCK_KEY;
+            return;
+        }
+        if state.locked {
+            return;
+        }
+        let now = get_time_us();
+        match offset {
+            WDOG_LOAD => {
+                state.load = value.max(1);
+                self.reload(&mut state, now);
+            }
+            WDOG_CONTROL => {
+                let enable =
+                    state.control & WDOG_CONTROL_INTEN == 0 && value & WDOG_CONTROL_INTEN != 0;
+                state.control = value & (WDOG_CONTROL_INTEN | WDOG_CONTROL_RESEN);
+                if enable {
+                    self.reload(&mut state, now);
+                }
+            }
+            WDOG_INTCLR => {
+                state.ris = false;
+                self.reload(&mut state, now);
+            }
+            _ => {}
+        }
+    }
+
+    fn check(&self, now: u64) -> Option<Expiry> {
+        let mut state = self.state.lock();
+        if state.control & WDOG_CONTROL_INTEN == 0 || now < state.deadline_us {
+            return None;
+        }
+        if !state.ris {
+            state.ris = true;
+            self.reload(&mut state, now);
+            return Some(Expiry::Interrupt);
+        }
+        if state.control & WDOG_CONTROL_RESEN != 0 {
+            // stop counting, the policy decides what happens to the zone
+            state.control = 0;
+            return Some(Expiry::Reset);
+        }
+        self.reload(&mut state, now);
+        None
+    }
+}
+
+pub fn wdt_mmio_handler(mmio: &mut MMIOAccess, zone_id: usize) -> HvResult {
+    let wdt = match WATCHDOGS.lock().get(&zone_id) {
+        Some(wdt) => wdt.clone(),
+        None => return hv_result_err!(ENODEV, format!("zone {} has no watchdog", zone_id)),
+    };
+    if mmio.size != 4 {
+        warn!("watchdog: unsupported {} bytes access", mmio.size);
+        return Ok(());
+    }
+    if mmio.is_write {
+        wdt.write(mmio.address, mmio.value as _);
+    } else {
+        mmio.value = wdt.read(mmio.address) as _;
+    }
+    Ok(())
+}
+
+impl Zone {
+    pub fn watchdog_init(&mut self, config: Option<&HvWatchdogConfig>) {
+        let config = match config {
+            Some(config) => *config,
+            None => return,
+        };
+        info!("zone {} watchdog at {:#x}", self.id, config.base);
+        self.mmio_region_register(
+            config.base as GuestPhysAddr,
+            WDT_MMIO_SIZE,
+            wdt_mmio_handler,
+            self.id,
+        );
+        WATCHDOGS
+            .lock()
+            .insert(self.id, Arc::new(VirtWatchdog::new(config)));
+    }
+}
+
+pub fn watchdog_reset(zone_id: usize) {
+    if let Some(wdt) = WATCHDOGS.lock().get(&zone_id) {
+        wdt.reset();
+    }
+}
+
+pub fn watchdog_remove(zone_id: usize) {
+    WATCHDOGS.lock().remove(&zone_id);
+}
+
+fn notify_root() {
+    #[cfg(not(target_arch = "loongarch64"))]
+    {
+        use crate::event::IPI_EVENT_WAKEUP_VIRTIO_DEVICE;
+        use crate::platform::IRQ_WAKEUP_VIRTIO_DEVICE;
+        send_event(
+            get_target_cpu(IRQ_WAKEUP_VIRTIO_DEVICE, 0),
+            SGI_IPI_ID as _,
+            IPI_EVENT_WAKEUP_VIRTIO_DEVICE,
+        );
+    }
+}
+
+fn watchdog_expire(zone_id: usize, policy: u32) {
+    let zone = match find_zone(zone_id) {
+        Some(zone) => zone,
+        None => return,
+    };
+    error!("zone {} watchdog expired, policy {}", zone_id, policy);
+    match policy {
+        WDT_POLICY_SHUTDOWN => {
+            drop(zone);
+            if let Err(e) = zone_shutdown(zone_id) {
+                error!("zone {} watchdog shutdown failed: {:?}", zone_id, e);
+            }
+        }
+        WDT_POLICY_REBOOT => {
+            if let Err(e) = zone_reboot(&zone) {
+                error!("zone {} watchdog reboot failed: {:?}", zone_id, e);
+            }
+        }
+        // WDT_POLICY_ERROR
+        _ => zone.write().is_err = true,
+    }
+    notify_root();
+}
+
+/// Check the watchdogs of all zones, called from `device_poll`. Only root zone
+/// cpus do, since an expiry may stop another zone, which only the root zone
+/// may do.
+pub fn watchdog_poll() {
+    if this_cpu_data().zone.as_ref().map(|zone| zone.read().id) != Some(0) {
+        return;
+    }
+    let now = get_time_us();
+    let next = NEXT_POLL_US.load(Ordering::Relaxed);
+    if now < next
+        || NEXT_POLL_US
+            .compare_exchange(
+                next,
+                now + WDT_POLL_INTERVAL_US,
+                Ordering::Relaxed,
+                Ordering::Relaxed,
+            )
+            .is_err()
+    {
+        return;
+    }
+
+    // the root zone can't be stopped
+    let expired: Vec<(usize, Expiry, HvWatchdogConfig)> = WATCHDOGS
+        .lock()
+        .iter()
+        .filter(|(&zone_id, _)| zone_id != 0)
+        .filter_map(|(&zone_id, wdt)| wdt.check(now).map(|expiry| (zone_id, expiry, wdt.config)))
+        .collect();
+
+    for (zone_id, expiry, config) in expired {
+        if find_zone(zone_id).is_none() {
+            // the zone failed to start or is gone
+            watchdog_remove(zone_id);
+            continue;
+        }
+        match expiry {
+            Expiry::Interrupt if config.irq != 0 => inject_zone_irq(zone_id, config.irq as _),
+            Expiry::Interrupt => {}
+            Expiry::Reset => watchdog_expire(zone_id, config.policy),
+        }
+    }
+}
@@ -15,33 +15,59 @@
 //
 #![allow(unused)]
 use crate::{
-    arch::ipi::{arch_check_events, arch_prepare_send_event, arch_send_event},
+    arch::{
+        cpu::wait_for_irq,
+        ipi::{arch_check_events, arch_clear_event_ipi, arch_prepare_send_event, arch_send_event},
+    },
     consts::{
-        IPI_EVENT_CLEAR_INJECT_IRQ, IPI_EVENT_SEND_IPI, IPI_EVENT_UPDATE_HART_LINE, MAX_CPU_NUM,
+        IPI_EVENT_CLEAR_INJECT_IRQ, IPI_EVENT_SEND_IPI, IPI_EVENT_UPDATE_HART_LINE, MAX_VCPU_NUM,
     },
-    cpu_data::this_cpu_data,
+    cpu_data::{get_cpu_data, this_cpu_data},
     device::{irqchip::inject_irq, virtio_trampoline::handle_virtio_irq},
     platform::IRQ_WAKEUP_VIRTIO_DEVICE,
 };
 use alloc::{collections::VecDeque, vec::Vec};
+use core::sync::atomic::Ordering;
+#[cfg(feature = "sched")]
+use spin::Lazy;
 use spin::Mutex;
 
 pub const IPI_EVENT_WAKEUP: usize = 0;
 pub const IPI_EVENT_SHUTDOWN: usize = 1;
 pub const IPI_EVENT_VIRTIO_INJECT_IRQ: usize = 2;
 pub const IPI_EVENT_WAKEUP_VIRTIO_DEVICE: usize = 3;
+pub const IPI_EVENT_PAUSE: usize = 7;
+pub const IPI_EVENT_RESUME: usize = 8;
+pub const IPI_EVENT_FLUSH_S2: usize = 9;
+pub const IPI_EVENT_VIRTIO_CFG_DONE: usize = 10;
 
+#[cfg(not(feature = "sched"))]
 #[percpu::def_percpu]
 static PERCPU_EVENTS: Mutex<VecDeque<usize>> = Mutex::new(VecDeque::new());
 
+// percpu areas only exist for physical cpus, events are sent to vcpus
+#[cfg(feature = "sched")]
+static VCPU_EVENTS: Lazy<Vec<Mutex<VecDeque<usize>>>> = Lazy::new(|| {
+    (0..MAX_VCPU_NUM)
+        .map(|_| Mutex::new(VecDeque::new()))
+        .collect()
+});
+
 // The caller ensures the cpu_id is valid
 #[inline(always)]
 fn get_percpu_events(cpu: usize) -> &'static Mutex<VecDeque<usize>> {
-    unsafe { PERCPU_EVENTS.remote_ref_raw(cpu) }
+    #[cfg(not(feature = "sched"))]
+    unsafe {
+        PERCPU_EVENTS.remote_ref_raw(cpu)
+    }
+    #[cfg(feature = "sched")]
+    {
+        &VCPU_EVENTS[cpu]
+    }
 }
 
 fn add_event(cpu: usize, event_id: usize) -> Option<()> {
-    if cpu >= MAX_CPU_NUM {
+    if cpu >= MAX_VCPU_NUM {
         return None;
     }
     let mut e = get_percpu_events(cpu).lock();
@@ -53,15 +79,28 @@ fn add_event(cpu: usize, event_id: usize) -> Option<()> {
     Some(())
 }
 
+/// Whether `event_id` is queued for `cpu` and not handled yet.
+pub fn event_pending(cpu: usize, event_id: usize) -> bool {
+    if cpu >= MAX_VCPU_NUM {
+        return false;
+    }
+    get_percpu_events(cpu).lock().contains(&event_id)
+}
+
 pub fn fetch_event(cpu: usize) -> Option<usize> {
-    if cpu >= MAX_CPU_NUM {
+    if cpu >= MAX_VCPU_NUM {
         return None;
     }
     get_percpu_events(cpu).lock().pop_front()
 }
 
+/// Whether `cpu` has events to handle.
+pub fn has_events(cpu: usize) -> bool {
+    cpu < MAX_VCPU_NUM && !get_percpu_events(cpu).lock().is_empty()
+}
+
 pub fn dump_events() {
-    for cpu in 0..MAX_CPU_NUM {
+    for cpu in 0..MAX_VCPU_NUM {
         let events = get_percpu_events(cpu).lock();
         if !events.is_empty() {
             debug!("cpu {} events: {:?}", cpu, *events);
@@ -70,14 +109,14 @@ pub fn dump_events() {
 }
 
 pub fn dump_cpu_events(cpu: usize) -> Vec<usize> {
-    if cpu >= MAX_CPU_NUM {
+    if cpu >= MAX_VCPU_NUM {
         return Vec::new();
     }
     get_percpu_events(cpu).lock().iter().cloned().collect()
 }
 
 pub fn clear_events(cpu: usize) {
-    if cpu >= MAX_CPU_NUM {
+    if cpu >= MAX_VCPU_NUM {
         return;
     }
     get_percpu_events(cpu).lock().clear();
@@ -103,6 +142,27 @@ pub fn check_events() -> bool {
             inject_irq(IRQ_WAKEUP_VIRTIO_DEVICE, false);
             true
         }
+        Some(IPI_EVENT_PAUSE) => {
+            cpu_data.arch_cpu.pause();
+            // events deferred during the pause have lost their ipi, handle them now
+            while check_events() {}
+            true
+        }
+        Some(IPI_EVENT_RESUME) => {
+            // the cpu is not paused, nothing to resume
+            true
+        }
+        Some(IPI_EVENT_VIRTIO_CFG_DONE) => {
+            // only wakes up a cpu waiting in `virtio_bridge_forward`
+            true
+        }
+        Some(IPI_EVENT_FLUSH_S2) => {
+            if let Some(zone) = cpu_data.zone.as_ref() {
+                zone.read().gpm.flush(None);
+            }
+            cpu_data.s2_flushes.fetch_add(1, Ordering::Release);
+            true
+        }
         Some(IPI_EVENT_CLEAR_INJECT_IRQ)
         | Some(IPI_EVENT_UPDATE_HART_LINE)
         | Some(IPI_EVENT_SEND_IPI) => {
@@ -134,6 +194,56 @@ pub fn check_events() -> bool {
     }
 }
 
+/// Park the current cpu until `IPI_EVENT_RESUME` arrives.
+///
+/// The caller is inside a trap handler, so the guest context stays untouched and the
+/// vcpu continues where it stopped after this returns. Events other than resume and
+/// shutdown are deferred until the cpu is resumed.
+pub fn wait_for_resume() {
+    let cpu_data = this_cpu_data();
+    let cpu = cpu_data.id;
+    let lock = cpu_data.ctrl_lock.lock();
+    cpu_data.paused = true;
+    drop(lock);
+
+    let mut deferred = VecDeque::new();
+    loop {
+        // the ipis of the events taken here must not reach the guest
+        arch_clear_event_ipi();
+        match fetch_event(cpu) {
+            Some(IPI_EVENT_RESUME) => break,
+            Some(IPI_EVENT_SHUTDOWN) => {
+                let lock = cpu_data.ctrl_lock.lock();
+                cpu_data.paused = false;
+                drop(lock);
+                cpu_data.arch_cpu.idle();
+            }
+            Some(event) => deferred.push_back(event),
+            #[cfg(not(feature = "sched"))]
+            None => wait_for_irq(),
+            #[cfg(feature = "sched")]
+            None => crate::sched::wait_for_event(),
+        }
+    }
+
+    let mut events = get_percpu_events(cpu).lock();
+    while let Some(event) = deferred.pop_back() {
+        events.push_front(event);
+    }
+    drop(events);
+
+    let lock = cpu_data.ctrl_lock.lock();
+    cpu_data.paused = false;
+    drop(lock);
+}
+
+/// Whether `cpu` is currently parked by `IPI_EVENT_PAUSE`.
+pub fn cpu_is_paused(cpu: usize) -> bool {
+    let cpu_data = get_cpu_data(cpu);
+    let _lock = cpu_data.ctrl_lock.lock();
+    cpu_data.paused
+}
+
 pub fn send_event(cpu_id: usize, ipi_int_id: usize, event_id: usize) {
     // #[cfg(target_arch = "loongarch64")]
     // {
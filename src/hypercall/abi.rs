@@ -0,0 +1,155 @@
+// Copyright (c) 2025 Syswonder
+// hvisor is licensed under Mulan PSL v2.
+// You can use this software according to the terms and conditions of the Mulan PSL v2.
+// You may obtain a copy of Mulan PSL v2 at:
+//     http://license.coscl.org.cn/MulanPSL2
+// THIS SOFTWARE IS PROVIDED ON AN "AS IS" BASIS, WITHOUT WARRANTIES OF ANY KIND, EITHER
+// EXPRESS OR IMPLIED, INCLUDING BUT NOT LIMITED TO NON-INFRINGEMENT, MERCHANTABILITY OR
+// FIT FOR A PARTICULAR PURPOSE.
+// See the Mulan PSL v2 for more details.
+//
+// Syswonder Website:
+//      https://www.syswonder.org
+//
+// Authors:
+//
+//! Hypercall ABI versioning and capability discovery.
+//!
+//! Tools should call `HvGetVersion` first, then `HvQueryFeatures` to learn
+//! which hypercalls and build options this hypervisor supports, instead of
+//! assuming an exact match with their own build.
+
+use super::HyperCallCode;
+use crate::config::CONFIG_MAGIC_VERSION;
+use core::convert::TryFrom;
+
+/// Bumped on incompatible changes of existing hypercalls or structures.
+pub const HV_ABI_VERSION_MAJOR: u32 = 1;
+/// Bumped when hypercalls, feature bits, zone config tags or trailing fields
+/// are added.
+pub const HV_ABI_VERSION_MINOR: u32 = 15;
+/// Value returned by `HvGetVersion`.
+pub const HV_ABI_VERSION: u32 = HV_ABI_VERSION_MAJOR << 16 | HV_ABI_VERSION_MINOR;
+
+pub const HV_FEATURE_PCI: u64 = 1 << 0;
+pub const HV_FEATURE_IOMMU: u64 = 1 << 1;
+pub const HV_FEATURE_STATS: u64 = 1 << 2;
+/// hvisor picks up virtio results by itself, `HvVirtioInjectIrq` is optional.
+pub const HV_FEATURE_VIRTIO_POSTED_IRQ: u64 = 1 << 3;
+/// Zone configs may carry `HV_CONFIG_TAG_NATIVE_VIRTIO` sections.
+pub const HV_FEATURE_NATIVE_VIRTIO: u64 = 1 << 4;
+/// Virtio bridges may set `VIRTIO_BRIDGE_F_CFG_DONE_IPI`, the vcpu sleeps
+/// until the backend is done.
+pub const HV_FEATURE_VIRTIO_CFG_WAIT: u64 = 1 << 5;
+
+pub const HV_ARCH_AARCH64: u32 = 1;
+pub const HV_ARCH_RISCV64: u32 = 2;
+pub const HV_ARCH_LOONGARCH64: u32 = 3;
+pub const HV_ARCH_X86_64: u32 = 4;
+
+pub const HV_IRQCHIP_UNKNOWN: u32 = 0;
+pub const HV_IRQCHIP_GICV2: u32 = 1;
+pub const HV_IRQCHIP_GICV3: u32 = 2;
+pub const HV_IRQCHIP_PLIC: u32 = 3;
+pub const HV_IRQCHIP_AIA: u32 = 4;
+pub const HV_IRQCHIP_LS7A2000: u32 = 5;
+pub const HV_IRQCHIP_X86_APIC: u32 = 6;
+
+/// Number of hypercall codes covered by `HvFeatures::hypercalls`.
+pub const HV_HYPERCALL_BITMAP_BITS: usize = 128;
+
+/// Filled by `HvQueryFeatures`. New fields are only ever appended, and the
+/// caller passes the size it knows about, so older tools keep working.
+#[repr(C)]
+#[derive(Debug, Clone, Copy)]
+pub struct HvFeatures {
+    pub abi_version: u32,
+    pub config_magic_version: u32,
+    pub arch: u32,
+    pub irqchip: u32,
+    /// Bitmap of `HV_FEATURE_*`.
+    pub features: u64,
+    /// Bit `n` is set if hypercall code `n` is supported.
+    pub hypercalls: [u64; HV_HYPERCALL_BITMAP_BITS / 64],
+}
+
+fn hv_arch() -> u32 {
+    if cfg!(target_arch = "aarch64") {
+        HV_ARCH_AARCH64
+    } else if cfg!(target_arch = "riscv64") {
+        HV_ARCH_RISCV64
+    } else if cfg!(target_arch = "loongarch64") {
+        HV_ARCH_LOONGARCH64
+    } else {
+        HV_ARCH_X86_64
+    }
+}
+
+fn hv_irqchip() -> u32 {
+    if cfg!(feature = "gicv2") {
+        HV_IRQCHIP_GICV2
+    } else if cfg!(feature = "gicv3") {
+        HV_IRQCHIP_GICV3
+    } else if cfg!(feature = "plic") {
+        HV_IRQCHIP_PLIC
+    } else if cfg!(feature = "aia") {
+        HV_IRQCHIP_AIA
+    } else if cfg!(feature = "loongson_7a2000") {
+        HV_IRQCHIP_LS7A2000
+    } else if cfg!(target_arch = "x86_64") {
+        HV_IRQCHIP_X86_APIC
+    } else {
+        HV_IRQCHIP_UNKNOWN
+    }
+}
+
+fn hv_features() -> u64 {
+    let mut features = 0;
+    // virtio irqs on loongarch64 go through the ls7a2000 workaround, which
+    // is only known to work for `HvVirtioInjectIrq`
+    if cfg!(not(target_arch = "loongarch64")) {
+        features |= HV_FEATURE_VIRTIO_POSTED_IRQ | HV_FEATURE_NATIVE_VIRTIO;
+    }
+    // a vcpu can only sleep where the wake-up ipi is taken while hvisor runs
+    if cfg!(any(target_arch = "aarch64", target_arch = "riscv64")) {
+        features |= HV_FEATURE_VIRTIO_CFG_WAIT;
+    }
+    if cfg!(feature = "pci") {
+        features |= HV_FEATURE_PCI;
+    }
+    if cfg!(feature = "iommu") {
+        features |= HV_FEATURE_IOMMU;
+    }
+    if cfg!(feature = "stats") {
+        features |= HV_FEATURE_STATS;
+    }
+    features
+}
+
+/// Hypercalls that only fail with `ENOSYS` in this build are left out.
+fn hv_hypercall_built(code: HyperCallCode) -> bool {
+    match code {
+        HyperCallCode::HvZoneStats => cfg!(feature = "stats"),
+        HyperCallCode::HvPciDevAttach | HyperCallCode::HvPciDevDetach => cfg!(feature = "pci"),
+        _ => true,
+    }
+}
+
+impl HvFeatures {
+    pub fn new() -> Self {
+        let mut hypercalls = [0u64; HV_HYPERCALL_BITMAP_BITS / 64];
+        for code in 0..HV_HYPERCALL_BITMAP_BITS {
+            if HyperCallCode::try_from(code as u64).is_ok_and(hv_hypercall_built) {
+                hypercalls[code / 64] |= 1 << (code % 64);
+            }
+        }
+        Self {
+            abi_version: HV_ABI_VERSION,
+            config_magic_version: CONFIG_MAGIC_VERSION as _,
+            arch: hv_arch(),
+            irqchip: hv_irqchip(),
+            features: hv_features(),
+            hypercalls,
+        }
+    }
+}
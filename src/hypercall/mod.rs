@@ -16,19 +16,35 @@
 #![allow(dead_code)]
 #![allow(unreachable_patterns)]
 
-use crate::arch::cpu::get_target_cpu;
-use crate::config::HvZoneConfig;
-use crate::consts::{INVALID_ADDRESS, MAX_CPU_NUM, MAX_WAIT_TIMES, PAGE_SIZE};
-use crate::cpu_data::{get_cpu_data, PerCpu};
-use crate::device::virtio_trampoline::{MAX_DEVS, VIRTIO_BRIDGE, VIRTIO_IRQS};
+mod abi;
+
+use crate::config::{
+    HvConfigMemoryRegion, HvIvcConfig, HvPciDevConfig, HvPciDevConfigWire, HvZoneConfig,
+};
+use crate::consts::{MAX_CPU_NUM, MAX_VCPU_NUM, PAGE_SIZE};
+use crate::cpu_data::{get_cpu_data, this_zone, PerCpu};
+use crate::device::virtio_mmio::virtio_mmio_register;
+use crate::device::virtio_trampoline::{
+    virtio_drain_res, virtio_zone_bridge_init, virtio_zone_bridge_remove, virtio_zone_bridges,
+    VIRTIO_BRIDGE,
+};
 use crate::error::HvResult;
+use crate::ivc::{ivc_attach, ivc_create, ivc_destroy, ivc_detach, ivc_info, IvcInfo};
+use crate::memory::grant::{grant_create, grant_map, grant_revoke, grant_unmap, HvGrantRegion};
+use crate::memory::hotplug::{zone_mem_add, zone_mem_remove};
+use crate::pci::pci_hotplug::{pci_dev_attach, pci_dev_detach};
+use crate::stats::{HvZoneStats, HV_ZONE_STATS_V1_SIZE};
 use crate::zone::{
-    add_zone, all_zones_info, find_zone, is_this_root_zone, remove_zone, zone_create, ZoneInfo,
+    add_zone, all_zones_info, find_zone, is_this_root_zone, root_zone, this_zone_id, zone_cpu_move,
+    zone_create, zone_reboot, zone_shutdown, Zone, ZoneInfo,
 };
 
-use crate::event::{send_event, IPI_EVENT_SHUTDOWN, IPI_EVENT_VIRTIO_INJECT_IRQ, IPI_EVENT_WAKEUP};
+use crate::event::{send_event, IPI_EVENT_VIRTIO_CFG_DONE, IPI_EVENT_WAKEUP};
+use abi::{HvFeatures, HV_ABI_VERSION};
+use alloc::sync::Arc;
 use core::convert::TryFrom;
 use numeric_enum_macro::numeric_enum;
+use spin::RwLock;
 
 numeric_enum! {
     #[repr(u64)]
@@ -43,6 +59,28 @@ numeric_enum! {
         HvClearInjectIrq = 20,
         HvIvcInfo = 5,
         HvConfigCheck = 6,
+        HvZonePause = 7,
+        HvZoneResume = 8,
+        HvZoneReboot = 9,
+        HvZoneStats = 10,
+        HvGetVersion = 11,
+        HvQueryFeatures = 12,
+        HvZoneCpuAdd = 13,
+        HvZoneCpuRemove = 14,
+        HvZoneMemAdd = 15,
+        HvZoneMemRemove = 16,
+        HvVirtioDevRegister = 17,
+        HvVirtioCfgDone = 18,
+        HvIvcCreate = 19,
+        HvIvcAttach = 21,
+        HvIvcDetach = 22,
+        HvIvcDestroy = 23,
+        HvGrantCreate = 24,
+        HvGrantRevoke = 25,
+        HvGrantMap = 26,
+        HvGrantUnmap = 27,
+        HvPciDevAttach = 28,
+        HvPciDevDetach = 29,
     }
 }
 pub const SGI_IPI_ID: u64 = 7;
@@ -63,7 +101,7 @@ impl<'a> HyperCall<'a> {
             Ok(code) => code,
             Err(_) => {
                 warn!("hypercall id={} unsupported!", code);
-                return Ok(0);
+                return hv_result_err!(ENOSYS);
             }
         };
         debug!(
@@ -72,17 +110,15 @@ impl<'a> HyperCall<'a> {
         );
         unsafe {
             match code {
-                HyperCallCode::HvVirtioInit => self.hv_virtio_init(arg0),
-                HyperCallCode::HvVirtioInjectIrq => self.hv_virtio_inject_irq(),
+                HyperCallCode::HvVirtioInit => self.hv_virtio_init(arg0, arg1),
+                HyperCallCode::HvVirtioInjectIrq => self.hv_virtio_inject_irq(arg0),
                 HyperCallCode::HvVirtioGetIrq => self.hv_virtio_get_irq(arg0 as *mut u32),
-                HyperCallCode::HvZoneStart => {
-                    self.hv_zone_start(&*(arg0 as *const HvZoneConfig), arg1)
-                }
+                HyperCallCode::HvZoneStart => self.hv_zone_start(arg0, arg1),
                 HyperCallCode::HvZoneShutdown => self.hv_zone_shutdown(arg0),
                 HyperCallCode::HvZoneList => self.hv_zone_list(&mut *(arg0 as *mut ZoneInfo), arg1),
                 HyperCallCode::HvClearInjectIrq => {
                     use crate::consts::IPI_EVENT_CLEAR_INJECT_IRQ;
-                    for i in 1..MAX_CPU_NUM {
+                    for i in 1..MAX_VCPU_NUM {
                         // if target cpu status is not running, we skip it
                         if !get_cpu_data(i).arch_cpu.power_on {
                             continue;
@@ -92,24 +128,72 @@ impl<'a> HyperCall<'a> {
                     HyperCallResult::Ok(0)
                 }
                 HyperCallCode::HvIvcInfo => self.hv_ivc_info(arg0),
-                HyperCallCode::HvConfigCheck => self.hv_zone_config_check(arg0 as *mut u64),
+                // arg1 == 0: old tools only ask for CONFIG_MAGIC_VERSION
+                HyperCallCode::HvConfigCheck if arg1 == 0 => {
+                    self.hv_zone_config_check(arg0 as *mut u64)
+                }
+                HyperCallCode::HvConfigCheck => self.hv_zone_config_dry_run(arg0, arg1),
+                HyperCallCode::HvZonePause => self.hv_zone_pause(arg0),
+                HyperCallCode::HvZoneResume => self.hv_zone_resume(arg0),
+                HyperCallCode::HvZoneReboot => self.hv_zone_reboot(arg0),
+                HyperCallCode::HvZoneStats => self.hv_zone_stats(arg0 as *mut HvZoneStats, arg1),
+                HyperCallCode::HvGetVersion => HyperCallResult::Ok(HV_ABI_VERSION as _),
+                HyperCallCode::HvQueryFeatures => {
+                    self.hv_query_features(arg0 as *mut HvFeatures, arg1)
+                }
+                HyperCallCode::HvZoneCpuAdd => self.hv_zone_cpu_add(arg0, arg1),
+                HyperCallCode::HvZoneCpuRemove => self.hv_zone_cpu_remove(arg0, arg1),
+                HyperCallCode::HvZoneMemAdd => {
+                    self.hv_zone_mem_add(arg0, arg1 as *const HvConfigMemoryRegion)
+                }
+                HyperCallCode::HvZoneMemRemove => {
+                    self.hv_zone_mem_remove(arg0, arg1 as *const HvConfigMemoryRegion)
+                }
+                HyperCallCode::HvVirtioDevRegister => self.hv_virtio_dev_register(arg0),
+                HyperCallCode::HvVirtioCfgDone => self.hv_virtio_cfg_done(arg0),
+                HyperCallCode::HvIvcCreate => self.hv_ivc_create(arg0 as *const HvIvcConfig),
+                HyperCallCode::HvIvcAttach => self.hv_ivc_attach(arg0, arg1 as *const HvIvcConfig),
+                HyperCallCode::HvIvcDetach => self.hv_ivc_detach(arg0, arg1),
+                HyperCallCode::HvIvcDestroy => self.hv_ivc_destroy(arg0),
+                HyperCallCode::HvGrantCreate => self.hv_grant_create(arg0),
+                HyperCallCode::HvGrantRevoke => self.hv_grant_revoke(arg0),
+                HyperCallCode::HvGrantMap => self.hv_grant_map(arg0, arg1),
+                HyperCallCode::HvGrantUnmap => self.hv_grant_unmap(arg0),
+                HyperCallCode::HvPciDevAttach => {
+                    self.hv_pci_dev_attach(arg0, arg1 as *const HvPciDevConfigWire)
+                }
+                HyperCallCode::HvPciDevDetach => {
+                    self.hv_pci_dev_detach(arg0, arg1 as *const HvPciDevConfigWire)
+                }
                 _ => {
                     warn!("hypercall id={} unsupported!", code as u64);
-                    Ok(0)
+                    hv_result_err!(ENOSYS)
                 }
             }
         }
     }
 
     // only root zone calls the function and set virtio shared region between el1 and el2.
-    fn hv_virtio_init(&mut self, shared_region_addr: u64) -> HyperCallResult {
+    // A non-zero zone_id sets up a region carrying only that zone's requests, a zero
+    // shared_region_addr then gives the zone back to the global region.
+    fn hv_virtio_init(&mut self, shared_region_addr: u64, zone_id: u64) -> HyperCallResult {
         info!(
-            "handle hvc init virtio, shared_region_addr = {:#x?}",
-            shared_region_addr
+            "handle hvc init virtio, shared_region_addr = {:#x?}, zone_id = {}",
+            shared_region_addr, zone_id
         );
         if !is_this_root_zone() {
             return hv_result_err!(EPERM, "Init virtio over non-root zones: unsupported!");
         }
+        if zone_id != 0 && shared_region_addr == 0 {
+            virtio_zone_bridge_remove(zone_id as _);
+            return HyperCallResult::Ok(0);
+        }
+        if zone_id != 0 {
+            virtio_zone_bridge_init(zone_id as _, shared_region_addr, |ipa| {
+                self.hv_get_real_pa(ipa) as usize
+            })?;
+            return HyperCallResult::Ok(0);
+        }
 
         let shared_region_addr_pa = self.hv_get_real_pa(shared_region_addr) as usize;
 
@@ -130,71 +214,73 @@ impl<'a> HyperCall<'a> {
         HyperCallResult::Ok(0)
     }
 
-    // Inject virtio device's irq to non root when a virtio device finishes one IO request. Only root zone calls.
-    fn hv_virtio_inject_irq(&mut self) -> HyperCallResult {
-        trace!("hv_virtio_inject_irq: hypercall for trigger target cpu to inject irq");
+    /// Let hvisor emulate the virtio-mmio registers of a device, `shared_page`
+    /// holds its `VirtioMmioShared` and stays shared with the backend.
+    fn hv_virtio_dev_register(&mut self, shared_page: u64) -> HyperCallResult {
+        info!(
+            "handle hvc virtio dev register, shared_page = {:#x?}",
+            shared_page
+        );
         if !is_this_root_zone() {
             return hv_result_err!(
                 EPERM,
-                "Virtio send irq operation over non-root zones: unsupported!"
+                "Register virtio device over non-root zones: unsupported!"
             );
         }
-        let mut res_agent = VIRTIO_BRIDGE.res_agent();
-        let mut map_irq = VIRTIO_IRQS.lock();
-        while !res_agent.is_empty() {
-            let (_res_front, irq_id, target_zone) = res_agent.peek_front();
-            let target_cpu = match find_zone(target_zone as _) {
-                Some(_zone) => get_target_cpu(irq_id as _, target_zone as _),
-                _ => {
-                    res_agent.advance_front();
-                    continue;
-                }
-            };
-
-            let irq_list = map_irq.entry(target_cpu).or_insert([0; MAX_DEVS + 1]);
-
-            self.wait_for_interrupt(irq_list);
-            if !irq_list[1..=irq_list[0] as usize].contains(&irq_id) {
-                let len = irq_list[0] as usize;
-                assert!(len + 1 < MAX_DEVS);
-                irq_list[len + 1] = irq_id;
-                irq_list[0] += 1;
-                send_event(
-                    target_cpu as _,
-                    SGI_IPI_ID as _,
-                    IPI_EVENT_VIRTIO_INJECT_IRQ,
-                );
-            }
+        let shared_page_pa = self.hv_get_real_pa(shared_page) as usize;
+        virtio_mmio_register(shared_page_pa)?;
+        HyperCallResult::Ok(0)
+    }
 
-            res_agent.advance_front();
+    /// Wake up `cpu_id`, sleeping on a request of a bridge with
+    /// `VIRTIO_BRIDGE_F_CFG_DONE_IPI` that the backend has just finished.
+    fn hv_virtio_cfg_done(&mut self, cpu_id: u64) -> HyperCallResult {
+        trace!("handle hvc virtio cfg done, cpu_id = {}", cpu_id);
+        if !is_this_root_zone() {
+            return hv_result_err!(EPERM, "Virtio cfg done over non-root zones: unsupported!");
+        }
+        if cpu_id as usize >= MAX_VCPU_NUM {
+            return hv_result_err!(EINVAL, format!("Virtio cfg done: invalid cpu {}", cpu_id));
         }
-        drop(res_agent);
+        send_event(cpu_id as _, SGI_IPI_ID as _, IPI_EVENT_VIRTIO_CFG_DONE);
         HyperCallResult::Ok(0)
     }
 
-    pub fn hv_zone_start(&mut self, config: &HvZoneConfig, config_size: u64) -> HyperCallResult {
-        let config_ipa = config as *const HvZoneConfig as u64;
-        let config_pa = self.hv_get_real_pa(config_ipa);
-        let config = unsafe { &*(config_pa as *const HvZoneConfig) };
-
-        debug!("hv_zone_start: config: {:#x?}", config);
+    // Inject virtio device's irq to non root when a virtio device finishes one IO request. Only root zone calls.
+    // A non-zero zone_id only drains the results of that zone's own region.
+    // Optional since results are also picked up by `virtio_res_poll`, see
+    // `HV_FEATURE_VIRTIO_POSTED_IRQ`; it only makes the injection immediate.
+    fn hv_virtio_inject_irq(&mut self, zone_id: u64) -> HyperCallResult {
+        trace!("hv_virtio_inject_irq: hypercall for trigger target cpu to inject irq");
         if !is_this_root_zone() {
             return hv_result_err!(
                 EPERM,
-                "Start zone operation over non-root zones: unsupported!"
+                "Virtio send irq operation over non-root zones: unsupported!"
             );
         }
-        if config_size != core::mem::size_of::<HvZoneConfig>() as _ {
+        // results left behind by backpressure are retried by `virtio_res_poll`
+        if zone_id == 0 && VIRTIO_BRIDGE.is_enabled() {
+            virtio_drain_res(&VIRTIO_BRIDGE);
+        }
+        for bridge in virtio_zone_bridges(zone_id as _) {
+            virtio_drain_res(&bridge);
+        }
+        HyperCallResult::Ok(0)
+    }
+
+    pub fn hv_zone_start(&mut self, config_ipa: u64, config_size: u64) -> HyperCallResult {
+        if !is_this_root_zone() {
             return hv_result_err!(
-                EINVAL,
-                format!(
-                    "hv_zone_start: config size should be {} bytes, but got {}",
-                    core::mem::size_of::<HvZoneConfig>(),
-                    config_size
-                )
+                EPERM,
+                "Start zone operation over non-root zones: unsupported!"
             );
         }
-        let zone = zone_create(config)?;
+        let config_pa = self.hv_get_real_pa(config_ipa);
+        let config = HvZoneConfig::from_raw(config_pa as *const u8, config_size as usize)?;
+
+        debug!("hv_zone_start: config: {:#x?}", config);
+        config.check()?;
+        let zone = zone_create(&config)?;
         let boot_cpu = zone.read().cpu_set.first_cpu().unwrap();
 
         let target_data = get_cpu_data(boot_cpu as _);
@@ -224,56 +310,461 @@ impl<'a> HyperCall<'a> {
         if zone_id == 0 {
             return hv_result_err!(EINVAL);
         }
-        // avoid virtio daemon send sgi to the shutdowning zone
-        let mut map_irq = VIRTIO_IRQS.lock();
+        zone_shutdown(zone_id as _)?;
+        info!("zone {} has been shutdown", zone_id);
+        HyperCallResult::Ok(0)
+    }
 
+    fn hv_zone_reboot(&mut self, zone_id: u64) -> HyperCallResult {
+        info!("handle hvc zone reboot, id={}", zone_id);
+        if !is_this_root_zone() {
+            return hv_result_err!(
+                EPERM,
+                "Reboot zone operation over non-root zones: unsupported!"
+            );
+        }
+        if zone_id == 0 {
+            return hv_result_err!(EINVAL, "Reboot zone: root zone cannot be rebooted");
+        }
         let zone = match find_zone(zone_id as _) {
             Some(zone) => zone,
             _ => {
-                return hv_result_err!(
-                    EINVAL,
-                    format!("Shutdown zone: zone {} not found!", zone_id)
+                return hv_result_err!(EINVAL, format!("Reboot zone: zone {} not found!", zone_id))
+            }
+        };
+        zone_reboot(&zone)?;
+        info!("zone {} has been rebooted", zone_id);
+        HyperCallResult::Ok(0)
+    }
+
+    /// Look up the non-root zone `zone_id` and check `cpu_id` for cpu hotplug.
+    fn hotplug_target(&self, zone_id: u64, cpu_id: u64) -> HvResult<Arc<RwLock<Zone>>> {
+        if !is_this_root_zone() {
+            return hv_result_err!(EPERM, "Cpu hotplug over non-root zones: unsupported!");
+        }
+        if cfg!(target_arch = "loongarch64") {
+            // every loongarch64 zone runs its guest as cpu 0
+            return hv_result_err!(ENOSYS, "Cpu hotplug: unsupported on loongarch64");
+        }
+        if cfg!(feature = "sched") {
+            // vcpus stay on their physical cpu, see crate::sched
+            return hv_result_err!(ENOSYS, "Cpu hotplug: unsupported with sched");
+        }
+        if zone_id == 0 {
+            return hv_result_err!(EINVAL, "Cpu hotplug: target must be a non-root zone");
+        }
+        if cpu_id as usize >= MAX_CPU_NUM {
+            return hv_result_err!(EINVAL, format!("Cpu hotplug: cpu {} not exist", cpu_id));
+        }
+        match find_zone(zone_id as _) {
+            Some(zone) => Ok(zone),
+            _ => hv_result_err!(EINVAL, format!("Cpu hotplug: zone {} not found!", zone_id)),
+        }
+    }
+
+    /// Give the root zone's offline `cpu_id` to zone `zone_id`.
+    fn hv_zone_cpu_add(&mut self, zone_id: u64, cpu_id: u64) -> HyperCallResult {
+        info!("handle hvc zone cpu add, id={}, cpu={}", zone_id, cpu_id);
+        let zone = self.hotplug_target(zone_id, cpu_id)?;
+        zone_cpu_move(&root_zone(), &zone, cpu_id as _)?;
+        HyperCallResult::Ok(0)
+    }
+
+    /// Give `cpu_id`, offlined by the guest of zone `zone_id`, back to the root zone.
+    fn hv_zone_cpu_remove(&mut self, zone_id: u64, cpu_id: u64) -> HyperCallResult {
+        info!("handle hvc zone cpu remove, id={}, cpu={}", zone_id, cpu_id);
+        let zone = self.hotplug_target(zone_id, cpu_id)?;
+        zone_cpu_move(&zone, &root_zone(), cpu_id as _)?;
+        HyperCallResult::Ok(0)
+    }
+
+    /// Look up the non-root zone `zone_id` and read the region for memory hotplug.
+    fn mem_hotplug_target(
+        &mut self,
+        zone_id: u64,
+        region: *const HvConfigMemoryRegion,
+    ) -> HvResult<(Arc<RwLock<Zone>>, HvConfigMemoryRegion)> {
+        if !is_this_root_zone() {
+            return hv_result_err!(EPERM, "Memory hotplug over non-root zones: unsupported!");
+        }
+        if zone_id == 0 {
+            return hv_result_err!(EINVAL, "Memory hotplug: target must be a non-root zone");
+        }
+        if region.is_null() {
+            return hv_result_err!(EINVAL, "Memory hotplug: region is null");
+        }
+        let region_pa = self.hv_get_real_pa(region as u64);
+        let region = unsafe { *(region_pa as *const HvConfigMemoryRegion) };
+        match find_zone(zone_id as _) {
+            Some(zone) => Ok((zone, region)),
+            _ => hv_result_err!(
+                EINVAL,
+                format!("Memory hotplug: zone {} not found!", zone_id)
+            ),
+        }
+    }
+
+    /// Map a RAM region into zone `zone_id`, also used to deflate a balloon.
+    fn hv_zone_mem_add(
+        &mut self,
+        zone_id: u64,
+        region: *const HvConfigMemoryRegion,
+    ) -> HyperCallResult {
+        let (zone, region) = self.mem_hotplug_target(zone_id, region)?;
+        info!(
+            "handle hvc zone mem add, id={}, region={:#x?}",
+            zone_id, region
+        );
+        zone_mem_add(&zone, &region)?;
+        HyperCallResult::Ok(0)
+    }
+
+    /// Unmap part of a RAM region of zone `zone_id`, also used to inflate a balloon.
+    fn hv_zone_mem_remove(
+        &mut self,
+        zone_id: u64,
+        region: *const HvConfigMemoryRegion,
+    ) -> HyperCallResult {
+        let (zone, region) = self.mem_hotplug_target(zone_id, region)?;
+        info!(
+            "handle hvc zone mem remove, id={}, region={:#x?}",
+            zone_id, region
+        );
+        zone_mem_remove(&zone, &region)?;
+        HyperCallResult::Ok(0)
+    }
+
+    /// Run the same checks as `hv_zone_start` on a config without creating the zone.
+    fn hv_zone_config_dry_run(&mut self, config_ipa: u64, config_size: u64) -> HyperCallResult {
+        if !is_this_root_zone() {
+            return hv_result_err!(EPERM, "Check zone config over non-root zones: unsupported!");
+        }
+        let config_pa = self.hv_get_real_pa(config_ipa);
+        let config = HvZoneConfig::from_raw(config_pa as *const u8, config_size as usize)?;
+        config.check()?;
+        HyperCallResult::Ok(0)
+    }
+
+    /// Copy at most `size` bytes of `HvFeatures` to the caller, so tools built
+    /// against an older (shorter) `HvFeatures` keep working. Returns the number
+    /// of bytes written.
+    fn hv_query_features(&mut self, features: *mut HvFeatures, size: u64) -> HyperCallResult {
+        if !is_this_root_zone() {
+            return hv_result_err!(EPERM, "Query features over non-root zones: unsupported!");
+        }
+        if features.is_null() {
+            return hv_result_err!(EINVAL, "hv_query_features: features is null");
+        }
+        let features_pa = self.hv_get_real_pa(features as u64);
+        let info = HvFeatures::new();
+        let len = core::cmp::min(size as usize, core::mem::size_of::<HvFeatures>());
+        unsafe {
+            core::ptr::copy_nonoverlapping(
+                &info as *const HvFeatures as *const u8,
+                features_pa as *mut u8,
+                len,
+            );
+        }
+        HyperCallResult::Ok(len)
+    }
+
+    fn hv_zone_stats(&mut self, stats: *mut HvZoneStats, size: u64) -> HyperCallResult {
+        if !is_this_root_zone() {
+            return hv_result_err!(
+                EPERM,
+                "Zone stats operation over non-root zones: unsupported!"
+            );
+        }
+        if !cfg!(feature = "stats") {
+            return hv_result_err!(ENOSYS, "hv_zone_stats: hvisor is built without STATS=on");
+        }
+        if stats.is_null() {
+            return hv_result_err!(EINVAL, "hv_zone_stats: stats is null");
+        }
+        // tools built before `cpuid_exits` get the part they know of
+        let size = size as usize;
+        if size != core::mem::size_of::<HvZoneStats>() && size != HV_ZONE_STATS_V1_SIZE {
+            return hv_result_err!(
+                EINVAL,
+                format!(
+                    "hv_zone_stats: stats size should be {} or {} bytes, but got {}",
+                    core::mem::size_of::<HvZoneStats>(),
+                    HV_ZONE_STATS_V1_SIZE,
+                    size
                 )
+            );
+        }
+        let zone_id_hva = Self::this_zone_hva(stats as u64, core::mem::size_of::<u32>())?;
+        let zone_id = unsafe { (zone_id_hva as *const u32).read_unaligned() };
+        let zone = match find_zone(zone_id as _) {
+            Some(zone) => zone,
+            _ => return hv_result_err!(EINVAL, format!("Zone stats: zone {} not found!", zone_id)),
+        };
+        // all integers, zero is a valid value
+        let mut info: HvZoneStats = unsafe { core::mem::zeroed() };
+        info.zone_id = zone_id;
+        zone.read().stats_fill(&mut info);
+        let bytes =
+            unsafe { core::slice::from_raw_parts(&info as *const HvZoneStats as *const u8, size) };
+        Self::copy_to_this_zone(stats as u64, bytes)?;
+        HyperCallResult::Ok(0)
+    }
+
+    fn hv_zone_pause(&mut self, zone_id: u64) -> HyperCallResult {
+        info!("handle hvc zone pause, id={}", zone_id);
+        if !is_this_root_zone() {
+            return hv_result_err!(
+                EPERM,
+                "Pause zone operation over non-root zones: unsupported!"
+            );
+        }
+        if zone_id == 0 {
+            return hv_result_err!(EINVAL, "Pause zone: root zone cannot be paused");
+        }
+        let zone = match find_zone(zone_id as _) {
+            Some(zone) => zone,
+            _ => return hv_result_err!(EINVAL, format!("Pause zone: zone {} not found!", zone_id)),
+        };
+        let cpu_set = {
+            let mut zone_w = zone.write();
+            if zone_w.is_paused {
+                return hv_result_err!(
+                    EBUSY,
+                    format!("Pause zone: zone {} already paused", zone_id)
+                );
             }
+            zone_w.is_paused = true;
+            zone_w.cpu_set
         };
-        let zone_w = zone.write();
-
-        zone_w.cpu_set.iter().for_each(|cpu_id| {
-            let _lock = get_cpu_data(cpu_id).ctrl_lock.lock();
-            get_cpu_data(cpu_id).cpu_on_entry = INVALID_ADDRESS;
-            send_event(cpu_id, SGI_IPI_ID as _, IPI_EVENT_SHUTDOWN);
-            // set the virtio irq list's len to 0
-            if let Some(irq_list) = map_irq.get_mut(&cpu_id) {
-                irq_list[0] = 0;
+
+        if let Err(e) = Zone::pause(&zone) {
+            // Let the cpus that did park continue instead of leaving the zone half paused.
+            error!("zone {} pause failed: {:?}", zone_id, e);
+            zone.write().is_paused = false;
+            zone.read().resume();
+            return Err(e);
+        }
+        info!(
+            "zone {} has been paused, cpu_set: {:#b}",
+            zone_id, cpu_set.bitmap
+        );
+        HyperCallResult::Ok(0)
+    }
+
+    fn hv_zone_resume(&mut self, zone_id: u64) -> HyperCallResult {
+        info!("handle hvc zone resume, id={}", zone_id);
+        if !is_this_root_zone() {
+            return hv_result_err!(
+                EPERM,
+                "Resume zone operation over non-root zones: unsupported!"
+            );
+        }
+        let zone = match find_zone(zone_id as _) {
+            Some(zone) => zone,
+            _ => {
+                return hv_result_err!(EINVAL, format!("Resume zone: zone {} not found!", zone_id))
             }
-        });
-
-        let mut count: usize = 0;
-
-        // wait all zone's cpus shutdown
-        while zone_w.cpu_set.iter().any(|cpu_id| {
-            let _lock = get_cpu_data(cpu_id).ctrl_lock.lock();
-            let power_on = get_cpu_data(cpu_id).arch_cpu.power_on;
-            count += 1;
-            if count > MAX_WAIT_TIMES {
-                if power_on {
-                    error!("cpu {} cannot be shut down", cpu_id);
-                    return false;
-                }
+        };
+        let mut zone_w = zone.write();
+        if !zone_w.is_paused {
+            return hv_result_err!(
+                EINVAL,
+                format!("Resume zone: zone {} is not paused", zone_id)
+            );
+        }
+        zone_w.resume();
+        zone_w.is_paused = false;
+        info!("zone {} has been resumed", zone_id);
+        HyperCallResult::Ok(0)
+    }
+
+    /// Host address of `size` bytes at `ipa` of the calling zone, which must
+    /// not cross a page.
+    fn this_zone_hva(ipa: u64, size: usize) -> HvResult<usize> {
+        if ipa as usize % PAGE_SIZE + size > PAGE_SIZE {
+            return hv_result_err!(EINVAL, format!("{:#x} crosses a page", ipa));
+        }
+        let query = unsafe { this_zone().read().gpm.page_table_query(ipa as _) };
+        let hpa = match query {
+            Ok((hpa, _, _)) => hpa,
+            Err(_) => return hv_result_err!(EFAULT, format!("{:#x} is not mapped", ipa)),
+        };
+        #[cfg(target_arch = "loongarch64")]
+        let hpa = hpa | crate::arch::mm::LOONGARCH64_CACHED_DMW_PREFIX as usize;
+        // hva == hpa
+        Ok(hpa)
+    }
+
+    /// Copy `data` to `ipa` of the calling zone a page at a time, since its
+    /// pages need not be contiguous in host memory.
+    fn copy_to_this_zone(ipa: u64, data: &[u8]) -> HvResult {
+        let mut done = 0;
+        while done < data.len() {
+            let ipa = ipa + done as u64;
+            let len = (PAGE_SIZE - ipa as usize % PAGE_SIZE).min(data.len() - done);
+            let hva = Self::this_zone_hva(ipa, len)?;
+            unsafe {
+                core::ptr::copy_nonoverlapping(data[done..].as_ptr(), hva as *mut u8, len);
             }
-            power_on
-        }) {}
-
-        zone_w.cpu_set.iter().for_each(|cpu_id| {
-            let _lock = get_cpu_data(cpu_id).ctrl_lock.lock();
-            get_cpu_data(cpu_id).zone = None;
-        });
-        zone_w.arch_irqchip_reset();
-
-        drop(zone_w);
-        drop(zone);
-        remove_zone(zone_id as _);
-        info!("zone {} has been shutdown", zone_id);
+            done += len;
+        }
+        Ok(())
+    }
+
+    /// Any zone may ask for its own ivc regions, `ivc_info_ipa` is in its
+    /// guest physical address space.
+    fn hv_ivc_info(&mut self, ivc_info_ipa: u64) -> HyperCallResult {
+        let zone_id = this_zone_id();
+        let ivc_info = match ivc_info(zone_id) {
+            Some(ivc_info) => ivc_info,
+            None => return hv_result_err!(ENODEV, format!("zone {} has no ivc config", zone_id)),
+        };
+        let hva = Self::this_zone_hva(ivc_info_ipa, core::mem::size_of::<IvcInfo>())?;
+        unsafe { core::ptr::write_unaligned(hva as *mut IvcInfo, ivc_info) };
+        HyperCallResult::Ok(0)
+    }
+
+    /// Grant part of the caller's RAM to another zone, `region` is in the
+    /// caller's guest physical address space. Returns the grant ref.
+    fn hv_grant_create(&mut self, region_ipa: u64) -> HyperCallResult {
+        let hva = Self::this_zone_hva(region_ipa, core::mem::size_of::<HvGrantRegion>())?;
+        let region = unsafe { core::ptr::read_unaligned(hva as *const HvGrantRegion) };
+        let gref = grant_create(&this_zone(), &region)?;
+        HyperCallResult::Ok(gref as _)
+    }
+
+    fn hv_grant_revoke(&mut self, gref: u64) -> HyperCallResult {
+        grant_revoke(&this_zone(), gref as _)?;
+        HyperCallResult::Ok(0)
+    }
+
+    /// Map a grant made to the caller at `ipa`.
+    fn hv_grant_map(&mut self, gref: u64, ipa: u64) -> HyperCallResult {
+        grant_map(&this_zone(), gref as _, ipa as _)?;
+        HyperCallResult::Ok(0)
+    }
+
+    fn hv_grant_unmap(&mut self, gref: u64) -> HyperCallResult {
+        grant_unmap(&this_zone(), gref as _)?;
+        HyperCallResult::Ok(0)
+    }
+
+    fn pci_dev_args(
+        &mut self,
+        zone_id: u64,
+        config: *const HvPciDevConfigWire,
+    ) -> HvResult<(Arc<RwLock<Zone>>, HvPciDevConfig)> {
+        if !is_this_root_zone() {
+            return hv_result_err!(EPERM, "Move pci devices over non-root zones: unsupported!");
+        }
+        if !cfg!(feature = "pci") {
+            return hv_result_err!(
+                ENOSYS,
+                "pci hotplug: hvisor is built without the pci feature"
+            );
+        }
+        if config.is_null() {
+            return hv_result_err!(EINVAL, "pci hotplug: config is null");
+        }
+        let zone = match find_zone(zone_id as _) {
+            Some(zone) => zone,
+            None => return hv_result_err!(EINVAL, format!("zone {} not found", zone_id)),
+        };
+        let config_pa = self.hv_get_real_pa(config as u64);
+        let config = unsafe { core::ptr::read_unaligned(config_pa as *const HvPciDevConfigWire) };
+        Ok((zone, HvPciDevConfig::from_wire(config)?))
+    }
+
+    /// Give a free pci function to a running zone.
+    fn hv_pci_dev_attach(
+        &mut self,
+        zone_id: u64,
+        config: *const HvPciDevConfigWire,
+    ) -> HyperCallResult {
+        let (zone, config) = self.pci_dev_args(zone_id, config)?;
+        info!("handle hvc pci dev attach, zone={}", zone_id);
+        pci_dev_attach(&zone, &config)?;
+        HyperCallResult::Ok(0)
+    }
+
+    /// Reset a pci function of a running zone and make it free again.
+    fn hv_pci_dev_detach(
+        &mut self,
+        zone_id: u64,
+        config: *const HvPciDevConfigWire,
+    ) -> HyperCallResult {
+        let (zone, config) = self.pci_dev_args(zone_id, config)?;
+        info!("handle hvc pci dev detach, zone={}", zone_id);
+        pci_dev_detach(&zone, &config)?;
+        HyperCallResult::Ok(0)
+    }
+
+    fn ivc_config_arg(&mut self, config: *const HvIvcConfig) -> HvResult<HvIvcConfig> {
+        if !is_this_root_zone() {
+            return hv_result_err!(
+                EPERM,
+                "Manage ivc channels over non-root zones: unsupported!"
+            );
+        }
+        if config.is_null() {
+            return hv_result_err!(EINVAL, "ivc: config is null");
+        }
+        let config_pa = self.hv_get_real_pa(config as u64);
+        Ok(unsafe { core::ptr::read_unaligned(config_pa as *const HvIvcConfig) })
+    }
+
+    fn ivc_zone_arg(&mut self, zone_id: u64) -> HvResult<Arc<RwLock<Zone>>> {
+        if !is_this_root_zone() {
+            return hv_result_err!(
+                EPERM,
+                "Manage ivc channels over non-root zones: unsupported!"
+            );
+        }
+        match find_zone(zone_id as _) {
+            Some(zone) => Ok(zone),
+            _ => hv_result_err!(EINVAL, format!("ivc: zone {} not found!", zone_id)),
+        }
+    }
+
+    /// Create channel `ivc_id` from the channel wide fields of `config`,
+    /// without any peer.
+    fn hv_ivc_create(&mut self, config: *const HvIvcConfig) -> HyperCallResult {
+        let config = self.ivc_config_arg(config)?;
+        info!("handle hvc ivc create, config={:#x?}", config);
+        ivc_create(&config)?;
+        HyperCallResult::Ok(0)
+    }
+
+    /// Map an existing channel into zone `zone_id` as peer `config.peer_id`,
+    /// at the ipas of `config`.
+    fn hv_ivc_attach(&mut self, zone_id: u64, config: *const HvIvcConfig) -> HyperCallResult {
+        let config = self.ivc_config_arg(config)?;
+        let zone = self.ivc_zone_arg(zone_id)?;
+        info!(
+            "handle hvc ivc attach, zone={}, config={:#x?}",
+            zone_id, config
+        );
+        ivc_attach(&zone, &config)?;
+        HyperCallResult::Ok(0)
+    }
+
+    fn hv_ivc_detach(&mut self, zone_id: u64, ivc_id: u64) -> HyperCallResult {
+        let zone = self.ivc_zone_arg(zone_id)?;
+        info!("handle hvc ivc detach, zone={}, ivc={}", zone_id, ivc_id);
+        ivc_detach(&zone, ivc_id as _)?;
+        HyperCallResult::Ok(0)
+    }
+
+    /// Detach every peer of channel `ivc_id` and free its shared memory.
+    fn hv_ivc_destroy(&mut self, ivc_id: u64) -> HyperCallResult {
+        if !is_this_root_zone() {
+            return hv_result_err!(
+                EPERM,
+                "Manage ivc channels over non-root zones: unsupported!"
+            );
+        }
+        info!("handle hvc ivc destroy, ivc={}", ivc_id);
+        ivc_destroy(ivc_id as _)?;
         HyperCallResult::Ok(0)
     }
 
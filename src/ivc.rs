@@ -0,0 +1,513 @@
+// Copyright (c) 2025 Syswonder
+// hvisor is licensed under Mulan PSL v2.
+// You can use this software according to the terms and conditions of the Mulan PSL v2.
+// You may obtain a copy of Mulan PSL v2 at:
+//     http://license.coscl.org.cn/MulanPSL2
+// THIS SOFTWARE IS PROVIDED ON AN "AS IS" BASIS, WITHOUT WARRANTIES OF ANY KIND, EITHER
+// EXPRESS OR IMPLIED, INCLUDING BUT NOT LIMITED TO NON-INFRINGEMENT, MERCHANTABILITY OR
+// FIT FOR A PARTICULAR PURPOSE.
+// See the Mulan PSL v2 for more details.
+//
+// Syswonder Website:
+//      https://www.syswonder.org
+//
+// Authors:
+//
+
+//! Inter-VM communication: zones that name the same `ivc_id` share one
+//! channel, with a read-write section and one output section per peer, plus
+//! a control table page emulated here. Writing a peer id to `CT_IPI_INVOKE`
+//! injects that peer's doorbell irq into its zone, if the zone is running;
+//! `CT_PEERS_ONLINE` lets peers see which of them are.
+//!
+//! Channels in zone configs exist while they have peers. The root zone can
+//! also create channels with `HvIvcCreate` and attach running zones to them,
+//! those stay until `HvIvcDestroy`.
+
+use alloc::collections::btree_map::BTreeMap;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use spin::{Mutex, RwLock};
+
+use crate::device::virtio_trampoline::inject_zone_cpu_irq;
+use crate::memory::hotplug::zone_unmap;
+use crate::{
+    config::{HvIvcConfig, CONFIG_MAX_IVC_CONFIGS},
+    consts::PAGE_SIZE,
+    error::HvResult,
+    memory::{Frame, GuestPhysAddr, MMIOAccess, MemFlags, MemoryRegion},
+    zone::{find_zone, this_zone_id, Zone},
+};
+
+/// Peers of a channel, bounded by the width of `CT_PEERS_ONLINE`.
+const IVC_MAX_PEERS: u32 = 32;
+
+// ivc_id -> ivc_record
+static IVC_RECORDS: Mutex<BTreeMap<u32, IvcRecord>> = Mutex::new(BTreeMap::new());
+
+#[repr(C, packed)]
+#[derive(Clone, Copy, Debug)]
+/// The ivc info that one zone should first accquire
+pub struct IvcInfo {
+    /// The number that one zone participates in ivc region
+    pub len: u64,
+    /// The ivc control table ipa of each ivc region
+    ivc_ct_ipas: [u64; CONFIG_MAX_IVC_CONFIGS],
+    /// The ivc shared memory ipa of each ivc region
+    ivc_shmem_ipas: [u64; CONFIG_MAX_IVC_CONFIGS],
+    /// The ivc_id of each ivc region
+    ivc_ids: [u32; CONFIG_MAX_IVC_CONFIGS],
+    /// The irq number of each ivc region
+    ivc_irqs: [u32; CONFIG_MAX_IVC_CONFIGS],
+}
+
+impl From<&[HvIvcConfig]> for IvcInfo {
+    fn from(configs: &[HvIvcConfig]) -> Self {
+        let mut ivc_ids = [0; CONFIG_MAX_IVC_CONFIGS];
+        let mut ivc_ct_ipas = [0; CONFIG_MAX_IVC_CONFIGS];
+        let mut ivc_shmem_ipas = [0; CONFIG_MAX_IVC_CONFIGS];
+        let mut ivc_irqs = [0; CONFIG_MAX_IVC_CONFIGS];
+        // TLV configs may carry more ivc regions than the fixed-size IvcInfo can describe.
+        if configs.len() > CONFIG_MAX_IVC_CONFIGS {
+            warn!(
+                "ivc info only reports the first {} of {} ivc regions",
+                CONFIG_MAX_IVC_CONFIGS,
+                configs.len()
+            );
+        }
+        let len = core::cmp::min(configs.len(), CONFIG_MAX_IVC_CONFIGS);
+        for i in 0..len {
+            let config = &configs[i];
+            ivc_ids[i] = config.ivc_id;
+            ivc_ct_ipas[i] = config.control_table_ipa;
+            ivc_shmem_ipas[i] = config.shared_mem_ipa;
+            ivc_irqs[i] = config.interrupt_num;
+        }
+        Self {
+            len: len as u64,
+            ivc_ids,
+            ivc_shmem_ipas,
+            ivc_ct_ipas,
+            ivc_irqs,
+        }
+    }
+}
+
+struct IvcRecord {
+    max_peers: u32,
+    rw_sec_size: u32,
+    out_sec_size: u32,
+    // peer id -> PeerInfo
+    peer_infos: BTreeMap<u32, PeerInfo>,
+    shared_mem: Frame,
+    /// Created by `HvIvcCreate`, kept without peers until `HvIvcDestroy`.
+    dynamic: bool,
+}
+
+struct PeerInfo {
+    zone_id: u32,
+    config: HvIvcConfig,
+    ipi_status: u32,
+}
+
+impl IvcRecord {
+    fn new(config: &HvIvcConfig, dynamic: bool) -> HvResult<Self> {
+        if config.max_peers == 0 || config.max_peers > IVC_MAX_PEERS {
+            return hv_result_err!(
+                EINVAL,
+                format!(
+                    "ivc {}: {} peers, at most {}",
+                    config.ivc_id, config.max_peers, IVC_MAX_PEERS
+                )
+            );
+        }
+        if config.rw_sec_size as usize % PAGE_SIZE != 0
+            || config.out_sec_size as usize % PAGE_SIZE != 0
+        {
+            return hv_result_err!(
+                EINVAL,
+                format!("ivc {}: section size must be page aligned", config.ivc_id)
+            );
+        }
+        let size =
+            config.rw_sec_size as usize + config.out_sec_size as usize * config.max_peers as usize;
+        Ok(Self {
+            max_peers: config.max_peers,
+            rw_sec_size: config.rw_sec_size,
+            out_sec_size: config.out_sec_size,
+            peer_infos: BTreeMap::new(),
+            shared_mem: Frame::new_contiguous(size / PAGE_SIZE, 0)?,
+            dynamic,
+        })
+    }
+
+    fn peer_of(&self, zone_id: usize) -> Option<(&u32, &PeerInfo)> {
+        self.peer_infos
+            .iter()
+            .find(|(_, info)| info.zone_id as usize == zone_id)
+    }
+}
+
+/// Add `zone_id` as a peer of `ivc_config.ivc_id`, creating the channel if
+/// `create` is set. Returns the start of the shared memory.
+fn insert_ivc_record(ivc_config: &HvIvcConfig, zone_id: u32, create: bool) -> HvResult<usize> {
+    let mut recs = IVC_RECORDS.lock();
+    let ivc_id = ivc_config.ivc_id;
+    if !recs.contains_key(&ivc_id) {
+        if !create {
+            return hv_result_err!(ENOENT, format!("ivc {} does not exist", ivc_id));
+        }
+        recs.insert(ivc_id, IvcRecord::new(ivc_config, false)?);
+    }
+    let rec = recs.get_mut(&ivc_id).unwrap();
+    if rec.max_peers != ivc_config.max_peers
+        || rec.rw_sec_size != ivc_config.rw_sec_size
+        || rec.out_sec_size != ivc_config.out_sec_size
+    {
+        return hv_result_err!(EINVAL, format!("ivc {}: config conflicts", ivc_id));
+    }
+    if ivc_config.peer_id >= rec.max_peers {
+        return hv_result_err!(
+            EINVAL,
+            format!(
+                "ivc {}: peer id {} exceeds max peers {}",
+                ivc_id, ivc_config.peer_id, rec.max_peers
+            )
+        );
+    }
+    if rec.peer_infos.contains_key(&ivc_config.peer_id) || rec.peer_of(zone_id as _).is_some() {
+        return hv_result_err!(
+            EBUSY,
+            format!(
+                "ivc {}: peer {} or zone {} already attached",
+                ivc_id, ivc_config.peer_id, zone_id
+            )
+        );
+    }
+    rec.peer_infos.insert(
+        ivc_config.peer_id,
+        PeerInfo {
+            zone_id,
+            config: *ivc_config,
+            ipi_status: IVC_IPI_OK,
+        },
+    );
+    Ok(rec.shared_mem.start_paddr())
+}
+
+/// Remove `zone_id` from channel `ivc_id`. A channel from a zone config is
+/// freed with its last peer; the caller drops it once no zone maps it anymore.
+fn remove_ivc_peer(ivc_id: u32, zone_id: usize) -> Option<IvcRecord> {
+    let mut recs = IVC_RECORDS.lock();
+    let rec = recs.get_mut(&ivc_id)?;
+    rec.peer_infos
+        .retain(|_, info| info.zone_id as usize != zone_id);
+    if rec.peer_infos.is_empty() && !rec.dynamic {
+        info!("ivc {}: free shared mem", ivc_id);
+        return recs.remove(&ivc_id);
+    }
+    None
+}
+
+impl Zone {
+    pub fn ivc_init(&mut self, ivc_configs: &[HvIvcConfig]) -> HvResult {
+        for ivc_config in ivc_configs {
+            if let Err(e) = self.ivc_attach(ivc_config, true) {
+                ivc_remove(self.id);
+                return Err(e);
+            }
+        }
+        Ok(())
+    }
+
+    fn ivc_attach(&mut self, ivc_config: &HvIvcConfig, create: bool) -> HvResult {
+        let start_paddr = insert_ivc_record(ivc_config, self.id as _, create)?;
+        info!(
+            "ivc init: zone {}'s shared mem begins at {:x}, ipa is {:x}",
+            self.id, start_paddr, ivc_config.shared_mem_ipa
+        );
+        if let Err(e) = self.ivc_map(ivc_config, start_paddr) {
+            drop(remove_ivc_peer(ivc_config.ivc_id, self.id));
+            return Err(e);
+        }
+        Ok(())
+    }
+
+    fn ivc_map(&mut self, ivc_config: &HvIvcConfig, start_paddr: usize) -> HvResult {
+        let sections = ivc_sections(ivc_config);
+        for (index, &(offset, size, flags)) in sections.iter().enumerate() {
+            if let Err(e) = self.gpm.insert(MemoryRegion::new_with_offset_mapper(
+                ivc_config.shared_mem_ipa as usize + offset,
+                start_paddr + offset,
+                size,
+                flags,
+            )) {
+                // the zone has not been told about the channel yet
+                for &(offset, size, _) in &sections[..index] {
+                    let _ = self
+                        .gpm
+                        .delete(ivc_config.shared_mem_ipa as usize + offset, size);
+                }
+                return Err(e);
+            }
+        }
+        self.mmio_region_register(
+            ivc_config.control_table_ipa as _,
+            PAGE_SIZE,
+            mmio_ivc_handler,
+            ivc_config.control_table_ipa as _,
+        );
+        Ok(())
+    }
+
+    fn ivc_unmap(&mut self, ivc_config: &HvIvcConfig) -> HvResult {
+        self.mmio_region_remove(ivc_config.control_table_ipa as _);
+        let mut result = Ok(());
+        for (offset, size, _) in ivc_sections(ivc_config) {
+            let deleted = self
+                .gpm
+                .delete(ivc_config.shared_mem_ipa as usize + offset, size);
+            if result.is_ok() {
+                result = deleted;
+            }
+        }
+        result
+    }
+}
+
+/// Offset, size and access of each section of the shared memory as a peer
+/// maps it: the read-write section, then the output section of every peer,
+/// writable only by its owner.
+fn ivc_sections(ivc_config: &HvIvcConfig) -> Vec<(usize, usize, MemFlags)> {
+    let rw_sec_size = ivc_config.rw_sec_size as usize;
+    let out_sec_size = ivc_config.out_sec_size as usize;
+    let mut sections = vec![(0, rw_sec_size, MemFlags::READ | MemFlags::WRITE)];
+    sections.extend((0..ivc_config.max_peers as usize).map(|i| {
+        let flags = if i == ivc_config.peer_id as usize {
+            MemFlags::READ | MemFlags::WRITE
+        } else {
+            MemFlags::READ
+        };
+        (rw_sec_size + i * out_sec_size, out_sec_size, flags)
+    }));
+    sections
+}
+
+/// What `HvIvcInfo` reports to `zone_id`, `None` if it has no channel.
+pub fn ivc_info(zone_id: usize) -> Option<IvcInfo> {
+    let configs: Vec<HvIvcConfig> = IVC_RECORDS
+        .lock()
+        .values()
+        .filter_map(|rec| rec.peer_of(zone_id))
+        .map(|(_, info)| info.config)
+        .collect();
+    if configs.is_empty() {
+        None
+    } else {
+        Some(IvcInfo::from(&configs[..]))
+    }
+}
+
+/// Create an empty channel, only the channel wide fields of `config` are used.
+pub fn ivc_create(config: &HvIvcConfig) -> HvResult {
+    let mut recs = IVC_RECORDS.lock();
+    if recs.contains_key(&config.ivc_id) {
+        return hv_result_err!(EEXIST, format!("ivc {} already exists", config.ivc_id));
+    }
+    recs.insert(config.ivc_id, IvcRecord::new(config, true)?);
+    info!("ivc {} created", config.ivc_id);
+    Ok(())
+}
+
+/// Map an existing channel into a running zone. Only adds translations, so
+/// the zone keeps running.
+pub fn ivc_attach(zone: &Arc<RwLock<Zone>>, config: &HvIvcConfig) -> HvResult {
+    zone.write().ivc_attach(config, false)
+}
+
+/// Unmap channel `ivc_id` from `zone`.
+pub fn ivc_detach(zone: &Arc<RwLock<Zone>>, ivc_id: u32) -> HvResult {
+    let zone_id = zone.read().id;
+    let config = IVC_RECORDS
+        .lock()
+        .get(&ivc_id)
+        .and_then(|rec| rec.peer_of(zone_id))
+        .map(|(_, info)| info.config);
+    let config = match config {
+        Some(config) => config,
+        None => {
+            return hv_result_err!(
+                ENOENT,
+                format!("zone {} is not a peer of ivc {}", zone_id, ivc_id)
+            )
+        }
+    };
+    zone_unmap(zone, |zone| zone.ivc_unmap(&config))?;
+    drop(remove_ivc_peer(ivc_id, zone_id));
+    info!("ivc {}: zone {} detached", ivc_id, zone_id);
+    Ok(())
+}
+
+/// Unmap channel `ivc_id` from all its peers and free it.
+pub fn ivc_destroy(ivc_id: u32) -> HvResult {
+    // Out of the list first, so that nobody attaches meanwhile.
+    let mut rec = match IVC_RECORDS.lock().remove(&ivc_id) {
+        Some(rec) => rec,
+        None => return hv_result_err!(ENOENT, format!("ivc {} does not exist", ivc_id)),
+    };
+    let peers: Vec<(u32, PeerInfo)> = core::mem::take(&mut rec.peer_infos).into_iter().collect();
+    let mut peers = peers.into_iter();
+    while let Some((peer_id, info)) = peers.next() {
+        let zone = match find_zone(info.zone_id as _) {
+            Some(zone) => zone,
+            None => continue,
+        };
+        if let Err(e) = zone_unmap(&zone, |zone| zone.ivc_unmap(&info.config)) {
+            // the remaining peers still map the shared memory
+            rec.peer_infos.insert(peer_id, info);
+            rec.peer_infos.extend(peers);
+            IVC_RECORDS.lock().insert(ivc_id, rec);
+            return Err(e);
+        }
+    }
+    info!("ivc {} destroyed", ivc_id);
+    Ok(())
+}
+
+/// Drop the zone from every channel, and free the shared memory of the
+/// config channels it was the last peer of.
+pub fn ivc_remove(zone_id: usize) {
+    let ivc_ids: Vec<u32> = IVC_RECORDS
+        .lock()
+        .iter()
+        .filter(|(_, rec)| rec.peer_of(zone_id).is_some())
+        .map(|(&ivc_id, _)| ivc_id)
+        .collect();
+    for ivc_id in ivc_ids {
+        drop(remove_ivc_peer(ivc_id, zone_id));
+    }
+}
+
+const CT_IVC_ID: GuestPhysAddr = 0x00;
+const CT_MAX_PEERS: GuestPhysAddr = 0x04;
+const CT_RW_SEC_SIZE: GuestPhysAddr = 0x08;
+const CT_OUT_SEC_SIZE: GuestPhysAddr = 0x0C;
+const CT_PEER_ID: GuestPhysAddr = 0x10;
+/// Write `peer id | (vcpu + 1) << 16` to ring a peer's doorbell, a vcpu of 0
+/// sends it to the cpu the irq is routed to.
+const CT_IPI_INVOKE: GuestPhysAddr = 0x14;
+/// `IVC_IPI_*` result of this peer's last `CT_IPI_INVOKE`.
+const CT_IPI_STATUS: GuestPhysAddr = 0x18;
+/// Bit n is set while peer n's zone is running.
+const CT_PEERS_ONLINE: GuestPhysAddr = 0x1C;
+
+pub const IVC_IPI_OK: u32 = 0;
+pub const IVC_IPI_PEER_OFFLINE: u32 = 1;
+pub const IVC_IPI_INVALID: u32 = 2;
+
+fn zone_is_running(zone_id: usize) -> bool {
+    find_zone(zone_id).map_or(false, |zone| {
+        let zone = zone.read();
+        !zone.is_err && !zone.is_paused
+    })
+}
+
+fn ring_doorbell(ivc_id: u32, value: usize) -> u32 {
+    let peer_id = (value & 0xffff) as u32;
+    let vcpu = (value >> 16 & 0xffff).checked_sub(1);
+    let peer = IVC_RECORDS
+        .lock()
+        .get(&ivc_id)
+        .and_then(|rec| rec.peer_infos.get(&peer_id))
+        .map(|info| (info.zone_id as usize, info.config.interrupt_num as usize));
+    // zone_shutdown takes VIRTIO_IRQS and the zone lock before IVC_RECORDS
+    let (peer_zone, irq_num) = match peer {
+        Some(peer) => peer,
+        None => return IVC_IPI_INVALID,
+    };
+    if !zone_is_running(peer_zone) {
+        return IVC_IPI_PEER_OFFLINE;
+    }
+    if inject_zone_cpu_irq(peer_zone, vcpu, irq_num) {
+        IVC_IPI_OK
+    } else {
+        IVC_IPI_INVALID
+    }
+}
+
+pub fn mmio_ivc_handler(mmio: &mut MMIOAccess, base: usize) -> HvResult {
+    let zone_id = this_zone_id();
+    let ivc_id = IVC_RECORDS
+        .lock()
+        .iter()
+        .find(|(_, rec)| {
+            rec.peer_of(zone_id).map_or(false, |(_, info)| {
+                info.config.control_table_ipa == base as u64
+            })
+        })
+        .map(|(&ivc_id, _)| ivc_id);
+    let ivc_id = match ivc_id {
+        Some(ivc_id) => ivc_id,
+        None => {
+            return hv_result_err!(
+                ENODEV,
+                format!("zone {} has no ivc control table at {:#x}", zone_id, base)
+            )
+        }
+    };
+
+    if mmio.address == CT_IPI_INVOKE && mmio.is_write {
+        let status = ring_doorbell(ivc_id, mmio.value);
+        if status != IVC_IPI_OK {
+            warn!(
+                "ivc {}: zone {} failed to ring {:#x}: {}",
+                ivc_id, zone_id, mmio.value, status
+            );
+        }
+        if let Some(rec) = IVC_RECORDS.lock().get_mut(&ivc_id) {
+            rec.peer_infos
+                .values_mut()
+                .filter(|info| info.zone_id as usize == zone_id)
+                .for_each(|info| info.ipi_status = status);
+        }
+        return Ok(());
+    }
+
+    let recs = IVC_RECORDS.lock();
+    let rec = match recs.get(&ivc_id) {
+        Some(rec) => rec,
+        None => return hv_result_err!(ENODEV, format!("ivc {} does not exist", ivc_id)),
+    };
+    let this_peer = rec.peer_of(zone_id);
+    let (peer_id, this_peer) = match this_peer {
+        Some(peer) => peer,
+        None => {
+            return hv_result_err!(
+                ENODEV,
+                format!("zone {} is not a peer of ivc {}", zone_id, ivc_id)
+            )
+        }
+    };
+    mmio.value = match mmio.address {
+        CT_IVC_ID => ivc_id as usize,
+        CT_MAX_PEERS => rec.max_peers as usize,
+        CT_RW_SEC_SIZE => rec.rw_sec_size as usize,
+        CT_OUT_SEC_SIZE => rec.out_sec_size as usize,
+        CT_PEER_ID => *peer_id as usize,
+        CT_IPI_STATUS => this_peer.ipi_status as usize,
+        CT_PEERS_ONLINE => {
+            let peers: Vec<_> = rec
+                .peer_infos
+                .iter()
+                .map(|(&peer_id, info)| (peer_id, info.zone_id as usize))
+                .collect();
+            drop(recs);
+            peers
+                .into_iter()
+                .filter(|&(_, peer_zone)| zone_is_running(peer_zone))
+                .fold(0, |online, (peer_id, _)| online | 1 << peer_id)
+        }
+        _ => return hv_result_err!(EFAULT),
+    };
+    Ok(())
+}
@@ -56,9 +56,13 @@ mod cpu_data;
 mod device;
 mod event;
 mod hypercall;
+mod ivc;
 mod memory;
 mod panic;
 mod platform;
+#[cfg(feature = "sched")]
+mod sched;
+mod stats;
 mod zone;
 
 mod pci;
@@ -119,11 +123,12 @@ fn primary_init_early() {
     // let revision = system_config.revision;
     info!("Hypervisor initialization in progress...");
     info!(
-        "build_mode: {}, log_level: {}, arch: {}, stats: {}",
+        "build_mode: {}, log_level: {}, arch: {}, stats: {}, sched: {}",
         option_env!("MODE").unwrap_or(""),
         option_env!("LOG").unwrap_or(""),
         option_env!("ARCH").unwrap_or(""),
         option_env!("STATS").unwrap_or("off"),
+        option_env!("SCHED").unwrap_or("off"),
     );
     memory::frame::init();
     memory::frame::test();
@@ -138,9 +143,8 @@ fn primary_init_early() {
     let root_config = root_zone_config();
 
     #[cfg(feature = "pci")]
-    if root_config.num_pci_bus > 0 {
-        let num_pci_bus = root_config.num_pci_bus as usize;
-        let _ = hvisor_pci_init(&root_config.pci_config[..num_pci_bus]);
+    if !root_config.pci_config().is_empty() {
+        let _ = hvisor_pci_init(root_config.pci_config(), root_config.pci_devs());
     }
 
     #[cfg(not(test))]
@@ -168,6 +172,8 @@ fn per_cpu_init(cpu: &mut PerCpu) {
     if cpu.zone.is_none() {
         warn!("CPU {} is not bound to zone0 (root zone)", cpu.id);
     }
+    #[cfg(feature = "sched")]
+    sched::percpu_init();
 }
 
 fn wakeup_secondary_cpus(this_id: usize, host_dtb: usize) {
@@ -0,0 +1,370 @@
+// Copyright (c) 2025 Syswonder
+// hvisor is licensed under Mulan PSL v2.
+// You can use this software according to the terms and conditions of the Mulan PSL v2.
+// You may obtain a copy of Mulan PSL v2 at:
+//     http://license.coscl.org.cn/MulanPSL2
+// THIS SOFTWARE IS PROVIDED ON AN "AS IS" BASIS, WITHOUT WARRANTIES OF ANY KIND, EITHER
+// EXPRESS OR IMPLIED, INCLUDING BUT NOT LIMITED TO NON-INFRINGEMENT, MERCHANTABILITY OR
+// FIT FOR A PARTICULAR PURPOSE.
+// See the Mulan PSL v2 for more details.
+//
+// Syswonder Website:
+//      https://www.syswonder.org
+//
+// Authors:
+//
+
+//! Zero-copy sharing of guest RAM between zones.
+//!
+//! A zone grants a page range of its RAM to one peer zone with
+//! `HvGrantCreate` and passes the returned grant ref to the peer, which maps
+//! the pages at an ipa of its choice with `HvGrantMap`. The granter gets its
+//! pages back with `HvGrantRevoke`, which also unmaps them from the peer.
+
+use alloc::collections::btree_map::BTreeMap;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use core::sync::atomic::{AtomicU32, Ordering};
+use spin::{Mutex, RwLock};
+
+use super::addr::is_aligned;
+use super::hotplug::zone_unmap;
+use super::{GuestPhysAddr, HostPhysAddr, MemFlags, MemoryRegion};
+use crate::config::MEM_TYPE_RAM;
+use crate::error::HvResult;
+use crate::zone::{find_zone, Zone};
+
+/// The peer may write the granted pages, otherwise they are read-only.
+pub const GRANT_F_WRITE: u64 = 1 << 0;
+
+/// Argument of `HvGrantCreate`, `ipa` is in the granter's address space.
+#[repr(C)]
+#[derive(Debug, Clone, Copy)]
+pub struct HvGrantRegion {
+    pub peer_zone_id: u64,
+    pub ipa: u64,
+    pub size: u64,
+    pub flags: u64,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum GrantState {
+    Granted,
+    /// The peer's cpus are being paused to unmap it.
+    Unmapping,
+    Mapped(GuestPhysAddr),
+}
+
+struct Grant {
+    granter: usize,
+    peer: usize,
+    hpa: HostPhysAddr,
+    size: usize,
+    flags: u64,
+    state: GrantState,
+}
+
+// grant ref -> grant
+static GRANTS: Mutex<BTreeMap<u32, Grant>> = Mutex::new(BTreeMap::new());
+static NEXT_GRANT_REF: AtomicU32 = AtomicU32::new(1);
+
+impl Zone {
+    /// Host address of the RAM at `ipa`, which must not cross a region.
+    fn ram_hpa(&self, ipa: u64, size: u64) -> Option<HostPhysAddr> {
+        self.memory_regions
+            .iter()
+            .find(|r| {
+                r.mem_type == MEM_TYPE_RAM
+                    && r.virtual_start <= ipa
+                    && ipa
+                        .checked_add(size)
+                        .map_or(false, |end| end <= r.virtual_start + r.size)
+            })
+            .map(|r| (r.physical_start + ipa - r.virtual_start) as HostPhysAddr)
+    }
+
+    fn grant_map(&mut self, ipa: GuestPhysAddr, grant: &Grant) -> HvResult {
+        let flags = if grant.flags & GRANT_F_WRITE != 0 {
+            MemFlags::READ | MemFlags::WRITE
+        } else {
+            MemFlags::READ
+        };
+        self.gpm.insert(MemoryRegion::new_with_offset_mapper(
+            ipa, grant.hpa, grant.size, flags,
+        ))?;
+        // devices of the peer may dma to the pages as well
+        #[cfg(all(feature = "iommu", target_arch = "aarch64"))]
+        if let Err(e) =
+            self.iommu_pt
+                .as_mut()
+                .unwrap()
+                .insert(MemoryRegion::new_with_offset_mapper(
+                    ipa, grant.hpa, grant.size, flags,
+                ))
+        {
+            self.gpm.delete(ipa, grant.size).unwrap();
+            return Err(e);
+        }
+        Ok(())
+    }
+
+    /// The caller flushes the TLBs, see `zone_unmap`.
+    fn grant_unmap(&mut self, ipa: GuestPhysAddr, size: usize) -> HvResult {
+        self.gpm.delete(ipa, size)?;
+        #[cfg(all(feature = "iommu", target_arch = "aarch64"))]
+        self.iommu_pt.as_mut().unwrap().delete(ipa, size)?;
+        Ok(())
+    }
+}
+
+/// Whether a grant made by `zone_id` covers part of `[hpa, hpa + size)`.
+pub fn is_granted(zone_id: usize, hpa: HostPhysAddr, size: usize) -> bool {
+    GRANTS.lock().values().any(|grant| {
+        grant.granter == zone_id && grant.hpa < hpa + size && hpa < grant.hpa + grant.size
+    })
+}
+
+/// Grant `region` of `granter`'s RAM to `region.peer_zone_id`, returns the
+/// grant ref the peer maps it with.
+pub fn grant_create(granter: &Arc<RwLock<Zone>>, region: &HvGrantRegion) -> HvResult<u32> {
+    if region.flags & !GRANT_F_WRITE != 0 {
+        return hv_result_err!(EINVAL, format!("grant: unknown flags {:#x}", region.flags));
+    }
+    if region.size == 0 || !is_aligned(region.ipa as _) || !is_aligned(region.size as _) {
+        return hv_result_err!(
+            EINVAL,
+            format!("grant: region {:#x?} is not page aligned", region)
+        );
+    }
+    if find_zone(region.peer_zone_id as _).is_none() {
+        return hv_result_err!(
+            EINVAL,
+            format!("grant: zone {} not found", region.peer_zone_id)
+        );
+    }
+    let granter = granter.read();
+    let granter_id = granter.id;
+    if region.peer_zone_id as usize == granter_id {
+        return hv_result_err!(EINVAL, "grant: a zone cannot grant to itself");
+    }
+    let hpa = match granter.ram_hpa(region.ipa, region.size) {
+        Some(hpa) => hpa,
+        None => {
+            return hv_result_err!(
+                EINVAL,
+                format!("grant: {:#x?} is not ram of zone {}", region, granter_id)
+            )
+        }
+    };
+    // grant_map takes GRANTS before the zone lock
+    drop(granter);
+
+    let gref = NEXT_GRANT_REF.fetch_add(1, Ordering::Relaxed);
+    GRANTS.lock().insert(
+        gref,
+        Grant {
+            granter: granter_id,
+            peer: region.peer_zone_id as _,
+            hpa,
+            size: region.size as _,
+            flags: region.flags,
+            state: GrantState::Granted,
+        },
+    );
+    info!(
+        "grant {}: zone {} grants {:#x} bytes at {:#x} to zone {}",
+        gref, granter_id, region.size, region.ipa, region.peer_zone_id
+    );
+    Ok(gref)
+}
+
+/// Map grant `gref` into `peer` at `ipa`. Only adds translations, so the
+/// peer keeps running.
+pub fn grant_map(peer: &Arc<RwLock<Zone>>, gref: u32, ipa: GuestPhysAddr) -> HvResult {
+    if !is_aligned(ipa) {
+        return hv_result_err!(
+            EINVAL,
+            format!("grant {}: ipa {:#x} is not aligned", gref, ipa)
+        );
+    }
+    let mut grants = GRANTS.lock();
+    let mut peer = peer.write();
+    let grant = match grants.get_mut(&gref) {
+        Some(grant) if grant.peer == peer.id => grant,
+        _ => {
+            return hv_result_err!(
+                ENOENT,
+                format!("grant {}: not granted to zone {}", gref, peer.id)
+            )
+        }
+    };
+    if grant.state != GrantState::Granted {
+        return hv_result_err!(EBUSY, format!("grant {}: already mapped", gref));
+    }
+    peer.grant_map(ipa, grant)?;
+    grant.state = GrantState::Mapped(ipa);
+    Ok(())
+}
+
+/// Mark grant `gref` as `Unmapping` if `check` passes and it is mapped, so
+/// that its peer can be paused without holding `GRANTS`. Returns where it is
+/// mapped.
+fn grant_begin_unmap(
+    gref: u32,
+    check: impl FnOnce(&Grant) -> HvResult,
+) -> HvResult<Option<(usize, GuestPhysAddr, usize)>> {
+    let mut grants = GRANTS.lock();
+    let grant = match grants.get_mut(&gref) {
+        Some(grant) => grant,
+        None => return hv_result_err!(ENOENT, format!("grant {} does not exist", gref)),
+    };
+    check(grant)?;
+    match grant.state {
+        GrantState::Granted => Ok(None),
+        GrantState::Unmapping => {
+            hv_result_err!(EBUSY, format!("grant {}: unmap in progress", gref))
+        }
+        GrantState::Mapped(ipa) => {
+            grant.state = GrantState::Unmapping;
+            Ok(Some((grant.peer, ipa, grant.size)))
+        }
+    }
+}
+
+/// Unmap grant `gref` from its peer, which keeps the grant.
+pub fn grant_unmap(peer: &Arc<RwLock<Zone>>, gref: u32) -> HvResult {
+    let peer_id = peer.read().id;
+    let mapped = grant_begin_unmap(gref, |grant| {
+        if grant.peer != peer_id {
+            return hv_result_err!(
+                ENOENT,
+                format!("grant {}: not granted to zone {}", gref, peer_id)
+            );
+        }
+        Ok(())
+    })?;
+    let (_, ipa, size) = match mapped {
+        Some(mapped) => mapped,
+        None => return hv_result_err!(EINVAL, format!("grant {}: not mapped", gref)),
+    };
+    let result = zone_unmap(peer, |zone| zone.grant_unmap(ipa, size));
+    if let Some(grant) = GRANTS.lock().get_mut(&gref) {
+        grant.state = match result {
+            Ok(_) => GrantState::Granted,
+            Err(_) => GrantState::Mapped(ipa),
+        };
+    }
+    result
+}
+
+/// End grant `gref` of `granter`, unmapping it from the peer first.
+pub fn grant_revoke(granter: &Arc<RwLock<Zone>>, gref: u32) -> HvResult {
+    let granter_id = granter.read().id;
+    let mapped = grant_begin_unmap(gref, |grant| {
+        if grant.granter != granter_id {
+            return hv_result_err!(
+                EPERM,
+                format!("grant {}: not granted by zone {}", gref, granter_id)
+            );
+        }
+        Ok(())
+    })?;
+    if let Some((peer_id, ipa, size)) = mapped {
+        // a peer that is gone took its mapping along
+        if let Some(peer) = find_zone(peer_id) {
+            if let Err(e) = zone_unmap(&peer, |zone| zone.grant_unmap(ipa, size)) {
+                if let Some(grant) = GRANTS.lock().get_mut(&gref) {
+                    grant.state = GrantState::Mapped(ipa);
+                }
+                return Err(e);
+            }
+        }
+    }
+    GRANTS.lock().remove(&gref);
+    info!("grant {} revoked", gref);
+    Ok(())
+}
+
+/// Mark the mapped grants `select` picks as `Unmapping` and return them as
+/// (gref, peer, ipa, size). Fails if one of them is being unmapped already.
+fn grant_begin_unmap_all(
+    grants: &mut BTreeMap<u32, Grant>,
+    select: impl Fn(&Grant) -> bool,
+) -> HvResult<Vec<(u32, usize, GuestPhysAddr, usize)>> {
+    if let Some((gref, _)) = grants
+        .iter()
+        .find(|(_, grant)| select(grant) && grant.state == GrantState::Unmapping)
+    {
+        return hv_result_err!(EBUSY, format!("grant {}: unmap in progress", gref));
+    }
+    let mut mapped = Vec::new();
+    for (&gref, grant) in grants.iter_mut().filter(|(_, grant)| select(grant)) {
+        if let GrantState::Mapped(ipa) = grant.state {
+            grant.state = GrantState::Unmapping;
+            mapped.push((gref, grant.peer, ipa, grant.size));
+        }
+    }
+    Ok(mapped)
+}
+
+/// Unmap grants marked by `grant_begin_unmap_all` from their peers. An
+/// unmapped grant is dropped if `revoke` holds for it, a grant a peer keeps
+/// stays mapped so that the caller can try again.
+fn grant_unmap_peers(
+    mapped: Vec<(u32, usize, GuestPhysAddr, usize)>,
+    revoke: impl Fn(&Grant) -> bool,
+) -> HvResult {
+    let mut result = Ok(());
+    for (gref, peer_id, ipa, size) in mapped {
+        // a peer that is gone took its mapping along
+        let unmapped = match find_zone(peer_id) {
+            Some(peer) => zone_unmap(&peer, |zone| zone.grant_unmap(ipa, size)),
+            None => Ok(()),
+        };
+        let mut grants = GRANTS.lock();
+        let grant = match grants.get_mut(&gref) {
+            Some(grant) => grant,
+            None => continue,
+        };
+        match unmapped {
+            Ok(_) if revoke(grant) => {
+                grants.remove(&gref);
+            }
+            Ok(_) => grant.state = GrantState::Granted,
+            Err(e) => {
+                error!(
+                    "grant {}: zone {} keeps the pages at {:#x}: {:?}",
+                    gref, peer_id, ipa, e
+                );
+                grant.state = GrantState::Mapped(ipa);
+                if result.is_ok() {
+                    result = Err(e);
+                }
+            }
+        }
+    }
+    result
+}
+
+/// A rebooting zone forgets its grants: revoke those it made and unmap those
+/// it mapped, the granters still own the latter.
+pub fn grant_reset(zone_id: usize) -> HvResult {
+    let mapped = grant_begin_unmap_all(&mut GRANTS.lock(), |grant| {
+        grant.granter == zone_id || grant.peer == zone_id
+    })?;
+    grant_unmap_peers(mapped, |grant| grant.granter == zone_id)?;
+    GRANTS.lock().retain(|_, grant| grant.granter != zone_id);
+    Ok(())
+}
+
+/// Called before the zone goes away, while its cpus are kept from making new
+/// grants: unmap the grants it made from their peers and drop them, along
+/// with the grants to it, whose mappings go with its page table.
+pub fn grant_remove(zone_id: usize) -> HvResult {
+    let mapped = grant_begin_unmap_all(&mut GRANTS.lock(), |grant| grant.granter == zone_id)?;
+    grant_unmap_peers(mapped, |_| true)?;
+    GRANTS
+        .lock()
+        .retain(|_, grant| grant.granter != zone_id && grant.peer != zone_id);
+    Ok(())
+}
@@ -0,0 +1,231 @@
+// Copyright (c) 2025 Syswonder
+// hvisor is licensed under Mulan PSL v2.
+// You can use this software according to the terms and conditions of the Mulan PSL v2.
+// You may obtain a copy of Mulan PSL v2 at:
+//     http://license.coscl.org.cn/MulanPSL2
+// THIS SOFTWARE IS PROVIDED ON AN "AS IS" BASIS, WITHOUT WARRANTIES OF ANY KIND, EITHER
+// EXPRESS OR IMPLIED, INCLUDING BUT NOT LIMITED TO NON-INFRINGEMENT, MERCHANTABILITY OR
+// FIT FOR A PARTICULAR PURPOSE.
+// See the Mulan PSL v2 for more details.
+//
+// Syswonder Website:
+//      https://www.syswonder.org
+//
+// Authors:
+//
+
+//! Adding and removing guest RAM of a running zone.
+//!
+//! A virtio-balloon backend in the root zone inflates by removing the pages
+//! the guest reported with `HvZoneMemRemove`, and deflates by adding them back
+//! at the same guest and host address with `HvZoneMemAdd`.
+
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use core::sync::atomic::Ordering;
+use spin::RwLock;
+
+use super::addr::is_aligned;
+use super::grant::is_granted;
+use super::{GuestPhysAddr, HostPhysAddr, MemFlags, MemoryRegion};
+use crate::arch::cpu::this_cpu_id;
+use crate::config::{check_memory_region, HvConfigMemoryRegion, MEM_TYPE_RAM};
+use crate::consts::MAX_WAIT_TIMES;
+use crate::cpu_data::{cpu_relax, get_cpu_data};
+use crate::error::HvResult;
+use crate::event::{cpu_is_paused, send_event, IPI_EVENT_FLUSH_S2};
+use crate::hypercall::SGI_IPI_ID;
+use crate::zone::{all_zones, this_zone_id, Zone};
+
+fn check_region_layout(region: &HvConfigMemoryRegion) -> HvResult {
+    if region.mem_type != MEM_TYPE_RAM {
+        return hv_result_err!(
+            EINVAL,
+            format!("memory hotplug: region {:#x?} is not ram", region)
+        );
+    }
+    if region.size == 0
+        || !is_aligned(region.virtual_start as _)
+        || !is_aligned(region.physical_start as _)
+        || !is_aligned(region.size as _)
+    {
+        return hv_result_err!(
+            EINVAL,
+            format!("memory hotplug: region {:#x?} is not page aligned", region)
+        );
+    }
+    Ok(())
+}
+
+impl Zone {
+    fn mem_map(&mut self, region: &HvConfigMemoryRegion) -> HvResult {
+        self.gpm.insert(MemoryRegion::new_with_offset_mapper(
+            region.virtual_start as GuestPhysAddr,
+            region.physical_start as HostPhysAddr,
+            region.size as _,
+            MemFlags::READ | MemFlags::WRITE | MemFlags::EXECUTE,
+        ))?;
+        #[cfg(all(feature = "iommu", target_arch = "aarch64"))]
+        if let Err(e) =
+            self.iommu_pt
+                .as_mut()
+                .unwrap()
+                .insert(MemoryRegion::new_with_offset_mapper(
+                    region.virtual_start as GuestPhysAddr,
+                    region.physical_start as HostPhysAddr,
+                    region.size as _,
+                    MemFlags::READ | MemFlags::WRITE,
+                ))
+        {
+            self.gpm
+                .delete(region.virtual_start as _, region.size as _)
+                .unwrap();
+            return Err(e);
+        }
+        self.memory_regions.push(*region);
+        Ok(())
+    }
+
+    /// Unmap `region`, which may be any page range inside one RAM region of
+    /// this zone. The caller flushes the TLBs.
+    fn mem_unmap(&mut self, region: &HvConfigMemoryRegion) -> HvResult {
+        let (start, end) = (region.virtual_start, region.virtual_start + region.size);
+        let index = match self.memory_regions.iter().position(|r| {
+            r.mem_type == MEM_TYPE_RAM
+                && r.virtual_start <= start
+                && end <= r.virtual_start + r.size
+                && r.physical_start + (start - r.virtual_start) == region.physical_start
+        }) {
+            Some(index) => index,
+            None => {
+                return hv_result_err!(
+                    EINVAL,
+                    format!(
+                        "memory hotplug: region {:#x?} is not ram of zone {}",
+                        region, self.id
+                    )
+                )
+            }
+        };
+        // the zone is paused, none of its cpus holds GRANTS waiting for its lock
+        if is_granted(self.id, region.physical_start as _, region.size as _) {
+            return hv_result_err!(
+                EBUSY,
+                format!(
+                    "memory hotplug: region {:#x?} of zone {} is granted to a peer",
+                    region, self.id
+                )
+            );
+        }
+        self.gpm.delete_range(start as _, region.size as _)?;
+        #[cfg(all(feature = "iommu", target_arch = "aarch64"))]
+        self.iommu_pt
+            .as_mut()
+            .unwrap()
+            .delete_range(start as _, region.size as _)?;
+
+        let old = self.memory_regions.remove(index);
+        if old.virtual_start < start {
+            self.memory_regions.push(HvConfigMemoryRegion {
+                size: start - old.virtual_start,
+                ..old
+            });
+        }
+        if end < old.virtual_start + old.size {
+            self.memory_regions.push(HvConfigMemoryRegion {
+                physical_start: region.physical_start + region.size,
+                virtual_start: end,
+                size: old.virtual_start + old.size - end,
+                ..old
+            });
+        }
+        Ok(())
+    }
+}
+
+/// Map a new RAM region into `zone`. Only adds translations, so running cpus
+/// need no flush.
+pub fn zone_mem_add(zone: &Arc<RwLock<Zone>>, region: &HvConfigMemoryRegion) -> HvResult {
+    check_region_layout(region)?;
+    check_memory_region(region, &all_zones())?;
+    zone.write().mem_map(region)
+}
+
+/// Take a RAM range away from `zone`.
+pub fn zone_mem_remove(zone: &Arc<RwLock<Zone>>, region: &HvConfigMemoryRegion) -> HvResult {
+    check_region_layout(region)?;
+    zone_unmap(zone, |zone| zone.mem_unmap(region))
+}
+
+/// Wait until the running cpus among `targets`, each with its count of
+/// stage-2 flushes before the ipi, have taken their `IPI_EVENT_FLUSH_S2`.
+fn wait_s2_flushes(zone_id: usize, targets: &[(usize, bool, usize)]) -> HvResult {
+    let mut count: usize = 0;
+    while targets.iter().any(|&(cpu_id, power_on, flushes)| {
+        power_on && get_cpu_data(cpu_id).s2_flushes.load(Ordering::Acquire) == flushes
+    }) {
+        count += 1;
+        if count > MAX_WAIT_TIMES {
+            return hv_result_err!(
+                EBUSY,
+                format!("zone {}: stage-2 flush not acknowledged in time", zone_id)
+            );
+        }
+        cpu_relax();
+    }
+    Ok(())
+}
+
+/// Remove translations of `zone` with `unmap` and flush its TLBs. The zone is
+/// paused, unless it already is, so that no cpu uses a stale translation while
+/// the TLBs are flushed. The caller's own zone cannot be paused, its other
+/// cpus flush when they take the ipi and the caller waits for them.
+pub fn zone_unmap(zone: &Arc<RwLock<Zone>>, unmap: impl FnOnce(&mut Zone) -> HvResult) -> HvResult {
+    let is_this_zone = zone.read().id == this_zone_id();
+    let need_pause = !is_this_zone && !core::mem::replace(&mut zone.write().is_paused, true);
+    if need_pause {
+        if let Err(e) = Zone::pause(zone) {
+            zone.write().is_paused = false;
+            zone.read().resume();
+            return Err(e);
+        }
+    }
+
+    let mut result = unmap(&mut zone.write());
+    if result.is_ok() {
+        let zone_r = zone.read();
+        let zone_id = zone_r.id;
+        #[cfg(feature = "iommu")]
+        crate::arch::iommu::iommu_flush_zone(zone_id);
+        // paused cpus handle the event before they enter the guest again
+        let targets: Vec<_> = zone_r
+            .cpu_set
+            .iter()
+            .filter(|&cpu_id| {
+                if is_this_zone {
+                    cpu_id != this_cpu_id()
+                } else {
+                    cpu_is_paused(cpu_id)
+                }
+            })
+            .map(|cpu_id| {
+                let cpu_data = get_cpu_data(cpu_id);
+                let _lock = cpu_data.ctrl_lock.lock();
+                let flushes = cpu_data.s2_flushes.load(Ordering::Acquire);
+                send_event(cpu_id, SGI_IPI_ID as _, IPI_EVENT_FLUSH_S2);
+                (cpu_id, cpu_data.arch_cpu.power_on, flushes)
+            })
+            .collect();
+        zone_r.gpm.flush(None);
+        drop(zone_r);
+        if is_this_zone {
+            result = wait_s2_flushes(zone_id, &targets);
+        }
+    }
+
+    if need_pause {
+        zone.write().is_paused = false;
+        zone.read().resume();
+    }
+    result
+}
@@ -202,6 +202,43 @@ where
         }
     }
 
+    /// Unmap `[start, start + size)`, which must lie inside one region. What is left of
+    /// that region on either side is mapped again as its own region.
+    pub fn delete_range(&mut self, start: PT::VA, size: usize) -> HvResult {
+        let (vstart, vend) = (start.into(), start.into() + size);
+        let region = match self.regions.range(..=start).last() {
+            Some((_, region)) if region.start.into() + region.size >= vend => region.clone(),
+            _ => {
+                return hv_result_err!(
+                    EINVAL,
+                    format!(
+                        "MemorySet::delete_range(): [{:#x?}, {:#x?}) is not inside one region",
+                        vstart, vend
+                    )
+                )
+            }
+        };
+        let (rstart, rend) = (region.start.into(), region.start.into() + region.size);
+        self.delete(region.start, region.size)?;
+        if rstart < vstart {
+            self.insert(MemoryRegion::new(
+                region.start,
+                vstart - rstart,
+                region.flags,
+                region.mapper.clone(),
+            ))?;
+        }
+        if vend < rend {
+            self.insert(MemoryRegion::new(
+                vend.into(),
+                rend - vend,
+                region.flags,
+                region.mapper,
+            ))?;
+        }
+        Ok(())
+    }
+
     pub fn clear(&mut self) {
         for region in self.regions.values() {
             self.pt.unmap(region).unwrap();
@@ -213,6 +250,10 @@ where
         self.pt.activate();
     }
 
+    pub fn flush(&self, vaddr: Option<PT::VA>) {
+        self.pt.flush(vaddr);
+    }
+
     pub unsafe fn page_table_query(
         &self,
         vaddr: PT::VA,
@@ -13,6 +13,7 @@
 //
 // Authors:
 //
+use core::sync::atomic::AtomicU64;
 use core::{ptr, usize};
 
 use crate::{cpu_data::this_zone, error::HvResult, zone::zone_error};
@@ -45,6 +46,8 @@ pub struct MMIOConfig {
     pub region: MMIORegion,
     pub handler: MMIOHandler,
     pub arg: usize,
+    /// Number of guest accesses handled by this region, see `crate::stats`.
+    pub accesses: AtomicU64,
 }
 
 impl MMIORegion {
@@ -15,7 +15,9 @@
 //
 pub mod addr;
 pub mod frame;
+pub mod grant;
 pub mod heap;
+pub mod hotplug;
 pub mod mapper;
 pub mod mm;
 pub mod mmio;
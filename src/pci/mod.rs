@@ -19,7 +19,11 @@ pub mod config_accessors;
 pub mod mem_alloc;
 pub mod pci_access;
 pub mod pci_config;
+pub mod pci_ext_cap;
 pub mod pci_handler;
+pub mod pci_hotplug;
+pub mod pci_msi;
+pub mod pci_sriov;
 pub mod pci_struct;
 pub mod vpci_dev;
 
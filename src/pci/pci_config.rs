@@ -18,7 +18,7 @@ use alloc::collections::btree_map::BTreeMap;
 use spin::{Lazy, Mutex};
 
 use crate::{
-    config::{HvPciConfig, HvPciDevConfig, CONFIG_MAX_PCI_DEV, CONFIG_PCI_BUS_MAXNUM},
+    config::{HvPciConfig, HvPciDevConfig},
     error::HvResult,
     pci::pci_struct::{ArcRwLockVirtualPciConfigSpace, Bdf},
     zone::Zone,
@@ -65,8 +65,8 @@ pub static GLOBAL_PCIE_LIST: Lazy<Mutex<BTreeMap<Bdf, ArcRwLockVirtualPciConfigS
         Mutex::new(m)
     });
 
-/* add all dev to GLOBAL_PCIE_LIST */
-pub fn hvisor_pci_init(pci_config: &[HvPciConfig]) -> HvResult {
+/* add all dev to GLOBAL_PCIE_LIST, with the VFs root_pci_devs asks for */
+pub fn hvisor_pci_init(pci_config: &[HvPciConfig], root_pci_devs: &[HvPciDevConfig]) -> HvResult {
     warn!("begin {:#x?}", pci_config);
     #[cfg(any(
         feature = "ecam_pcie",
@@ -150,7 +150,14 @@ pub fn hvisor_pci_init(pci_config: &[HvPciConfig]) -> HvResult {
             rootcomplex_config.bus_range_begin as usize..rootcomplex_config.bus_range_end as usize;
 
         let domain = rootcomplex_config.domain;
-        let e = rootcomplex.enumerate(Some(range), domain, allocator_opt);
+        let num_vfs = root_pci_devs
+            .iter()
+            .filter(|dev| dev.domain == domain && dev.num_vfs != 0)
+            .map(|dev| (Bdf::new_from_config(*dev), dev.num_vfs))
+            .collect();
+        let e = rootcomplex
+            .enumerate(Some(range), domain, allocator_opt)
+            .with_vfs(num_vfs);
         info!("begin enumerate {:#x?}", e);
         for node in e {
             info!("node {:#?}", node);
@@ -167,7 +174,7 @@ impl Zone {
     pub fn guest_pci_init(
         &mut self,
         _zone_id: usize,
-        alloc_pci_devs: &[HvPciDevConfig; CONFIG_MAX_PCI_DEV],
+        alloc_pci_devs: &[HvPciDevConfig],
         num_pci_devs: u64,
         pci_config: &[HvPciConfig],
         _num_pci_config: usize,
@@ -283,6 +290,7 @@ impl Zone {
                         let vdev = guard.remove(&bdf).unwrap();
                         let mut vdev_inner = vdev.read().clone();
                         vdev_inner.set_vbdf(vbdf);
+                        vdev_inner.set_ext_cap_policy(dev_config);
                         self.vpci_bus.insert(vbdf, vdev_inner);
                     }
                 } else {
@@ -317,7 +325,7 @@ impl Zone {
 
     pub fn virtual_pci_mmio_init(
         &mut self,
-        pci_rootcomplex_config: &[HvPciConfig; CONFIG_PCI_BUS_MAXNUM],
+        pci_rootcomplex_config: &[HvPciConfig],
         _num_pci_config: usize,
     ) {
         for rootcomplex_config in pci_rootcomplex_config {
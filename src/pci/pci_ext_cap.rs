@@ -0,0 +1,224 @@
+// Copyright (c) 2025 Syswonder
+// hvisor is licensed under Mulan PSL v2.
+// You can use this software according to the terms and conditions of the Mulan PSL v2.
+// You may obtain a copy of Mulan PSL v2 at:
+//     http://license.coscl.org.cn/MulanPSL2
+// THIS SOFTWARE IS PROVIDED ON AN "AS IS" BASIS, WITHOUT WARRANTIES OF ANY KIND, EITHER
+// EXPRESS OR IMPLIED, INCLUDING BUT NOT LIMITED TO NON-INFRINGEMENT, MERCHANTABILITY OR
+// FIT FOR A PARTICULAR PURPOSE.
+// See the Mulan PSL v2 for more details.
+//
+// Syswonder Website:
+//      https://www.syswonder.org
+//
+// Authors:
+//
+
+//! PCIe extended capabilities (0x100-0xFFF).
+//!
+//! Every extended capability of a passthrough endpoint gets a policy from the
+//! zone's `HvPciDevConfig`. Passed capabilities go to hardware, hidden ones
+//! are unlinked from the list and read as zero, emulated ones are served
+//! from a copy taken when the zone starts. The header of every capability is
+//! emulated so the next pointers skip hidden capabilities.
+
+use alloc::{sync::Arc, vec, vec::Vec};
+use bit_field::BitField;
+
+use super::pci_access::PciRW;
+use super::pci_struct::PciCapabilityRegion;
+use super::PciConfigAddress;
+use crate::config::HvPciDevConfig;
+use crate::error::HvResult;
+
+pub const PCI_EXT_CAP_START: PciConfigAddress = 0x100;
+pub const PCI_EXT_CAP_END: PciConfigAddress = 0x1000;
+
+pub const PCI_EXT_CAP_ID_AER: u16 = 0x0001;
+pub const PCI_EXT_CAP_ID_SRIOV: u16 = 0x0010;
+
+/// Uncorrectable and correctable error status of AER, both RW1C.
+const AER_STATUS_REGS: [PciConfigAddress; 2] = [0x04, 0x10];
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ExtCapPolicy {
+    Pass,
+    Hide,
+    Emulate,
+}
+
+impl ExtCapPolicy {
+    /// Policy of capability `id`, ids beyond the config bitmaps are passed.
+    pub fn from_config(config: &HvPciDevConfig, id: u16) -> Self {
+        let bit = id as usize;
+        if bit >= 64 {
+            ExtCapPolicy::Pass
+        } else if config.ext_cap_hide.get_bit(bit) {
+            ExtCapPolicy::Hide
+        } else if config.ext_cap_emulate.get_bit(bit) {
+            ExtCapPolicy::Emulate
+        } else {
+            ExtCapPolicy::Pass
+        }
+    }
+}
+
+/// Offset, id and version of each extended capability in list order.
+pub fn ext_capability_walk(backend: &Arc<dyn PciRW>) -> Vec<(PciConfigAddress, u16, u8)> {
+    let mut caps = Vec::new();
+    let mut offset = PCI_EXT_CAP_START;
+    // bounded in case the list loops
+    let max_caps = ((PCI_EXT_CAP_END - PCI_EXT_CAP_START) / 4) as usize;
+    while offset >= PCI_EXT_CAP_START && offset < PCI_EXT_CAP_END && caps.len() < max_caps {
+        let header = match backend.read(offset, 4) {
+            Ok(header) => header as u32,
+            Err(_) => break,
+        };
+        if header == 0 || header == u32::MAX {
+            break;
+        }
+        caps.push((
+            offset,
+            header.get_bits(0..16) as u16,
+            header.get_bits(16..20) as u8,
+        ));
+        offset = (header.get_bits(20..32) & !0x3) as PciConfigAddress;
+    }
+    caps
+}
+
+pub struct ExtCapabilityRegion {
+    offset: PciConfigAddress,
+    size: usize,
+    backend: Arc<dyn PciRW>,
+    id: u16,
+    version: u8,
+    /// Next capability the zone can see.
+    next: PciConfigAddress,
+    policy: ExtCapPolicy,
+    /// Registers after the header, for `Emulate`.
+    shadow: Vec<u32>,
+}
+
+impl ExtCapabilityRegion {
+    pub fn new(
+        offset: PciConfigAddress,
+        size: usize,
+        backend: Arc<dyn PciRW>,
+        id: u16,
+        version: u8,
+        next: PciConfigAddress,
+        policy: ExtCapPolicy,
+    ) -> Self {
+        let mut shadow = vec![];
+        if policy == ExtCapPolicy::Emulate {
+            shadow = (1..size / 4)
+                .map(|i| backend.read(offset + i as u64 * 4, 4).unwrap_or(0) as u32)
+                .collect();
+            if id == PCI_EXT_CAP_ID_AER {
+                for reg in AER_STATUS_REGS {
+                    if let Some(status) = shadow.get_mut(reg as usize / 4 - 1) {
+                        *status = 0;
+                    }
+                }
+            }
+        }
+        Self {
+            offset,
+            size,
+            backend,
+            id,
+            version,
+            next,
+            policy,
+            shadow,
+        }
+    }
+
+    pub fn policy(&self) -> ExtCapPolicy {
+        self.policy
+    }
+
+    /// The first capability can't be unlinked, a hidden one there reads as a
+    /// null capability.
+    fn header(&self) -> u32 {
+        let mut header = 0u32;
+        match self.policy {
+            ExtCapPolicy::Hide if self.offset != PCI_EXT_CAP_START => return 0,
+            ExtCapPolicy::Hide => {}
+            _ => {
+                header.set_bits(0..16, self.id as u32);
+                header.set_bits(16..20, self.version as u32);
+            }
+        }
+        header.set_bits(20..32, self.next as u32);
+        header
+    }
+
+    fn is_rw1c(&self, reg: PciConfigAddress) -> bool {
+        self.id == PCI_EXT_CAP_ID_AER && AER_STATUS_REGS.contains(&reg)
+    }
+}
+
+impl PciCapabilityRegion for ExtCapabilityRegion {
+    fn read(&self, offset: PciConfigAddress, size: usize) -> HvResult<u32> {
+        let reg = offset & !0x3;
+        let dword = match (reg, self.policy) {
+            (0, _) => self.header(),
+            (_, ExtCapPolicy::Pass) => {
+                return self
+                    .backend
+                    .read(self.offset + offset, size)
+                    .map(|v| v as u32)
+            }
+            (_, ExtCapPolicy::Hide) => 0,
+            (_, ExtCapPolicy::Emulate) => self.shadow[reg as usize / 4 - 1],
+        };
+        let value = dword >> ((offset & 0x3) * 8);
+        Ok(if size >= 4 {
+            value
+        } else {
+            value & ((1u32 << (size * 8)) - 1)
+        })
+    }
+
+    fn write(&mut self, offset: PciConfigAddress, size: usize, value: u32) -> HvResult {
+        let reg = offset & !0x3;
+        match (reg, self.policy) {
+            // the header is read-only
+            (0, _) | (_, ExtCapPolicy::Hide) => {}
+            (_, ExtCapPolicy::Pass) => {
+                self.backend
+                    .write(self.offset + offset, size, value as usize)?;
+            }
+            (_, ExtCapPolicy::Emulate) => {
+                let shift = (offset & 0x3) * 8;
+                let mask = if size >= 4 {
+                    u32::MAX
+                } else {
+                    ((1u32 << (size * 8)) - 1) << shift
+                };
+                let is_rw1c = self.is_rw1c(reg);
+                let dword = &mut self.shadow[reg as usize / 4 - 1];
+                if is_rw1c {
+                    *dword &= !((value << shift) & mask);
+                } else {
+                    *dword = (*dword & !mask) | ((value << shift) & mask);
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn get_offset(&self) -> PciConfigAddress {
+        self.offset
+    }
+
+    fn get_size(&self) -> usize {
+        self.size
+    }
+
+    fn next_cap(&self) -> HvResult<PciConfigAddress> {
+        Ok(self.next)
+    }
+}
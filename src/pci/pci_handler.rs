@@ -14,16 +14,18 @@
 // Authors:
 //
 
-use alloc::string::String;
+use alloc::{string::String, sync::Arc};
+use spin::Mutex;
 
 use crate::cpu_data::this_zone;
 use crate::error::HvResult;
 use crate::memory::MMIOAccess;
 use crate::memory::{GuestPhysAddr, HostPhysAddr, MemFlags, MemoryRegion};
-use crate::zone::is_this_root_zone;
+use crate::zone::{is_this_root_zone, Zone};
 
 use super::pci_access::{BridgeField, EndpointField, HeaderType, PciField, PciMemType};
 use super::pci_config::GLOBAL_PCIE_LIST;
+use super::pci_msi::{msix_map, Msix};
 use super::pci_struct::{ArcRwLockVirtualPciConfigSpace, BIT_LENTH};
 use super::vpci_dev::VpciDevType;
 use super::PciConfigAddress;
@@ -46,6 +48,9 @@ use crate::{
     },
 };
 
+/// Capabilities start after the type 0 header.
+const PCI_CAP_START: PciConfigAddress = 0x40;
+
 macro_rules! pci_log {
     ($($arg:tt)*) => {
         // info!($($arg)*);
@@ -319,23 +324,9 @@ fn handle_endpoint_access(
                                         new_vaddr as u64
                                     };
 
-                                let zone = this_zone();
-                                let mut guard = zone.write();
-                                let gpm = &mut guard.gpm;
-
-                                if !gpm
-                                    .try_delete(old_vaddr.try_into().unwrap(), bar_size as usize)
-                                    .is_ok()
-                                {
-                                    // warn!("delete bar {}: can not found 0x{:x}", slot, old_vaddr);
-                                }
-                                gpm.try_insert_quiet(MemoryRegion::new_with_offset_mapper(
-                                    new_vaddr as GuestPhysAddr,
-                                    paddr as HostPhysAddr,
-                                    bar_size as _,
-                                    MemFlags::READ | MemFlags::WRITE,
-                                ))?;
-                                drop(guard);
+                                bar_remap(
+                                    &dev, slot, bar_type, old_vaddr, new_vaddr, paddr, bar_size,
+                                )?;
                                 /* after update gpm, mem barrier is needed
                                  */
                                 #[cfg(target_arch = "aarch64")]
@@ -518,6 +509,146 @@ fn handle_endpoint_access(
     }
 }
 
+/* msi and msi-x of a device are programmed by the zone it belongs to, the
+ * root zone in direct mode can only read them
+ */
+fn handle_endpoint_cap_access(
+    dev: ArcRwLockVirtualPciConfigSpace,
+    offset: PciConfigAddress,
+    size: usize,
+    value: usize,
+    is_write: bool,
+    is_dev_belong_to_zone: bool,
+) -> HvResult<Option<usize>> {
+    dev.with_cap(|capabilities| {
+        let Some((cap_offset, cap)) = capabilities.range(..=offset).next_back() else {
+            return Ok(None);
+        };
+        let relative_offset = offset - *cap_offset;
+        if relative_offset as usize + size > cap.get_size() {
+            return Ok(None);
+        }
+        if !is_write {
+            cap.with_region(|region| region.read(relative_offset, size))
+                .map(|val| Some(val as usize))
+        } else {
+            if is_dev_belong_to_zone {
+                cap.with_region_mut(|region| region.write(relative_offset, size, value as u32))?;
+            }
+            Ok(None)
+        }
+    })
+}
+
+/* offset and size of the parts of a bar mapped to the zone, and the msi-x
+ * table whose pages are left out between them
+ */
+fn bar_pieces(
+    dev: &ArcRwLockVirtualPciConfigSpace,
+    slot: usize,
+    bar_type: PciMemType,
+    bar_size: usize,
+) -> (Option<Arc<Mutex<Msix>>>, [(usize, usize); 2]) {
+    let bir = if bar_type == PciMemType::Mem64High {
+        slot - 1
+    } else {
+        slot
+    };
+    let msix = dev.msix().filter(|msix| {
+        let msix = msix.lock();
+        let (start, size) = msix.window();
+        msix.bir() == bir && start + size <= bar_size
+    });
+    let pieces = match msix.as_ref().map(|msix| msix.lock().window()) {
+        Some((start, size)) => [(0, start), (start + size, bar_size - start - size)],
+        None => [(0, bar_size), (bar_size, 0)],
+    };
+    (msix, pieces)
+}
+
+/* move a bar of the zone from old_vaddr to new_vaddr, the pages holding
+ * an msi-x table are trapped instead of mapped
+ */
+fn bar_remap(
+    dev: &ArcRwLockVirtualPciConfigSpace,
+    slot: usize,
+    bar_type: PciMemType,
+    old_vaddr: u64,
+    new_vaddr: u64,
+    paddr: HostPhysAddr,
+    bar_size: u64,
+) -> HvResult {
+    let (msix, pieces) = bar_pieces(dev, slot, bar_type, bar_size as usize);
+
+    let zone = this_zone();
+    let mut guard = zone.write();
+    for &(start, size) in pieces.iter().filter(|(_, size)| *size != 0) {
+        // nothing is mapped at the old address the first time
+        let _ = guard
+            .gpm
+            .try_delete(old_vaddr as GuestPhysAddr + start, size);
+    }
+    for &(start, size) in pieces.iter().filter(|(_, size)| *size != 0) {
+        guard
+            .gpm
+            .try_insert_quiet(MemoryRegion::new_with_offset_mapper(
+                new_vaddr as GuestPhysAddr + start,
+                paddr + start,
+                size,
+                MemFlags::READ | MemFlags::WRITE,
+            ))?;
+    }
+    if let Some(msix) = msix {
+        msix_map(
+            &mut guard,
+            &msix,
+            old_vaddr as GuestPhysAddr,
+            new_vaddr as GuestPhysAddr,
+            paddr,
+        )?;
+    }
+    Ok(())
+}
+
+/* unmap the bars and rom of a device taken away from the zone, the same
+ * ranges the zone mapped when it programmed them
+ */
+pub fn dev_unmap(zone: &mut Zone, dev: &ArcRwLockVirtualPciConfigSpace) {
+    let page_size = |size: u64| {
+        if crate::memory::addr::is_aligned(size as usize) {
+            size as usize
+        } else {
+            crate::memory::PAGE_SIZE
+        }
+    };
+    for slot in 0..6 {
+        let bar_type = dev.with_bar_ref(slot, |bar| bar.get_type());
+        if !matches!(
+            bar_type,
+            PciMemType::Mem32 | PciMemType::Mem64High | PciMemType::Io
+        ) {
+            continue;
+        }
+        let vaddr = dev.with_bar_ref(slot, |bar| bar.get_virtual_value64()) & !0xf;
+        if vaddr == 0 {
+            continue;
+        }
+        let vaddr = crate::memory::addr::align_up(vaddr as usize);
+        let bar_size = page_size(dev.with_bar_ref(slot, |bar| bar.get_size()));
+        let (_, pieces) = bar_pieces(dev, slot, bar_type, bar_size);
+        for &(start, size) in pieces.iter().filter(|(_, size)| *size != 0) {
+            let _ = zone.gpm.try_delete(vaddr + start, size);
+        }
+    }
+    if dev.with_rom_ref(|rom| rom.get_type()) == PciMemType::Rom {
+        let vaddr = dev.with_rom_ref(|rom| rom.get_virtual_value64()) & !0xf;
+        if vaddr != 0 {
+            let rom_size = page_size(dev.with_rom_ref(|rom| rom.get_size()));
+            let _ = zone.gpm.try_delete(vaddr as GuestPhysAddr, rom_size);
+        }
+    }
+}
+
 fn handle_pci_bridge_access(
     _dev: ArcRwLockVirtualPciConfigSpace,
     _field: BridgeField,
@@ -595,6 +726,19 @@ fn handle_config_space_access(
                     VpciDevType::Physical => {
                         let config_type = dev.get_config_type();
                         match config_type {
+                            // only msi and msi-x are emulated above the header
+                            HeaderType::Endpoint if offset >= PCI_CAP_START => {
+                                if let Some(val) = handle_endpoint_cap_access(
+                                    dev,
+                                    offset,
+                                    size,
+                                    value,
+                                    is_write,
+                                    is_dev_belong_to_zone,
+                                )? {
+                                    mmio.value = val;
+                                }
+                            }
                             HeaderType::Endpoint => {
                                 if let Some(val) = handle_endpoint_access(
                                     dev,
@@ -0,0 +1,299 @@
+// Copyright (c) 2025 Syswonder
+// hvisor is licensed under Mulan PSL v2.
+// You can use this software according to the terms and conditions of the Mulan PSL v2.
+// You may obtain a copy of Mulan PSL v2 at:
+//     http://license.coscl.org.cn/MulanPSL2
+// THIS SOFTWARE IS PROVIDED ON AN "AS IS" BASIS, WITHOUT WARRANTIES OF ANY KIND, EITHER
+// EXPRESS OR IMPLIED, INCLUDING BUT NOT LIMITED TO NON-INFRINGEMENT, MERCHANTABILITY OR
+// FIT FOR A PARTICULAR PURPOSE.
+// See the Mulan PSL v2 for more details.
+//
+// Syswonder Website:
+//      https://www.syswonder.org
+//
+// Authors:
+//
+
+//! Moving passthrough PCI functions between running zones.
+//!
+//! The root zone detaches a function from one zone and attaches it to
+//! another. On detach the zone loses the BARs and the MSI-X table, the IOMMU
+//! aborts the function's DMA, and the function is reset and put back into
+//! `GLOBAL_PCIE_LIST` with the BARs hvisor assigned. Attach is the same as
+//! handing the function out in the zone config. A zone being shut down
+//! gives up all its functions the same way.
+//!
+//! Hotplug is not signalled to the guests yet: the virtual bridges have no
+//! emulated PCIe hotplug slot (presence detect, data link layer state change,
+//! attention button) to raise it with, which is still to be done. Until then
+//! a function disappears from the zone's config space on detach and shows up
+//! on attach, so the guest has to release it before the detach and rescan
+//! the bus after the attach, e.g. through sysfs on Linux.
+
+use alloc::{sync::Arc, vec::Vec};
+use bit_field::BitField;
+use core::ops::Range;
+use spin::RwLock;
+
+use super::pci_access::{HeaderType, PciRW};
+use super::pci_config::GLOBAL_PCIE_LIST;
+use super::pci_handler::dev_unmap;
+use super::pci_msi::msix_unmap;
+use super::pci_struct::{ArcRwLockVirtualPciConfigSpace, Bdf};
+use super::vpci_dev::VpciDevType;
+use super::PciConfigAddress;
+use crate::arch::time::get_time_us;
+use crate::config::HvPciDevConfig;
+use crate::error::HvResult;
+use crate::memory::hotplug::zone_unmap;
+use crate::zone::Zone;
+
+const PCI_EXP_DEVCAP: PciConfigAddress = 0x04;
+const PCI_EXP_DEVCAP_FLR: usize = 28;
+const PCI_EXP_DEVCTL: PciConfigAddress = 0x08;
+const PCI_EXP_DEVCTL_BCR_FLR: usize = 15;
+const PCI_EXP_DEVSTA: PciConfigAddress = 0x0a;
+const PCI_EXP_DEVSTA_TRPND: usize = 5;
+
+/// A function must not be accessed for 100ms after FLR.
+const PCI_FLR_DELAY_US: u64 = 100_000;
+
+const PCI_PM_CTRL: PciConfigAddress = 0x04;
+const PCI_PM_CTRL_STATE: Range<usize> = 0..2;
+const PCI_PM_CTRL_NO_SOFT_RESET: usize = 3;
+const PCI_PM_D0: usize = 0;
+const PCI_PM_D3HOT: usize = 3;
+/// Transitions from and to D3hot take up to 10ms.
+const PCI_PM_D3HOT_DELAY_US: u64 = 10_000;
+
+fn delay_us(us: u64) {
+    let start = get_time_us();
+    while get_time_us() - start < us {
+        core::hint::spin_loop();
+    }
+}
+
+/// Reset a function with FLR if its PCI Express capability at `exp_offset`
+/// offers it, otherwise by a trip through D3hot if its power management
+/// capability at `pm_offset` doesn't rule that out. Returns false if the
+/// function can't be reset.
+pub fn function_reset(
+    backend: &Arc<dyn PciRW>,
+    exp_offset: Option<PciConfigAddress>,
+    pm_offset: Option<PciConfigAddress>,
+) -> bool {
+    exp_offset.is_some_and(|exp| flr(backend, exp))
+        || pm_offset.is_some_and(|pm| pm_reset(backend, pm))
+}
+
+fn flr(backend: &Arc<dyn PciRW>, exp: PciConfigAddress) -> bool {
+    let devcap = backend.read(exp + PCI_EXP_DEVCAP, 4).unwrap_or(0);
+    if !devcap.get_bit(PCI_EXP_DEVCAP_FLR) {
+        return false;
+    }
+    // give requests already sent a chance to complete
+    let start = get_time_us();
+    while backend
+        .read(exp + PCI_EXP_DEVSTA, 2)
+        .unwrap_or(0)
+        .get_bit(PCI_EXP_DEVSTA_TRPND)
+        && get_time_us() - start < PCI_FLR_DELAY_US
+    {
+        core::hint::spin_loop();
+    }
+    let mut devctl = backend.read(exp + PCI_EXP_DEVCTL, 2).unwrap_or(0);
+    devctl.set_bit(PCI_EXP_DEVCTL_BCR_FLR, true);
+    let _ = backend.write(exp + PCI_EXP_DEVCTL, 2, devctl);
+    delay_us(PCI_FLR_DELAY_US);
+    true
+}
+
+/// D3hot to D0 resets functions that don't set No_Soft_Reset.
+fn pm_reset(backend: &Arc<dyn PciRW>, pm: PciConfigAddress) -> bool {
+    let mut ctrl = backend.read(pm + PCI_PM_CTRL, 2).unwrap_or(0);
+    if ctrl.get_bit(PCI_PM_CTRL_NO_SOFT_RESET) {
+        return false;
+    }
+    for state in [PCI_PM_D3HOT, PCI_PM_D0] {
+        ctrl.set_bits(PCI_PM_CTRL_STATE, state);
+        let _ = backend.write(pm + PCI_PM_CTRL, 2, ctrl);
+        delay_us(PCI_PM_D3HOT_DELAY_US);
+    }
+    true
+}
+
+/// Bridges and host bridges are shared between zones and can't be moved. A PF
+/// whose VFs hvisor enabled is only released when its zone goes away, see
+/// `check_vfs`.
+fn is_movable(dev: &ArcRwLockVirtualPciConfigSpace) -> bool {
+    dev.get_dev_type() == VpciDevType::Physical
+        && dev.get_config_type() == HeaderType::Endpoint
+        && !dev.with_config_value(|config_value| config_value.get_class().0 == 0x6)
+}
+
+/// Resetting a PF disables its VFs, which other zones may be using.
+fn check_vfs(dev: &ArcRwLockVirtualPciConfigSpace) -> HvResult {
+    if dev.vfs_enabled() {
+        return hv_result_err!(
+            EBUSY,
+            format!("pci hotplug: {:#x?} has its vfs enabled", dev.get_bdf())
+        );
+    }
+    Ok(())
+}
+
+fn device_id(bdf: Bdf) -> usize {
+    ((bdf.bus() as usize) << 8) | ((bdf.device() as usize) << 3) | bdf.function() as usize
+}
+
+fn iommu_attach(_zone: &Zone, _bdf: Bdf) {
+    #[cfg(all(feature = "iommu", target_arch = "aarch64"))]
+    {
+        let iommu_pt_addr = _zone.iommu_pt.as_ref().map_or(0, |pt| pt.root_paddr());
+        crate::arch::iommu::iommu_add_device(_zone.id, device_id(_bdf), iommu_pt_addr);
+    }
+    #[cfg(target_arch = "x86_64")]
+    crate::arch::iommu::iommu_attach_device(_zone.id, device_id(_bdf), _zone.gpm.root_paddr());
+}
+
+fn iommu_detach(_bdf: Bdf) {
+    #[cfg(any(
+        all(feature = "iommu", target_arch = "aarch64"),
+        target_arch = "x86_64"
+    ))]
+    crate::arch::iommu::iommu_remove_device(device_id(_bdf));
+}
+
+fn physical_bdf(config: &HvPciDevConfig) -> HvResult<Bdf> {
+    if config.dev_type != VpciDevType::Physical {
+        return hv_result_err!(EINVAL, "pci hotplug: only physical functions can be moved");
+    }
+    Ok(Bdf::new_from_config(*config))
+}
+
+/// Give the free function `config` to the running `zone`.
+pub fn pci_dev_attach(zone: &Arc<RwLock<Zone>>, config: &HvPciDevConfig) -> HvResult {
+    let bdf = physical_bdf(config)?;
+    if zone.read().vpci_bus.get(&bdf).is_some() {
+        return hv_result_err!(
+            EBUSY,
+            format!(
+                "pci hotplug: zone {} has a device at {:#x?}",
+                zone.read().id,
+                bdf
+            )
+        );
+    }
+
+    let mut guard = GLOBAL_PCIE_LIST.lock();
+    let dev = match guard.get(&bdf) {
+        Some(dev) if is_movable(dev) => {
+            check_vfs(dev)?;
+            guard.remove(&bdf).unwrap()
+        }
+        Some(_) => return hv_result_err!(EINVAL, format!("pci hotplug: {:#x?} is a bridge", bdf)),
+        None => return hv_result_err!(EBUSY, format!("pci hotplug: {:#x?} is not free", bdf)),
+    };
+    drop(guard);
+
+    let mut vdev = dev.read().clone();
+    vdev.set_vbdf(bdf);
+    vdev.set_ext_cap_policy(config);
+
+    let mut zone_w = zone.write();
+    iommu_attach(&zone_w, bdf);
+    zone_w.vpci_bus.insert(bdf, vdev);
+    zone_w.pci_devs.push(*config);
+    info!("pci hotplug: {:#x?} attached to zone {}", bdf, zone_w.id);
+    Ok(())
+}
+
+/// Take the function `config` away from the running `zone`, reset it and
+/// make it free.
+pub fn pci_dev_detach(zone: &Arc<RwLock<Zone>>, config: &HvPciDevConfig) -> HvResult {
+    let bdf = physical_bdf(config)?;
+    let vbdf = zone
+        .write()
+        .vpci_bus
+        .devs()
+        .iter()
+        .find(|(_, dev)| dev.get_bdf() == bdf && is_movable(dev))
+        .map(|(vbdf, _)| *vbdf);
+    let Some(vbdf) = vbdf else {
+        return hv_result_err!(
+            ENODEV,
+            format!("pci hotplug: {:#x?} not in zone {}", bdf, zone.read().id)
+        );
+    };
+    if let Some(dev) = zone.read().vpci_bus.get(&vbdf) {
+        check_vfs(dev)?;
+    }
+
+    let mut detached = None;
+    zone_unmap(zone, |zone| {
+        let Some(dev) = zone.vpci_bus.remove(&vbdf) else {
+            return hv_result_err!(ENODEV);
+        };
+        dev_unmap(zone, &dev);
+        if let Some(msix) = dev.msix() {
+            msix_unmap(zone, &msix);
+        }
+        zone.pci_devs
+            .retain(|other| Bdf::new_from_config(*other) != bdf);
+        detached = Some(dev);
+        Ok(())
+    })?;
+    release(detached.unwrap());
+    info!(
+        "pci hotplug: {:#x?} detached from zone {}",
+        bdf,
+        zone.read().id
+    );
+    Ok(())
+}
+
+/// Abort the DMA of a function no zone owns any more, reset it and put it
+/// back into `GLOBAL_PCIE_LIST`.
+fn release(dev: ArcRwLockVirtualPciConfigSpace) {
+    let bdf = dev.get_bdf();
+    iommu_detach(bdf);
+    {
+        let mut dev = dev.write();
+        dev.reclaim();
+        dev.set_vbdf(bdf);
+    }
+    GLOBAL_PCIE_LIST.lock().insert(bdf, dev);
+}
+
+/// Stop the DMA of the functions of a rebooting zone, which keeps them.
+pub fn pci_zone_reset(zone: &Arc<RwLock<Zone>>) {
+    zone.write()
+        .vpci_bus
+        .devs()
+        .values()
+        .filter(|dev| is_movable(dev))
+        .for_each(|dev| dev.read().clear_bus_master());
+}
+
+/// Reset the functions of a zone being shut down and make them free, so no
+/// DMA the zone set up outlives it. The cpus of the zone must be stopped.
+pub fn pci_zone_remove(zone: &Arc<RwLock<Zone>>) {
+    let devs: Vec<_> = {
+        let mut zone_w = zone.write();
+        let vbdfs: Vec<_> = zone_w
+            .vpci_bus
+            .devs()
+            .iter()
+            .filter(|(_, dev)| is_movable(dev))
+            .map(|(vbdf, _)| *vbdf)
+            .collect();
+        vbdfs
+            .iter()
+            .filter_map(|vbdf| zone_w.vpci_bus.remove(vbdf))
+            .collect()
+    };
+    for dev in devs {
+        info!("zone {}: reset pci {:#x?}", zone.read().id, dev.get_bdf());
+        release(dev);
+    }
+}
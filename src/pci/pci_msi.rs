@@ -0,0 +1,530 @@
+// Copyright (c) 2025 Syswonder
+// hvisor is licensed under Mulan PSL v2.
+// You can use this software according to the terms and conditions of the Mulan PSL v2.
+// You may obtain a copy of Mulan PSL v2 at:
+//     http://license.coscl.org.cn/MulanPSL2
+// THIS SOFTWARE IS PROVIDED ON AN "AS IS" BASIS, WITHOUT WARRANTIES OF ANY KIND, EITHER
+// EXPRESS OR IMPLIED, INCLUDING BUT NOT LIMITED TO NON-INFRINGEMENT, MERCHANTABILITY OR
+// FIT FOR A PARTICULAR PURPOSE.
+// See the Mulan PSL v2 for more details.
+//
+// Syswonder Website:
+//      https://www.syswonder.org
+//
+// Authors:
+//
+
+//! MSI and MSI-X of passthrough endpoints.
+//!
+//! A zone only ever programs the guest's view of the capability and of the
+//! MSI-X table. A message reaches the device once the zone's interrupt
+//! controller accepted it, see `Zone::virqc_msi_translate`, otherwise the
+//! vector stays disabled or masked in hardware.
+//!
+//! This only covers what the zone writes to the device, a device can still
+//! write other messages to a doorbell by DMA. The GIC ITS at least ties them
+//! to the device's own DeviceID. On x86_64, without VT-d interrupt remapping,
+//! they reach whatever LAPIC they name, so only the root zone's devices get
+//! MSIs there.
+//!
+//! A multi-message MSI block is only enabled if its vectors translate to one
+//! address and consecutive data, as the device derives them from the first.
+
+use alloc::{collections::btree_map::BTreeMap, sync::Arc, vec, vec::Vec};
+use bit_field::BitField;
+use core::ops::Range;
+use core::ptr;
+use spin::Mutex;
+
+use super::pci_access::PciRW;
+use super::pci_struct::PciCapabilityRegion;
+use super::PciConfigAddress;
+use crate::cpu_data::this_zone;
+use crate::error::HvResult;
+use crate::memory::addr::{align_down, align_up};
+use crate::memory::{mmio_perform_access, GuestPhysAddr, HostPhysAddr, MMIOAccess};
+use crate::zone::{this_zone_id, Zone};
+
+const MSI_CTRL: PciConfigAddress = 0x02;
+const MSI_ADDRESS_LO: PciConfigAddress = 0x04;
+const MSI_ADDRESS_HI: PciConfigAddress = 0x08;
+const MSI_CTRL_ENABLE: usize = 0;
+const MSI_CTRL_MME: Range<usize> = 4..7;
+const MSI_CTRL_64BIT: usize = 7;
+const MSI_CTRL_PVM: usize = 8;
+
+const MSIX_CTRL: PciConfigAddress = 0x02;
+const MSIX_TABLE: PciConfigAddress = 0x04;
+const MSIX_CAP_SIZE: usize = 12;
+const MSIX_CTRL_TABLE_SIZE: Range<usize> = 0..11;
+const MSIX_CTRL_ENABLE: usize = 15;
+const MSIX_ENTRY_SIZE: usize = 16;
+const MSIX_ENTRY_CTRL: usize = 3;
+const MSIX_ENTRY_CTRL_MASKBIT: usize = 0;
+
+/// Merge a `size` byte write at byte `shift` of a dword into `old`.
+fn merge_dword(old: u32, offset: PciConfigAddress, size: usize, value: u32) -> u32 {
+    let shift = (offset & 0x3) * 8;
+    let mask = if size >= 4 {
+        u32::MAX
+    } else {
+        ((1u32 << (size * 8)) - 1) << shift
+    };
+    (old & !mask) | ((value << shift) & mask)
+}
+
+fn extract(dword: u32, offset: PciConfigAddress, size: usize) -> u32 {
+    let value = dword >> ((offset & 0x3) * 8);
+    if size >= 4 {
+        value
+    } else {
+        value & ((1u32 << (size * 8)) - 1)
+    }
+}
+
+/// Whether a device can send `messages`, the translated vectors of an MSI
+/// block: it only changes the low bits of the first one's data.
+fn is_msi_block(messages: &[(u64, u32)]) -> bool {
+    let (address, data) = messages[0];
+    data as usize % messages.len() == 0
+        && messages
+            .iter()
+            .enumerate()
+            .all(|(i, &message)| message == (address, data + i as u32))
+}
+
+/// MSI capability, the guest's enable, message count, address and data are
+/// kept here and only written to hardware after translation. Per-vector mask
+/// and pending bits pass through.
+pub struct MsiCapabilityRegion {
+    offset: PciConfigAddress,
+    backend: Arc<dyn PciRW>,
+    is_64bit: bool,
+    has_mask: bool,
+    ctrl: u16,
+    address: u64,
+    data: u16,
+}
+
+impl MsiCapabilityRegion {
+    pub fn new(offset: PciConfigAddress, backend: Arc<dyn PciRW>) -> Self {
+        let mut ctrl = backend.read(offset + MSI_CTRL, 2).unwrap_or(0) as u16;
+        if ctrl.get_bit(MSI_CTRL_ENABLE) {
+            ctrl.set_bit(MSI_CTRL_ENABLE, false);
+            let _ = backend.write(offset + MSI_CTRL, 2, ctrl as usize);
+        }
+        Self {
+            offset,
+            backend,
+            is_64bit: ctrl.get_bit(MSI_CTRL_64BIT),
+            has_mask: ctrl.get_bit(MSI_CTRL_PVM),
+            ctrl: 0,
+            address: 0,
+            data: 0,
+        }
+    }
+
+    fn data_reg(&self) -> PciConfigAddress {
+        if self.is_64bit {
+            0x0c
+        } else {
+            0x08
+        }
+    }
+
+    fn hw_ctrl(&self) -> HvResult<u16> {
+        let mut ctrl = self.backend.read(self.offset + MSI_CTRL, 2)? as u16;
+        ctrl.set_bit(MSI_CTRL_ENABLE, self.ctrl.get_bit(MSI_CTRL_ENABLE));
+        ctrl.set_bits(MSI_CTRL_MME, self.ctrl.get_bits(MSI_CTRL_MME));
+        Ok(ctrl)
+    }
+
+    fn read_dword(&self, reg: PciConfigAddress) -> HvResult<u32> {
+        Ok(match reg {
+            0 => {
+                let id = self.backend.read(self.offset, 2)? as u32;
+                id | ((self.hw_ctrl()? as u32) << 16)
+            }
+            MSI_ADDRESS_LO => self.address as u32,
+            MSI_ADDRESS_HI if self.is_64bit => (self.address >> 32) as u32,
+            reg if reg == self.data_reg() => self.data as u32,
+            reg => self.backend.read(self.offset + reg, 4)? as u32,
+        })
+    }
+
+    /// Write the guest's message to hardware, or leave MSI disabled if any
+    /// vector of the block does not belong to this zone.
+    fn sync(&self) -> HvResult {
+        let mut ctrl = self.hw_ctrl()?;
+        ctrl.set_bit(MSI_CTRL_ENABLE, false);
+        self.backend
+            .write(self.offset + MSI_CTRL, 2, ctrl as usize)?;
+        if !self.ctrl.get_bit(MSI_CTRL_ENABLE) {
+            return Ok(());
+        }
+
+        let vectors = 1u32 << self.ctrl.get_bits(MSI_CTRL_MME);
+        let zone = this_zone();
+        let zone = zone.read();
+        let messages = (0..vectors)
+            .map(|i| zone.virqc_msi_translate(self.address, self.data as u32 + i))
+            .collect::<Option<Vec<_>>>();
+        drop(zone);
+        let (address, data) = match messages
+            .filter(|messages| is_msi_block(messages))
+            .and_then(|messages| messages.first().copied())
+            .filter(|&(address, _)| self.is_64bit || address >> 32 == 0)
+        {
+            Some(message) => message,
+            None => {
+                warn!(
+                    "msi at {:#x}: message {:#x}/{:#x} not owned by zone or not contiguous, left disabled",
+                    self.offset, self.address, self.data
+                );
+                return Ok(());
+            }
+        };
+
+        self.backend
+            .write(self.offset + MSI_ADDRESS_LO, 4, address as u32 as usize)?;
+        if self.is_64bit {
+            self.backend
+                .write(self.offset + MSI_ADDRESS_HI, 4, (address >> 32) as usize)?;
+        }
+        self.backend
+            .write(self.offset + self.data_reg(), 2, data as u16 as usize)?;
+        ctrl.set_bit(MSI_CTRL_ENABLE, true);
+        self.backend.write(self.offset + MSI_CTRL, 2, ctrl as usize)
+    }
+}
+
+impl PciCapabilityRegion for MsiCapabilityRegion {
+    fn read(&self, offset: PciConfigAddress, size: usize) -> HvResult<u32> {
+        let dword = self.read_dword(offset & !0x3)?;
+        Ok(extract(dword, offset, size))
+    }
+
+    fn write(&mut self, offset: PciConfigAddress, size: usize, value: u32) -> HvResult {
+        let reg = offset & !0x3;
+        if self.has_mask && reg > self.data_reg() {
+            // mask and pending bits
+            return self
+                .backend
+                .write(self.offset + offset, size, value as usize);
+        }
+        let dword = merge_dword(self.read_dword(reg)?, offset, size, value);
+        match reg {
+            0 => {
+                let ctrl = (dword >> 16) as u16;
+                self.ctrl
+                    .set_bit(MSI_CTRL_ENABLE, ctrl.get_bit(MSI_CTRL_ENABLE));
+                self.ctrl
+                    .set_bits(MSI_CTRL_MME, ctrl.get_bits(MSI_CTRL_MME));
+            }
+            MSI_ADDRESS_LO => {
+                self.address.set_bits(0..32, (dword & !0x3) as u64);
+            }
+            MSI_ADDRESS_HI if self.is_64bit => {
+                self.address.set_bits(32..64, dword as u64);
+            }
+            reg if reg == self.data_reg() => self.data = dword as u16,
+            _ => return Ok(()),
+        }
+        self.sync()
+    }
+
+    fn get_offset(&self) -> PciConfigAddress {
+        self.offset
+    }
+
+    fn get_size(&self) -> usize {
+        self.data_reg() as usize + if self.has_mask { 12 } else { 4 }
+    }
+}
+
+/// MSI-X state of an endpoint, shared by its capability and the trapped
+/// pages of its table.
+pub struct Msix {
+    cap_offset: PciConfigAddress,
+    backend: Arc<dyn PciRW>,
+    bir: usize,
+    table_offset: usize,
+    /// Enable bit as the guest wrote it.
+    enable: bool,
+    /// Table as the guest wrote it, address low, address high, data and
+    /// vector control of each entry.
+    entries: Vec<[u32; 4]>,
+    /// Host address of the table once the guest mapped its BAR.
+    hpa: Option<HostPhysAddr>,
+}
+
+impl Msix {
+    pub fn new(cap_offset: PciConfigAddress, backend: Arc<dyn PciRW>) -> Self {
+        let mut ctrl = backend.read(cap_offset + MSIX_CTRL, 2).unwrap_or(0) as u16;
+        let table = backend.read(cap_offset + MSIX_TABLE, 4).unwrap_or(0) as u32;
+        if ctrl.get_bit(MSIX_CTRL_ENABLE) {
+            ctrl.set_bit(MSIX_CTRL_ENABLE, false);
+            let _ = backend.write(cap_offset + MSIX_CTRL, 2, ctrl as usize);
+        }
+        let mut entry = [0u32; 4];
+        entry[MSIX_ENTRY_CTRL].set_bit(MSIX_ENTRY_CTRL_MASKBIT, true);
+        Self {
+            cap_offset,
+            backend,
+            bir: table.get_bits(0..3) as usize,
+            table_offset: (table & !0x7) as usize,
+            enable: false,
+            entries: vec![entry; ctrl.get_bits(MSIX_CTRL_TABLE_SIZE) as usize + 1],
+            hpa: None,
+        }
+    }
+
+    /// BAR holding the table.
+    pub fn bir(&self) -> usize {
+        self.bir
+    }
+
+    /// Offset and size of the pages of the table within its BAR.
+    pub fn window(&self) -> (usize, usize) {
+        let start = align_down(self.table_offset);
+        let end = align_up(self.table_offset + self.entries.len() * MSIX_ENTRY_SIZE);
+        (start, end - start)
+    }
+
+    fn hw_ctrl(&self) -> HvResult<u16> {
+        let mut ctrl = self.backend.read(self.cap_offset + MSIX_CTRL, 2)? as u16;
+        ctrl.set_bit(MSIX_CTRL_ENABLE, self.enable);
+        Ok(ctrl)
+    }
+
+    /// Hardware is only enabled once every entry has been written by us.
+    fn sync_ctrl(&self) -> HvResult {
+        let mut ctrl = self.hw_ctrl()?;
+        ctrl.set_bit(MSIX_CTRL_ENABLE, self.enable && self.hpa.is_some());
+        self.backend
+            .write(self.cap_offset + MSIX_CTRL, 2, ctrl as usize)
+    }
+
+    fn sync_entry(&self, zone: &Zone, index: usize) {
+        let Some(hpa) = self.hpa else {
+            return;
+        };
+        let hw = (hpa + index * MSIX_ENTRY_SIZE) as *mut u32;
+        let [lo, hi, data, ctrl] = self.entries[index];
+        let masked = ctrl.get_bit(MSIX_ENTRY_CTRL_MASKBIT);
+        let message = zone.virqc_msi_translate(((hi as u64) << 32) | lo as u64, data);
+        unsafe {
+            // masked while the message changes
+            ptr::write_volatile(hw.add(MSIX_ENTRY_CTRL), 1 << MSIX_ENTRY_CTRL_MASKBIT);
+            if let Some((address, data)) = message {
+                ptr::write_volatile(hw, address as u32);
+                ptr::write_volatile(hw.add(1), (address >> 32) as u32);
+                ptr::write_volatile(hw.add(2), data);
+                if !masked {
+                    ptr::write_volatile(hw.add(MSIX_ENTRY_CTRL), 0);
+                }
+            }
+        }
+        if message.is_none() && !masked {
+            warn!(
+                "msi-x entry {}: message {:#x}/{:#x} not owned by zone {}, left masked",
+                index,
+                ((hi as u64) << 32) | lo as u64,
+                data,
+                zone.id
+            );
+        }
+    }
+
+    /// The guest moved the BAR holding the table to host address `hpa`.
+    fn set_hpa(&mut self, zone: &Zone, hpa: HostPhysAddr) -> HvResult {
+        if self.hpa == Some(hpa) {
+            return Ok(());
+        }
+        self.hpa = Some(hpa);
+        for index in 0..self.entries.len() {
+            self.sync_entry(zone, index);
+        }
+        self.sync_ctrl()
+    }
+
+    /// Drop what the guest programmed, the device is left with MSI-X
+    /// disabled and every entry masked. The table stays where it is.
+    fn reset_guest(&mut self) {
+        self.enable = false;
+        let _ = self.sync_ctrl();
+        for entry in self.entries.iter_mut() {
+            *entry = [0, 0, 0, 1 << MSIX_ENTRY_CTRL_MASKBIT];
+        }
+        if let Some(hpa) = self.hpa {
+            for index in 0..self.entries.len() {
+                let hw = (hpa + index * MSIX_ENTRY_SIZE) as *mut u32;
+                unsafe {
+                    ptr::write_volatile(hw.add(MSIX_ENTRY_CTRL), 1 << MSIX_ENTRY_CTRL_MASKBIT)
+                };
+            }
+        }
+    }
+
+    /// Drop the zone's programming and forget where the table is.
+    fn reset(&mut self) {
+        self.reset_guest();
+        self.hpa = None;
+    }
+
+    fn table_access(&mut self, zone: &Zone, mmio: &mut MMIOAccess, hpa: HostPhysAddr) {
+        let table = self.window().0 + mmio.address;
+        let in_table = table >= self.table_offset
+            && table + mmio.size <= self.table_offset + self.entries.len() * MSIX_ENTRY_SIZE;
+        if !in_table || !(mmio.size == 4 || mmio.size == 8) || table % mmio.size != 0 {
+            // pending bits or whatever else shares the pages
+            mmio_perform_access(hpa - (self.table_offset - self.window().0), mmio);
+            return;
+        }
+
+        let index = (table - self.table_offset) / MSIX_ENTRY_SIZE;
+        let reg = (table - self.table_offset) % MSIX_ENTRY_SIZE / 4;
+        let dwords = mmio.size / 4;
+        if mmio.is_write {
+            for i in 0..dwords {
+                self.entries[index][reg + i] = (mmio.value >> (32 * i)) as u32;
+            }
+            self.sync_entry(zone, index);
+        } else {
+            mmio.value = (0..dwords)
+                .map(|i| (self.entries[index][reg + i] as usize) << (32 * i))
+                .sum();
+        }
+    }
+}
+
+/// MSI-X capability, only the enable bit is virtualized.
+pub struct MsixCapabilityRegion {
+    msix: Arc<Mutex<Msix>>,
+}
+
+impl MsixCapabilityRegion {
+    pub fn new(msix: Arc<Mutex<Msix>>) -> Self {
+        Self { msix }
+    }
+}
+
+impl PciCapabilityRegion for MsixCapabilityRegion {
+    fn read(&self, offset: PciConfigAddress, size: usize) -> HvResult<u32> {
+        let msix = self.msix.lock();
+        let reg = offset & !0x3;
+        let dword = if reg == 0 {
+            let id = msix.backend.read(msix.cap_offset, 2)? as u32;
+            id | ((msix.hw_ctrl()? as u32) << 16)
+        } else {
+            msix.backend.read(msix.cap_offset + reg, 4)? as u32
+        };
+        Ok(extract(dword, offset, size))
+    }
+
+    fn write(&mut self, offset: PciConfigAddress, size: usize, value: u32) -> HvResult {
+        let mut msix = self.msix.lock();
+        if offset & !0x3 != 0 {
+            // table and pba locations are read-only
+            return Ok(());
+        }
+        let old = (msix.hw_ctrl()? as u32) << 16;
+        let ctrl = (merge_dword(old, offset, size, value) >> 16) as u16;
+        msix.enable = ctrl.get_bit(MSIX_CTRL_ENABLE);
+        let mut hw_ctrl = ctrl;
+        hw_ctrl.set_bit(MSIX_CTRL_ENABLE, msix.enable && msix.hpa.is_some());
+        msix.backend
+            .write(msix.cap_offset + MSIX_CTRL, 2, hw_ctrl as usize)
+    }
+
+    fn get_offset(&self) -> PciConfigAddress {
+        self.msix.lock().cap_offset
+    }
+
+    fn get_size(&self) -> usize {
+        MSIX_CAP_SIZE
+    }
+}
+
+/// Trapped table pages, keyed by zone and guest address of the pages.
+static MSIX_WINDOWS: Mutex<BTreeMap<(usize, GuestPhysAddr), Arc<Mutex<Msix>>>> =
+    Mutex::new(BTreeMap::new());
+
+/// Trap the table pages of a BAR the guest moved from `old_bar` to `new_bar`,
+/// the BAR itself is at `bar_hpa`. The caller maps the rest of the BAR.
+pub fn msix_map(
+    zone: &mut Zone,
+    msix: &Arc<Mutex<Msix>>,
+    old_bar: GuestPhysAddr,
+    new_bar: GuestPhysAddr,
+    bar_hpa: HostPhysAddr,
+) -> HvResult {
+    let (start, size) = msix.lock().window();
+    let mut windows = MSIX_WINDOWS.lock();
+    if windows.remove(&(zone.id, old_bar + start)).is_some() {
+        zone.mmio_region_remove(old_bar + start);
+    }
+    windows.insert((zone.id, new_bar + start), msix.clone());
+    drop(windows);
+    zone.mmio_region_register(new_bar + start, size, mmio_msix_handler, new_bar + start);
+    let mut msix = msix.lock();
+    let hpa = bar_hpa + msix.table_offset;
+    msix.set_hpa(zone, hpa)
+}
+
+pub fn mmio_msix_handler(mmio: &mut MMIOAccess, base: usize) -> HvResult {
+    let msix = match MSIX_WINDOWS.lock().get(&(this_zone_id(), base)) {
+        Some(msix) => msix.clone(),
+        None => return hv_result_err!(ENODEV, format!("no msi-x table at {:#x}", base)),
+    };
+    let zone = this_zone();
+    let zone = zone.read();
+    let mut msix = msix.lock();
+    match msix.hpa {
+        Some(hpa) => msix.table_access(&zone, mmio, hpa),
+        None => return hv_result_err!(ENODEV),
+    }
+    Ok(())
+}
+
+/// Stop trapping the table of a device taken away from `zone` and disable
+/// its MSI-X.
+pub fn msix_unmap(zone: &mut Zone, msix: &Arc<Mutex<Msix>>) {
+    let mut windows = MSIX_WINDOWS.lock();
+    let keys: Vec<_> = windows
+        .iter()
+        .filter(|((id, _), other)| *id == zone.id && Arc::ptr_eq(other, msix))
+        .map(|(key, _)| *key)
+        .collect();
+    for key in keys {
+        windows.remove(&key);
+        zone.mmio_region_remove(key.1);
+    }
+    drop(windows);
+    msix.lock().reset();
+}
+
+/// Disable the MSI-X of a zone that reboots. The tables stay trapped, the
+/// BARs keep their mapping across the reboot.
+pub fn msix_reset(zone_id: usize) {
+    MSIX_WINDOWS
+        .lock()
+        .iter()
+        .filter(|((id, _), _)| *id == zone_id)
+        .for_each(|(_, msix)| msix.lock().reset_guest());
+}
+
+/// Forget the tables of a zone being shut down and disable their MSI-X.
+pub fn msix_remove(zone_id: usize) {
+    let mut windows = MSIX_WINDOWS.lock();
+    let keys: Vec<_> = windows
+        .keys()
+        .filter(|(id, _)| *id == zone_id)
+        .copied()
+        .collect();
+    for key in keys {
+        if let Some(msix) = windows.remove(&key) {
+            msix.lock().reset();
+        }
+    }
+}
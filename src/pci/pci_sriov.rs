@@ -0,0 +1,154 @@
+// Copyright (c) 2025 Syswonder
+// hvisor is licensed under Mulan PSL v2.
+// You can use this software according to the terms and conditions of the Mulan PSL v2.
+// You may obtain a copy of Mulan PSL v2 at:
+//     http://license.coscl.org.cn/MulanPSL2
+// THIS SOFTWARE IS PROVIDED ON AN "AS IS" BASIS, WITHOUT WARRANTIES OF ANY KIND, EITHER
+// EXPRESS OR IMPLIED, INCLUDING BUT NOT LIMITED TO NON-INFRINGEMENT, MERCHANTABILITY OR
+// FIT FOR A PARTICULAR PURPOSE.
+// See the Mulan PSL v2 for more details.
+//
+// Syswonder Website:
+//      https://www.syswonder.org
+//
+// Authors:
+//
+
+//! SR-IOV physical functions owned by hvisor.
+//!
+//! A PF listed with `num_vfs` in the root zone's config gets its VFs enabled
+//! while the bus is enumerated. The VFs go to `GLOBAL_PCIE_LIST` like any
+//! other endpoint, so single VFs can be given to zones by `HvPciDevConfig`,
+//! each with its own requester id and thus its own IOMMU stream. The root
+//! zone keeps the PF, but no zone sees its SR-IOV capability any more.
+
+use alloc::sync::Arc;
+use bit_field::BitField;
+
+use super::config_accessors::{PciConfigMmio, PciRegion};
+use super::pci_access::{Bar, PciBarRW, PciRW, PciRWBase};
+use super::pci_ext_cap::{ext_capability_walk, PCI_EXT_CAP_ID_SRIOV};
+use super::pci_struct::{Bdf, CONFIG_LENTH};
+use super::PciConfigAddress;
+
+const SRIOV_CTRL: PciConfigAddress = 0x08;
+const SRIOV_CTRL_VF_ENABLE: usize = 0;
+const SRIOV_CTRL_VF_MSE: usize = 3;
+const SRIOV_TOTAL_VFS: PciConfigAddress = 0x0e;
+const SRIOV_NUM_VFS: PciConfigAddress = 0x10;
+const SRIOV_VF_OFFSET: PciConfigAddress = 0x14;
+const SRIOV_VF_STRIDE: PciConfigAddress = 0x16;
+const SRIOV_VF_DEVICE_ID: PciConfigAddress = 0x1a;
+const SRIOV_SUPPORTED_PAGE_SIZES: PciConfigAddress = 0x1c;
+const SRIOV_SYSTEM_PAGE_SIZE: PciConfigAddress = 0x20;
+const SRIOV_VF_BAR0: PciConfigAddress = 0x24;
+
+/// VFs must not be accessed for 100ms after VF Enable is set.
+const SRIOV_VF_ENABLE_DELAY_US: u64 = 100_000;
+
+/// The VF BARs of the SR-IOV capability, laid out like the BARs of a type 0
+/// header so `PciBarRW::parse_bar` can size them.
+#[derive(Debug)]
+struct VfBars(PciConfigMmio);
+
+impl PciRWBase for VfBars {
+    fn backend(&self) -> &dyn PciRegion {
+        &self.0
+    }
+}
+impl PciBarRW for VfBars {
+    fn bar_limit(&self) -> u8 {
+        6
+    }
+}
+
+pub struct SriovCap {
+    backend: Arc<dyn PciRW>,
+    /// Config space address of the PF.
+    pf_address: PciConfigAddress,
+    offset: PciConfigAddress,
+}
+
+impl SriovCap {
+    pub fn new(backend: Arc<dyn PciRW>, pf_address: PciConfigAddress) -> Option<Self> {
+        let (offset, _, _) = ext_capability_walk(&backend)
+            .into_iter()
+            .find(|&(_, id, _)| id == PCI_EXT_CAP_ID_SRIOV)?;
+        Some(Self {
+            backend,
+            pf_address,
+            offset,
+        })
+    }
+
+    fn read(&self, reg: PciConfigAddress, size: usize) -> usize {
+        self.backend.read(self.offset + reg, size).unwrap_or(0)
+    }
+
+    fn write(&self, reg: PciConfigAddress, size: usize, value: usize) {
+        let _ = self.backend.write(self.offset + reg, size, value);
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.read(SRIOV_CTRL, 2).get_bit(SRIOV_CTRL_VF_ENABLE)
+    }
+
+    /// Ask for `num_vfs` VFs with 4K pages, returns how many the PF offers.
+    pub fn set_num_vfs(&self, num_vfs: u16) -> u16 {
+        let num_vfs = num_vfs.min(self.read(SRIOV_TOTAL_VFS, 2) as u16);
+        if self.read(SRIOV_SUPPORTED_PAGE_SIZES, 4).get_bit(0) {
+            self.write(SRIOV_SYSTEM_PAGE_SIZE, 4, 1);
+        }
+        self.write(SRIOV_NUM_VFS, 2, num_vfs as usize);
+        num_vfs
+    }
+
+    /// Only valid once NumVFs is set, offset and stride depend on it.
+    pub fn vf_bdf(&self, pf: Bdf, index: u16) -> Bdf {
+        let pf_rid = ((pf.bus() as u16) << 8) | ((pf.device() as u16) << 3) | pf.function() as u16;
+        let offset = self.read(SRIOV_VF_OFFSET, 2) as u16;
+        let stride = self.read(SRIOV_VF_STRIDE, 2) as u16;
+        let rid = pf_rid
+            .wrapping_add(offset)
+            .wrapping_add(stride.wrapping_mul(index));
+        Bdf::new(
+            pf.domain(),
+            rid.get_bits(8..16) as u8,
+            rid.get_bits(3..8) as u8,
+            rid.get_bits(0..3) as u8,
+        )
+    }
+
+    pub fn vf_device_id(&self) -> u16 {
+        self.read(SRIOV_VF_DEVICE_ID, 2) as u16
+    }
+
+    /// BARs of VF 0, VF `n` sits `n * size` above.
+    pub fn vf_bars(&self) -> Bar {
+        VfBars(self.vf_bar_region()).parse_bar()
+    }
+
+    pub fn write_vf_bar(&self, slot: u8, value: u32) {
+        let _ = VfBars(self.vf_bar_region()).write_bar(slot, value);
+    }
+
+    fn vf_bar_region(&self) -> PciConfigMmio {
+        // parse_bar finds BAR0 at 0x10
+        PciConfigMmio::new(
+            self.pf_address + self.offset + SRIOV_VF_BAR0 - 0x10,
+            CONFIG_LENTH,
+        )
+    }
+
+    /// Enable the VFs and their memory space, and wait until they answer.
+    pub fn enable(&self) {
+        let mut ctrl = self.read(SRIOV_CTRL, 2);
+        ctrl.set_bit(SRIOV_CTRL_VF_ENABLE, true);
+        ctrl.set_bit(SRIOV_CTRL_VF_MSE, true);
+        self.write(SRIOV_CTRL, 2, ctrl);
+        let start = crate::arch::time::get_time_us();
+        while crate::arch::time::get_time_us() - start < SRIOV_VF_ENABLE_DELAY_US {
+            core::hint::spin_loop();
+        }
+    }
+}
@@ -23,7 +23,7 @@ use core::{
     ops::{Deref, DerefMut, Range},
     str::FromStr,
 };
-use spin::RwLock;
+use spin::{Mutex, RwLock};
 
 use super::{
     config_accessors::{PciConfigAccessor, PciConfigMmio},
@@ -33,6 +33,13 @@ use super::{
         PciConfigHeader, PciField, PciHeaderRW, PciMem, PciMemType, PciRW, PciRomRW,
     },
     pci_access::{BaseClass, DeviceId, DeviceRevision, Interface, SubClass, VendorId},
+    pci_ext_cap::{
+        ext_capability_walk, ExtCapPolicy, ExtCapabilityRegion, PCI_EXT_CAP_END,
+        PCI_EXT_CAP_ID_SRIOV,
+    },
+    pci_hotplug::function_reset,
+    pci_msi::{MsiCapabilityRegion, Msix, MsixCapabilityRegion},
+    pci_sriov::SriovCap,
     PciConfigAddress,
 };
 
@@ -143,7 +150,7 @@ impl ConfigValue {
 const MAX_DEVICE: u8 = 31;
 const MAX_FUNCTION: u8 = 7;
 pub const CONFIG_LENTH: u64 = 256;
-pub const BIT_LENTH: usize = 128 * 8;
+pub const BIT_LENTH: usize = PCI_EXT_CAP_END as usize;
 
 // PCIe Device/Port Type values
 const PCI_EXP_TYPE_ROOT_PORT: u16 = 4;
@@ -363,6 +370,7 @@ pub struct VirtualPciConfigSpace {
     bararr: Bar,
     rom: PciMem,
     capabilities: PciCapabilityList,
+    msix: Option<Arc<Mutex<Msix>>>,
 
     dev_type: VpciDevType,
 }
@@ -395,6 +403,10 @@ impl ArcRwLockVirtualPciConfigSpace {
         self.0.read().get_dev_type()
     }
 
+    pub fn vfs_enabled(&self) -> bool {
+        self.0.read().vfs_enabled()
+    }
+
     pub fn get_config_type(&self) -> HeaderType {
         self.0.read().get_config_type()
     }
@@ -490,6 +502,11 @@ impl ArcRwLockVirtualPciConfigSpace {
         f(rom)
     }
 
+    /// MSI-X state of the device, if it has the capability
+    pub fn msix(&self) -> Option<Arc<Mutex<Msix>>> {
+        self.0.read().msix.clone()
+    }
+
     /// Execute a closure with a reference to the capabilities list
     pub fn with_cap<F, R>(&self, f: F) -> R
     where
@@ -585,6 +602,17 @@ impl VirtualPciConfigSpace {
         self.dev_type
     }
 
+    /// Whether this is a PF with its VFs enabled, which a function reset
+    /// would take away.
+    pub fn vfs_enabled(&self) -> bool {
+        ext_capability_walk(&self.backend)
+            .iter()
+            .any(|&(offset, id, _)| {
+                id == PCI_EXT_CAP_ID_SRIOV
+                    && self.backend.read(offset + 0x08, 2).unwrap_or(0).get_bit(0)
+            })
+    }
+
     pub fn get_config_value(&self) -> &ConfigValue {
         &self.config_value
     }
@@ -670,6 +698,7 @@ impl VirtualPciConfigSpace {
             bararr,
             rom: PciMem::default(),
             capabilities: PciCapabilityList::new(),
+            msix: None,
             dev_type,
         }
     }
@@ -700,6 +729,7 @@ impl VirtualPciConfigSpace {
             bararr,
             rom,
             capabilities: PciCapabilityList::new(),
+            msix: None,
             dev_type: VpciDevType::Physical,
         }
     }
@@ -727,6 +757,7 @@ impl VirtualPciConfigSpace {
             bararr,
             rom,
             capabilities: PciCapabilityList::new(),
+            msix: None,
             dev_type: VpciDevType::Physical,
         }
     }
@@ -752,6 +783,7 @@ impl VirtualPciConfigSpace {
             bararr: Bar::default(),
             rom: PciMem::default(),
             capabilities: PciCapabilityList::new(),
+            msix: None,
             dev_type: VpciDevType::Physical,
         }
     }
@@ -776,6 +808,7 @@ impl VirtualPciConfigSpace {
             bararr: Bar::default(),
             rom: PciMem::default(),
             capabilities: PciCapabilityList::new(),
+            msix: None,
             dev_type: VpciDevType::Physical,
         }
     }
@@ -941,9 +974,19 @@ pub struct PciIterator<B: BarAllocator> {
     is_mulitple_function: bool,
     is_finish: bool,
     accessor: Arc<dyn PciConfigAccessor>,
+    /// VFs to enable per PF, see pci_sriov
+    num_vfs: BTreeMap<Bdf, u16>,
+    /// VFs of the last PF, returned before enumeration goes on
+    vfs: Vec<VirtualPciConfigSpace>,
 }
 
 impl<B: BarAllocator> PciIterator<B> {
+    /// Enable `num_vfs` VFs of each listed PF and enumerate them too.
+    pub fn with_vfs(mut self, num_vfs: BTreeMap<Bdf, u16>) -> Self {
+        self.num_vfs = num_vfs;
+        self
+    }
+
     fn get_pci_addr_base(&self, bdf: Bdf) -> PciConfigAddress {
         match self.accessor.get_pci_addr_base(bdf) {
             Ok(addr) => addr,
@@ -1055,6 +1098,10 @@ impl<B: BarAllocator> PciIterator<B> {
 
                 let _ = node.capability_enumerate();
 
+                if let Some(&num_vfs) = self.num_vfs.get(&bdf) {
+                    self.sriov_init(bdf, address, parent_bus, num_vfs);
+                }
+
                 Some(node)
             }
             HeaderType::PciBridge => {
@@ -1094,6 +1141,103 @@ impl<B: BarAllocator> PciIterator<B> {
         }
     }
 
+    fn sriov_init(&mut self, pf: Bdf, pf_address: PciConfigAddress, parent_bus: u8, num_vfs: u16) {
+        let backend: Arc<dyn PciRW> = Arc::new(EndpointHeader::new_with_region(
+            PciConfigMmio::new(pf_address, CONFIG_LENTH),
+        ));
+        let Some(sriov) = SriovCap::new(backend.clone(), pf_address) else {
+            warn!("{:#?}: no sr-iov capability, no vfs", pf);
+            return;
+        };
+        if sriov.is_enabled() {
+            warn!("{:#?}: vfs already enabled", pf);
+            return;
+        }
+        let num_vfs = sriov.set_num_vfs(num_vfs);
+        if num_vfs == 0 {
+            return;
+        }
+
+        // each vf bar is a slice of one window per bar
+        let mut vf_bars = sriov.vf_bars();
+        if let Some(a) = &mut self.allocator {
+            let mut i = 0;
+            while i < 6 {
+                let size = vf_bars[i].get_size();
+                let window = (size * num_vfs as u64).next_power_of_two();
+                let value = match vf_bars[i].get_type() {
+                    PciMemType::Mem32 => a.alloc_memory32(window),
+                    PciMemType::Mem64Low => a.alloc_memory64(window),
+                    _ => {
+                        i += 1;
+                        continue;
+                    }
+                };
+                let Some(value) = value else {
+                    warn!("{:#?}: no space for vf bar {}", pf, i);
+                    sriov.set_num_vfs(0);
+                    return;
+                };
+                vf_bars[i].set_value(value);
+                sriov.write_vf_bar(i as u8, value as u32);
+                if vf_bars[i].get_type() == PciMemType::Mem64Low {
+                    i += 1;
+                    vf_bars[i].set_value(value);
+                    sriov.write_vf_bar(i as u8, (value >> 32) as u32);
+                }
+                i += 1;
+            }
+        }
+        // without an allocator the vf bars keep what the firmware assigned
+        let unassigned = (0..6).find(|&i| {
+            matches!(
+                vf_bars[i].get_type(),
+                PciMemType::Mem32 | PciMemType::Mem64Low
+            ) && vf_bars[i].get_value64() == 0
+        });
+        if let Some(i) = unassigned {
+            warn!("{:#?}: vf bar {} is not assigned, no vfs", pf, i);
+            sriov.set_num_vfs(0);
+            return;
+        }
+        sriov.enable();
+
+        let vendor_id =
+            PciConfigHeader::new_with_region(PciConfigMmio::new(pf_address, CONFIG_LENTH))
+                .id()
+                .1;
+        let device_id = sriov.vf_device_id();
+        for index in 0..num_vfs {
+            let bdf = sriov.vf_bdf(pf, index);
+            let region = PciConfigMmio::new(self.address(parent_bus, bdf), CONFIG_LENTH);
+            let ep = EndpointHeader::new_with_region(region);
+            let class_and_revision = ep.revision_and_class();
+
+            let mut bararr = vf_bars;
+            for bar in &mut bararr {
+                if bar.get_type() != PciMemType::Unused {
+                    let value = bar.get_value64() + bar.get_size() * index as u64;
+                    bar.set_value(value);
+                    bar.set_virtual_value(value);
+                }
+            }
+
+            let mut node = VirtualPciConfigSpace::endpoint(
+                bdf,
+                self.get_pci_addr_base(bdf),
+                Arc::new(ep),
+                bararr,
+                PciMem::default(),
+                class_and_revision,
+                (device_id, vendor_id),
+            );
+            let _ = node.capability_enumerate();
+            node.config_value_init();
+            info!("{:#?}: vf {} at {:#?}", pf, index, bdf);
+            self.vfs.push(node);
+        }
+    }
+
     fn rom_init<D: PciRomRW + PciHeaderRW>(dev: &mut D) -> PciMem {
         let mut rom = dev.parse_rom();
         if rom.get_type() == PciMemType::Rom {
@@ -1254,6 +1398,9 @@ impl<B: BarAllocator> Iterator for PciIterator<B> {
     type Item = VirtualPciConfigSpace;
 
     fn next(&mut self) -> Option<Self::Item> {
+        if !self.vfs.is_empty() {
+            return Some(self.vfs.remove(0));
+        }
         while !self.is_finish {
             if let Some(mut node) = self.get_node() {
                 node.config_value_init();
@@ -1279,6 +1426,10 @@ impl<B: BarAllocator> Iterator for PciIterator<B> {
                 let parent_bus = parent.primary_bus;
                 node.set_host_bdf(host_bdf);
                 node.set_parent_bdf(parent_bdf);
+                for vf in self.vfs.iter_mut() {
+                    vf.set_host_bdf(host_bdf);
+                    vf.set_parent_bdf(parent_bdf);
+                }
                 self.next(match node.config_value.get_class().0 {
                     // class code 0x6 is bridge and class.1 0x0 is host bridge
                     0x6 if node.config_value.get_class().1 == 0x4 => {
@@ -1417,6 +1568,8 @@ impl RootComplex {
             is_mulitple_function: false,
             is_finish: false,
             accessor: self.accessor.clone(), // accessor to iterator
+            num_vfs: BTreeMap::new(),
+            vfs: Vec::new(),
         }
     }
 
@@ -1456,6 +1609,12 @@ impl VirtualRootComplex {
             .insert(bdf, ArcRwLockVirtualPciConfigSpace::new(dev))
     }
 
+    pub fn remove(&mut self, bdf: &Bdf) -> Option<ArcRwLockVirtualPciConfigSpace> {
+        let dev = self.devs.remove(bdf)?;
+        self.base_to_bdf.remove(&dev.read().get_base());
+        Some(dev)
+    }
+
     pub fn devs(&mut self) -> &mut BTreeMap<Bdf, ArcRwLockVirtualPciConfigSpace> {
         &mut self.devs
     }
@@ -1566,6 +1725,8 @@ pub enum CapabilityType {
     PciExpress,
     // MSI-X capability, Cap ID = `0x11`
     MsiX,
+    // PCIe extended capability at 0x100 and above, by its 16-bit ID
+    Extended(u16),
     // Unknown capability
     Unknown,
 }
@@ -1591,6 +1752,7 @@ impl Debug for CapabilityType {
             CapabilityType::AGP3 => write!(f, "AGP3(0x0E)"),
             CapabilityType::PciExpress => write!(f, "PciExpress(0x10)"),
             CapabilityType::MsiX => write!(f, "MsiX(0x11)"),
+            CapabilityType::Extended(id) => write!(f, "Extended({:#06x})", id),
             CapabilityType::Unknown => write!(f, "Unknown(0x00)"),
         }
     }
@@ -1637,6 +1799,7 @@ impl CapabilityType {
             CapabilityType::AGP3 => 0x0E,
             CapabilityType::PciExpress => 0x10,
             CapabilityType::MsiX => 0x11,
+            CapabilityType::Extended(id) => *id as PciConfigAddress,
             CapabilityType::Unknown => 0x00,
         }
     }
@@ -1826,6 +1989,18 @@ impl Debug for PciCapabilityList {
     }
 }
 
+/// Extended capabilities carry no size, each one is taken to end where the
+/// next one in config space starts.
+fn ext_capability_size(caps: &[(PciConfigAddress, u16, u8)], offset: PciConfigAddress) -> usize {
+    let end = caps
+        .iter()
+        .map(|cap| cap.0)
+        .filter(|&other| other > offset)
+        .min()
+        .unwrap_or(PCI_EXT_CAP_END);
+    (end - offset) as usize
+}
+
 impl VirtualPciConfigSpace {
     fn _capability_enumerate(&self, backend: Arc<dyn PciRW>) -> CapabilityIterator {
         CapabilityIterator {
@@ -1836,19 +2011,199 @@ impl VirtualPciConfigSpace {
 
     pub fn capability_enumerate(&mut self) {
         let mut capabilities = PciCapabilityList::new();
-        for capability in self._capability_enumerate(self.backend.clone()) {
+        for mut capability in self._capability_enumerate(self.backend.clone()) {
+            let offset = capability.get_offset();
+            // msi and msi-x of endpoints are emulated, see pci_msi
             match capability.get_type() {
-                CapabilityType::Msi => {}
-                CapabilityType::MsiX => {}
+                CapabilityType::Msi if self.config_type == HeaderType::Endpoint => {
+                    capability = PciCapability::new_virt(
+                        CapabilityType::Msi,
+                        Arc::new(RwLock::new(MsiCapabilityRegion::new(
+                            offset,
+                            self.backend.clone(),
+                        ))),
+                    );
+                    let end = offset as usize + capability.get_size();
+                    self.access.set_bits(offset as usize..end);
+                }
+                CapabilityType::MsiX if self.config_type == HeaderType::Endpoint => {
+                    let msix = Arc::new(Mutex::new(Msix::new(offset, self.backend.clone())));
+                    capability = PciCapability::new_virt(
+                        CapabilityType::MsiX,
+                        Arc::new(RwLock::new(MsixCapabilityRegion::new(msix.clone()))),
+                    );
+                    let end = offset as usize + capability.get_size();
+                    self.access.set_bits(offset as usize..end);
+                    self.msix = Some(msix);
+                }
                 CapabilityType::PciExpress => {}
                 _ => {}
             }
-            capabilities.insert(capability.get_offset(), capability);
+            capabilities.insert(offset, capability);
+        }
+        if capabilities
+            .values()
+            .any(|capability| capability.get_type() == CapabilityType::PciExpress)
+        {
+            self.ext_capability_enumerate(&mut capabilities);
         }
         info!("capability {:#?}", capabilities);
         self.capabilities = capabilities;
     }
 
+    fn ext_capability_enumerate(&self, capabilities: &mut PciCapabilityList) {
+        let caps = ext_capability_walk(&self.backend);
+        for (offset, id, version) in &caps {
+            let region = ExtCapabilityRegion::new(
+                *offset,
+                ext_capability_size(&caps, *offset),
+                self.backend.clone(),
+                *id,
+                *version,
+                self.backend.read(*offset, 4).unwrap_or(0).get_bits(20..32) as PciConfigAddress,
+                ExtCapPolicy::Pass,
+            );
+            capabilities.insert(
+                *offset,
+                PciCapability::new_virt(
+                    CapabilityType::Extended(*id),
+                    Arc::new(RwLock::new(region)),
+                ),
+            );
+        }
+    }
+
+    /// Apply the extended capability policies of `config` to this copy of a
+    /// passthrough endpoint, before it is added to a zone.
+    pub fn set_ext_cap_policy(&mut self, config: &HvPciDevConfig) {
+        if self.config_type != HeaderType::Endpoint || self.dev_type != VpciDevType::Physical {
+            return;
+        }
+        let caps = ext_capability_walk(&self.backend);
+        if caps.is_empty() {
+            return;
+        }
+        let policies: Vec<_> = caps
+            .iter()
+            .map(|&(offset, id, _)| {
+                // hvisor owns the sr-iov capability of a pf whose vfs it enabled
+                if id == PCI_EXT_CAP_ID_SRIOV
+                    && self.backend.read(offset + 0x08, 2).unwrap_or(0).get_bit(0)
+                {
+                    ExtCapPolicy::Hide
+                } else {
+                    ExtCapPolicy::from_config(config, id)
+                }
+            })
+            .collect();
+        for (i, &(offset, id, version)) in caps.iter().enumerate() {
+            // the next capability the zone can see
+            let next = caps[i + 1..]
+                .iter()
+                .zip(&policies[i + 1..])
+                .find(|(_, policy)| **policy != ExtCapPolicy::Hide)
+                .map_or(0, |(cap, _)| cap.0);
+            let size = ext_capability_size(&caps, offset);
+            let policy = policies[i];
+            let region = ExtCapabilityRegion::new(
+                offset,
+                size,
+                self.backend.clone(),
+                id,
+                version,
+                next,
+                policy,
+            );
+            let emulated = if policy == ExtCapPolicy::Pass {
+                4
+            } else {
+                size
+            };
+            self.access
+                .set_bits(offset as usize..offset as usize + emulated);
+            if policy != ExtCapPolicy::Pass {
+                info!(
+                    "{:#?}: extended capability {:#06x} {:?}",
+                    self.bdf, id, policy
+                );
+            }
+            self.capabilities.insert(
+                offset,
+                PciCapability::new_virt(
+                    CapabilityType::Extended(id),
+                    Arc::new(RwLock::new(region)),
+                ),
+            );
+        }
+    }
+
+    fn clear_command(&self, bits: Range<usize>) {
+        let mut command = self.backend.read(0x04, 2).unwrap_or(0);
+        command.set_bits(bits, 0);
+        let _ = self.backend.write(0x04, 2, command);
+    }
+
+    /// Stop the DMA of a passthrough endpoint, the zone enables bus mastering
+    /// again once it has set the function up.
+    pub fn clear_bus_master(&self) {
+        if self.config_type == HeaderType::Endpoint && self.dev_type == VpciDevType::Physical {
+            self.clear_command(2..3);
+        }
+    }
+
+    /// Take a passthrough endpoint back from a zone: stop its DMA, reset the
+    /// function and reprogram the BARs hvisor assigned. What the zone wrote
+    /// to the BARs, MSI and MSI-X is dropped.
+    pub fn reclaim(&mut self) {
+        if self.config_type != HeaderType::Endpoint || self.dev_type != VpciDevType::Physical {
+            return;
+        }
+        // io space, memory space and bus master
+        self.clear_command(0..3);
+
+        let find = |cap_type| {
+            self.capabilities
+                .iter()
+                .find(|(_, capability)| capability.get_type() == cap_type)
+                .map(|(offset, _)| *offset)
+        };
+        let exp_offset = find(CapabilityType::PciExpress);
+        let pm_offset = find(CapabilityType::PowerManagement);
+        if self.vfs_enabled() {
+            warn!(
+                "{:#?}: vfs enabled, no function reset, only bus master is cleared",
+                self.bdf
+            );
+        } else if !function_reset(&self.backend, exp_offset, pm_offset) {
+            warn!(
+                "{:#?}: no function reset, only bus master is cleared",
+                self.bdf
+            );
+        }
+
+        for slot in 0..6 {
+            let bar = self.bararr[slot];
+            if bar.get_type() == PciMemType::Unused {
+                continue;
+            }
+            let _ = self.backend.write(
+                0x10 + slot as PciConfigAddress * 4,
+                4,
+                bar.get_value() as usize,
+            );
+            self.bararr[slot].set_virtual_value(0);
+        }
+        if self.rom.get_type() == PciMemType::Rom {
+            // rom decoding stays off until the next owner enables it
+            let _ = self
+                .backend
+                .write(0x30, 4, self.rom.get_value() as usize & !0x1);
+            self.rom.set_virtual_value(0);
+        }
+        self.config_value_init();
+        self.capability_enumerate();
+    }
+
     //TODO: check secondary link by read cap
     pub fn has_secondary_link(&self) -> bool {
         match self.config_type {
@@ -64,6 +64,7 @@ pub(crate) const DEFAULT_CSPACE_U32: [u32; STANDARD_CFG_SIZE / 4] = {
 };
 
 pub mod standard;
+pub mod virtio_pci;
 
 /*
  * PciConfigAccessStatus is used to return the result of the config space access
@@ -84,9 +85,22 @@ pub enum VpciDevType {
     #[default]
     Physical = 0,
     StandardVdev = 1,
+    VirtioPci = 2,
     // Add new device types here
 }
 
+impl VpciDevType {
+    /// The type with discriminant `value`, for configs read from memory.
+    pub fn from_u32(value: u32) -> Option<Self> {
+        match value {
+            0 => Some(Self::Physical),
+            1 => Some(Self::StandardVdev),
+            2 => Some(Self::VirtioPci),
+            _ => None,
+        }
+    }
+}
+
 pub trait VpciDeviceHandler: Sync + Send {
     fn read_cfg(
         &self,
@@ -110,8 +124,10 @@ pub trait VpciDeviceHandler: Sync + Send {
  * 1. Add the variant to VpciDevType enum above
  * 2. Add the handler registration here: (&module::HANDLER, VpciDevType::YourType)
  */
-static HANDLERS: &[(&dyn VpciDeviceHandler, VpciDevType)] =
-    &[(&standard::HANDLER, VpciDevType::StandardVdev)];
+static HANDLERS: &[(&dyn VpciDeviceHandler, VpciDevType)] = &[
+    (&standard::HANDLER, VpciDevType::StandardVdev),
+    (&virtio_pci::HANDLER, VpciDevType::VirtioPci),
+];
 
 pub(crate) fn get_handler(dev_type: VpciDevType) -> Option<&'static dyn VpciDeviceHandler> {
     HANDLERS
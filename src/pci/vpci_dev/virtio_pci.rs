@@ -0,0 +1,898 @@
+// Copyright (c) 2025 Syswonder
+// hvisor is licensed under Mulan PSL v2.
+// You can use this software according to the terms and conditions of the Mulan PSL v2.
+// You may obtain a copy of Mulan PSL v2 at:
+//     http://license.coscl.org.cn/MulanPSL2
+// THIS SOFTWARE IS PROVIDED ON AN "AS IS" BASIS, WITHOUT WARRANTIES OF ANY KIND, EITHER
+// EXPRESS OR IMPLIED, INCLUDING BUT NOT LIMITED TO NON-INFRINGEMENT, MERCHANTABILITY OR
+// FIT FOR A PARTICULAR PURPOSE.
+// See the Mulan PSL v2 for more details.
+//
+// Syswonder Website:
+//      https://www.syswonder.org
+//
+// Authors:
+//
+
+//! virtio-pci (modern) transport on top of the virtio bridge.
+//!
+//! The backend in the root zone does not need to know about PCI: hvisor
+//! translates every access to the device's BAR0 into the matching virtio-mmio
+//! register access and forwards it through the zone's virtio bridge. The
+//! device shows up to the backend as a virtio-mmio window at the ECAM address
+//! of its function, e.g. `ecam_base + (bdf << 12)`.
+//!
+//! BAR0 layout:
+//!
+//! ```text
+//! 0x0000  common config
+//! 0x1000  ISR status
+//! 0x2000  device config
+//! 0x3000  notify, one u32 per queue
+//! 0x3800  MSI-X table
+//! 0x3c00  MSI-X PBA
+//! ```
+//!
+//! Interrupts are MSI-X only. The backend pushes `VIRTIO_PCI_IRQ | bdf << 8 |
+//! queue` (`VIRTIO_PCI_IRQ_CONFIG` as queue for a config change) to the
+//! response ring instead of an irq number, and hvisor sends the message the
+//! guest programmed for that queue.
+//!
+//! The messages are only delivered on x86_64, through the virtual LAPIC.
+//! There is no vITS or vIMSIC to send them to on the other architectures, so
+//! zone configs with a virtio-pci device are refused there.
+
+use super::{PciConfigAccessStatus, VpciDeviceHandler};
+use crate::cpu_data::this_zone;
+use crate::device::virtio_mmio::{
+    CONFIG, CONFIG_GENERATION, DEVICE_FEATURES, DEVICE_FEATURES_SEL, DEVICE_ID, DRIVER_FEATURES,
+    DRIVER_FEATURES_SEL, INTERRUPT_ACK, INTERRUPT_STATUS, QUEUE_DESC_HIGH, QUEUE_DESC_LOW,
+    QUEUE_DEVICE_HIGH, QUEUE_DEVICE_LOW, QUEUE_DRIVER_HIGH, QUEUE_DRIVER_LOW, QUEUE_NOTIFY,
+    QUEUE_NUM, QUEUE_NUM_MAX, QUEUE_READY, QUEUE_SEL, STATUS, VIRTIO_MMIO_MAX_QUEUES,
+};
+use crate::device::virtio_trampoline::virtio_bridge_forward;
+use crate::error::HvResult;
+use crate::memory::{GuestPhysAddr, MMIOAccess};
+use crate::pci::pci_access::PciMemType;
+use crate::pci::pci_struct::{
+    ArcRwLockVirtualPciConfigSpace, Bdf, CapabilityType, PciCapability, PciCapabilityRegion,
+    VirtualPciConfigSpace,
+};
+use crate::pci::PciConfigAddress;
+use crate::zone::this_zone_id;
+use alloc::collections::BTreeMap;
+use alloc::sync::Arc;
+use bit_field::BitField;
+use spin::{Mutex, RwLock};
+
+/// Set in the `irq_id` of a response for a virtio-pci device.
+pub const VIRTIO_PCI_IRQ: u32 = 1 << 31;
+/// Queue number in a `VIRTIO_PCI_IRQ` response for a config change.
+pub const VIRTIO_PCI_IRQ_CONFIG: u32 = 0xff;
+
+const VIRTIO_PCI_VENDOR_ID: u32 = 0x1af4;
+/// Modern devices use 0x1040 + virtio device id.
+const VIRTIO_PCI_DEVICE_ID_BASE: u32 = 0x1040;
+const VIRTIO_PCI_REVISION: u32 = 1;
+const PCI_CLASS_OTHER: u32 = 0xff;
+
+const VIRTIO_PCI_CAP_COMMON_CFG: u8 = 1;
+const VIRTIO_PCI_CAP_NOTIFY_CFG: u8 = 2;
+const VIRTIO_PCI_CAP_ISR_CFG: u8 = 3;
+const VIRTIO_PCI_CAP_DEVICE_CFG: u8 = 4;
+const VIRTIO_MSI_NO_VECTOR: u16 = 0xffff;
+
+// Capabilities in config space
+const CAP_COMMON: PciConfigAddress = 0x40;
+const CAP_NOTIFY: PciConfigAddress = 0x50;
+const CAP_ISR: PciConfigAddress = 0x64;
+const CAP_DEVICE: PciConfigAddress = 0x74;
+const CAP_MSIX: PciConfigAddress = 0x84;
+const VIRTIO_CAP_LEN: usize = 16;
+const VIRTIO_NOTIFY_CAP_LEN: usize = 20;
+const MSIX_CAP_LEN: usize = 12;
+
+// Regions in BAR0
+const BAR_SIZE: usize = 0x4000;
+const COMMON_CFG: usize = 0x0000;
+const COMMON_CFG_LEN: usize = 0x38;
+const ISR_CFG: usize = 0x1000;
+const ISR_CFG_LEN: usize = 0x4;
+const DEVICE_CFG: usize = 0x2000;
+/// The rest of the backend's 4K virtio-mmio window after `CONFIG`.
+const DEVICE_CFG_LEN: usize = 0x1000 - CONFIG;
+const NOTIFY_CFG: usize = 0x3000;
+const NOTIFY_OFF_MULTIPLIER: usize = 4;
+const NOTIFY_CFG_LEN: usize = VIRTIO_MMIO_MAX_QUEUES * NOTIFY_OFF_MULTIPLIER;
+const MSIX_TABLE: usize = 0x3800;
+const MSIX_PBA: usize = 0x3c00;
+const MSIX_VECTORS: usize = 16;
+const MSIX_ENTRY_SIZE: usize = 16;
+
+// struct virtio_pci_common_cfg
+const COMMON_DFSELECT: usize = 0x00;
+const COMMON_DF: usize = 0x04;
+const COMMON_GFSELECT: usize = 0x08;
+const COMMON_GF: usize = 0x0c;
+const COMMON_MSIX: usize = 0x10;
+const COMMON_NUMQ: usize = 0x12;
+const COMMON_STATUS: usize = 0x14;
+const COMMON_CFGGENERATION: usize = 0x15;
+const COMMON_Q_SELECT: usize = 0x16;
+const COMMON_Q_SIZE: usize = 0x18;
+const COMMON_Q_MSIX: usize = 0x1a;
+const COMMON_Q_ENABLE: usize = 0x1c;
+const COMMON_Q_NOFF: usize = 0x1e;
+const COMMON_Q_DESCLO: usize = 0x20;
+const COMMON_Q_DEVICEHI: usize = 0x34;
+
+const MSIX_CTRL_MASKALL: usize = 14;
+const MSIX_CTRL_ENABLE: usize = 15;
+const MSIX_ENTRY_CTRL_MASKBIT: usize = 0;
+
+/// Devices seen by guests, indexed by (zone id, ECAM address).
+static VIRTIO_PCI_DEVS: Mutex<BTreeMap<(usize, usize), Arc<Mutex<VirtioPciDev>>>> =
+    Mutex::new(BTreeMap::new());
+
+#[derive(Clone, Copy)]
+struct VirtioPciQueue {
+    /// 0 until the guest writes it, reads fall through to QueueNumMax.
+    size: u16,
+    msix_vector: u16,
+    /// desc, driver and device, low and high halves.
+    addrs: [u32; 6],
+}
+
+impl Default for VirtioPciQueue {
+    fn default() -> Self {
+        Self {
+            size: 0,
+            msix_vector: VIRTIO_MSI_NO_VECTOR,
+            addrs: [0; 6],
+        }
+    }
+}
+
+/// What the guest programmed and the backend does not need to know.
+struct VirtioPciDev {
+    /// ECAM address of the function, which is also where the backend sees the device.
+    base: usize,
+    bdf: u16,
+    /// Read from the backend the first time the guest reads the id.
+    virtio_id: Option<u32>,
+    device_features_sel: u32,
+    driver_features_sel: u32,
+    driver_features: [u32; 2],
+    config_msix_vector: u16,
+    queue_sel: u16,
+    queues: [VirtioPciQueue; VIRTIO_MMIO_MAX_QUEUES],
+    msix_ctrl: u16,
+    msix_table: [[u32; 4]; MSIX_VECTORS],
+    msix_pending: u64,
+}
+
+impl VirtioPciDev {
+    fn new(base: usize, bdf: u16) -> Self {
+        let mut dev = Self {
+            base,
+            bdf,
+            virtio_id: None,
+            device_features_sel: 0,
+            driver_features_sel: 0,
+            driver_features: [0; 2],
+            config_msix_vector: VIRTIO_MSI_NO_VECTOR,
+            queue_sel: 0,
+            queues: [VirtioPciQueue::default(); VIRTIO_MMIO_MAX_QUEUES],
+            msix_ctrl: 0,
+            msix_table: [[0; 4]; MSIX_VECTORS],
+            msix_pending: 0,
+        };
+        dev.reset_msix();
+        dev
+    }
+
+    /// Device reset by the driver, MSI-X belongs to PCI and survives it.
+    fn reset(&mut self) {
+        self.device_features_sel = 0;
+        self.driver_features_sel = 0;
+        self.driver_features = [0; 2];
+        self.config_msix_vector = VIRTIO_MSI_NO_VECTOR;
+        self.queue_sel = 0;
+        self.queues = [VirtioPciQueue::default(); VIRTIO_MMIO_MAX_QUEUES];
+    }
+
+    fn reset_msix(&mut self) {
+        self.msix_ctrl = (MSIX_VECTORS - 1) as u16;
+        self.msix_table = [[0, 0, 0, 1 << MSIX_ENTRY_CTRL_MASKBIT]; MSIX_VECTORS];
+        self.msix_pending = 0;
+    }
+
+    fn queue(&mut self) -> Option<&mut VirtioPciQueue> {
+        self.queues.get_mut(self.queue_sel as usize)
+    }
+
+    fn msix_enabled(&self) -> bool {
+        self.msix_ctrl.get_bit(MSIX_CTRL_ENABLE)
+    }
+
+    fn msix_masked(&self, vector: usize) -> bool {
+        self.msix_ctrl.get_bit(MSIX_CTRL_MASKALL)
+            || self.msix_table[vector][3].get_bit(MSIX_ENTRY_CTRL_MASKBIT)
+    }
+
+    /// Send the message of `vector`, or keep it pending while it is masked.
+    fn msix_signal(&mut self, zone_id: usize, vector: u16) {
+        let vector = vector as usize;
+        if !self.msix_enabled() || vector >= MSIX_VECTORS {
+            return;
+        }
+        if self.msix_masked(vector) {
+            self.msix_pending.set_bit(vector, true);
+            return;
+        }
+        let entry = self.msix_table[vector];
+        let address = (entry[1] as u64) << 32 | entry[0] as u64;
+        if !msi_deliver(zone_id, address, entry[2]) {
+            warn!(
+                "virtio-pci {:#x}: cannot deliver msi {:#x}/{:#x}",
+                self.bdf, address, entry[2]
+            );
+        }
+    }
+
+    /// Send the pending messages that got unmasked.
+    fn msix_flush(&mut self, zone_id: usize) {
+        if !self.msix_enabled() {
+            return;
+        }
+        for vector in 0..MSIX_VECTORS {
+            if self.msix_pending.get_bit(vector) && !self.msix_masked(vector) {
+                self.msix_pending.set_bit(vector, false);
+                self.msix_signal(zone_id, vector as u16);
+            }
+        }
+    }
+}
+
+#[cfg(target_arch = "x86_64")]
+fn msi_deliver(zone_id: usize, address: u64, data: u32) -> bool {
+    crate::device::irqchip::pic::inject_msi(zone_id, address, data)
+}
+
+/// The ITS, IMSIC and other doorbells are not emulated for virtual devices
+/// yet, `check_pci_devs` keeps virtio-pci devices out of zones on these
+/// architectures.
+#[cfg(not(target_arch = "x86_64"))]
+fn msi_deliver(_zone_id: usize, _address: u64, _data: u32) -> bool {
+    false
+}
+
+fn bdf_to_u16(bdf: Bdf) -> u16 {
+    (bdf.bus() as u16) << 8 | (bdf.device() as u16) << 3 | bdf.function() as u16
+}
+
+/// The state of the device at `base` in the current zone, created on first use.
+fn virtio_pci_dev(base: usize, bdf: u16) -> Arc<Mutex<VirtioPciDev>> {
+    let zone_id = this_zone_id();
+    VIRTIO_PCI_DEVS
+        .lock()
+        .entry((zone_id, base))
+        .or_insert_with(|| Arc::new(Mutex::new(VirtioPciDev::new(base, bdf))))
+        .clone()
+}
+
+/// Do a virtio-mmio register access on the backend.
+fn backend_access(
+    base: usize,
+    reg: usize,
+    size: usize,
+    is_write: bool,
+    value: u32,
+) -> HvResult<u32> {
+    let mut mmio = MMIOAccess {
+        address: base + reg,
+        size,
+        is_write,
+        value: value as _,
+    };
+    virtio_bridge_forward(&mut mmio, reg == QUEUE_NOTIFY)?;
+    Ok(mmio.value as u32)
+}
+
+fn backend_read(base: usize, reg: usize) -> HvResult<u32> {
+    backend_access(base, reg, 4, false, 0)
+}
+
+fn backend_write(base: usize, reg: usize, value: u32) -> HvResult {
+    backend_access(base, reg, 4, true, value).map(|_| ())
+}
+
+/// A `virtio_pci_cap`, or a `virtio_pci_notify_cap` if `notify_off_multiplier`
+/// is set. Read-only.
+struct VirtioPciCap {
+    offset: PciConfigAddress,
+    bytes: [u8; VIRTIO_NOTIFY_CAP_LEN],
+    len: usize,
+}
+
+impl VirtioPciCap {
+    fn new(
+        offset: PciConfigAddress,
+        next: PciConfigAddress,
+        cfg_type: u8,
+        bar_offset: usize,
+        length: usize,
+        notify_off_multiplier: Option<usize>,
+    ) -> Self {
+        let len = match notify_off_multiplier {
+            Some(_) => VIRTIO_NOTIFY_CAP_LEN,
+            None => VIRTIO_CAP_LEN,
+        };
+        let mut bytes = [0u8; VIRTIO_NOTIFY_CAP_LEN];
+        bytes[0] = CapabilityType::Vendor.to_id() as u8;
+        bytes[1] = next as u8;
+        bytes[2] = len as u8;
+        bytes[3] = cfg_type;
+        // bar 0, id 0 and padding
+        bytes[8..12].copy_from_slice(&(bar_offset as u32).to_le_bytes());
+        bytes[12..16].copy_from_slice(&(length as u32).to_le_bytes());
+        if let Some(multiplier) = notify_off_multiplier {
+            bytes[16..20].copy_from_slice(&(multiplier as u32).to_le_bytes());
+        }
+        Self { offset, bytes, len }
+    }
+}
+
+impl PciCapabilityRegion for VirtioPciCap {
+    fn read(&self, offset: PciConfigAddress, size: usize) -> HvResult<u32> {
+        let start = offset as usize;
+        if start + size > self.len {
+            return hv_result_err!(EINVAL);
+        }
+        let mut value = [0u8; 4];
+        value[..size].copy_from_slice(&self.bytes[start..start + size]);
+        Ok(u32::from_le_bytes(value))
+    }
+
+    fn write(&mut self, offset: PciConfigAddress, size: usize, value: u32) -> HvResult {
+        warn!(
+            "virtio-pci cap {:#x}: ignore write offset {:#x} size {} value {:#x}",
+            self.offset, offset, size, value
+        );
+        Ok(())
+    }
+
+    fn get_offset(&self) -> PciConfigAddress {
+        self.offset
+    }
+
+    fn get_size(&self) -> usize {
+        self.len
+    }
+}
+
+/// MSI-X capability backed by the device state, so that BAR0 accesses see it.
+struct VirtioPciMsixCap {
+    base: usize,
+    bdf: u16,
+}
+
+impl PciCapabilityRegion for VirtioPciMsixCap {
+    fn read(&self, offset: PciConfigAddress, size: usize) -> HvResult<u32> {
+        let ctrl = virtio_pci_dev(self.base, self.bdf).lock().msix_ctrl as u32;
+        let header = CapabilityType::MsiX.to_id() as u32 | ctrl << 16;
+        match (offset, size) {
+            (0x0, 4) => Ok(header),
+            (0x0, 2) => Ok(header & 0xffff),
+            (0x0, 1) => Ok(CapabilityType::MsiX.to_id() as u32),
+            (0x1, 1) => Ok(0),
+            (0x2, 2) => Ok(ctrl),
+            (0x4, 4) => Ok(MSIX_TABLE as u32),
+            (0x8, 4) => Ok(MSIX_PBA as u32),
+            _ => {
+                warn!(
+                    "virtio-pci msix cap invalid read offset {:#x} size {}",
+                    offset, size
+                );
+                Ok(0)
+            }
+        }
+    }
+
+    fn write(&mut self, offset: PciConfigAddress, size: usize, value: u32) -> HvResult {
+        let value = match (offset, size) {
+            (0x0, 4) => value >> 16,
+            (0x2, 2) => value,
+            (0x3, 1) => value << 8,
+            _ => {
+                warn!(
+                    "virtio-pci msix cap invalid write offset {:#x} size {}",
+                    offset, size
+                );
+                return Ok(());
+            }
+        };
+        let dev = virtio_pci_dev(self.base, self.bdf);
+        let mut dev = dev.lock();
+        dev.msix_ctrl
+            .set_bit(MSIX_CTRL_ENABLE, value.get_bit(MSIX_CTRL_ENABLE));
+        dev.msix_ctrl
+            .set_bit(MSIX_CTRL_MASKALL, value.get_bit(MSIX_CTRL_MASKALL));
+        dev.msix_flush(this_zone_id());
+        Ok(())
+    }
+
+    fn get_offset(&self) -> PciConfigAddress {
+        CAP_MSIX
+    }
+
+    fn get_size(&self) -> usize {
+        MSIX_CAP_LEN
+    }
+}
+
+/// Handler for virtio-pci devices
+pub struct VirtioPciHandler;
+
+impl VpciDeviceHandler for VirtioPciHandler {
+    fn read_cfg(
+        &self,
+        dev: ArcRwLockVirtualPciConfigSpace,
+        offset: PciConfigAddress,
+        size: usize,
+    ) -> HvResult<PciConfigAccessStatus> {
+        pci_virt_log!(
+            "virtio pci read_cfg, offset {:#x}, size {:#x}",
+            offset,
+            size
+        );
+        let offset = offset as usize;
+        let value: u32 = match offset & !0x3 {
+            0x00 | 0x2c => {
+                let base = dev.inner().read().get_base() as usize;
+                let state = virtio_pci_dev(base, bdf_to_u16(dev.get_bdf()));
+                let cached = state.lock().virtio_id;
+                let virtio_id = match cached {
+                    Some(id) => id,
+                    None => backend_read(base, DEVICE_ID)?,
+                };
+                if virtio_id == 0 || virtio_id == u32::MAX {
+                    // no backend behind it (yet)
+                    return Ok(PciConfigAccessStatus::Done(0xFFFF_FFFF));
+                }
+                state.lock().virtio_id = Some(virtio_id);
+                if offset < 0x2c {
+                    (VIRTIO_PCI_DEVICE_ID_BASE + virtio_id) << 16 | VIRTIO_PCI_VENDOR_ID
+                } else {
+                    virtio_id << 16 | VIRTIO_PCI_VENDOR_ID
+                }
+            }
+            0x08 => PCI_CLASS_OTHER << 24 | VIRTIO_PCI_REVISION,
+            0x10 => {
+                if size != 4 {
+                    return Ok(PciConfigAccessStatus::Default);
+                }
+                let slot = 0;
+                let size_read = dev.with_bar_ref(slot, |bar| bar.get_size_read());
+                if size_read {
+                    let value = dev.with_bar_ref(slot, |bar| bar.get_size_with_flag());
+                    dev.with_bar_ref_mut(slot, |bar| bar.clear_size_read());
+                    value as u32
+                } else {
+                    dev.with_bar_ref(slot, |bar| bar.get_virtual_value())
+                }
+            }
+            0x34 => CAP_COMMON as u32,
+            _ => return Ok(PciConfigAccessStatus::Default),
+        };
+        let value = value >> ((offset & 0x3) * 8);
+        let value = match size {
+            1 => value & 0xff,
+            2 => value & 0xffff,
+            _ => value,
+        };
+        Ok(PciConfigAccessStatus::Done(value as usize))
+    }
+
+    fn write_cfg(
+        &self,
+        dev: ArcRwLockVirtualPciConfigSpace,
+        offset: PciConfigAddress,
+        size: usize,
+        value: usize,
+    ) -> HvResult<PciConfigAccessStatus> {
+        pci_virt_log!(
+            "virtio pci write_cfg, offset {:#x}, size {:#x}, value {:#x}",
+            offset,
+            size,
+            value
+        );
+        match (offset, size) {
+            // command, status, cache line size, latency timer and interrupt line
+            (0x04, _) | (0x06, 2) | (0x0c, 1) | (0x0d, 1) | (0x3c, 1) => {
+                Ok(PciConfigAccessStatus::Done(value))
+            }
+            (0x10, 4) => {
+                let slot = 0;
+                if value == 0xFFFF_FFFF {
+                    dev.with_bar_ref_mut(slot, |bar| bar.set_size_read());
+                    return Ok(PciConfigAccessStatus::Done(value));
+                }
+                let old = dev.with_bar_ref(slot, |bar| bar.get_virtual_value()) as usize & !0xf;
+                dev.with_bar_ref_mut(slot, |bar| bar.set_virtual_value(value as u64));
+                let new = value & !0xf;
+                if old != new {
+                    let base = dev.inner().read().get_base() as usize;
+                    let zone = this_zone();
+                    let mut guard = zone.write();
+                    if old != 0 {
+                        guard.mmio_region_remove(old as GuestPhysAddr);
+                    }
+                    if new != 0 {
+                        pci_virt_log!(
+                            "virtio pci write_cfg, register mmio region {:#x}, size {:#x}",
+                            new,
+                            BAR_SIZE
+                        );
+                        guard.mmio_region_register(
+                            new as GuestPhysAddr,
+                            BAR_SIZE,
+                            mmio_virtio_pci_handler,
+                            base,
+                        );
+                    }
+                }
+                Ok(PciConfigAccessStatus::Done(value))
+            }
+            (0x14..=0x27, _) | (0x30, 4) => Ok(PciConfigAccessStatus::Done(value)),
+            _ => {
+                warn!(
+                    "virtio pci write_cfg, invalid offset {:#x}, size {:#x}, value {:#x}",
+                    offset, size, value
+                );
+                Ok(PciConfigAccessStatus::Reject)
+            }
+        }
+    }
+
+    fn vdev_init(&self, mut dev: VirtualPciConfigSpace) -> VirtualPciConfigSpace {
+        dev.with_bararr_mut(|bararr| {
+            bararr[0].config_init(PciMemType::Mem32, false, BAR_SIZE as u64, 0);
+        });
+
+        let base = dev.get_base() as usize;
+        let bdf = bdf_to_u16(dev.get_bdf());
+        let caps: [(PciConfigAddress, Arc<RwLock<dyn PciCapabilityRegion>>); 5] = [
+            (
+                CAP_COMMON,
+                Arc::new(RwLock::new(VirtioPciCap::new(
+                    CAP_COMMON,
+                    CAP_NOTIFY,
+                    VIRTIO_PCI_CAP_COMMON_CFG,
+                    COMMON_CFG,
+                    COMMON_CFG_LEN,
+                    None,
+                ))),
+            ),
+            (
+                CAP_NOTIFY,
+                Arc::new(RwLock::new(VirtioPciCap::new(
+                    CAP_NOTIFY,
+                    CAP_ISR,
+                    VIRTIO_PCI_CAP_NOTIFY_CFG,
+                    NOTIFY_CFG,
+                    NOTIFY_CFG_LEN,
+                    Some(NOTIFY_OFF_MULTIPLIER),
+                ))),
+            ),
+            (
+                CAP_ISR,
+                Arc::new(RwLock::new(VirtioPciCap::new(
+                    CAP_ISR,
+                    CAP_DEVICE,
+                    VIRTIO_PCI_CAP_ISR_CFG,
+                    ISR_CFG,
+                    ISR_CFG_LEN,
+                    None,
+                ))),
+            ),
+            (
+                CAP_DEVICE,
+                Arc::new(RwLock::new(VirtioPciCap::new(
+                    CAP_DEVICE,
+                    CAP_MSIX,
+                    VIRTIO_PCI_CAP_DEVICE_CFG,
+                    DEVICE_CFG,
+                    DEVICE_CFG_LEN,
+                    None,
+                ))),
+            ),
+            (
+                CAP_MSIX,
+                Arc::new(RwLock::new(VirtioPciMsixCap { base, bdf })),
+            ),
+        ];
+        dev.with_cap_mut(|capabilities| {
+            for (offset, region) in caps {
+                let cap_type = if offset == CAP_MSIX {
+                    CapabilityType::MsiX
+                } else {
+                    CapabilityType::Vendor
+                };
+                capabilities.insert(offset, PciCapability::new_virt(cap_type, region));
+            }
+        });
+        dev
+    }
+}
+
+/// Static handler instance for virtio-pci devices
+pub const HANDLER: VirtioPciHandler = VirtioPciHandler;
+
+/// BAR0 of a virtio-pci device, `base` is the ECAM address of the function.
+pub fn mmio_virtio_pci_handler(mmio: &mut MMIOAccess, base: usize) -> HvResult {
+    let zone_id = this_zone_id();
+    let dev = match VIRTIO_PCI_DEVS.lock().get(&(zone_id, base)) {
+        Some(dev) => dev.clone(),
+        None => return hv_result_err!(ENODEV, format!("no virtio-pci device at {:#x}", base)),
+    };
+    let offset = mmio.address;
+    let value = mmio.value as u32;
+    let mut result = 0;
+    match offset {
+        _ if (COMMON_CFG..COMMON_CFG + COMMON_CFG_LEN).contains(&offset) => {
+            result = common_cfg_access(&dev, offset - COMMON_CFG, mmio.size, mmio.is_write, value)?;
+        }
+        _ if (ISR_CFG..ISR_CFG + ISR_CFG_LEN).contains(&offset) => {
+            if !mmio.is_write {
+                // reading the ISR status acks it
+                result = backend_read(base, INTERRUPT_STATUS)?;
+                if result != 0 && result != u32::MAX {
+                    backend_write(base, INTERRUPT_ACK, result)?;
+                }
+            }
+        }
+        _ if (DEVICE_CFG..DEVICE_CFG + DEVICE_CFG_LEN).contains(&offset) => {
+            result = backend_access(
+                base,
+                CONFIG + offset - DEVICE_CFG,
+                mmio.size,
+                mmio.is_write,
+                value,
+            )?;
+        }
+        _ if (NOTIFY_CFG..NOTIFY_CFG + NOTIFY_CFG_LEN).contains(&offset) => {
+            if mmio.is_write {
+                let queue = (offset - NOTIFY_CFG) / NOTIFY_OFF_MULTIPLIER;
+                backend_write(base, QUEUE_NOTIFY, queue as u32)?;
+            }
+        }
+        _ if (MSIX_TABLE..MSIX_TABLE + MSIX_VECTORS * MSIX_ENTRY_SIZE).contains(&offset) => {
+            let vector = (offset - MSIX_TABLE) / MSIX_ENTRY_SIZE;
+            let word = (offset % MSIX_ENTRY_SIZE) / 4;
+            let mut dev = dev.lock();
+            match (mmio.is_write, mmio.size) {
+                (false, 4) => result = dev.msix_table[vector][word],
+                (false, 8) if word % 2 == 0 => {
+                    mmio.value = (dev.msix_table[vector][word + 1] as usize) << 32
+                        | dev.msix_table[vector][word] as usize;
+                    return Ok(());
+                }
+                (true, 4) => dev.msix_table[vector][word] = value,
+                (true, 8) if word % 2 == 0 => {
+                    dev.msix_table[vector][word] = value;
+                    dev.msix_table[vector][word + 1] = (mmio.value >> 32) as u32;
+                }
+                _ => warn!("virtio-pci: unsupported msix table access {:#x?}", mmio),
+            }
+            if mmio.is_write {
+                dev.msix_flush(zone_id);
+            }
+        }
+        _ if (MSIX_PBA..MSIX_PBA + MSIX_VECTORS.div_ceil(8)).contains(&offset) => {
+            // read-only
+            let pending = dev.lock().msix_pending;
+            result = (pending >> ((offset - MSIX_PBA) * 8)) as u32;
+        }
+        _ => warn!("virtio-pci: unhandled bar access {:#x?}", mmio),
+    }
+    if !mmio.is_write {
+        mmio.value = result as usize;
+    }
+    Ok(())
+}
+
+/// Translate an access to `struct virtio_pci_common_cfg`.
+fn common_cfg_access(
+    dev: &Mutex<VirtioPciDev>,
+    offset: usize,
+    size: usize,
+    is_write: bool,
+    value: u32,
+) -> HvResult<u32> {
+    let base = dev.lock().base;
+    if !is_write {
+        let mut dev = dev.lock();
+        let result = match (offset, size) {
+            (COMMON_DFSELECT, 4) => dev.device_features_sel,
+            (COMMON_DF, 4) => {
+                drop(dev);
+                return backend_read(base, DEVICE_FEATURES);
+            }
+            (COMMON_GFSELECT, 4) => dev.driver_features_sel,
+            (COMMON_GF, 4) => {
+                let sel = dev.driver_features_sel as usize;
+                dev.driver_features.get(sel).copied().unwrap_or(0)
+            }
+            (COMMON_MSIX, 2) => dev.config_msix_vector as u32,
+            (COMMON_NUMQ, 2) => VIRTIO_MMIO_MAX_QUEUES as u32,
+            (COMMON_STATUS, 1) => {
+                drop(dev);
+                return backend_read(base, STATUS).map(|status| status & 0xff);
+            }
+            (COMMON_CFGGENERATION, 1) => {
+                drop(dev);
+                return backend_read(base, CONFIG_GENERATION).map(|gen| gen & 0xff);
+            }
+            (COMMON_Q_SELECT, 2) => dev.queue_sel as u32,
+            (COMMON_Q_SIZE, 2) => match dev.queue().map(|queue| queue.size) {
+                Some(0) => {
+                    drop(dev);
+                    return backend_read(base, QUEUE_NUM_MAX).map(|num| num & 0xffff);
+                }
+                Some(size) => size as u32,
+                None => 0,
+            },
+            (COMMON_Q_MSIX, 2) => {
+                dev.queue()
+                    .map_or(VIRTIO_MSI_NO_VECTOR, |queue| queue.msix_vector) as u32
+            }
+            (COMMON_Q_ENABLE, 2) => {
+                drop(dev);
+                return backend_read(base, QUEUE_READY).map(|ready| ready & 0x1);
+            }
+            (COMMON_Q_NOFF, 2) => dev.queue_sel as u32,
+            (COMMON_Q_DESCLO..=COMMON_Q_DEVICEHI, 4) => {
+                let idx = (offset - COMMON_Q_DESCLO) / 4;
+                dev.queue().map_or(0, |queue| queue.addrs[idx])
+            }
+            _ => {
+                warn!(
+                    "virtio-pci: invalid common cfg read offset {:#x} size {}",
+                    offset, size
+                );
+                0
+            }
+        };
+        return Ok(result);
+    }
+
+    let mut guard = dev.lock();
+    let forward = match (offset, size) {
+        (COMMON_DFSELECT, 4) => {
+            guard.device_features_sel = value;
+            Some((DEVICE_FEATURES_SEL, value))
+        }
+        (COMMON_GFSELECT, 4) => {
+            guard.driver_features_sel = value;
+            Some((DRIVER_FEATURES_SEL, value))
+        }
+        (COMMON_GF, 4) => {
+            let sel = guard.driver_features_sel as usize;
+            if let Some(features) = guard.driver_features.get_mut(sel) {
+                *features = value;
+            }
+            Some((DRIVER_FEATURES, value))
+        }
+        (COMMON_MSIX, 2) => {
+            guard.config_msix_vector = msix_vector(value);
+            None
+        }
+        (COMMON_STATUS, 1) => {
+            if value == 0 {
+                guard.reset();
+            }
+            Some((STATUS, value))
+        }
+        (COMMON_Q_SELECT, 2) => {
+            guard.queue_sel = value as u16;
+            Some((QUEUE_SEL, value))
+        }
+        (COMMON_Q_SIZE, 2) => {
+            if let Some(queue) = guard.queue() {
+                queue.size = value as u16;
+            }
+            Some((QUEUE_NUM, value))
+        }
+        (COMMON_Q_MSIX, 2) => {
+            if let Some(queue) = guard.queue() {
+                queue.msix_vector = msix_vector(value);
+            }
+            None
+        }
+        (COMMON_Q_ENABLE, 2) => Some((QUEUE_READY, value)),
+        (COMMON_Q_DESCLO..=COMMON_Q_DEVICEHI, 4) => {
+            let idx = (offset - COMMON_Q_DESCLO) / 4;
+            if let Some(queue) = guard.queue() {
+                queue.addrs[idx] = value;
+            }
+            const QUEUE_ADDRS: [usize; 6] = [
+                QUEUE_DESC_LOW,
+                QUEUE_DESC_HIGH,
+                QUEUE_DRIVER_LOW,
+                QUEUE_DRIVER_HIGH,
+                QUEUE_DEVICE_LOW,
+                QUEUE_DEVICE_HIGH,
+            ];
+            Some((QUEUE_ADDRS[idx], value))
+        }
+        _ => {
+            warn!(
+                "virtio-pci: invalid common cfg write offset {:#x} size {} value {:#x}",
+                offset, size, value
+            );
+            None
+        }
+    };
+    // the backend may take a while, don't keep the other vcpus spinning on the lock
+    drop(guard);
+    if let Some((reg, value)) = forward {
+        backend_write(base, reg, value)?;
+    }
+    Ok(0)
+}
+
+/// The vector the device reports back, `VIRTIO_MSI_NO_VECTOR` tells the driver
+/// that it cannot be used.
+fn msix_vector(value: u32) -> u16 {
+    if (value as usize) < MSIX_VECTORS {
+        value as u16
+    } else {
+        VIRTIO_MSI_NO_VECTOR
+    }
+}
+
+/// Handle a `VIRTIO_PCI_IRQ` response of the backend for zone `zone_id`.
+pub fn virtio_pci_signal(zone_id: usize, irq_id: u32) {
+    let bdf = irq_id.get_bits(8..24) as u16;
+    let queue = irq_id.get_bits(0..8);
+    let dev = VIRTIO_PCI_DEVS
+        .lock()
+        .iter()
+        .find(|((id, _), dev)| *id == zone_id && dev.lock().bdf == bdf)
+        .map(|(_, dev)| dev.clone());
+    let dev = match dev {
+        Some(dev) => dev,
+        None => {
+            warn!("virtio-pci: zone {} has no device {:#x}", zone_id, bdf);
+            return;
+        }
+    };
+    let mut dev = dev.lock();
+    let vector = if queue == VIRTIO_PCI_IRQ_CONFIG {
+        dev.config_msix_vector
+    } else {
+        match dev.queues.get(queue as usize) {
+            Some(queue) => queue.msix_vector,
+            None => return,
+        }
+    };
+    dev.msix_signal(zone_id, vector);
+}
+
+/// Forget the state the guest set up, used when the zone reboots.
+pub fn virtio_pci_reset(zone_id: usize) {
+    VIRTIO_PCI_DEVS
+        .lock()
+        .iter()
+        .filter(|((id, _), _)| *id == zone_id)
+        .for_each(|(_, dev)| {
+            let mut dev = dev.lock();
+            dev.reset();
+            dev.reset_msix();
+        });
+}
+
+pub fn virtio_pci_remove(zone_id: usize) {
+    VIRTIO_PCI_DEVS.lock().retain(|(id, _), _| *id != zone_id);
+}
@@ -0,0 +1,418 @@
+// Copyright (c) 2025 Syswonder
+// hvisor is licensed under Mulan PSL v2.
+// You can use this software according to the terms and conditions of the Mulan PSL v2.
+// You may obtain a copy of Mulan PSL v2 at:
+//     http://license.coscl.org.cn/MulanPSL2
+// THIS SOFTWARE IS PROVIDED ON AN "AS IS" BASIS, WITHOUT WARRANTIES OF ANY KIND, EITHER
+// EXPRESS OR IMPLIED, INCLUDING BUT NOT LIMITED TO NON-INFRINGEMENT, MERCHANTABILITY OR
+// FIT FOR A PARTICULAR PURPOSE.
+// See the Mulan PSL v2 for more details.
+//
+// Syswonder Website:
+//      https://www.syswonder.org
+//
+// Authors:
+//
+//! Time-sharing of physical cpus between vcpus, built with `SCHED=on` (cargo
+//! feature `sched`).
+//!
+//! Every vcpu has its own per-CPU data area and hypervisor stack, and is pinned to
+//! physical cpu `vcpu % MAX_CPU_NUM`, so each physical cpu has up to
+//! `SCHED_MAX_VCPUS_PER_CPU` vcpus. The zone cpu mask in the config selects the
+//! physical cpus, and `zone_create` takes a free vcpu on each of them; `Zone::cpu_set`
+//! holds the vcpus. The first `MAX_CPU_NUM` vcpus are the ones hvisor boots on, the
+//! others start in `idle_loop` the first time they are picked.
+//!
+//! A vcpu is switched out only inside hvisor: when its guest executes WFI, when it
+//! waits in `wait_for_event` or `cpu_relax`, and on the way back to the guest when the
+//! EL2 timer or another cpu asked for a reschedule. Its trap frame and hypervisor call
+//! stack stay on its own stack until it is picked again, like a kernel thread. Never
+//! switch with a spinlock held: the next vcpu may trap and spin on it forever.
+//!
+//! Each physical cpu picks the vcpu to run from its run queue by
+//! 1. pending hypervisor events first, so shutdown, pause and virtio notifications
+//!    never wait behind a guest,
+//! 2. then the highest `HvSchedConfig::priority` among runnable vcpus,
+//! 3. round-robin between vcpus of the same priority every time slice with
+//!    `SCHED_POLICY_RR`, no preemption by the same priority with `SCHED_POLICY_FIFO`.
+
+use alloc::vec::Vec;
+use spin::{Lazy, Mutex};
+
+use crate::arch::cpu::{this_cpu_id, this_pcpu_id};
+use crate::arch::sched as arch_sched;
+use crate::config::{HvSchedConfig, SCHED_POLICY_FIFO};
+use crate::consts::{MAX_CPU_NUM, MAX_VCPU_NUM, SCHED_MAX_VCPUS_PER_CPU};
+use crate::cpu_data::{get_cpu_data, PerCpu};
+use crate::error::HvResult;
+use crate::event::{check_events, has_events};
+use crate::zone::Zone;
+
+#[cfg(not(all(target_arch = "aarch64", feature = "gicv3")))]
+compile_error!("the `sched` feature is only supported on aarch64 with gicv3");
+
+/// Time slice of `SCHED_POLICY_RR` when the zone config gives none.
+pub const SCHED_DEFAULT_SLICE_US: u32 = 10_000;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum VcpuState {
+    /// Running or preempted.
+    Ready,
+    /// The guest executed WFI.
+    WaitIrq,
+    /// Blocked in hvisor until an event arrives, or not started.
+    WaitEvent,
+}
+
+#[derive(Debug)]
+struct RunQueueEntry {
+    vcpu: usize,
+    state: VcpuState,
+    policy: u32,
+    priority: u32,
+    /// Time slice in timer ticks.
+    slice: u64,
+}
+
+impl RunQueueEntry {
+    fn set_config(&mut self, config: &HvSchedConfig) {
+        let slice_us = match config.slice_us {
+            0 => SCHED_DEFAULT_SLICE_US,
+            slice_us => slice_us,
+        };
+        self.policy = config.policy;
+        self.priority = config.priority;
+        self.slice = arch_sched::us_to_ticks(slice_us as _);
+    }
+}
+
+#[derive(Debug)]
+struct RunQueue {
+    /// The vcpus of this physical cpu, entry `i` is vcpu `cpu + i * MAX_CPU_NUM`.
+    entries: Vec<RunQueueEntry>,
+    /// Index of the vcpu whose context is loaded.
+    current: usize,
+    /// When the current vcpu yields to other vcpus of the same priority.
+    slice_end: u64,
+    yielded: bool,
+    need_resched: bool,
+}
+
+impl RunQueue {
+    fn new(cpu: usize) -> Self {
+        let mut entries: Vec<RunQueueEntry> = (0..SCHED_MAX_VCPUS_PER_CPU)
+            .map(|i| RunQueueEntry {
+                vcpu: cpu + i * MAX_CPU_NUM,
+                state: VcpuState::WaitEvent,
+                policy: 0,
+                priority: 0,
+                slice: 0,
+            })
+            .collect();
+        entries
+            .iter_mut()
+            .for_each(|entry| entry.set_config(&HvSchedConfig::default()));
+        // hvisor boots on the first vcpu of every cpu
+        entries[0].state = VcpuState::Ready;
+        Self {
+            entries,
+            current: 0,
+            slice_end: 0,
+            yielded: false,
+            need_resched: false,
+        }
+    }
+
+    fn entry_mut(&mut self, vcpu: usize) -> &mut RunQueueEntry {
+        &mut self.entries[vcpu / MAX_CPU_NUM]
+    }
+
+    fn runnable(&self, idx: usize, now: u64) -> bool {
+        let entry = &self.entries[idx];
+        has_events(entry.vcpu)
+            || match entry.state {
+                VcpuState::Ready => true,
+                VcpuState::WaitIrq => {
+                    arch_sched::vcpu_irq_pending(entry.vcpu, idx == self.current, now)
+                }
+                VcpuState::WaitEvent => false,
+            }
+    }
+
+    fn pick_next(&self, now: u64) -> Option<usize> {
+        let n = self.entries.len();
+        let key = |idx: usize| {
+            (
+                has_events(self.entries[idx].vcpu),
+                self.entries[idx].priority,
+            )
+        };
+        // start after the current vcpu, so it loses ties unless it keeps its slice
+        let mut best: Option<(usize, (bool, u32))> = None;
+        for idx in (1..=n).map(|i| (self.current + i) % n) {
+            // a vcpu that yielded waits for the others, whatever their priority
+            if self.yielded && idx == self.current {
+                continue;
+            }
+            if self.runnable(idx, now) && best.map_or(true, |(_, best_key)| key(idx) > best_key) {
+                best = Some((idx, key(idx)));
+            }
+        }
+        let Some((idx, best_key)) = best else {
+            return (self.yielded && self.runnable(self.current, now)).then_some(self.current);
+        };
+        let current = &self.entries[self.current];
+        let keeps_slice = current.state == VcpuState::Ready
+            && !self.yielded
+            && (current.policy == SCHED_POLICY_FIFO || now < self.slice_end);
+        if idx != self.current && keeps_slice && key(self.current) == best_key {
+            return Some(self.current);
+        }
+        Some(idx)
+    }
+
+    /// When the EL2 timer has to fire next: at the end of the time slice if another
+    /// vcpu of the same priority is waiting for it, or when the virtual timer of a vcpu
+    /// waiting for interrupts expires.
+    fn next_deadline(&self, now: u64, idle: bool) -> Option<u64> {
+        let current = &self.entries[self.current];
+        let shares_slice = |idx: usize| {
+            !idle
+                && current.policy != SCHED_POLICY_FIFO
+                && self.entries[idx].priority == current.priority
+                && self.runnable(idx, now)
+        };
+        (0..self.entries.len())
+            .filter(|&idx| idx != self.current)
+            .filter_map(|idx| {
+                if shares_slice(idx) {
+                    return Some(self.slice_end);
+                }
+                match self.entries[idx].state {
+                    VcpuState::WaitIrq => arch_sched::vcpu_timer_deadline(self.entries[idx].vcpu),
+                    _ => None,
+                }
+            })
+            .min()
+    }
+}
+
+static RUN_QUEUES: Lazy<Vec<Mutex<RunQueue>>> = Lazy::new(|| {
+    (0..MAX_CPU_NUM)
+        .map(|cpu| Mutex::new(RunQueue::new(cpu)))
+        .collect()
+});
+
+/// Bitmap of the vcpus owned by a zone.
+static VCPU_OWNED: Mutex<u64> = Mutex::new(0);
+
+/// The physical cpu `vcpu` is pinned to.
+pub fn vcpu_pcpu(vcpu: usize) -> usize {
+    vcpu % MAX_CPU_NUM
+}
+
+fn cpu_vcpus(cpu: usize) -> impl Iterator<Item = usize> {
+    (0..SCHED_MAX_VCPUS_PER_CPU).map(move |i| cpu + i * MAX_CPU_NUM)
+}
+
+/// A vcpu on physical cpu `cpu` that no zone owns.
+pub fn free_vcpu(cpu: usize) -> Option<usize> {
+    let owned = *VCPU_OWNED.lock();
+    cpu_vcpus(cpu).find(|&vcpu| owned & (1 << vcpu) == 0)
+}
+
+/// The vcpu of `zone` on physical cpu `cpu`, a zone has at most one per cpu.
+pub fn zone_vcpu(zone: &Zone, cpu: usize) -> Option<usize> {
+    zone.cpu_set.iter().find(|&vcpu| vcpu_pcpu(vcpu) == cpu)
+}
+
+/// Vcpus taken for a zone being created. They are given back when this is dropped,
+/// unless the zone got them with `commit`.
+pub struct VcpuReservation {
+    vcpus: u64,
+}
+
+impl VcpuReservation {
+    pub fn vcpus(&self) -> impl Iterator<Item = usize> + '_ {
+        (0..MAX_VCPU_NUM).filter(move |&vcpu| self.vcpus & (1 << vcpu) != 0)
+    }
+
+    /// Apply the zone's scheduling config to the vcpus and keep them.
+    pub fn commit(self, config: &HvSchedConfig) {
+        for vcpu in self.vcpus() {
+            RUN_QUEUES[vcpu_pcpu(vcpu)]
+                .lock()
+                .entry_mut(vcpu)
+                .set_config(config);
+        }
+        core::mem::forget(self);
+    }
+}
+
+impl Drop for VcpuReservation {
+    fn drop(&mut self) {
+        *VCPU_OWNED.lock() &= !self.vcpus;
+    }
+}
+
+/// Take a free vcpu on each physical cpu in `cpus`.
+pub fn reserve_vcpus(cpus: &[u64]) -> HvResult<VcpuReservation> {
+    let mut owned = VCPU_OWNED.lock();
+    let mut vcpus = 0;
+    for &cpu in cpus {
+        match cpu_vcpus(cpu as _).find(|&vcpu| (*owned | vcpus) & (1 << vcpu) == 0) {
+            Some(vcpu) => vcpus |= 1 << vcpu,
+            None => {
+                return hv_result_err!(EBUSY, format!("cpu {} has no free vcpu left", cpu));
+            }
+        }
+    }
+    *owned |= vcpus;
+    Ok(VcpuReservation { vcpus })
+}
+
+/// Give `vcpu` back after its zone is gone.
+pub fn release_vcpu(vcpu: usize) {
+    RUN_QUEUES[vcpu_pcpu(vcpu)]
+        .lock()
+        .entry_mut(vcpu)
+        .set_config(&HvSchedConfig::default());
+    *VCPU_OWNED.lock() &= !(1 << vcpu);
+}
+
+/// Set up the vcpus of this physical cpu that hvisor did not boot on.
+pub fn percpu_init() {
+    for vcpu in cpu_vcpus(this_pcpu_id()).skip(1) {
+        PerCpu::new(vcpu);
+    }
+    Lazy::force(&RUN_QUEUES);
+}
+
+/// Switch to the vcpu that should run on this physical cpu, waiting at EL2 while none
+/// is runnable. Returns when the current vcpu is picked again.
+pub fn schedule() {
+    let cpu = this_pcpu_id();
+    let prev = this_cpu_id();
+    loop {
+        let mut rq = RUN_QUEUES[cpu].lock();
+        rq.need_resched = false;
+        let now = arch_sched::now();
+        match rq.pick_next(now) {
+            Some(next) => {
+                if next != rq.current || rq.yielded || now >= rq.slice_end {
+                    rq.slice_end = now + rq.entries[next].slice;
+                }
+                rq.current = next;
+                rq.yielded = false;
+                rq.entries[next].state = VcpuState::Ready;
+                let next = rq.entries[next].vcpu;
+                drop(rq);
+                if next != prev {
+                    arch_sched::switch_to(prev, next);
+                }
+                break;
+            }
+            None => {
+                arch_sched::set_preempt_timer(rq.next_deadline(now, true));
+                drop(rq);
+                // irqs taken here go to the current vcpu or are queued for their own
+                arch_sched::wait_for_irq();
+            }
+        }
+    }
+    arm_preempt_timer();
+}
+
+fn arm_preempt_timer() {
+    let rq = RUN_QUEUES[this_pcpu_id()].lock();
+    arch_sched::set_preempt_timer(rq.next_deadline(arch_sched::now(), false));
+}
+
+fn block(state: VcpuState) {
+    let vcpu = this_cpu_id();
+    RUN_QUEUES[vcpu_pcpu(vcpu)].lock().entry_mut(vcpu).state = state;
+    schedule();
+}
+
+/// Let the current vcpu sleep until an interrupt is pending for it, e.g. on guest WFI.
+pub fn wait_for_irq() {
+    block(VcpuState::WaitIrq);
+}
+
+/// Let the current vcpu sleep until it has a pending event.
+pub fn wait_for_event() {
+    block(VcpuState::WaitEvent);
+}
+
+/// Let every other runnable vcpu of this cpu run first, also those of a lower
+/// priority, which may be the ones the current vcpu waits for.
+pub fn yield_now() {
+    RUN_QUEUES[this_pcpu_id()].lock().yielded = true;
+    schedule();
+}
+
+/// Make the physical cpu of `vcpu` reconsider which vcpu to run, because `vcpu` got an
+/// event or an interrupt.
+pub fn kick(vcpu: usize) {
+    let cpu = vcpu_pcpu(vcpu);
+    RUN_QUEUES[cpu].lock().need_resched = true;
+    if cpu != this_pcpu_id() {
+        arch_sched::send_kick(cpu);
+    }
+}
+
+/// Handle a kick from another physical cpu.
+pub fn kicked() {
+    RUN_QUEUES[this_pcpu_id()].lock().need_resched = true;
+}
+
+/// Handle the EL2 timer interrupt.
+pub fn timer_tick() {
+    arch_sched::set_preempt_timer(None);
+    RUN_QUEUES[this_pcpu_id()].lock().need_resched = true;
+}
+
+/// Called before returning to the guest: switch if asked to, then handle the events
+/// of the vcpu that goes on.
+pub fn schedule_tail() {
+    if RUN_QUEUES[this_pcpu_id()].lock().need_resched {
+        schedule();
+    }
+    while check_events() {}
+    arch_sched::vcpu_enter();
+}
+
+/// The vcpu on this physical cpu whose zone owns `irq_id`, if it isn't a per-cpu irq.
+pub fn irq_target(irq_id: usize) -> Option<usize> {
+    if irq_id < 32 || irq_id >= crate::config::CONFIG_MAX_INTERRUPTS {
+        return None;
+    }
+    let vcpus: Vec<usize> = {
+        let rq = RUN_QUEUES[this_pcpu_id()].lock();
+        let current = rq.entries[rq.current].vcpu;
+        core::iter::once(current)
+            .chain(rq.entries.iter().map(|entry| entry.vcpu))
+            .collect()
+    };
+    vcpus.into_iter().find(|&vcpu| {
+        get_cpu_data(vcpu)
+            .zone
+            .as_ref()
+            .is_some_and(|zone| zone.read().irq_in_zone(irq_id as _))
+    })
+}
+
+/// Body of a vcpu without a running guest: handle its events until
+/// `IPI_EVENT_WAKEUP` starts the guest.
+pub fn idle_loop() -> ! {
+    loop {
+        while check_events() {}
+        wait_for_event();
+    }
+}
+
+/// Where a vcpu starts the first time it is picked.
+pub fn vcpu_first_run() -> ! {
+    arm_preempt_timer();
+    idle_loop()
+}
@@ -0,0 +1,171 @@
+// Copyright (c) 2025 Syswonder
+// hvisor is licensed under Mulan PSL v2.
+// You can use this software according to the terms and conditions of the Mulan PSL v2.
+// You may obtain a copy of Mulan PSL v2 at:
+//     http://license.coscl.org.cn/MulanPSL2
+// THIS SOFTWARE IS PROVIDED ON AN "AS IS" BASIS, WITHOUT WARRANTIES OF ANY KIND, EITHER
+// EXPRESS OR IMPLIED, INCLUDING BUT NOT LIMITED TO NON-INFRINGEMENT, MERCHANTABILITY OR
+// FIT FOR A PARTICULAR PURPOSE.
+// See the Mulan PSL v2 for more details.
+//
+// Syswonder Website:
+//      https://www.syswonder.org
+//
+// Authors:
+//
+//! Per-vCPU and per-zone runtime statistics.
+//!
+//! Counters are only updated when hvisor is built with `STATS=on` (cargo
+//! feature `stats`); otherwise the counting helpers compile to nothing and
+//! `HvZoneStats` reports all zeros.
+
+use core::mem::offset_of;
+use core::sync::atomic::{AtomicU64, Ordering};
+
+use crate::cpu_data::get_cpu_data;
+use crate::zone::Zone;
+
+/// Max vCPUs reported in one `HvZoneStats`, same width as `ZoneInfo::cpus`.
+pub const STATS_MAX_CPUS: usize = 64;
+/// Max MMIO regions reported in one `HvZoneStats`.
+pub const STATS_MAX_MMIO_REGIONS: usize = 64;
+
+pub const EXIT_REASON_NUM: usize = 7;
+/// Exit classes reported in `HvVcpuStats::exits`, the later ones have their
+/// own fields at the end of `HvZoneStats`.
+const HV_VCPU_EXIT_REASONS: usize = 6;
+
+/// Coarse classification of guest exits, shared by all architectures.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[repr(usize)]
+pub enum ExitReason {
+    /// Data abort / page fault on an emulated MMIO region.
+    Mmio = 0,
+    /// Hypercall into hvisor (hvc, ecall with the hvisor EID, vmcall, hvcl).
+    Hypercall = 1,
+    /// Firmware call forwarded or emulated by hvisor (smc, SBI, ...).
+    FirmwareCall = 2,
+    /// Physical interrupt taken while the guest was running.
+    Irq = 3,
+    /// Trapped system register, CSR, MSR or I/O port access.
+    SysReg = 4,
+    /// Anything else.
+    Other = 5,
+    /// x86 cpuid, emulated by hvisor.
+    Cpuid = 6,
+}
+
+#[derive(Debug, Default)]
+pub struct VcpuStats {
+    exits: [AtomicU64; EXIT_REASON_NUM],
+    irqs_injected: AtomicU64,
+}
+
+impl VcpuStats {
+    pub fn reset(&self) {
+        for exit in self.exits.iter() {
+            exit.store(0, Ordering::Relaxed);
+        }
+        self.irqs_injected.store(0, Ordering::Relaxed);
+    }
+}
+
+#[inline(always)]
+pub fn stats_inc(_counter: &AtomicU64) {
+    #[cfg(feature = "stats")]
+    _counter.fetch_add(1, Ordering::Relaxed);
+}
+
+/// Count one guest exit of `reason` on the current cpu.
+#[inline(always)]
+pub fn count_exit(_reason: ExitReason) {
+    #[cfg(feature = "stats")]
+    stats_inc(&crate::cpu_data::this_cpu_data().stats.exits[_reason as usize]);
+}
+
+/// Count one virtual interrupt injected into the vCPU running on `cpu_id`.
+#[inline(always)]
+pub fn count_irq_injected(_cpu_id: usize) {
+    #[cfg(feature = "stats")]
+    stats_inc(&get_cpu_data(_cpu_id).stats.irqs_injected);
+}
+
+#[repr(C)]
+#[derive(Clone, Copy, Debug)]
+pub struct HvVcpuStats {
+    cpu_id: u32,
+    _reserved: u32,
+    exits: [u64; HV_VCPU_EXIT_REASONS],
+    irqs_injected: u64,
+}
+
+#[repr(C)]
+#[derive(Clone, Copy, Debug)]
+pub struct HvMmioStats {
+    start: u64,
+    size: u64,
+    accesses: u64,
+}
+
+/// Buffer filled by the `HvZoneStats` hypercall. The caller sets `zone_id`,
+/// hvisor fills in everything else.
+#[repr(C)]
+#[derive(Clone, Copy, Debug)]
+pub struct HvZoneStats {
+    pub zone_id: u32,
+    vcpu_num: u32,
+    mmio_num: u32,
+    _reserved: u32,
+    vcpus: [HvVcpuStats; STATS_MAX_CPUS],
+    mmio: [HvMmioStats; STATS_MAX_MMIO_REGIONS],
+    /// `ExitReason::Cpuid` exits of each of `vcpus`.
+    cpuid_exits: [u64; STATS_MAX_CPUS],
+}
+
+/// Size of `HvZoneStats` before `cpuid_exits` was added, still accepted.
+pub const HV_ZONE_STATS_V1_SIZE: usize = offset_of!(HvZoneStats, cpuid_exits);
+
+impl Zone {
+    pub fn stats_reset(&self) {
+        self.cpu_set
+            .iter()
+            .for_each(|cpu_id| get_cpu_data(cpu_id).stats.reset());
+        for mmio in self.mmio.iter() {
+            mmio.accesses.store(0, Ordering::Relaxed);
+        }
+    }
+
+    pub fn stats_fill(&self, stats: &mut HvZoneStats) {
+        let mut vcpu_num = 0;
+        for (i, cpu_id) in self.cpu_set.iter().take(STATS_MAX_CPUS).enumerate() {
+            let cpu_stats = &get_cpu_data(cpu_id).stats;
+            let slot = &mut stats.vcpus[i];
+            slot.cpu_id = cpu_id as _;
+            for (dst, src) in slot.exits.iter_mut().zip(cpu_stats.exits.iter()) {
+                *dst = src.load(Ordering::Relaxed);
+            }
+            slot.irqs_injected = cpu_stats.irqs_injected.load(Ordering::Relaxed);
+            stats.cpuid_exits[i] =
+                cpu_stats.exits[ExitReason::Cpuid as usize].load(Ordering::Relaxed);
+            vcpu_num += 1;
+        }
+        stats.vcpu_num = vcpu_num;
+
+        let mut mmio_num = 0;
+        for (slot, mmio) in stats.mmio.iter_mut().zip(self.mmio.iter()) {
+            slot.start = mmio.region.start as _;
+            slot.size = mmio.region.size as _;
+            slot.accesses = mmio.accesses.load(Ordering::Relaxed);
+            mmio_num += 1;
+        }
+        if self.mmio.len() > STATS_MAX_MMIO_REGIONS {
+            warn!(
+                "zone {} has {} mmio regions, only the first {} are reported",
+                self.id,
+                self.mmio.len(),
+                STATS_MAX_MMIO_REGIONS
+            );
+        }
+        stats.mmio_num = mmio_num;
+    }
+}
@@ -16,7 +16,7 @@
 use alloc::sync::Arc;
 use alloc::vec::Vec;
 // use psci::error::INVALID_ADDRESS;
-use crate::consts::{INVALID_ADDRESS, MAX_CPU_NUM};
+use crate::consts::{INVALID_ADDRESS, MAX_VCPU_NUM, MAX_WAIT_TIMES};
 use crate::pci::pci_struct::VirtualRootComplex;
 use spin::RwLock;
 
@@ -27,13 +27,29 @@ use alloc::collections::btree_map::BTreeMap;
 
 use crate::arch::mm::new_s2_memory_set;
 use crate::arch::s2pt::Stage2PageTable;
-use crate::config::{HvZoneConfig, CONFIG_NAME_MAXLEN};
+use crate::config::{HvConfigMemoryRegion, HvPciDevConfig, HvZoneConfig, CONFIG_NAME_MAXLEN};
 
-use crate::cpu_data::{get_cpu_data, this_zone, CpuSet};
+use crate::cpu_data::{cpu_relax, get_cpu_data, this_cpu_data, this_zone, CpuSet};
+use crate::device::virtio_mmio::{virtio_mmio_remove, virtio_mmio_reset};
+use crate::device::virtio_native::{native_virtio_remove, native_virtio_reset};
+use crate::device::virtio_trampoline::{virtio_zone_bridge_remove, VIRTIO_IRQS};
+use crate::device::watchdog::{watchdog_remove, watchdog_reset};
 use crate::error::HvResult;
+use crate::event::{
+    cpu_is_paused, send_event, IPI_EVENT_PAUSE, IPI_EVENT_RESUME, IPI_EVENT_SHUTDOWN,
+    IPI_EVENT_WAKEUP,
+};
+use crate::hypercall::SGI_IPI_ID;
+use crate::ivc::ivc_remove;
 use crate::memory::addr::GuestPhysAddr;
+use crate::memory::grant::{grant_remove, grant_reset};
 use crate::memory::{MMIOConfig, MMIOHandler, MMIORegion, MemorySet};
+use crate::pci::pci_hotplug::{pci_zone_remove, pci_zone_reset};
+use crate::pci::pci_msi::{msix_remove, msix_reset};
+use crate::pci::vpci_dev::virtio_pci::{virtio_pci_remove, virtio_pci_reset};
+use crate::stats::stats_inc;
 use core::panic;
+use core::sync::atomic::AtomicU64;
 
 #[cfg(feature = "dwc_pcie")]
 #[derive(Debug)]
@@ -117,7 +133,13 @@ pub struct Zone {
     pub gpm: MemorySet<Stage2PageTable>,
     pub iommu_pt: Option<MemorySet<Stage2PageTable>>,
     pub is_err: bool,
+    pub is_paused: bool,
+    pub entry_point: usize,
+    pub dtb_ipa: usize,
     pub vpci_bus: VirtualRootComplex,
+    /// Memory regions and pci devices from the zone config, kept for `HvZoneConfig::check`.
+    pub memory_regions: Vec<HvConfigMemoryRegion>,
+    pub pci_devs: Vec<HvPciDevConfig>,
     #[cfg(feature = "dwc_pcie")]
     pub atu_configs: VirtualAtuConfigs,
 }
@@ -129,7 +151,7 @@ impl Zone {
             id: zoneid,
             gpm: new_s2_memory_set(),
             cpu_num: 0,
-            cpu_set: CpuSet::new(MAX_CPU_NUM as usize, 0),
+            cpu_set: CpuSet::new(MAX_VCPU_NUM as usize, 0),
             mmio: Vec::new(),
             irq_bitmap: [0; 1024 / 32],
             iommu_pt: if cfg!(feature = "iommu") {
@@ -138,28 +160,79 @@ impl Zone {
                 None
             },
             is_err: false,
+            is_paused: false,
+            entry_point: INVALID_ADDRESS,
+            dtb_ipa: INVALID_ADDRESS,
             vpci_bus: VirtualRootComplex::new(),
+            memory_regions: Vec::new(),
+            pci_devs: Vec::new(),
             #[cfg(feature = "dwc_pcie")]
             atu_configs: VirtualAtuConfigs::new(),
         }
     }
 
-    // pub fn suspend(&self) {
-    //     trace!("suspending cpu_set = {:#x?}", self.cpu_set);
-    //     self.cpu_set.iter_except(this_cpu_id()).for_each(|cpu_id| {
-    //         trace!("try to suspend cpu_id = {:#x?}", cpu_id);
-    //         suspend_cpu(cpu_id);
-    //     });
-    //     info!("send sgi done!");
-    // }
+    /// Park every running cpu of `zone`, keeping its guest context and stage-2 table.
+    /// The zone lock is not held while waiting, the target cpus may need it to
+    /// reach the ipi.
+    pub fn pause(zone: &RwLock<Zone>) -> HvResult {
+        let (zone_id, cpu_set) = {
+            let zone_r = zone.read();
+            (zone_r.id, zone_r.cpu_set)
+        };
+        trace!("pausing cpu_set = {:#x?}", cpu_set);
+        let running: Vec<usize> = cpu_set
+            .iter()
+            .filter(|&cpu_id| {
+                let cpu_data = get_cpu_data(cpu_id);
+                let _lock = cpu_data.ctrl_lock.lock();
+                if cpu_data.arch_cpu.power_on {
+                    send_event(cpu_id, SGI_IPI_ID as _, IPI_EVENT_PAUSE);
+                    true
+                } else {
+                    false
+                }
+            })
+            .collect();
 
-    // pub fn resume(&self) {
-    //     trace!("resuming cpu_set = {:#x?}", self.cpu_set);
-    //     self.cpu_set.iter_except(this_cpu_id()).for_each(|cpu_id| {
-    //         trace!("try to resume cpu_id = {:#x?}", cpu_id);
-    //         resume_cpu(cpu_id);
-    //     });
-    // }
+        let mut count: usize = 0;
+        while running.iter().any(|&cpu_id| !cpu_is_paused(cpu_id)) {
+            count += 1;
+            if count > MAX_WAIT_TIMES {
+                return hv_result_err!(EBUSY, format!("zone {} cannot be paused in time", zone_id));
+            }
+            cpu_relax();
+        }
+        Ok(())
+    }
+
+    /// Let the cpus of this zone continue where they stopped. Every cpu `pause`
+    /// sent the ipi to gets one, including those that have not taken the pause
+    /// yet, since the resume then follows the pause in their event queue.
+    pub fn resume(&self) {
+        trace!("resuming cpu_set = {:#x?}", self.cpu_set);
+        self.cpu_set.iter().for_each(|cpu_id| {
+            let cpu_data = get_cpu_data(cpu_id);
+            let _lock = cpu_data.ctrl_lock.lock();
+            if cpu_data.arch_cpu.power_on {
+                trace!("try to resume cpu_id = {:#x?}", cpu_id);
+                send_event(cpu_id, SGI_IPI_ID as _, IPI_EVENT_RESUME);
+            }
+        });
+    }
+
+    fn cpu_attach(&mut self, cpu_id: usize) -> HvResult {
+        self.virqc_cpu_add(cpu_id)?;
+        self.cpu_set.set_bit(cpu_id);
+        self.cpu_num += 1;
+        Ok(())
+    }
+
+    fn cpu_detach(&mut self, cpu_id: usize) -> HvResult {
+        self.virqc_cpu_remove(cpu_id)?;
+        self.cpu_set.clear_bit(cpu_id);
+        self.cpu_num -= 1;
+        Ok(())
+    }
 
     // pub fn owns_cpu(&self, id: usize) -> bool {
     //     self.cpu_set.contains_cpu(id)
@@ -185,6 +258,7 @@ impl Zone {
                 region: MMIORegion { start, size },
                 handler,
                 arg,
+                accesses: AtomicU64::new(0),
             })
         }
     }
@@ -209,7 +283,10 @@ impl Zone {
         self.mmio
             .iter()
             .find(|cfg| cfg.region.contains_region(addr, size))
-            .map(|cfg| (cfg.region, cfg.handler, cfg.arg))
+            .map(|cfg| {
+                stats_inc(&cfg.accesses);
+                (cfg.region, cfg.handler, cfg.arg)
+            })
     }
     /// If irq_id belongs to this zone
     pub fn irq_in_zone(&self, irq_id: u32) -> bool {
@@ -236,14 +313,23 @@ pub fn add_zone(zone: Arc<RwLock<Zone>>) {
 
 /// Remove zone from ZONE_LIST
 pub fn remove_zone(zone_id: usize) {
+    let removed_zone = take_zone(zone_id);
+    assert_eq!(Arc::strong_count(&removed_zone), 1);
+}
+
+/// Take zone `zone_id` out of the zone list, so it can't be found anymore.
+fn take_zone(zone_id: usize) -> Arc<RwLock<Zone>> {
     let mut zone_list = ZONE_LIST.write();
     let (idx, _) = zone_list
         .iter()
         .enumerate()
         .find(|(_, zone)| zone.read().id == zone_id)
         .unwrap();
-    let removed_zone = zone_list.remove(idx);
-    assert_eq!(Arc::strong_count(&removed_zone), 1);
+    zone_list.remove(idx)
+}
+
+pub fn all_zones() -> Vec<Arc<RwLock<Zone>>> {
+    ZONE_LIST.read().clone()
 }
 
 pub fn find_zone(zone_id: usize) -> Option<Arc<RwLock<Zone>>> {
@@ -275,37 +361,22 @@ pub fn this_zone_id() -> usize {
     this_zone().read().id
 }
 
-pub fn zone_create(config: &HvZoneConfig) -> HvResult<Arc<RwLock<Zone>>> {
-    // we create the new zone here
-    // TODO: create Zone with cpu_set
-    let zone_id = config.zone_id as usize;
-
-    if find_zone(zone_id).is_some() {
-        return hv_result_err!(
-            EINVAL,
-            format!("Failed to create zone: zone_id {} already exists", zone_id)
-        );
-    }
-
-    let mut zone = Zone::new(zone_id, &config.name);
-    zone.pt_init(config.memory_regions()).unwrap();
-    zone.mmio_init(&config.arch_config);
-
+/// The steps of `zone_create` that take devices and channels from the other
+/// zones, undone by `zone_setup_undo` if one fails.
+fn zone_setup(zone: &mut Zone, config: &HvZoneConfig) -> HvResult {
+    let zone_id = zone.id;
     #[cfg(feature = "pci")]
     {
-        let _ = zone.virtual_pci_mmio_init(&config.pci_config, config.num_pci_bus as usize);
-        let _ = zone.guest_pci_init(
+        zone.virtual_pci_mmio_init(config.pci_config(), config.pci_config().len());
+        zone.guest_pci_init(
             zone_id,
-            &config.alloc_pci_devs,
-            config.num_pci_devs,
-            &config.pci_config,
-            config.num_pci_bus as usize,
-        );
+            config.pci_devs(),
+            config.pci_devs().len() as _,
+            config.pci_config(),
+            config.pci_config().len(),
+        )?;
     }
 
-    // #[cfg(target_arch = "aarch64")]
-    // zone.ivc_init(config.ivc_config());
-
     /* loongarch page table emergency */
     /* Kai: Maybe unnecessary but i can't boot vms on my 3A6000 PC without this function. */
     // #[cfg(target_arch = "loongarch64")]
@@ -314,32 +385,11 @@ pub fn zone_create(config: &HvZoneConfig) -> HvResult<Arc<RwLock<Zone>>> {
     //     config.pci_config[0].ecam_size as _,
     // )?;
 
-    let mut cpu_num = 0;
-    for cpu_id in config.cpus().iter() {
-        if let Some(zone) = get_cpu_data(*cpu_id as _).zone.clone() {
-            return hv_result_err!(
-                EBUSY,
-                format!(
-                    "Failed to create zone: cpu {} already belongs to zone {}",
-                    cpu_id,
-                    zone.read().id
-                )
-            );
-        }
-        zone.cpu_set.set_bit(*cpu_id as _);
-        cpu_num += 1;
-    }
-    zone.cpu_num = cpu_num;
-    info!("zone cpu_set: {:#b}", zone.cpu_set.bitmap);
-    let cpu_set = zone.cpu_set;
-
     zone.arch_zone_pre_configuration(config)?;
-    // #[cfg(target_arch = "aarch64")]
-    // zone.ivc_init(config.ivc_config());
+    zone.ivc_init(config.ivc_config())?;
 
     #[cfg(all(feature = "iommu", target_arch = "aarch64"))]
-    zone.iommu_pt_init(config.memory_regions(), &config.arch_config)
-        .unwrap();
+    zone.iommu_pt_init(config.memory_regions(), &config.arch_config)?;
 
     /* loongarch page table emergency */
     /* Kai: Maybe unnecessary but i can't boot vms on my 3A6000 PC without this function. */
@@ -355,10 +405,75 @@ pub fn zone_create(config: &HvZoneConfig) -> HvResult<Arc<RwLock<Zone>>> {
         &config.alloc_pci_devs,
     );*/
 
-    zone.arch_zone_post_configuration(config)?;
+    zone.arch_zone_post_configuration(config)
+}
+
+/// Give back what a failed `zone_setup` took, as `zone_shutdown` does.
+fn zone_setup_undo(zone: Zone) {
+    let zone_id = zone.id;
+    ivc_remove(zone_id);
+    virtio_pci_remove(zone_id);
+    msix_remove(zone_id);
+    pci_zone_remove(&Arc::new(RwLock::new(zone)));
+}
+
+pub fn zone_create(config: &HvZoneConfig) -> HvResult<Arc<RwLock<Zone>>> {
+    // we create the new zone here
+    // TODO: create Zone with cpu_set
+    let zone_id = config.zone_id as usize;
+
+    if find_zone(zone_id).is_some() {
+        return hv_result_err!(
+            EINVAL,
+            format!("Failed to create zone: zone_id {} already exists", zone_id)
+        );
+    }
+
+    #[cfg(not(feature = "sched"))]
+    for cpu_id in config.cpus().iter() {
+        if let Some(zone) = get_cpu_data(*cpu_id as _).zone.clone() {
+            return hv_result_err!(
+                EBUSY,
+                format!(
+                    "Failed to create zone: cpu {} already belongs to zone {}",
+                    cpu_id,
+                    zone.read().id
+                )
+            );
+        }
+    }
+    // a vcpu on each cpu of the config, given back if creation fails
+    #[cfg(feature = "sched")]
+    let vcpus = crate::sched::reserve_vcpus(&config.cpus())?;
+
+    let mut zone = Zone::new(zone_id, &config.name);
+    zone.memory_regions = config.memory_regions().to_vec();
+    zone.pci_devs = config.pci_devs().to_vec();
+    zone.pt_init(config.memory_regions())?;
+    zone.mmio_init(&config.arch_config);
+
+    #[cfg(not(feature = "sched"))]
+    let cpu_ids = config.cpus().into_iter().map(|cpu_id| cpu_id as usize);
+    #[cfg(feature = "sched")]
+    let cpu_ids = vcpus.vcpus();
+    let mut cpu_num = 0;
+    for cpu_id in cpu_ids {
+        zone.cpu_set.set_bit(cpu_id);
+        cpu_num += 1;
+    }
+    zone.cpu_num = cpu_num;
+    info!("zone cpu_set: {:#b}", zone.cpu_set.bitmap);
+
+    if let Err(e) = zone_setup(&mut zone, config) {
+        zone_setup_undo(zone);
+        return Err(e);
+    }
+    let cpu_set = zone.cpu_set;
 
     // Initialize the virtual interrupt controller, it needs zone.cpu_num
     zone.virqc_init(config);
+    zone.watchdog_init(config.watchdog());
+    zone.native_virtio_init(config.native_virtio());
 
     zone.irq_bitmap_init(config.interrupts_bitmap());
 
@@ -372,6 +487,11 @@ pub fn zone_create(config: &HvZoneConfig) -> HvResult<Arc<RwLock<Zone>>> {
         }
     }
 
+    zone.entry_point = config.entry_point as _;
+    zone.dtb_ipa = dtb_ipa as _;
+    // cpus are reused across zones, don't report the previous owner's counters
+    zone.stats_reset();
+
     let new_zone_pointer = Arc::new(RwLock::new(zone));
     {
         cpu_set.iter().for_each(|cpuid| {
@@ -389,10 +509,284 @@ pub fn zone_create(config: &HvZoneConfig) -> HvResult<Arc<RwLock<Zone>>> {
             }
         });
     }
+    #[cfg(feature = "sched")]
+    vcpus.commit(&config.sched());
 
     Ok(new_zone_pointer)
 }
 
+/// Reboot `zone` in place: stop its vcpus, reset the virtual interrupt controller and
+/// restart the boot cpu at the zone's entry with the original dtb.
+///
+/// The stage-2 table, mmio handlers and vpci devices are kept. If the current cpu belongs
+/// to the zone it is left running, and the caller has to restart or park it.
+pub fn zone_reboot(zone: &Arc<RwLock<Zone>>) -> HvResult {
+    let zone_r = zone.read();
+    let zone_id = zone_r.id;
+    let cpu_set = zone_r.cpu_set;
+    let (entry, dtb_ipa) = (zone_r.entry_point, zone_r.dtb_ipa);
+    drop(zone_r);
+    info!("zone {} rebooting, entry: {:#x}", zone_id, entry);
+
+    let this_id = this_cpu_data().id;
+    // avoid virtio daemon send sgi to the rebooting zone
+    let mut map_irq = VIRTIO_IRQS.lock();
+    cpu_set.iter().for_each(|cpu_id| {
+        if cpu_id != this_id {
+            let _lock = get_cpu_data(cpu_id).ctrl_lock.lock();
+            send_event(cpu_id, SGI_IPI_ID as _, IPI_EVENT_SHUTDOWN);
+        }
+        if let Some(irq_list) = map_irq.get_mut(&cpu_id) {
+            irq_list.clear();
+        }
+    });
+    drop(map_irq);
+
+    let mut count: usize = 0;
+    while cpu_set.iter_except(this_id).any(|cpu_id| {
+        let _lock = get_cpu_data(cpu_id).ctrl_lock.lock();
+        get_cpu_data(cpu_id).arch_cpu.power_on
+    }) {
+        count += 1;
+        if count > MAX_WAIT_TIMES {
+            return hv_result_err!(
+                EBUSY,
+                format!("zone {} reboot: cpus cannot be stopped", zone_id)
+            );
+        }
+        cpu_relax();
+    }
+    // its peers keep the grants they failed to unmap, so a retried reboot can
+    // finish the job
+    grant_reset(zone_id)?;
+
+    let mut zone_w = zone.write();
+    zone_w.arch_irqchip_reset();
+    zone_w.is_err = false;
+    zone_w.is_paused = false;
+    drop(zone_w);
+    watchdog_reset(zone_id);
+    virtio_mmio_reset(zone_id);
+    native_virtio_reset(zone_id);
+    virtio_pci_reset(zone_id);
+    msix_reset(zone_id);
+    pci_zone_reset(zone);
+
+    cpu_set.iter().for_each(|cpu_id| {
+        let cpu_data = get_cpu_data(cpu_id);
+        let _lock = cpu_data.ctrl_lock.lock();
+        cpu_data.cpu_on_entry = entry;
+        cpu_data.dtb_ipa = dtb_ipa;
+    });
+
+    let boot_cpu = cpu_set.first_cpu().unwrap();
+    if boot_cpu != this_id {
+        let _lock = get_cpu_data(boot_cpu).ctrl_lock.lock();
+        send_event(boot_cpu, SGI_IPI_ID as _, IPI_EVENT_WAKEUP);
+    }
+    Ok(())
+}
+
+/// Stop every cpu of the non-root zone `zone_id` and remove it from the zone list.
+pub fn zone_shutdown(zone_id: usize) -> HvResult {
+    let zone = match find_zone(zone_id) {
+        Some(zone) => zone,
+        _ => {
+            return hv_result_err!(
+                EINVAL,
+                format!("Shutdown zone: zone {} not found!", zone_id)
+            )
+        }
+    };
+    // the peers have to unmap the zone's grants before its memory goes back to
+    // the root zone, keep it paused meanwhile so that it grants nothing new
+    let need_pause = !core::mem::replace(&mut zone.write().is_paused, true);
+    if need_pause {
+        if let Err(e) = Zone::pause(&zone) {
+            zone.write().is_paused = false;
+            zone.read().resume();
+            return Err(e);
+        }
+    }
+    // unmapping its grants pauses the peers, whose cpus may wait for VIRTIO_IRQS
+    if let Err(e) = grant_remove(zone_id) {
+        if need_pause {
+            zone.write().is_paused = false;
+            zone.read().resume();
+        }
+        return Err(e);
+    }
+    drop(zone);
+
+    // avoid virtio daemon send sgi to the shutdowning zone
+    let mut map_irq = VIRTIO_IRQS.lock();
+
+    let zone = match find_zone(zone_id) {
+        Some(zone) => zone,
+        _ => {
+            return hv_result_err!(
+                EINVAL,
+                format!("Shutdown zone: zone {} not found!", zone_id)
+            )
+        }
+    };
+    let zone_w = zone.write();
+    let cpu_set = zone_w.cpu_set;
+
+    cpu_set.iter().for_each(|cpu_id| {
+        let _lock = get_cpu_data(cpu_id).ctrl_lock.lock();
+        get_cpu_data(cpu_id).cpu_on_entry = INVALID_ADDRESS;
+        send_event(cpu_id, SGI_IPI_ID as _, IPI_EVENT_SHUTDOWN);
+        // set the virtio irq list's len to 0
+        if let Some(irq_list) = map_irq.get_mut(&cpu_id) {
+            irq_list.clear();
+        }
+    });
+    // the cpus may need the locks on their way to idle, with `sched` even on this cpu
+    drop(zone_w);
+    drop(map_irq);
+
+    let mut count: usize = 0;
+
+    // wait all zone's cpus shutdown
+    while cpu_set.iter().any(|cpu_id| {
+        let _lock = get_cpu_data(cpu_id).ctrl_lock.lock();
+        let power_on = get_cpu_data(cpu_id).arch_cpu.power_on;
+        count += 1;
+        if count > MAX_WAIT_TIMES {
+            if power_on {
+                error!("cpu {} cannot be shut down", cpu_id);
+                return false;
+            }
+        }
+        power_on
+    }) {
+        cpu_relax();
+    }
+
+    let mut map_irq = VIRTIO_IRQS.lock();
+    let zone_w = zone.write();
+    cpu_set.iter().for_each(|cpu_id| {
+        let _lock = get_cpu_data(cpu_id).ctrl_lock.lock();
+        get_cpu_data(cpu_id).zone = None;
+        if let Some(irq_list) = map_irq.get_mut(&cpu_id) {
+            irq_list.clear();
+        }
+        #[cfg(feature = "sched")]
+        crate::sched::release_vcpu(cpu_id);
+    });
+    zone_w.arch_irqchip_reset();
+    drop(map_irq);
+
+    drop(zone_w);
+    drop(zone);
+    watchdog_remove(zone_id);
+    virtio_mmio_remove(zone_id);
+    virtio_zone_bridge_remove(zone_id);
+    native_virtio_remove(zone_id);
+    ivc_remove(zone_id);
+    virtio_pci_remove(zone_id);
+    msix_remove(zone_id);
+    // nothing injects into a zone that can't be found, so the function resets
+    // don't have to hold up the other zones' irqs
+    let zone = take_zone(zone_id);
+    drop(map_irq);
+    // the zone's dma has to stop before its memory goes back to the root zone
+    pci_zone_remove(&zone);
+    assert_eq!(Arc::strong_count(&zone), 1);
+    drop(zone);
+    Ok(())
+}
+
+/// Guest-initiated reset of the current zone, e.g. PSCI SYSTEM_RESET or SBI SRST.
+pub fn this_zone_reboot() -> ! {
+    let zone = this_zone();
+    let result = zone_reboot(&zone);
+    let boot_cpu = zone.read().cpu_set.first_cpu();
+    if let Err(e) = &result {
+        error!("zone {} reboot failed: {:?}", zone.read().id, e);
+    }
+    drop(zone);
+
+    let cpu_data = this_cpu_data();
+    // `zone_reboot` wakes the first cpu of the zone unless it is this one
+    if result.is_ok() && boot_cpu == Some(cpu_data.id) {
+        let lock = cpu_data.ctrl_lock.lock();
+        cpu_data.arch_cpu.power_on = false;
+        drop(lock);
+        cpu_data.arch_cpu.run();
+    }
+    cpu_data.arch_cpu.idle()
+}
+
+/// Move the powered-off `cpu_id` from zone `from` to zone `to`.
+///
+/// The cpu must have been taken offline in `from` (PSCI CPU_OFF, SBI HSM HART_STOP or
+/// INIT) and stays off in `to` until its guest starts it with PSCI CPU_ON, SBI HSM
+/// HART_START or INIT-SIPI.
+pub fn zone_cpu_move(from: &Arc<RwLock<Zone>>, to: &Arc<RwLock<Zone>>, cpu_id: usize) -> HvResult {
+    // same lock order as the virtio irq path: VIRTIO_IRQS, zone, ctrl_lock
+    let mut map_irq = VIRTIO_IRQS.lock();
+    let mut from_w = from.write();
+    let mut to_w = to.write();
+    let (from_id, to_id) = (from_w.id, to_w.id);
+    if !from_w.cpu_set.contains_cpu(cpu_id) {
+        return hv_result_err!(
+            EINVAL,
+            format!("cpu {} does not belong to zone {}", cpu_id, from_id)
+        );
+    }
+    if from_w.cpu_num == 1 {
+        return hv_result_err!(
+            EBUSY,
+            format!("cpu {} is the last cpu of zone {}", cpu_id, from_id)
+        );
+    }
+    if from_w.is_paused || to_w.is_paused {
+        return hv_result_err!(EBUSY, "cannot move cpus of a paused zone");
+    }
+
+    let cpu_data = get_cpu_data(cpu_id);
+    let _lock = cpu_data.ctrl_lock.lock();
+    if cpu_data.arch_cpu.power_on {
+        return hv_result_err!(
+            EBUSY,
+            format!("cpu {} is still online in zone {}", cpu_id, from_id)
+        );
+    }
+
+    from_w.cpu_detach(cpu_id)?;
+    if let Err(e) = to_w.cpu_attach(cpu_id) {
+        if let Err(e) = from_w.cpu_attach(cpu_id) {
+            error!(
+                "cpu {} cannot be given back to zone {}: {:?}",
+                cpu_id, from_id, e
+            );
+        }
+        return Err(e);
+    }
+
+    #[cfg(target_arch = "aarch64")]
+    {
+        let boot_cpu = to_w.cpu_set.first_cpu().unwrap();
+        cpu_data.arch_cpu.is_aarch32 = get_cpu_data(boot_cpu).arch_cpu.is_aarch32;
+    }
+    cpu_data.zone = Some(to.clone());
+    cpu_data.boot_cpu = false;
+    cpu_data.cpu_on_entry = INVALID_ADDRESS;
+    cpu_data.dtb_ipa = to_w.dtb_ipa;
+    cpu_data.stats.reset();
+    crate::event::clear_events(cpu_id);
+    if let Some(irq_list) = map_irq.get_mut(&cpu_id) {
+        irq_list.clear();
+    }
+    info!(
+        "cpu {} moved from zone {} to zone {}, cpu_set: {:#b} -> {:#b}",
+        cpu_id, from_id, to_id, from_w.cpu_set.bitmap, to_w.cpu_set.bitmap
+    );
+    Ok(())
+}
+
 #[repr(C)]
 #[derive(Clone, Copy, Debug)]
 pub struct ZoneInfo {